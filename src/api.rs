@@ -0,0 +1,435 @@
+use axum::{
+    extract::{FromRef, Path, Query, State},
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{
+    bot,
+    config::Config,
+    db::{Database, SubscribeError},
+    filter, reddit,
+    state::BotState,
+    types::{SubscribeOutcome, Subscription, SubscriptionArgs},
+};
+
+/// State shared by the API's handlers, which take the parts they need.
+#[derive(Clone)]
+struct ApiState {
+    config: Arc<Config>,
+    bot_state: Arc<BotState>,
+}
+
+impl FromRef<ApiState> for Arc<Config> {
+    fn from_ref(state: &ApiState) -> Self {
+        state.config.clone()
+    }
+}
+
+impl FromRef<ApiState> for Arc<BotState> {
+    fn from_ref(state: &ApiState) -> Self {
+        state.bot_state.clone()
+    }
+}
+
+/// Error returned to API clients as `{"error": "..."}`.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        error!("api request failed: {err:?}");
+        ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+    }
+}
+
+impl From<SubscribeError> for ApiError {
+    fn from(err: SubscribeError) -> Self {
+        let status = match err {
+            SubscribeError::AlreadySubscribed(_) => StatusCode::CONFLICT,
+            SubscribeError::QuotaExceeded(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            SubscribeError::Blocked(_) => StatusCode::FORBIDDEN,
+            SubscribeError::Db(err) => return err.into(),
+        };
+        ApiError::new(status, err.to_string())
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: self.message,
+        };
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct UnsubscribeQuery {
+    subreddit: String,
+}
+
+#[derive(Debug, Serialize)]
+struct UnsubscribeResponse {
+    subreddit: String,
+}
+
+pub fn router(config: Arc<Config>, bot_state: Arc<BotState>) -> Router {
+    Router::new()
+        .route(
+            "/chats/:chat_id/subscriptions",
+            get(list_subscriptions)
+                .post(add_subscription)
+                .delete(remove_subscription),
+        )
+        .route("/metrics", get(metrics))
+        .route_layer(middleware::from_fn_with_state(config.clone(), authorize))
+        .with_state(ApiState { config, bot_state })
+}
+
+/// Serves the API until `shutdown` resolves.
+pub async fn serve(
+    config: Arc<Config>,
+    bot_state: Arc<BotState>,
+    addr: SocketAddr,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> anyhow::Result<()> {
+    info!("serving api on {addr}");
+    axum::Server::try_bind(&addr)?
+        .serve(router(config, bot_state).into_make_service())
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    Ok(())
+}
+
+/// Requires `Authorization: Bearer <api_token>` on every request. Without a configured token all
+/// requests are rejected.
+async fn authorize<B>(
+    State(config): State<Arc<Config>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ApiError> {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match (&config.api_token, token) {
+        (Some(expected), Some(token)) if expected.expose_secret() == token => {
+            Ok(next.run(request).await)
+        }
+        _ => Err(ApiError::new(StatusCode::UNAUTHORIZED, "Unauthorized")),
+    }
+}
+
+/// Fetch durations and Reddit's rate limit in Prometheus text exposition format.
+async fn metrics(State(bot_state): State<Arc<BotState>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        format!("{}\n", bot_state.render_metrics()),
+    )
+}
+
+async fn list_subscriptions(
+    State(config): State<Arc<Config>>,
+    Path(chat_id): Path<i64>,
+) -> Result<Json<Vec<Subscription>>, ApiError> {
+    let db = Database::open(&config)?;
+    Ok(Json(db.get_subscriptions_for_chat(chat_id)?))
+}
+
+async fn add_subscription(
+    State(config): State<Arc<Config>>,
+    Path(chat_id): Path<i64>,
+    Json(args): Json<SubscriptionArgs>,
+) -> Result<(StatusCode, Json<SubscriptionArgs>), ApiError> {
+    if args.subreddit.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Subreddit is required",
+        ));
+    }
+
+    if args
+        .min_upvote_ratio
+        .is_some_and(|ratio| !filter::is_valid_upvote_ratio(ratio))
+    {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "min_upvote_ratio must be between 0.0 and 1.0",
+        ));
+    }
+
+    if args
+        .top_percentile
+        .is_some_and(|percentile| !filter::is_valid_top_percentile(percentile))
+    {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "top_percentile must be between 1 and 100",
+        ));
+    }
+
+    let db = Database::open(&config)?;
+    let (subreddit, outcome) = bot::subscribe(&config, &db, chat_id, args.clone()).await?;
+    match outcome {
+        SubscribeOutcome::Subscribed => {}
+        SubscribeOutcome::Rejected(err) => return Err(err.into()),
+        SubscribeOutcome::NoSuchSubreddit => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                format!("No such subreddit r/{subreddit}"),
+            ))
+        }
+        SubscribeOutcome::Frontpage => {
+            return Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                reddit::FRONTPAGE_REQUIRES_OAUTH,
+            ))
+        }
+    }
+    info!("subscribed in chat id {chat_id} to r/{subreddit} via api");
+    Ok((
+        StatusCode::CREATED,
+        Json(SubscriptionArgs { subreddit, ..args }),
+    ))
+}
+
+async fn remove_subscription(
+    State(config): State<Arc<Config>>,
+    Path(chat_id): Path<i64>,
+    Query(query): Query<UnsubscribeQuery>,
+) -> Result<Json<UnsubscribeResponse>, ApiError> {
+    let db = Database::open(&config)?;
+    let subreddit = db.unsubscribe(chat_id, &query.subreddit)?.ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            format!("Not subscribed to r/{}", query.subreddit),
+        )
+    })?;
+    Ok(Json(UnsubscribeResponse { subreddit }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SecretString;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    const TOKEN: &str = "secret";
+
+    fn test_config(db_name: &str) -> Arc<Config> {
+        Arc::new(Config {
+            db_path: db_name.into(),
+            api_token: Some(SecretString::new(TOKEN.to_string())),
+            ..Default::default()
+        })
+    }
+
+    /// Serves about.json like Reddit does: r/rust exists and is spelled `Rust`, other subreddits
+    /// redirect to the search. Returns the url to use as the reddit base url.
+    async fn spawn_mock_reddit() -> String {
+        use axum::{http::HeaderValue, response::IntoResponse};
+
+        async fn about(Path(subreddit): Path<String>) -> Response {
+            if subreddit.eq_ignore_ascii_case("rust") {
+                Json(serde_json::json!({
+                    "kind": "t5",
+                    "data": { "display_name": "Rust", "display_name_prefixed": "r/Rust" }
+                }))
+                .into_response()
+            } else {
+                let mut response = StatusCode::FOUND.into_response();
+                response.headers_mut().insert(
+                    header::LOCATION,
+                    HeaderValue::from_static("/subreddits/search.json"),
+                );
+                response
+            }
+        }
+
+        let app = Router::new().route("/r/:subreddit/about.json", get(about));
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    fn request(method: &str, uri: &str, body: Option<serde_json::Value>) -> Request<Body> {
+        let builder = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {TOKEN}"));
+        match body {
+            Some(body) => builder
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+            None => builder.body(Body::empty()).unwrap(),
+        }
+    }
+
+    async fn send(config: &Arc<Config>, request: Request<Body>) -> (StatusCode, serde_json::Value) {
+        let response = router(config.clone(), Arc::new(BotState::new()))
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let json = serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null);
+        (status, json)
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_endpoints() {
+        let config = Arc::new(Config {
+            db_path: "api_test_subscriptions_endpoints".into(),
+            api_token: Some(SecretString::new(TOKEN.to_string())),
+            reddit_base_url: Some(spawn_mock_reddit().await),
+            ..Default::default()
+        });
+        // Keeps the shared in-memory database alive for the duration of the test
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+
+        let (status, body) = send(&config, request("GET", "/chats/1/subscriptions", None)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!([]));
+
+        let (status, body) = send(
+            &config,
+            request(
+                "POST",
+                "/chats/1/subscriptions",
+                Some(serde_json::json!({ "subreddit": "rust", "limit": 5, "type": "video" })),
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        // Stored with the name spelled as on Reddit
+        assert_eq!(body["subreddit"], "Rust");
+
+        let (status, _) = send(
+            &config,
+            request(
+                "POST",
+                "/chats/1/subscriptions",
+                Some(serde_json::json!({ "subreddit": "RUST" })),
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let (status, _) = send(
+            &config,
+            request(
+                "POST",
+                "/chats/1/subscriptions",
+                Some(serde_json::json!({ "subreddit": "doesnotexist" })),
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = send(&config, request("GET", "/chats/1/subscriptions", None)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["subreddit"], "Rust");
+        assert_eq!(body[0]["limit"], 5);
+        assert_eq!(body[0]["type"], "video");
+
+        // Wildcards don't match other subscriptions
+        let (status, _) = send(
+            &config,
+            request("DELETE", "/chats/1/subscriptions?subreddit=%25", None),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(db.get_subscriptions_for_chat(1).unwrap().len(), 1);
+
+        let (status, body) = send(
+            &config,
+            request("DELETE", "/chats/1/subscriptions?subreddit=rust", None),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["subreddit"], "Rust");
+        assert!(db.get_subscriptions_for_chat(1).unwrap().is_empty());
+
+        let (status, _) = send(
+            &config,
+            request("DELETE", "/chats/1/subscriptions?subreddit=rust", None),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_requires_bearer_token() {
+        let config = test_config("api_test_requires_bearer_token");
+
+        let request = Request::builder()
+            .uri("/chats/1/subscriptions")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(&config, request).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let request = Request::builder()
+            .uri("/chats/1/subscriptions")
+            .header(header::AUTHORIZATION, "Bearer wrong")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = send(&config, request).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint() {
+        let config = test_config("api_test_metrics_endpoint");
+        let bot_state = Arc::new(BotState::new());
+        bot_state.record_fetch("rust", std::time::Duration::from_millis(300));
+
+        let response = router(config.clone(), bot_state.clone())
+            .oneshot(request("GET", "/metrics", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(body.contains("tgreddit_fetch_duration_seconds_count{subreddit=\"rust\"} 1\n"));
+
+        let request = Request::builder()
+            .uri("/metrics")
+            .body(Body::empty())
+            .unwrap();
+        let response = router(config, bot_state).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
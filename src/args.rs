@@ -7,6 +7,7 @@ pub fn parse_args() -> getopts::Matches {
     let mut opts = Options::new();
     opts.optopt("", "debug-post", "", "");
     opts.optopt("", "chat-id", "", "");
+    opts.optflag("", "check-db", "");
     match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
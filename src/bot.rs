@@ -1,16 +1,27 @@
 use crate::{handle_post::handle_video_link, *};
 use anyhow::Result;
+use itertools::Itertools;
 use lazy_static::lazy_static;
 use regex::Regex;
-use std::{env, sync::Arc};
+use std::{env, str::FromStr, sync::Arc};
 use teloxide::{
-    dispatching::DefaultKey,
-    types::MessageId,
+    dispatching::{DefaultKey, UpdateHandler},
+    types::{ChatMemberKind, MessageId},
+    update_listeners::webhooks,
     utils::command::{BotCommands, ParseError},
 };
+use thiserror::Error;
 use url::Url;
 
 const TELEGRAM_BOT_API_URL_ENV: &str = "TELEGRAM_BOT_API_URL";
+/// Maximum number of posts listed by /history.
+const HISTORY_LIMIT: u32 = 20;
+/// Maximum number of posts listed by /queue.
+const QUEUE_LIMIT: u32 = 20;
+/// Maximum number of subreddits listed by /metrics.
+const METRICS_SUBREDDIT_LIMIT: usize = 10;
+/// Days without a delivered post after which /inactivesubs lists a subscription by default.
+const DEFAULT_INACTIVE_DAYS: i64 = 30;
 
 #[derive(BotCommands, Clone)]
 #[command(
@@ -25,28 +36,180 @@ pub enum Command {
         parse_with = parse_subscribe_message
     )]
     Sub(SubscriptionArgs),
+    #[command(
+        rename = "subscribe_many",
+        description = "subscribe to several subreddits with the same options: /subscribe_many <subreddit>... [option=value]..."
+    )]
+    SubscribeMany(String),
+    #[command(
+        description = "replace the options of a subscription without sending its posts again: /resubscribe <subreddit> [option=value]...",
+        parse_with = parse_subscribe_message
+    )]
+    Resubscribe(SubscriptionArgs),
     #[command(description = "unsubscribe from subreddit's top posts")]
     Unsub(String),
-    #[command(description = "list subreddit subscriptions")]
-    ListSubs,
+    #[command(
+        description = "change the number of top posts considered: /limit <subreddit> <limit>"
+    )]
+    Limit(String),
+    #[command(
+        description = "list subreddit subscriptions, sorted by name, created, delivered or errors"
+    )]
+    ListSubs(String),
+    #[command(description = "show the options in effect for a subscription: /show <subreddit>")]
+    Show(String),
+    #[command(
+        description = "send a subscription's posts to the forum topic this is sent in: /topic <subreddit> [here|off]"
+    )]
+    Topic(String),
+    #[command(description = "label a subscription with a group: /group <subreddit> <group|off>")]
+    Group(String),
+    #[command(description = "list subscriptions by group, or only those of one: /mysubs [group]")]
+    MySubs(String),
+    #[command(description = "list supported filters and options")]
+    Filters,
     #[command(description = "get top posts", parse_with = parse_subscribe_message)]
     Get(SubscriptionArgs),
     #[command(description = "register channel to which the bot is supposed to post")]
     RegisterChannel(i64),
-    #[command(description = "repost to the registered channel", parse_with = "split")]
+    #[command(description = "unregister a repost channel")]
+    UnregisterChannel(i64),
+    #[command(description = "list registered repost channels")]
+    ListChannels,
+    #[command(
+        description = "repost to the registered channels",
+        parse_with = "split"
+    )]
     RepostToChannel {
         message_id: i32,
         description: String,
     },
+    #[command(
+        description = "deliver unseen posts created since a time, e.g. 2023-05-01T12:00:00Z, 2023-05-01 or 24h"
+    )]
+    Since(String),
+    #[command(
+        description = "stop sending posts for a while, e.g. /snooze 8h, or resume with /snooze off"
+    )]
+    Snooze(String),
+    #[command(description = "delete all data stored about this chat")]
+    Forget(String),
+    #[command(description = "show bot version, uptime and poll status")]
+    Status,
+    #[command(description = "show subreddit fetch duration metrics")]
+    Metrics,
+    #[command(description = "show how much of reddit's rate limit is left")]
+    RedditStatus,
+    #[command(description = "show the database file size and row counts")]
+    DbStats,
+    #[command(
+        description = "list subscriptions of all chats that delivered nothing for a while, e.g. /inactivesubs 30d"
+    )]
+    InactiveSubs(String),
+    #[command(description = "check all subscriptions for new posts now")]
+    PollNow,
+    #[command(description = "deliver posts without notification: on or off")]
+    Silent(String),
+    #[command(
+        description = "deliver new posts of all subscriptions as one chronological feed: on or off"
+    )]
+    MergedFeed(String),
+    #[command(description = "cover the media of NSFW posts with a spoiler: on or off")]
+    NsfwSpoiler(String),
+    #[command(
+        description = "send a header before the first post of each day: on, off or the header text, where {date} is replaced with the date"
+    )]
+    DailyHeader(String),
+    #[command(
+        description = "choose what link posts show: thumbnail, preview (telegram's link preview) or none"
+    )]
+    WebPreview(String),
+    #[command(description = "choose how captions are formatted: html, markdownv2 or none")]
+    ParseMode(String),
+    #[command(
+        description = "choose the sort of subscriptions created without one: top, new, hot, rising or off"
+    )]
+    DefaultSort(String),
+    #[command(
+        description = "choose what captions show: title, flair, score, author, subreddit, comments, hashtag, or default"
+    )]
+    Caption(String),
+    #[command(description = "show how a reddit post would be sent, without sending it")]
+    TestMedia(String),
+    #[command(description = "copy subscriptions and settings of another chat to this chat")]
+    Clone(String),
+    #[command(
+        description = "skip posts already seen in another chat: /link <chat id>, or list linked chats"
+    )]
+    Link(String),
+    #[command(description = "stop skipping posts seen in another chat: /unlink <chat id>")]
+    Unlink(String),
+    #[command(
+        description = "fix the stored name of a subscription, e.g. its casing: /renamesub <from> <to>"
+    )]
+    RenameSub(String),
+    #[command(description = "search titles of posts delivered to this chat")]
+    History(String),
+    #[command(description = "show which subscriptions delivered the same links or crossposts")]
+    Overlap,
+    #[command(
+        description = "show which subscriptions delivered the most content already delivered from another"
+    )]
+    Duplicates,
+    #[command(
+        description = "show what is stored about a post delivered to this chat: /post <post id>"
+    )]
+    Post(String),
+    #[command(description = "export the posts delivered to this chat as a csv file")]
+    ExportHistory,
+    #[command(description = "list posts waiting to be sent to this chat")]
+    Queue,
+    #[command(
+        description = "check that the bot may send posts here, or to another chat: /checkperms [chat id]"
+    )]
+    CheckPerms(String),
+    #[command(
+        description = "show the stored row of a subscription: /debug sub <chat id> <subreddit>"
+    )]
+    Debug(String),
 }
 
 pub struct MyBot {
     pub dispatcher: Dispatcher<Arc<Bot>, anyhow::Error, DefaultKey>,
     pub tg: Arc<Bot>,
+    config: Arc<config::Config>,
+}
+
+/// Handles every update, regardless of whether it was received with long polling or a webhook.
+fn schema() -> UpdateHandler<anyhow::Error> {
+    dptree::entry()
+        .branch(
+            Update::filter_message().branch(
+                dptree::filter(|msg: Message, config: Arc<config::Config>| {
+                    msg.from()
+                        .map(|user| config.authorized_user_ids.contains(&user.id.0))
+                        .unwrap_or_default()
+                })
+                .branch(
+                    dptree::entry()
+                        .filter_command::<Command>()
+                        .endpoint(handle_command),
+                )
+                .branch(dptree::entry().endpoint(handle_no_command)),
+            ),
+        )
+        .branch(
+            Update::filter_callback_query().branch(
+                dptree::filter(|msg: CallbackQuery, config: Arc<config::Config>| {
+                    config.authorized_user_ids.contains(&msg.from.id.0)
+                })
+                .endpoint(callback_handler),
+            ),
+        )
 }
 
 impl MyBot {
-    pub async fn new(config: Arc<config::Config>) -> Result<Self> {
+    pub async fn new(config: Arc<config::Config>, state: Arc<BotState>) -> Result<Self> {
         let client = teloxide::net::default_reqwest_settings()
             .timeout(Duration::from_secs(600))
             .build()
@@ -63,33 +226,8 @@ impl MyBot {
 
         let tg = Arc::new(tg);
 
-        let handler = dptree::entry()
-            .branch(
-                Update::filter_message().branch(
-                    dptree::filter(|msg: Message, config: Arc<config::Config>| {
-                        msg.from()
-                            .map(|user| config.authorized_user_ids.contains(&user.id.0))
-                            .unwrap_or_default()
-                    })
-                    .branch(
-                        dptree::entry()
-                            .filter_command::<Command>()
-                            .endpoint(handle_command),
-                    )
-                    .branch(dptree::entry().endpoint(handle_no_command)),
-                ),
-            )
-            .branch(
-                Update::filter_callback_query().branch(
-                    dptree::filter(|msg: CallbackQuery, config: Arc<config::Config>| {
-                        config.authorized_user_ids.contains(&msg.from.id.0)
-                    })
-                    .endpoint(callback_handler),
-                ),
-            );
-
-        let dispatcher = Dispatcher::builder(tg.clone(), handler)
-            .dependencies(dptree::deps![config.clone()])
+        let dispatcher = Dispatcher::builder(tg.clone(), schema())
+            .dependencies(dptree::deps![config.clone(), state])
             .default_handler(|upd| async move {
                 warn!("unhandled update: {:?}", upd);
             })
@@ -98,21 +236,50 @@ impl MyBot {
             ))
             .build();
 
-        let my_bot = MyBot { dispatcher, tg };
+        let my_bot = MyBot {
+            dispatcher,
+            tg,
+            config,
+        };
         Ok(my_bot)
     }
 
-    pub fn spawn(
+    /// Receives updates with a webhook if `telegram_webhook_url` and `telegram_webhook_addr` are
+    /// configured, and with long polling otherwise.
+    pub async fn spawn(
         mut self,
-    ) -> (
+    ) -> Result<(
         tokio::task::JoinHandle<()>,
         teloxide::dispatching::ShutdownToken,
-    ) {
+    )> {
         let shutdown_token = self.dispatcher.shutdown_token();
-        (
-            tokio::spawn(async move { self.dispatcher.dispatch().await }),
-            shutdown_token,
-        )
+        let handle = match (
+            &self.config.telegram_webhook_url,
+            self.config.telegram_webhook_addr,
+        ) {
+            (Some(url), Some(addr)) => {
+                let url = Url::parse(url).context("invalid telegram_webhook_url")?;
+                info!("receiving updates with webhook {url} on {addr}");
+                let listener = webhooks::axum(self.tg.clone(), webhooks::Options::new(addr, url))
+                    .await
+                    .context("failed to set up telegram webhook")?;
+                tokio::spawn(async move {
+                    self.dispatcher
+                        .dispatch_with_listener(
+                            listener,
+                            LoggingErrorHandler::with_custom_text(
+                                "an error from the webhook listener",
+                            ),
+                        )
+                        .await
+                })
+            }
+            (None, None) => tokio::spawn(async move { self.dispatcher.dispatch().await }),
+            _ => anyhow::bail!(
+                "telegram_webhook_url and telegram_webhook_addr must be configured together"
+            ),
+        };
+        Ok((handle, shutdown_token))
     }
 }
 
@@ -134,7 +301,7 @@ pub async fn handle_no_command(
         // Check if the text matches the YouTube regex
         if RE_YOUTUBE.is_match(text) {
             let link = Url::parse(text)?;
-            handle_video_link(&db, tg, message.chat.id.0, &link).await?;
+            handle_video_link(config, &db, tg, message.chat.id.0, &link).await?;
         } else {
             let id = RE_REDDIT
                 .captures(text)
@@ -142,8 +309,17 @@ pub async fn handle_no_command(
                 .get(1)
                 .context("Couldn't find reddit post id")?
                 .as_str();
-            let post = reddit::get_link(id).await?;
-            process_post(&db, message.chat.id.0, &post, config, tg).await?;
+            let post = reddit::RedditClient::new(config)?.get_link(id).await?;
+            process_post(
+                &db,
+                message.chat.id.0,
+                message.thread_id,
+                false,
+                &post,
+                config,
+                tg,
+            )
+            .await?;
         }
 
         Ok(())
@@ -162,12 +338,14 @@ pub async fn handle_command(
     tg: Arc<Bot>,
     command: Command,
     config: Arc<config::Config>,
+    state: Arc<BotState>,
 ) -> Result<()> {
     async fn handle(
         message: &Message,
         tg: &Bot,
         command: Command,
         config: Arc<config::Config>,
+        state: Arc<BotState>,
     ) -> Result<()> {
         let db = db::Database::open(&config)?;
         match command {
@@ -175,54 +353,211 @@ pub async fn handle_command(
                 tg.send_message(message.chat.id, Command::descriptions().to_string())
                     .await?;
             }
-            Command::Sub(mut args) => {
+            Command::Sub(args) => {
                 let chat_id = message.chat.id.0;
-                let subreddit_about = reddit::get_subreddit_about(&args.subreddit).await;
-                match subreddit_about {
-                    Ok(data) => {
-                        args.subreddit = data.display_name;
-                        db.subscribe(chat_id, &args)?;
-                        info!("subscribed in chat id {chat_id} with {args:#?};");
-                        tg.send_message(
-                            ChatId(chat_id),
-                            format!("Subscribed to r/{}", args.subreddit),
-                        )
-                        .await?;
-                    }
-                    Err(reddit::SubredditAboutError::NoSuchSubreddit) => {
-                        tg.send_message(ChatId(chat_id), "No such subreddit")
-                            .await?;
-                    }
+                let (subreddit, outcome) = subscribe(&config, &db, chat_id, args).await?;
+                let reply = match outcome {
+                    SubscribeOutcome::Subscribed => format!("Subscribed to r/{subreddit}"),
+                    SubscribeOutcome::Rejected(err) => format!("Error: {err}"),
+                    SubscribeOutcome::NoSuchSubreddit => "No such subreddit".to_string(),
+                    SubscribeOutcome::Frontpage => reddit::FRONTPAGE_REQUIRES_OAUTH.to_string(),
+                };
+                tg.send_message(ChatId(chat_id), reply).await?;
+            }
+            Command::SubscribeMany(input) => {
+                let chat_id = message.chat.id.0;
+                let all_args = match parse_subscribe_many_args(&input) {
+                    Ok(all_args) => all_args,
                     Err(err) => {
-                        Err(err).context("Couldn't download about.json for subreddit")?;
+                        tg.send_message(ChatId(chat_id), format!("Error: {err}"))
+                            .await?;
+                        return Ok(());
                     }
+                };
+                let mut results = vec![];
+                for args in all_args {
+                    results.push(subscribe(&config, &db, chat_id, args).await?);
                 }
+                tg.send_message(
+                    ChatId(chat_id),
+                    messages::format_subscribe_summary(&results),
+                )
+                .await?;
+            }
+            Command::Resubscribe(args) => {
+                let chat_id = message.chat.id.0;
+                let reply = match db.update_subscription(chat_id, &args)? {
+                    true => format!("Updated the subscription to r/{}", args.subreddit),
+                    false => format!("Error: Not subscribed to r/{}", args.subreddit),
+                };
+                tg.send_message(ChatId(chat_id), reply).await?;
             }
             Command::Unsub(subreddit) => {
                 let chat_id = message.chat.id.0;
                 let subreddit = subreddit.replace("r/", "");
-                let reply = match db.unsubscribe(chat_id, &subreddit) {
-                    Ok(sub) => format!("Unsubscribed from r/{sub}"),
-                    Err(_) => format!("Error: Not subscribed to r/{subreddit}"),
+                let reply = match db.unsubscribe(chat_id, &subreddit)? {
+                    Some(sub) => format!("Unsubscribed from r/{sub}"),
+                    None => format!("Error: Not subscribed to r/{subreddit}"),
+                };
+                tg.send_message(ChatId(chat_id), reply).await?;
+            }
+            Command::Limit(args) => {
+                let chat_id = message.chat.id.0;
+                let parsed = match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+                    [subreddit, limit] => limit
+                        .parse::<u32>()
+                        .ok()
+                        .map(|limit| (subreddit.replace("r/", ""), limit)),
+                    _ => None,
+                };
+                let Some((subreddit, limit)) = parsed else {
+                    tg.send_message(message.chat.id, "Usage: /limit <subreddit> <limit>")
+                        .await?;
+                    return Ok(());
+                };
+                let reply = match db.set_subscription_limit(chat_id, &subreddit, limit)? {
+                    Some(limit) => format!("Limit of r/{subreddit} set to {limit}"),
+                    None => format!("Error: Not subscribed to r/{subreddit}"),
                 };
                 tg.send_message(ChatId(chat_id), reply).await?;
             }
-            Command::ListSubs => {
-                let subs = db.get_subscriptions_for_chat(message.chat.id.0)?;
+            Command::ListSubs(sort) => {
+                let sort = sort.trim();
+                let sort = if sort.is_empty() {
+                    SubscriptionSort::default()
+                } else if let Ok(sort) = sort.parse() {
+                    sort
+                } else {
+                    tg.send_message(
+                        message.chat.id,
+                        "Usage: /listsubs [name|created|delivered|errors]",
+                    )
+                    .await?;
+                    return Ok(());
+                };
+                let subs = db.get_subscriptions_for_chat_sorted(message.chat.id.0, sort)?;
                 let reply = messages::format_subscription_list(&subs);
                 tg.send_message(message.chat.id, reply).await?;
             }
+            Command::Show(subreddit) => {
+                let subreddit = subreddit.trim().replace("r/", "");
+                if subreddit.is_empty() {
+                    tg.send_message(message.chat.id, "Usage: /show <subreddit>")
+                        .await?;
+                    return Ok(());
+                }
+                let reply = match db.get_subscription(message.chat.id.0, &subreddit)? {
+                    Some(sub) => format!("r/{}: {}", sub.subreddit, sub.describe()),
+                    None => format!("Error: Not subscribed to r/{subreddit}"),
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Topic(args) => {
+                let mut args = args.split_whitespace();
+                let (Some(subreddit), action) = (args.next(), args.next()) else {
+                    tg.send_message(message.chat.id, "Usage: /topic <subreddit> [here|off]")
+                        .await?;
+                    return Ok(());
+                };
+                let subreddit = subreddit.replace("r/", "");
+                let chat_id = message.chat.id.0;
+                let reply = match action {
+                    None => match db.get_subscription_thread(chat_id, &subreddit)? {
+                        Some(Some(thread_id)) => {
+                            format!("Posts from r/{subreddit} are sent to topic {thread_id}")
+                        }
+                        Some(None) => format!("Posts from r/{subreddit} are sent to the chat"),
+                        None => format!("Error: Not subscribed to r/{subreddit}"),
+                    },
+                    Some("here") => match message.thread_id {
+                        Some(thread_id) => {
+                            if db.set_subscription_thread(chat_id, &subreddit, Some(thread_id))? {
+                                format!("Posts from r/{subreddit} will be sent to this topic")
+                            } else {
+                                format!("Error: Not subscribed to r/{subreddit}")
+                            }
+                        }
+                        None => "Error: Send this command in a forum topic".to_string(),
+                    },
+                    Some("off") => {
+                        if db.set_subscription_thread(chat_id, &subreddit, None)? {
+                            format!("Posts from r/{subreddit} will be sent to the chat")
+                        } else {
+                            format!("Error: Not subscribed to r/{subreddit}")
+                        }
+                    }
+                    Some(_) => "Usage: /topic <subreddit> [here|off]".to_string(),
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Group(args) => {
+                let Some((subreddit, group)) = args.trim().split_once(char::is_whitespace) else {
+                    tg.send_message(message.chat.id, "Usage: /group <subreddit> <group|off>")
+                        .await?;
+                    return Ok(());
+                };
+                let subreddit = subreddit.replace("r/", "");
+                let group = group.trim();
+                let group = (group != "off").then_some(group);
+                let reply = if db.set_subscription_group(message.chat.id.0, &subreddit, group)? {
+                    match group {
+                        Some(group) => format!("Added r/{subreddit} to group {group}"),
+                        None => format!("Removed r/{subreddit} from its group"),
+                    }
+                } else {
+                    format!("Error: Not subscribed to r/{subreddit}")
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::MySubs(group) => {
+                let chat_id = message.chat.id.0;
+                let group = group.trim();
+                let reply = if group.is_empty() {
+                    messages::format_grouped_subscription_list(
+                        db.get_subscriptions_for_chat(chat_id)?,
+                    )
+                } else {
+                    let subs = db.get_subscriptions_for_chat_in_group(chat_id, group)?;
+                    if subs.is_empty() {
+                        format!("No subscriptions in group {group}")
+                    } else {
+                        messages::format_subscription_list(&subs)
+                    }
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Filters => {
+                tg.send_message(message.chat.id, messages::format_filter_list())
+                    .await?;
+            }
             Command::Get(args) => {
                 handle_get_command(db, args, config, message, tg).await?;
             }
             Command::RegisterChannel(channel_id) => {
-                db.set_repost_channel(message.chat.id.0, channel_id)?;
+                db.add_repost_channel(message.chat.id.0, channel_id)?;
                 tg.send_message(
                     message.chat.id,
                     format!("Repost channel {channel_id} added successfully"),
                 )
                 .await?;
             }
+            Command::UnregisterChannel(channel_id) => {
+                let reply = if db.remove_repost_channel(message.chat.id.0, channel_id)? {
+                    format!("Repost channel {channel_id} removed")
+                } else {
+                    format!("Error: Repost channel {channel_id} not registered")
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::ListChannels => {
+                let channels = db.get_repost_channels(message.chat.id.0)?;
+                let reply = if channels.is_empty() {
+                    "No repost channels".to_string()
+                } else {
+                    channels.iter().join("\n")
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
             Command::RepostToChannel {
                 description,
                 message_id,
@@ -233,12 +568,530 @@ pub async fn handle_command(
                 };
                 handle_repost(db, message.chat.id, tg, message_id, button_data).await?;
             }
+            Command::Since(input) => {
+                let Some(since) = parse_since(input.trim(), chrono::Utc::now()) else {
+                    tg.send_message(
+                        message.chat.id,
+                        "Usage: /since <2023-05-01T12:00:00Z|2023-05-01|24h|7d>",
+                    )
+                    .await?;
+                    return Ok(());
+                };
+                let reddit = reddit::RedditClient::new(&config)?;
+                let delivered =
+                    catch_up_since(&config, tg, &reddit, &state, message.chat.id.0, &since).await?;
+                tg.send_message(
+                    message.chat.id,
+                    format!("Delivered {delivered} post(s) created since {since}"),
+                )
+                .await?;
+            }
+            Command::Snooze(input) => {
+                let chat_id = message.chat.id.0;
+                let now = chrono::Utc::now();
+                let reply = match input.trim() {
+                    "" => match db.get_snooze_until(chat_id, now)? {
+                        Some(until) => format!("Snoozed until {until}"),
+                        None => "Not snoozed".to_string(),
+                    },
+                    "off" => {
+                        db.snooze_chat(chat_id, None)?;
+                        "Snooze ended, new posts will be sent again".to_string()
+                    }
+                    input => match parse_duration(input)
+                        .filter(|duration| *duration > chrono::Duration::zero())
+                        .and_then(|duration| now.checked_add_signed(duration))
+                    {
+                        Some(until) => {
+                            db.snooze_chat(chat_id, Some(until))?;
+                            format!(
+                                "Snoozed until {until}. New posts are marked seen without \
+                                 being sent."
+                            )
+                        }
+                        _ => "Usage: /snooze <30m|8h|2d|off>".to_string(),
+                    },
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Forget(confirmation) => {
+                let reply = if confirmation.trim() == "confirm" {
+                    db.forget_chat(message.chat.id.0)?;
+                    info!("forgot chat id {}", message.chat.id.0);
+                    "All data of this chat has been deleted"
+                } else {
+                    "This deletes all subscriptions, repost channels and post history of this \
+                     chat. Send /forget confirm to proceed."
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Status => {
+                if !is_admin(message, &config) {
+                    tg.send_message(message.chat.id, "Only admins can do that")
+                        .await?;
+                    return Ok(());
+                }
+                let status = BotStatus {
+                    version: env!("CARGO_PKG_VERSION"),
+                    uptime: chrono::Utc::now() - state.started_at,
+                    last_poll_at: state.last_poll_at(),
+                    subscription_count: db.get_all_subscriptions()?.len(),
+                    chat_post_count: db.count_posts(message.chat.id.0)?,
+                    schema_version: db.schema_version()?,
+                };
+                tg.send_message(message.chat.id, messages::format_status(&status))
+                    .await?;
+            }
+            Command::DbStats => {
+                if !is_admin(message, &config) {
+                    tg.send_message(message.chat.id, "Only admins can do that")
+                        .await?;
+                    return Ok(());
+                }
+                let file_size = std::fs::metadata(&config.db_path)
+                    .map(|metadata| metadata.len())
+                    .ok();
+                tg.send_message(
+                    message.chat.id,
+                    messages::format_db_stats(file_size, &db.table_counts()?),
+                )
+                .await?;
+            }
+            Command::InactiveSubs(value) => {
+                if !is_admin(message, &config) {
+                    tg.send_message(message.chat.id, "Only admins can do that")
+                        .await?;
+                    return Ok(());
+                }
+                let value = value.trim();
+                let inactive_for = if value.is_empty() {
+                    Some(chrono::Duration::days(DEFAULT_INACTIVE_DAYS))
+                } else {
+                    parse_duration(value)
+                };
+                let Some(inactive_for) = inactive_for else {
+                    tg.send_message(message.chat.id, "Usage: /inactivesubs [duration, e.g. 30d]")
+                        .await?;
+                    return Ok(());
+                };
+                let subs = db.get_inactive_subscriptions(chrono::Utc::now() - inactive_for)?;
+                tg.send_message(
+                    message.chat.id,
+                    messages::format_inactive_subscriptions(&subs),
+                )
+                .await?;
+            }
+            Command::PollNow => {
+                if !is_admin(message, &config) {
+                    tg.send_message(message.chat.id, "Only admins can do that")
+                        .await?;
+                    return Ok(());
+                }
+                let reply = if spawn_poll_now(config.clone(), Arc::new(tg.clone()), state.clone()) {
+                    "Checking all subscriptions for new posts"
+                } else {
+                    "A check for new posts is already in progress"
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Metrics => {
+                if !is_admin(message, &config) {
+                    tg.send_message(message.chat.id, "Only admins can do that")
+                        .await?;
+                    return Ok(());
+                }
+                // The full histogram is served by the API, as it doesn't fit in a message
+                let reply = [
+                    state.last_cycle().map(|summary| format!("Last {summary}")),
+                    Some(state.fetch_digest(METRICS_SUBREDDIT_LIMIT)).filter(|d| !d.is_empty()),
+                ]
+                .into_iter()
+                .flatten()
+                .join("\n\n");
+                let reply = if reply.is_empty() {
+                    "No subreddits have been fetched yet".to_string()
+                } else {
+                    reply
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::RedditStatus => {
+                if !is_admin(message, &config) {
+                    tg.send_message(message.chat.id, "Only admins can do that")
+                        .await?;
+                    return Ok(());
+                }
+                let status = state.reddit_rate_limit();
+                tg.send_message(
+                    message.chat.id,
+                    messages::format_reddit_status(status.as_ref(), chrono::Utc::now()),
+                )
+                .await?;
+            }
+            Command::Silent(value) => {
+                let reply = match value.trim() {
+                    "on" => {
+                        db.set_silent(message.chat.id.0, true)?;
+                        "Posts will be delivered silently"
+                    }
+                    "off" => {
+                        db.set_silent(message.chat.id.0, false)?;
+                        "Posts will be delivered with notification"
+                    }
+                    _ => "Usage: /silent on|off",
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::MergedFeed(value) => {
+                let reply = match value.trim() {
+                    "on" => {
+                        db.set_merged_feed(message.chat.id.0, true)?;
+                        "New posts of all subscriptions will be delivered oldest first"
+                    }
+                    "off" => {
+                        db.set_merged_feed(message.chat.id.0, false)?;
+                        "New posts will be delivered per subscription"
+                    }
+                    _ => "Usage: /mergedfeed on|off",
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::NsfwSpoiler(value) => {
+                let reply = match value.trim() {
+                    "on" => {
+                        db.set_nsfw_spoiler(message.chat.id.0, true)?;
+                        "Media of NSFW posts will be covered with a spoiler"
+                    }
+                    "off" => {
+                        db.set_nsfw_spoiler(message.chat.id.0, false)?;
+                        "Media of NSFW posts will be sent uncovered"
+                    }
+                    _ => "Usage: /nsfwspoiler on|off",
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::DailyHeader(value) => {
+                let reply = match value.trim() {
+                    "" => "Usage: /dailyheader on|off|<text>".to_string(),
+                    "off" => {
+                        db.set_daily_header(message.chat.id.0, None)?;
+                        "Daily header disabled".to_string()
+                    }
+                    header => {
+                        let header = if header == "on" {
+                            messages::DEFAULT_DAILY_HEADER
+                        } else {
+                            header
+                        };
+                        db.set_daily_header(message.chat.id.0, Some(header))?;
+                        format!("The first post of each day will be preceded by: {header}")
+                    }
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::WebPreview(value) => {
+                let reply = match value.trim().parse::<WebPreview>() {
+                    Ok(web_preview) => {
+                        db.set_web_preview(message.chat.id.0, web_preview)?;
+                        match web_preview {
+                            WebPreview::Thumbnail => {
+                                "Link posts will be sent with their thumbnail, if any"
+                            }
+                            WebPreview::Preview => "Link posts will be sent with a link preview",
+                            WebPreview::None => "Link posts will be sent without a preview",
+                        }
+                    }
+                    Err(_) => "Usage: /webpreview thumbnail|preview|none",
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::ParseMode(value) => {
+                let reply = match value.trim().parse::<ParseMode>() {
+                    Ok(parse_mode) => {
+                        db.set_parse_mode(message.chat.id.0, parse_mode)?;
+                        match parse_mode {
+                            ParseMode::Html => "Captions will be formatted with HTML",
+                            ParseMode::MarkdownV2 => "Captions will be formatted with MarkdownV2",
+                            ParseMode::None => "Captions will be sent as plain text",
+                        }
+                    }
+                    Err(_) => "Usage: /parsemode html|markdownv2|none",
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::DefaultSort(value) => {
+                let chat_id = message.chat.id.0;
+                let reply = match value.trim() {
+                    "" => format!(
+                        "New subscriptions are sorted by {}",
+                        db.get_default_sort(chat_id)?.unwrap_or_default()
+                    ),
+                    "off" => {
+                        db.set_default_sort(chat_id, None)?;
+                        format!(
+                            "New subscriptions are sorted by {}",
+                            reddit::ListingSort::default()
+                        )
+                    }
+                    value => match value.parse::<reddit::ListingSort>() {
+                        Ok(sort) => {
+                            db.set_default_sort(chat_id, Some(sort))?;
+                            format!("New subscriptions are sorted by {sort}")
+                        }
+                        Err(_) => "Usage: /defaultsort top|new|hot|rising|off".to_owned(),
+                    },
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Caption(args) => {
+                let chat_id = message.chat.id.0;
+                let args = args.trim();
+                if args.is_empty() {
+                    tg.send_message(
+                        message.chat.id,
+                        messages::format_caption_components(&db.get_caption_components(chat_id)?),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+                let components = if args == "default" {
+                    None
+                } else {
+                    match parse_caption_components(args) {
+                        Ok(components) => Some(components),
+                        Err(component) => {
+                            tg.send_message(
+                                message.chat.id,
+                                format!("Error: Unknown caption component {component}"),
+                            )
+                            .await?;
+                            return Ok(());
+                        }
+                    }
+                };
+                db.set_caption_components(chat_id, components.as_deref())?;
+                tg.send_message(
+                    message.chat.id,
+                    messages::format_caption_components(&db.get_caption_components(chat_id)?),
+                )
+                .await?;
+            }
+            Command::Clone(source_chat_id) => {
+                if !is_admin(message, &config) {
+                    tg.send_message(message.chat.id, "Only admins can do that")
+                        .await?;
+                    return Ok(());
+                }
+                let Ok(source_chat_id) = source_chat_id.trim().parse::<i64>() else {
+                    tg.send_message(message.chat.id, "Usage: /clone <source chat id>")
+                        .await?;
+                    return Ok(());
+                };
+                let copy = db.copy_chat(source_chat_id, message.chat.id.0)?;
+                info!(
+                    "cloned chat id {source_chat_id} to chat id {}, added {} subscription(s), skipped {}",
+                    message.chat.id.0,
+                    copy.added,
+                    copy.skipped.len()
+                );
+                let reply = std::iter::once(format!(
+                    "Added {} subscription(s) from chat {source_chat_id}",
+                    copy.added
+                ))
+                .chain(
+                    copy.skipped
+                        .iter()
+                        .map(|(subreddit, reason)| format!("Skipped r/{subreddit}: {reason}")),
+                )
+                .join("\n");
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Link(other_chat_id) => {
+                if !is_admin(message, &config) {
+                    tg.send_message(message.chat.id, "Only admins can do that")
+                        .await?;
+                    return Ok(());
+                }
+                let chat_id = message.chat.id.0;
+                let other_chat_id = other_chat_id.trim();
+                let reply = if other_chat_id.is_empty() {
+                    let linked = db.get_linked_chats(chat_id)?;
+                    if linked.is_empty() {
+                        "No linked chats".to_string()
+                    } else {
+                        format!("Linked chats: {}", linked.iter().join(", "))
+                    }
+                } else if let Ok(other_chat_id) = other_chat_id.parse::<i64>() {
+                    if other_chat_id == chat_id {
+                        "A chat can't be linked to itself".to_string()
+                    } else {
+                        db.link_chats(chat_id, other_chat_id)?;
+                        format!("Posts already seen in chat {other_chat_id} will be skipped here, and the other way around")
+                    }
+                } else {
+                    "Usage: /link <chat id>".to_string()
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Unlink(other_chat_id) => {
+                if !is_admin(message, &config) {
+                    tg.send_message(message.chat.id, "Only admins can do that")
+                        .await?;
+                    return Ok(());
+                }
+                let Ok(other_chat_id) = other_chat_id.trim().parse::<i64>() else {
+                    tg.send_message(message.chat.id, "Usage: /unlink <chat id>")
+                        .await?;
+                    return Ok(());
+                };
+                let reply = if db.unlink_chats(message.chat.id.0, other_chat_id)? {
+                    format!("Unlinked chat {other_chat_id}")
+                } else {
+                    format!("Error: Not linked to chat {other_chat_id}")
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::RenameSub(args) => {
+                if !is_admin(message, &config) {
+                    tg.send_message(message.chat.id, "Only admins can do that")
+                        .await?;
+                    return Ok(());
+                }
+                let args = args.split_whitespace().collect::<Vec<_>>();
+                let [from, to] = args[..] else {
+                    tg.send_message(message.chat.id, "Usage: /renamesub <from> <to>")
+                        .await?;
+                    return Ok(());
+                };
+                let (from, to) = (from.replace("r/", ""), to.replace("r/", ""));
+                let reply = match db.rename_subreddit(message.chat.id.0, &from, &to) {
+                    Ok(true) => format!("Renamed r/{from} to r/{to}"),
+                    Ok(false) => format!("Error: Not subscribed to r/{from}"),
+                    Err(err) => format!("Error: {err}"),
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::History(query) => {
+                let query = query.trim();
+                if query.is_empty() {
+                    tg.send_message(message.chat.id, "Usage: /history <query>")
+                        .await?;
+                    return Ok(());
+                }
+                let entries = db.search_history(message.chat.id.0, query, HISTORY_LIMIT)?;
+                tg.send_message(
+                    message.chat.id,
+                    messages::format_history(&entries, config.links_base_url.as_deref()),
+                )
+                .parse_mode(teloxide::types::ParseMode::Html)
+                .disable_web_page_preview(true)
+                .await?;
+            }
+            Command::Overlap => {
+                let overlap = db.subreddit_overlap(message.chat.id.0)?;
+                tg.send_message(message.chat.id, messages::format_overlap(&overlap))
+                    .await?;
+            }
+            Command::Duplicates => {
+                let stats = db.duplicate_stats(message.chat.id.0)?;
+                tg.send_message(message.chat.id, messages::format_duplicate_stats(&stats))
+                    .await?;
+            }
+            Command::Post(post_id) => {
+                let post_id = post_id.trim();
+                let post_id = post_id.strip_prefix("t3_").unwrap_or(post_id);
+                if post_id.is_empty() {
+                    tg.send_message(message.chat.id, "Usage: /post <post id>")
+                        .await?;
+                    return Ok(());
+                }
+                let reply = match db.get_post_record(message.chat.id.0, post_id)? {
+                    Some(record) => messages::format_post_record(&record),
+                    None => format!("No post {post_id} stored for this chat"),
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::ExportHistory => {
+                let csv = db.export_history_csv(message.chat.id.0)?;
+                tg.send_document(
+                    message.chat.id,
+                    InputFile::memory(csv.into_bytes()).file_name("history.csv"),
+                )
+                .await?;
+            }
+            Command::Debug(args) => {
+                if !is_admin(message, &config) {
+                    tg.send_message(message.chat.id, "Only admins can do that")
+                        .await?;
+                    return Ok(());
+                }
+                let parsed = match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+                    ["sub", chat_id, subreddit] => chat_id
+                        .parse::<i64>()
+                        .ok()
+                        .map(|chat_id| (chat_id, subreddit.to_string())),
+                    _ => None,
+                };
+                let Some((chat_id, subreddit)) = parsed else {
+                    tg.send_message(message.chat.id, "Usage: /debug sub <chat id> <subreddit>")
+                        .await?;
+                    return Ok(());
+                };
+                let reply = match db.get_subscription_raw(chat_id, &subreddit)? {
+                    Some(row) => messages::format_subscription_raw(&row),
+                    None => format!("No subscription to r/{subreddit} in chat {chat_id}"),
+                };
+                tg.send_message(message.chat.id, reply).await?;
+            }
+            Command::Queue => {
+                let (posts, total) = db.get_pending_posts(message.chat.id.0, QUEUE_LIMIT)?;
+                tg.send_message(
+                    message.chat.id,
+                    messages::format_queue(&posts, total, config.links_base_url.as_deref()),
+                )
+                .parse_mode(teloxide::types::ParseMode::Html)
+                .disable_web_page_preview(true)
+                .await?;
+            }
+            Command::CheckPerms(chat_id) => {
+                let chat_id = chat_id.trim();
+                let chat = if chat_id.is_empty() {
+                    message.chat.clone()
+                } else if let Ok(chat_id) = chat_id.parse::<i64>() {
+                    tg.get_chat(ChatId(chat_id)).await?
+                } else {
+                    tg.send_message(message.chat.id, "Usage: /checkperms [chat id]")
+                        .await?;
+                    return Ok(());
+                };
+                let me = tg.get_me().await?;
+                let member = tg.get_chat_member(chat.id, me.id).await?;
+                let permissions = bot_permissions(&member.kind, chat.is_channel());
+                tg.send_message(
+                    message.chat.id,
+                    messages::format_bot_permissions(&permissions),
+                )
+                .await?;
+            }
+            Command::TestMedia(url) => {
+                let Some(post_id) = reddit::parse_post_id(url.trim()) else {
+                    tg.send_message(message.chat.id, "Usage: /testmedia <reddit post url>")
+                        .await?;
+                    return Ok(());
+                };
+                let post = reddit::RedditClient::new(&config)?
+                    .get_link(&post_id)
+                    .await?;
+                let plan = handle_post::plan_post_send(&config, &post).await;
+                tg.send_message(message.chat.id, messages::format_send_plan(&post, &plan))
+                    .await?;
+            }
         };
 
         Ok(())
     }
 
-    if let Err(err) = handle(&message, &tg, command, config).await {
+    if let Err(err) = handle(&message, &tg, command, config, state).await {
         error!("failed to handle message: {:?}", err);
         tg.send_message(message.chat.id, "Something went wrong")
             .await?;
@@ -247,6 +1100,85 @@ pub async fn handle_command(
     Ok(())
 }
 
+/// Interprets the bot's membership of a chat. Posting to a channel requires the administrator
+/// right `can_post_messages`, while in groups only restrictions take rights away.
+fn bot_permissions(member: &ChatMemberKind, is_channel: bool) -> BotPermissions {
+    let (can_send_messages, can_send_media, missing) = match member {
+        ChatMemberKind::Owner(_) => (true, true, vec![]),
+        ChatMemberKind::Administrator(admin) if is_channel && !admin.can_post_messages => {
+            (false, false, vec!["can_post_messages"])
+        }
+        ChatMemberKind::Administrator(_) => (true, true, vec![]),
+        ChatMemberKind::Member if is_channel => (false, false, vec!["can_post_messages"]),
+        ChatMemberKind::Member => (true, true, vec![]),
+        ChatMemberKind::Restricted(restricted) => {
+            let mut missing = vec![];
+            if !restricted.is_member {
+                missing.push("is_member");
+            }
+            if !restricted.can_send_messages {
+                missing.push("can_send_messages");
+            }
+            if !restricted.can_send_media_messages {
+                missing.push("can_send_media_messages");
+            }
+            let can_send_messages = restricted.is_member && restricted.can_send_messages;
+            (
+                can_send_messages,
+                can_send_messages && restricted.can_send_media_messages,
+                missing,
+            )
+        }
+        ChatMemberKind::Left | ChatMemberKind::Banned(_) => (false, false, vec!["is_member"]),
+    };
+    BotPermissions {
+        can_send_messages,
+        can_send_media,
+        missing,
+    }
+}
+
+/// Subscribes the chat to the subreddit, stored with its name spelled as on Reddit. Returns the
+/// name with the outcome. Shared by the bot's commands and the HTTP API.
+pub async fn subscribe(
+    config: &config::Config,
+    db: &db::Database,
+    chat_id: i64,
+    mut args: SubscriptionArgs,
+) -> Result<(String, SubscribeOutcome)> {
+    if reddit::is_frontpage(&args.subreddit) {
+        return Ok((args.subreddit, SubscribeOutcome::Frontpage));
+    }
+    let subreddit_about = reddit::RedditClient::new(config)?
+        .get_subreddit_about(&args.subreddit)
+        .await;
+    let outcome = match subreddit_about {
+        Ok(data) => {
+            args.subreddit = data.display_name;
+            match db.subscribe(chat_id, &args) {
+                Ok(()) => {
+                    info!("subscribed in chat id {chat_id} with {args:#?};");
+                    SubscribeOutcome::Subscribed
+                }
+                Err(db::SubscribeError::Db(err)) => return Err(err),
+                Err(err) => SubscribeOutcome::Rejected(err),
+            }
+        }
+        Err(reddit::SubredditAboutError::NoSuchSubreddit) => SubscribeOutcome::NoSuchSubreddit,
+        Err(err) => {
+            return Err(err).context("Couldn't download about.json for subreddit");
+        }
+    };
+    Ok((args.subreddit, outcome))
+}
+
+fn is_admin(message: &Message, config: &config::Config) -> bool {
+    message
+        .from()
+        .map(|user| config.is_admin(user.id.0))
+        .unwrap_or_default()
+}
+
 async fn handle_repost(
     db: db::Database,
     chat_id: ChatId,
@@ -254,20 +1186,23 @@ async fn handle_repost(
     message_id: i32,
     caption: Option<String>,
 ) -> Result<()> {
-    let Some(repost_channel_id) = db.get_repost_channel(chat_id.0)? else {
+    let repost_channel_ids = db.get_repost_channels(chat_id.0)?;
+    if repost_channel_ids.is_empty() {
         tg.send_message(chat_id, "Repost channel not registered".to_string())
             .await?;
         return Ok(());
-    };
+    }
     let caption = if let Some(caption) = &caption {
         caption
     } else {
         ""
     };
-    tg.copy_message(ChatId(repost_channel_id), chat_id, MessageId(message_id))
-        .caption(caption)
-        .send()
-        .await?;
+    for repost_channel_id in repost_channel_ids {
+        tg.copy_message(ChatId(repost_channel_id), chat_id, MessageId(message_id))
+            .caption(caption)
+            .send()
+            .await?;
+    }
     Ok(())
 }
 
@@ -295,14 +1230,20 @@ async fn handle_repost_gallery(
         media_group.push(InputMedia::Photo(input_media_photo))
     }
 
-    let Some(repost_channel_id) = db.get_repost_channel(chat_id.0)? else {
+    let repost_channel_ids = db.get_repost_channels(chat_id.0)?;
+    if repost_channel_ids.is_empty() {
         tg.send_message(chat_id, "Repost channel not registered".to_string())
             .await?;
         return Ok(());
-    };
+    }
 
-    tg.send_media_group(ChatId(repost_channel_id), media_group)
-        .await?;
+    let media_groups = handle_post::split_media_group(media_group);
+    for repost_channel_id in repost_channel_ids {
+        for media_group in &media_groups {
+            tg.send_media_group(ChatId(repost_channel_id), media_group.clone())
+                .await?;
+        }
+    }
     Ok(())
 }
 
@@ -322,24 +1263,51 @@ async fn handle_get_command(
         .time
         .or(config.default_time)
         .unwrap_or(config::DEFAULT_TIME_PERIOD);
-    let filter = args.filter.or(config.default_filter);
+    let mut filter = PostFilter {
+        post_type: args.filter.or(config.default_filter),
+        min_score: args.min_score,
+        min_upvote_ratio: args.min_upvote_ratio,
+        dedup_titles: false,
+        oc_only: args.oc_only,
+        media_only: args.media_only,
+        expand_imgur_albums: config.imgur_client_id.is_some(),
+        blocked_authors: args.blocked_authors.clone(),
+    };
     let chat_id = message.chat.id.0;
-    let posts = reddit::get_subreddit_top_posts(subreddit, limit, &time)
+    let sort = match args.sort {
+        Some(sort) => sort,
+        None => db::Database::open(&config)?
+            .get_default_sort(chat_id)?
+            .unwrap_or_default(),
+    };
+    let posts = reddit::RedditClient::new(&config)?
+        .get_subreddit_top_posts(&reddit::ListingQuery {
+            subreddit: subreddit.to_owned(),
+            limit,
+            sort,
+            time,
+            include_over_18: args.allow_nsfw,
+        })
         .await
-        .context("failed to get posts")?
+        .context("failed to get posts")?;
+    filter = filter.with_top_percentile(&posts, args.top_percentile);
+    let posts = filter::rank_posts(posts, args.rank, args.min_rank, chrono::Utc::now())
         .into_iter()
-        .filter(|p| {
-            if filter.is_some() {
-                filter.as_ref() == Some(&p.post_type)
-            } else {
-                true
-            }
-        })
+        .filter(|p| filter.matches(p))
         .collect::<Vec<_>>();
     debug!("got {} post(s) for subreddit /r/{}", posts.len(), subreddit);
     if !posts.is_empty() {
         for post in posts {
-            process_post(&db, chat_id, &post, &config, tg).await?;
+            process_post(
+                &db,
+                chat_id,
+                message.thread_id,
+                args.text_only,
+                &post,
+                &config,
+                tg,
+            )
+            .await?;
         }
     } else {
         tg.send_message(message.chat.id, "No posts found").await?;
@@ -347,59 +1315,207 @@ async fn handle_get_command(
     Ok(())
 }
 
-fn parse_subscribe_message(input: String) -> Result<(SubscriptionArgs,), ParseError> {
-    lazy_static! {
-        static ref SUBREDDIT_RE: Regex = Regex::new(r"^[^\s]+").unwrap();
-        static ref LIMIT_RE: Regex = Regex::new(r"\blimit=(\d+)\b").unwrap();
-        static ref TIME_RE: Regex = Regex::new(r"\btime=(\w+)\b").unwrap();
-        static ref FILTER_RE: Regex = Regex::new(r"\bfilter=(\w+)\b").unwrap();
-    }
-
-    let subreddit_match = SUBREDDIT_RE
-        .find(&input)
-        .ok_or_else(|| ParseError::Custom("No subreddit given".into()))?;
-    let subreddit = subreddit_match
-        .as_str()
-        .to_string()
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SubscriptionArgsError {
+    #[error("No subreddit given")]
+    NoSubreddit,
+    #[error("Unknown option: {0}")]
+    UnknownOption(String),
+    #[error("Option {0} given more than once")]
+    DuplicateOption(String),
+    #[error("Expected key=value, got: {0}")]
+    MalformedOption(String),
+    #[error("Invalid value for {key}: {value}")]
+    InvalidValue { key: String, value: String },
+    #[error("Options {0} and {1} can't be combined")]
+    ConflictingOptions(&'static str, &'static str),
+}
+
+/// Parses caption components separated by whitespace or commas. Returns the first unknown
+/// component on failure.
+fn parse_caption_components(args: &str) -> Result<Vec<CaptionComponent>, String> {
+    args.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|component| !component.is_empty())
+        .map(|component| CaptionComponent::from_str(component).map_err(|_| component.to_string()))
+        .collect()
+}
+
+/// Parses subscription arguments of the form `<subreddit> [key=value]...`, e.g.
+/// `r/foo limit=5 time=week type=video min_score=1000`.
+fn parse_subscription_args(input: &str) -> Result<SubscriptionArgs, SubscriptionArgsError> {
+    fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, SubscriptionArgsError> {
+        value
+            .parse()
+            .map_err(|_| SubscriptionArgsError::InvalidValue {
+                key: key.to_string(),
+                value: value.to_string(),
+            })
+    }
+
+    let mut tokens = input.split_whitespace();
+    let subreddit = tokens
+        .next()
+        .ok_or(SubscriptionArgsError::NoSubreddit)?
         .replace("/r/", "")
         .replace("r/", "");
-    let rest = &input[(subreddit_match.end())..];
 
-    let limit: Option<u32> = LIMIT_RE
-        .captures(rest)
-        .and_then(|caps| caps.get(1))
-        .and_then(|m| m.as_str().parse().ok());
+    let mut args = SubscriptionArgs {
+        subreddit,
+        ..Default::default()
+    };
+    let mut seen_keys = vec![];
 
-    let time = Ok(TIME_RE.captures(rest))
-        .map(|o| o.and_then(|caps| caps.get(1)))
-        .and_then(|o| match o {
-            Some(m) => m
-                .as_str()
-                .parse::<TopPostsTimePeriod>()
-                .map(Some)
-                .map_err(|e| ParseError::IncorrectFormat(e.into())),
-            None => Ok(None),
-        })?;
-
-    let filter = Ok(FILTER_RE.captures(rest))
-        .map(|o| o.and_then(|caps| caps.get(1)))
-        .and_then(|o| match o {
-            Some(m) => m
-                .as_str()
-                .parse::<PostType>()
-                .map(Some)
-                .map_err(|e| ParseError::IncorrectFormat(e.into())),
-            None => Ok(None),
-        })?;
+    for token in tokens {
+        let (key, value) = token
+            .split_once('=')
+            .filter(|(key, value)| !key.is_empty() && !value.is_empty())
+            .ok_or_else(|| SubscriptionArgsError::MalformedOption(token.to_string()))?;
+        // filter is the original name of type
+        let key = if key == "filter" { "type" } else { key };
+        if seen_keys.contains(&key) {
+            return Err(SubscriptionArgsError::DuplicateOption(key.to_string()));
+        }
+        seen_keys.push(key);
 
-    let args = SubscriptionArgs {
-        subreddit,
-        limit,
-        time,
-        filter,
+        match key {
+            "limit" => args.limit = Some(parse_value(key, value)?),
+            "sort" => args.sort = Some(parse_value(key, value)?),
+            "time" => args.time = Some(parse_value(key, value)?),
+            "type" => args.filter = Some(parse_value(key, value)?),
+            "min_score" => args.min_score = Some(parse_value(key, value)?),
+            "min_upvote_ratio" => {
+                let ratio = parse_value(key, value)?;
+                if !filter::is_valid_upvote_ratio(ratio) {
+                    return Err(SubscriptionArgsError::InvalidValue {
+                        key: key.to_string(),
+                        value: value.to_string(),
+                    });
+                }
+                args.min_upvote_ratio = Some(ratio);
+            }
+            "rank" => args.rank = Some(parse_value(key, value)?),
+            "min_rank" => {
+                let min_rank: f64 = parse_value(key, value)?;
+                if !min_rank.is_finite() {
+                    return Err(SubscriptionArgsError::InvalidValue {
+                        key: key.to_string(),
+                        value: value.to_string(),
+                    });
+                }
+                args.min_rank = Some(min_rank);
+            }
+            "top_percentile" => {
+                let percentile = parse_value(key, value)?;
+                if !filter::is_valid_top_percentile(percentile) {
+                    return Err(SubscriptionArgsError::InvalidValue {
+                        key: key.to_string(),
+                        value: value.to_string(),
+                    });
+                }
+                args.top_percentile = Some(percentile);
+            }
+            "dedup_titles" => args.dedup_titles = parse_value(key, value)?,
+            "oc_only" => args.oc_only = parse_value(key, value)?,
+            "media_only" => args.media_only = parse_value(key, value)?,
+            "text_only" => args.text_only = parse_value(key, value)?,
+            "catch_up" => args.catch_up = Some(parse_value(key, value)?),
+            "top_comment" => args.top_comment = parse_value(key, value)?,
+            "send_order" => args.send_order = parse_value(key, value)?,
+            "nsfw" => args.allow_nsfw = parse_value(key, value)?,
+            "block_authors" => args.blocked_authors = parse_author_list(value),
+            _ => return Err(SubscriptionArgsError::UnknownOption(key.to_string())),
+        }
+    }
+
+    if args.media_only && args.text_only {
+        return Err(SubscriptionArgsError::ConflictingOptions(
+            "media_only",
+            "text_only",
+        ));
+    }
+
+    Ok(args)
+}
+
+/// Parses `<subreddit>... [key=value]...`, where the subreddits are separated by whitespace,
+/// newlines or commas and the options apply to each of them. Subreddits listed more than once
+/// are only subscribed to once.
+fn parse_subscribe_many_args(input: &str) -> Result<Vec<SubscriptionArgs>, SubscriptionArgsError> {
+    let (options, names): (Vec<&str>, Vec<&str>) = input
+        .split_whitespace()
+        .partition(|token| token.contains('='));
+    let options = options.join(" ");
+
+    let mut all_args: Vec<SubscriptionArgs> = vec![];
+    for name in names.iter().flat_map(|name| name.split(',')) {
+        if name.is_empty() {
+            continue;
+        }
+        let args = parse_subscription_args(&format!("{name} {options}"))?;
+        if !all_args
+            .iter()
+            .any(|other| other.subreddit.eq_ignore_ascii_case(&args.subreddit))
+        {
+            all_args.push(args);
+        }
+    }
+
+    if all_args.is_empty() {
+        return Err(SubscriptionArgsError::NoSubreddit);
+    }
+    Ok(all_args)
+}
+
+/// Parses a comma-separated list of usernames, with or without the `u/` prefix.
+fn parse_author_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|author| {
+            let author = author.trim();
+            author
+                .strip_prefix("/u/")
+                .or_else(|| author.strip_prefix("u/"))
+                .unwrap_or(author)
+                .to_string()
+        })
+        .filter(|author| !author.is_empty())
+        .collect()
+}
+
+/// Parses an absolute time or a time relative to `now`, such as `24h` or `7d`.
+fn parse_since(
+    input: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(input) {
+        return Some(time.with_timezone(&chrono::Utc));
+    }
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(input, "%Y-%m-%d") {
+        return Some(date.and_hms_opt(0, 0, 0)?.and_utc());
+    }
+
+    now.checked_sub_signed(parse_duration(input)?)
+}
+
+/// Parses a duration of minutes, hours or days, e.g. `30m`, `24h` or `7d`.
+fn parse_duration(input: &str) -> Option<chrono::Duration> {
+    let unit_start = input.find(|c: char| !c.is_ascii_digit())?;
+    let amount: i64 = input[..unit_start].parse().ok()?;
+    let unit_secs = match &input[unit_start..] {
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
     };
+    let secs = amount.checked_mul(unit_secs)?;
+    // chrono::Duration panics beyond i64::MAX milliseconds
+    (secs <= i64::MAX / 1000).then(|| chrono::Duration::seconds(secs))
+}
 
-    Ok((args,))
+fn parse_subscribe_message(input: String) -> Result<(SubscriptionArgs,), ParseError> {
+    parse_subscription_args(&input)
+        .map(|args| (args,))
+        .map_err(|e| ParseError::Custom(e.into()))
 }
 
 async fn callback_handler(
@@ -437,8 +1553,9 @@ async fn callback_handler(
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use crate::reddit::TopPostsTimePeriod;
 
     #[test]
     fn test_parse_subscribe_message_only_subreddit() {
@@ -448,8 +1565,23 @@ mod tests {
             SubscriptionArgs {
                 subreddit: "AnimalsBeingJerks".to_string(),
                 limit: None,
+                sort: None,
                 time: None,
                 filter: None,
+                min_score: None,
+                min_upvote_ratio: None,
+                top_percentile: None,
+                rank: None,
+                min_rank: None,
+                dedup_titles: false,
+                oc_only: false,
+                media_only: false,
+                text_only: false,
+                catch_up: None,
+                send_order: SendOrder::TopFirst,
+                allow_nsfw: false,
+                top_comment: false,
+                blocked_authors: vec![],
             },
         )
     }
@@ -462,8 +1594,23 @@ mod tests {
             SubscriptionArgs {
                 subreddit: "AnimalsBeingJerks".to_string(),
                 limit: None,
+                sort: None,
                 time: None,
                 filter: None,
+                min_score: None,
+                min_upvote_ratio: None,
+                top_percentile: None,
+                rank: None,
+                min_rank: None,
+                dedup_titles: false,
+                oc_only: false,
+                media_only: false,
+                text_only: false,
+                catch_up: None,
+                send_order: SendOrder::TopFirst,
+                allow_nsfw: false,
+                top_comment: false,
+                blocked_authors: vec![],
             },
         );
 
@@ -473,8 +1620,23 @@ mod tests {
             SubscriptionArgs {
                 subreddit: "AnimalsBeingJerks".to_string(),
                 limit: None,
+                sort: None,
                 time: None,
                 filter: None,
+                min_score: None,
+                min_upvote_ratio: None,
+                top_percentile: None,
+                rank: None,
+                min_rank: None,
+                dedup_titles: false,
+                oc_only: false,
+                media_only: false,
+                text_only: false,
+                catch_up: None,
+                send_order: SendOrder::TopFirst,
+                allow_nsfw: false,
+                top_comment: false,
+                blocked_authors: vec![],
             },
         )
     }
@@ -489,9 +1651,465 @@ mod tests {
             SubscriptionArgs {
                 subreddit: "AnimalsBeingJerks".to_string(),
                 limit: Some(5),
+                sort: None,
+                time: Some(TopPostsTimePeriod::Week),
+                filter: Some(PostType::Video),
+                min_score: None,
+                min_upvote_ratio: None,
+                top_percentile: None,
+                rank: None,
+                min_rank: None,
+                dedup_titles: false,
+                oc_only: false,
+                media_only: false,
+                text_only: false,
+                catch_up: None,
+                send_order: SendOrder::TopFirst,
+                allow_nsfw: false,
+                top_comment: false,
+                blocked_authors: vec![],
+            },
+        );
+
+        let args = parse_subscribe_message("AnimalsBeingJerks time=24h".to_string()).unwrap();
+        assert_eq!(args.0.time, Some(TopPostsTimePeriod::Day));
+
+        let args = parse_subscribe_message(
+            "AnimalsBeingJerks block_authors=u/Reposter,,/u/spammer,[deleted]".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            args.0.blocked_authors,
+            vec!["Reposter", "spammer", "[deleted]"]
+        );
+    }
+
+    #[test]
+    fn test_parse_since() {
+        use chrono::TimeZone;
+
+        let now = chrono::Utc.with_ymd_and_hms(2023, 5, 2, 12, 0, 0).unwrap();
+        assert_eq!(
+            parse_since("2023-05-01T10:30:00Z", now),
+            Some(chrono::Utc.with_ymd_and_hms(2023, 5, 1, 10, 30, 0).unwrap())
+        );
+        assert_eq!(
+            parse_since("2023-05-01", now),
+            Some(chrono::Utc.with_ymd_and_hms(2023, 5, 1, 0, 0, 0).unwrap())
+        );
+        assert_eq!(
+            parse_since("24h", now),
+            Some(chrono::Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap())
+        );
+        assert_eq!(
+            parse_since("2d", now),
+            Some(chrono::Utc.with_ymd_and_hms(2023, 4, 30, 12, 0, 0).unwrap())
+        );
+        assert_eq!(parse_since("yesterday", now), None);
+        assert_eq!(parse_since("5y", now), None);
+        assert_eq!(parse_since("", now), None);
+        assert_eq!(parse_since("99999999999d", now), None);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30m"), Some(chrono::Duration::minutes(30)));
+        assert_eq!(parse_duration("8h"), Some(chrono::Duration::hours(8)));
+        assert_eq!(parse_duration("2d"), Some(chrono::Duration::days(2)));
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("8"), None);
+        assert_eq!(parse_duration("8w"), None);
+        assert_eq!(parse_duration("99999999999999999999d"), None);
+        assert_eq!(parse_duration("999999999999999d"), None);
+        assert!(parse_duration("99999999999d").is_some());
+    }
+
+    #[test]
+    fn test_parse_subscription_args() {
+        assert_eq!(
+            parse_subscription_args("r/foo limit=5 time=week type=video min_score=1000").unwrap(),
+            SubscriptionArgs {
+                subreddit: "foo".to_string(),
+                limit: Some(5),
+                sort: None,
                 time: Some(TopPostsTimePeriod::Week),
                 filter: Some(PostType::Video),
+                min_score: Some(1000),
+                min_upvote_ratio: None,
+                top_percentile: None,
+                rank: None,
+                min_rank: None,
+                dedup_titles: false,
+                oc_only: false,
+                media_only: false,
+                text_only: false,
+                catch_up: None,
+                send_order: SendOrder::TopFirst,
+                allow_nsfw: false,
+                top_comment: false,
+                blocked_authors: vec![],
+            },
+        );
+        assert_eq!(
+            parse_subscription_args("foo dedup_titles=true oc_only=true send_order=chronological")
+                .unwrap(),
+            SubscriptionArgs {
+                subreddit: "foo".to_string(),
+                dedup_titles: true,
+                oc_only: true,
+                send_order: SendOrder::Chronological,
+                ..Default::default()
             },
+        );
+        assert_eq!(
+            parse_subscription_args(""),
+            Err(SubscriptionArgsError::NoSubreddit)
+        );
+    }
+
+    #[test]
+    fn test_parse_subscription_args_unknown_key() {
+        assert_eq!(
+            parse_subscription_args("foo limit=5 colour=blue"),
+            Err(SubscriptionArgsError::UnknownOption("colour".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_subscription_args_conflicting_options() {
+        assert!(
+            parse_subscription_args("AskReddit text_only=true")
+                .unwrap()
+                .text_only
+        );
+        assert_eq!(
+            parse_subscription_args("foo text_only=true media_only=true"),
+            Err(SubscriptionArgsError::ConflictingOptions(
+                "media_only",
+                "text_only"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_subscription_args_malformed_values() {
+        assert_eq!(
+            parse_subscription_args("foo limit=five"),
+            Err(SubscriptionArgsError::InvalidValue {
+                key: "limit".to_string(),
+                value: "five".to_string()
+            })
+        );
+        assert_eq!(
+            parse_subscription_args("foo time=fortnight"),
+            Err(SubscriptionArgsError::InvalidValue {
+                key: "time".to_string(),
+                value: "fortnight".to_string()
+            })
+        );
+        assert_eq!(
+            parse_subscription_args("foo min_score=-1"),
+            Err(SubscriptionArgsError::InvalidValue {
+                key: "min_score".to_string(),
+                value: "-1".to_string()
+            })
+        );
+        assert_eq!(
+            parse_subscription_args("foo min_upvote_ratio=1.5"),
+            Err(SubscriptionArgsError::InvalidValue {
+                key: "min_upvote_ratio".to_string(),
+                value: "1.5".to_string()
+            })
+        );
+        assert_eq!(
+            parse_subscription_args("foo min_upvote_ratio=0.8")
+                .unwrap()
+                .min_upvote_ratio,
+            Some(0.8)
+        );
+        assert_eq!(
+            parse_subscription_args("foo limit"),
+            Err(SubscriptionArgsError::MalformedOption("limit".to_string()))
+        );
+        assert_eq!(
+            parse_subscription_args("foo limit="),
+            Err(SubscriptionArgsError::MalformedOption("limit=".to_string()))
+        );
+        assert_eq!(
+            parse_subscription_args("foo type=video filter=image"),
+            Err(SubscriptionArgsError::DuplicateOption("type".to_string()))
+        );
+    }
+
+    pub(crate) type SentRequests = Arc<std::sync::Mutex<Vec<(String, serde_json::Value)>>>;
+
+    /// Serves a fake Telegram Bot API that records requests and answers every method with a
+    /// message. Returns the url to use as the bot's api url.
+    pub(crate) async fn spawn_mock_telegram(sent: SentRequests) -> Url {
+        use axum::{extract::Path, routing::post, Json, Router};
+
+        let app = Router::new().route(
+            "/:token/:method",
+            post(
+                move |Path((_token, method)): Path<(String, String)>,
+                      Json(body): Json<serde_json::Value>| async move {
+                    sent.lock().unwrap().push((method, body));
+                    Json(serde_json::json!({
+                        "ok": true,
+                        "result": {
+                            "message_id": 2,
+                            "date": 1684000000,
+                            "chat": { "id": 42, "type": "private", "first_name": "Test" },
+                            "text": "ok"
+                        }
+                    }))
+                },
+            ),
+        );
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+        let url = Url::parse(&format!("http://{}", server.local_addr())).unwrap();
+        tokio::spawn(server);
+        url
+    }
+
+    fn help_update() -> Update {
+        command_update("/help")
+    }
+
+    fn command_update(text: &str) -> Update {
+        let command_len = text.split_whitespace().next().unwrap().len();
+        // Both long polling and the webhook listener deserialize updates from this JSON shape
+        let update = serde_json::json!({
+            "update_id": 1,
+            "message": {
+                "message_id": 1,
+                "date": 1684000000,
+                "chat": { "id": 42, "type": "private", "first_name": "Test" },
+                "from": { "id": 7, "is_bot": false, "first_name": "Test" },
+                "text": text,
+                "entities": [{ "type": "bot_command", "offset": 0, "length": command_len }]
+            }
+        });
+        // teloxide fails to deserialize updates from a serde_json::Value
+        serde_json::from_str(&update.to_string()).unwrap()
+    }
+
+    fn me() -> teloxide::types::Me {
+        serde_json::from_str(
+            r#"{
+                "id": 1,
+                "is_bot": true,
+                "first_name": "tgreddit",
+                "username": "tgreddit_bot",
+                "can_join_groups": true,
+                "can_read_all_group_messages": false,
+                "supports_inline_queries": false
+            }"#,
         )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_schema_handles_update() {
+        let sent = SentRequests::default();
+        let api_url = spawn_mock_telegram(sent.clone()).await;
+        let tg = Arc::new(Bot::new("token").set_api_url(api_url));
+
+        let handle = |authorized_user_ids: Vec<u64>| {
+            let config = Arc::new(config::Config {
+                authorized_user_ids,
+                ..Default::default()
+            });
+            let deps = dptree::deps![
+                help_update(),
+                me(),
+                tg.clone(),
+                config,
+                Arc::new(BotState::new())
+            ];
+            async move { schema().dispatch(deps).await }
+        };
+
+        let result = handle(vec![7]).await;
+        assert!(matches!(result, std::ops::ControlFlow::Break(Ok(()))));
+        {
+            let sent = sent.lock().unwrap();
+            assert_eq!(sent.len(), 1);
+            let (method, body) = &sent[0];
+            assert_eq!(method, "SendMessage");
+            assert_eq!(body["chat_id"], 42);
+            assert!(body["text"]
+                .as_str()
+                .unwrap()
+                .contains("These commands are supported"));
+        }
+
+        // Updates from unauthorized users are not handled
+        let result = handle(vec![8]).await;
+        assert!(matches!(result, std::ops::ControlFlow::Continue(_)));
+        assert_eq!(sent.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_snooze_overflowing_duration() {
+        let sent = SentRequests::default();
+        let api_url = spawn_mock_telegram(sent.clone()).await;
+        let tg = Arc::new(Bot::new("token").set_api_url(api_url));
+        let config = Arc::new(config::Config {
+            authorized_user_ids: vec![7],
+            ..Default::default()
+        });
+        let deps = dptree::deps![
+            command_update("/snooze 99999999999d"),
+            me(),
+            tg,
+            config,
+            Arc::new(BotState::new())
+        ];
+
+        let result = schema().dispatch(deps).await;
+        assert!(matches!(result, std::ops::ControlFlow::Break(Ok(()))));
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1["text"], "Usage: /snooze <30m|8h|2d|off>");
+    }
+
+    #[test]
+    fn test_filter_list_covers_subscription_options() {
+        let options = [
+            "limit=5",
+            "sort=new",
+            "time=week",
+            "type=video",
+            "min_score=1",
+            "min_upvote_ratio=0.5",
+            "rank=velocity",
+            "min_rank=1",
+            "top_percentile=10",
+            "dedup_titles=true",
+            "oc_only=true",
+            "media_only=true",
+            "text_only=true",
+            "catch_up=3",
+            "top_comment=true",
+            "send_order=chronological",
+            "nsfw=true",
+            "block_authors=spez",
+        ];
+        let list = messages::format_filter_list();
+        for option in options {
+            assert!(
+                parse_subscription_args(&format!("foo {option}")).is_ok(),
+                "{option} is not accepted"
+            );
+            let key = option.split_once('=').unwrap().0;
+            assert!(list.contains(&format!("{key}=")), "{key} is not listed");
+        }
+    }
+
+    #[test]
+    fn test_parse_subscribe_many_args() {
+        let all_args =
+            parse_subscribe_many_args("r/aww pics,/r/gifs\nAww limit=3 type=image").unwrap();
+        assert_eq!(
+            all_args
+                .iter()
+                .map(|args| args.subreddit.as_str())
+                .collect::<Vec<_>>(),
+            vec!["aww", "pics", "gifs"]
+        );
+        assert!(all_args
+            .iter()
+            .all(|args| args.limit == Some(3) && args.filter == Some(PostType::Image)));
+
+        assert_eq!(
+            parse_subscribe_many_args("limit=3"),
+            Err(SubscriptionArgsError::NoSubreddit)
+        );
+        assert_eq!(
+            parse_subscribe_many_args("aww pics color=red"),
+            Err(SubscriptionArgsError::UnknownOption("color".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_bot_permissions() {
+        let member = |json: serde_json::Value| {
+            let user = serde_json::json!({ "id": 1, "is_bot": true, "first_name": "bot" });
+            let mut json = json;
+            json["user"] = user;
+            serde_json::from_value::<teloxide::types::ChatMember>(json)
+                .unwrap()
+                .kind
+        };
+        let admin = |can_post_messages| {
+            member(serde_json::json!({
+                "status": "administrator",
+                "can_be_edited": false,
+                "is_anonymous": false,
+                "can_manage_chat": true,
+                "can_delete_messages": false,
+                "can_manage_video_chats": false,
+                "can_restrict_members": false,
+                "can_promote_members": false,
+                "can_change_info": false,
+                "can_invite_users": false,
+                "can_post_messages": can_post_messages,
+            }))
+        };
+        let allowed = BotPermissions {
+            can_send_messages: true,
+            can_send_media: true,
+            missing: vec![],
+        };
+
+        assert_eq!(bot_permissions(&admin(true), true), allowed);
+        assert_eq!(
+            bot_permissions(&admin(false), true),
+            BotPermissions {
+                can_send_messages: false,
+                can_send_media: false,
+                missing: vec!["can_post_messages"],
+            }
+        );
+        assert_eq!(bot_permissions(&admin(false), false), allowed);
+
+        let regular = member(serde_json::json!({ "status": "member" }));
+        assert_eq!(bot_permissions(&regular, false), allowed);
+        assert!(!bot_permissions(&regular, true).can_send_messages);
+
+        let restricted = member(serde_json::json!({
+            "status": "restricted",
+            "until_date": 0,
+            "is_member": true,
+            "can_send_messages": true,
+            "can_send_media_messages": false,
+            "can_send_other_messages": false,
+            "can_add_web_page_previews": false,
+            "can_change_info": false,
+            "can_invite_users": false,
+            "can_pin_messages": false,
+            "can_manage_topics": false,
+            "can_send_polls": false,
+        }));
+        assert_eq!(
+            bot_permissions(&restricted, false),
+            BotPermissions {
+                can_send_messages: true,
+                can_send_media: false,
+                missing: vec!["can_send_media_messages"],
+            }
+        );
+
+        let banned = member(serde_json::json!({ "status": "kicked", "until_date": 0 }));
+        assert_eq!(
+            bot_permissions(&banned, false),
+            BotPermissions {
+                can_send_messages: false,
+                can_send_media: false,
+                missing: vec!["is_member"],
+            }
+        );
     }
 }
@@ -1,21 +1,42 @@
 use log::error;
 use secrecy::{ExposeSecret, Secret};
 use serde::Deserialize;
-use std::{env, path::PathBuf};
+use std::{env, net::SocketAddr, path::PathBuf, time::Duration};
 
 use crate::{
     reddit::{PostType, TopPostsTimePeriod},
+    types::RedditSpoilers,
     PKG_NAME,
 };
 
 const CONFIG_PATH_ENV: &str = "CONFIG_PATH";
+const DB_PATH_ENV: &str = "TGREDDIT_DB_PATH";
+const TELEGRAM_BOT_TOKEN_ENV: &str = "TGREDDIT_TELEGRAM_BOT_TOKEN";
+const AUTHORIZED_USER_IDS_ENV: &str = "TGREDDIT_AUTHORIZED_USER_IDS";
+const CHECK_INTERVAL_SECS_ENV: &str = "TGREDDIT_CHECK_INTERVAL_SECS";
+const LINKS_BASE_URL_ENV: &str = "TGREDDIT_LINKS_BASE_URL";
 pub const DEFAULT_LIMIT: u32 = 1;
 pub const DEFAULT_TIME_PERIOD: TopPostsTimePeriod = TopPostsTimePeriod::Day;
+const DEFAULT_CATCH_UP_MAX_POSTS: u32 = 50;
+const DEFAULT_MEDIA_DOWNLOAD_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_VIDEO_DOWNLOAD_TIMEOUT_SECS: u64 = 600;
+const DEFAULT_ALL_TIME_POLL_DECAY: f64 = 2.0;
+const DEFAULT_FAILURE_DIGEST_INTERVAL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_FAILURE_DIGEST_MIN_FAILURES: u32 = 3;
+const DEFAULT_RECORD_POST_RETRIES: u32 = 3;
+const DEFAULT_ALL_TIME_MAX_POLL_INTERVAL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_SEND_CONCURRENCY: usize = 1;
+const DEFAULT_VIDEO_WORKERS: usize = 2;
+const DEFAULT_VIDEO_QUEUE_CAPACITY: usize = 8;
 
 #[derive(Debug, Deserialize)]
 pub struct SecretString(Secret<String>);
 
 impl SecretString {
+    pub fn new(secret: String) -> Self {
+        SecretString(Secret::new(secret))
+    }
+
     pub fn expose_secret(&self) -> &str {
         self.0.expose_secret()
     }
@@ -30,29 +51,179 @@ impl Default for SecretString {
 #[derive(Deserialize, Debug, Default)]
 pub struct Config {
     pub authorized_user_ids: Vec<u64>,
+    #[serde(default)]
+    pub admin_user_ids: Vec<u64>,
     #[serde(default = "default_db_path")]
     pub db_path: PathBuf,
     pub telegram_bot_token: SecretString,
     pub check_interval_secs: u64,
+    #[serde(default)]
+    pub poll_jitter_secs: u64,
+    #[serde(default)]
+    pub first_poll_stagger_secs: u64,
+    #[serde(default)]
+    pub align_polls_to_interval: bool,
     #[serde(default = "default_skip_initial_send")]
     pub skip_initial_send: bool,
+    #[serde(default)]
+    pub send_sticky_on_subscribe: bool,
     pub links_base_url: Option<String>,
+    pub reddit_base_url: Option<String>,
     pub default_limit: Option<u32>,
     pub default_time: Option<TopPostsTimePeriod>,
     pub default_filter: Option<PostType>,
+    pub imgur_client_id: Option<SecretString>,
+    #[serde(default = "default_catch_up_max_posts")]
+    pub catch_up_max_posts: u32,
+    pub api_addr: Option<SocketAddr>,
+    pub api_token: Option<SecretString>,
+    pub redis_url: Option<SecretString>,
+    pub telegram_webhook_url: Option<String>,
+    pub telegram_webhook_addr: Option<SocketAddr>,
+    #[serde(default = "default_media_download_timeout_secs")]
+    pub media_download_timeout_secs: u64,
+    #[serde(default = "default_video_download_timeout_secs")]
+    pub video_download_timeout_secs: u64,
+    #[serde(default = "default_video_workers")]
+    pub video_workers: usize,
+    #[serde(default = "default_video_queue_capacity")]
+    pub video_queue_capacity: usize,
+    pub caption_footer: Option<String>,
+    #[serde(default)]
+    pub caption_footer_raw: bool,
+    #[serde(default)]
+    pub reddit_spoilers: RedditSpoilers,
+    pub image_proxy_base: Option<String>,
+    #[serde(default)]
+    pub tracking_params: Vec<String>,
+    #[serde(default)]
+    pub check_db_integrity: bool,
+    #[serde(default = "default_all_time_poll_decay")]
+    pub all_time_poll_decay: f64,
+    #[serde(default = "default_all_time_max_poll_interval_secs")]
+    pub all_time_max_poll_interval_secs: u64,
+    pub admin_chat_id: Option<i64>,
+    #[serde(default = "default_failure_digest_interval_secs")]
+    pub failure_digest_interval_secs: u64,
+    #[serde(default = "default_failure_digest_min_failures")]
+    pub failure_digest_min_failures: u32,
+    #[serde(default = "default_record_post_retries")]
+    pub record_post_retries: u32,
+    #[serde(default = "default_link_fallback_on_send_failure")]
+    pub link_fallback_on_send_failure: bool,
+    #[serde(default)]
+    pub convert_webp_to_jpeg: bool,
+    pub max_subscriptions_per_chat: Option<u32>,
+    pub max_gallery_images: Option<usize>,
+    pub max_messages_per_minute: Option<u32>,
+    #[serde(default = "default_send_concurrency")]
+    pub send_concurrency: usize,
+    pub post_retention_days: Option<u32>,
+    pub tombstone_retention_days: Option<u32>,
+    #[serde(default)]
+    pub prune_empty_chats: bool,
+    #[serde(default)]
+    pub blocked_subreddits: Vec<String>,
+}
+
+impl Config {
+    /// Admin commands are available to all authorized users unless admins are configured.
+    pub fn is_admin(&self, user_id: u64) -> bool {
+        if self.admin_user_ids.is_empty() {
+            self.authorized_user_ids.contains(&user_id)
+        } else {
+            self.admin_user_ids.contains(&user_id)
+        }
+    }
+
+    /// Timeout of downloading a single image.
+    pub fn media_download_timeout(&self) -> Duration {
+        Duration::from_secs(self.media_download_timeout_secs)
+    }
+
+    /// Longest interval between checks of a subscription to the all-time top list.
+    pub fn all_time_max_poll_interval(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.all_time_max_poll_interval_secs as i64)
+    }
+
+    /// How long seen posts are kept before they are pruned. Posts are never pruned by default.
+    pub fn post_retention(&self) -> Option<chrono::Duration> {
+        self.post_retention_days
+            .map(|days| chrono::Duration::days(days.into()))
+    }
+
+    /// How long the ids of pruned posts are kept to recognize them as seen.
+    pub fn tombstone_retention(&self) -> Option<chrono::Duration> {
+        self.tombstone_retention_days
+            .map(|days| chrono::Duration::days(days.into()))
+    }
+
+    /// Timeout of downloading and transcoding a video.
+    pub fn video_download_timeout(&self) -> Duration {
+        Duration::from_secs(self.video_download_timeout_secs)
+    }
 }
 
 pub fn read_config() -> Config {
     env::var(CONFIG_PATH_ENV)
         .map_err(|_| format!("{CONFIG_PATH_ENV} environment variable not set"))
         .and_then(|config_path| std::fs::read_to_string(config_path).map_err(|e| e.to_string()))
-        .and_then(|str| toml::from_str(&str).map_err(|e| e.to_string()))
+        .and_then(|str| parse_config(&str, |name| env::var(name).ok()))
         .unwrap_or_else(|err| {
             error!("failed to read config: {err}");
             std::process::exit(1);
         })
 }
 
+/// Parses the TOML config and overlays values of environment variables, as looked up with
+/// `var`, on top of it.
+fn parse_config(str: &str, var: impl Fn(&str) -> Option<String>) -> Result<Config, String> {
+    let mut config: Config = toml::from_str(str).map_err(|e| e.to_string())?;
+    apply_env_overrides(&mut config, var)?;
+    #[cfg(not(feature = "redis"))]
+    if config.redis_url.is_some() {
+        return Err("redis_url requires tgreddit to be built with the redis feature".to_string());
+    }
+    Ok(config)
+}
+
+/// Environment variables take precedence over values in the config file.
+fn apply_env_overrides(
+    config: &mut Config,
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<(), String> {
+    fn invalid_value(name: &str, value: &str) -> String {
+        format!("invalid value for {name}: {value}")
+    }
+
+    if let Some(db_path) = var(DB_PATH_ENV) {
+        config.db_path = PathBuf::from(db_path);
+    }
+    if let Some(token) = var(TELEGRAM_BOT_TOKEN_ENV) {
+        config.telegram_bot_token = SecretString::new(token);
+    }
+    if let Some(user_ids) = var(AUTHORIZED_USER_IDS_ENV) {
+        config.authorized_user_ids = user_ids
+            .split(',')
+            .map(|id| {
+                id.trim()
+                    .parse()
+                    .map_err(|_| invalid_value(AUTHORIZED_USER_IDS_ENV, &user_ids))
+            })
+            .collect::<Result<_, _>>()?;
+    }
+    if let Some(secs) = var(CHECK_INTERVAL_SECS_ENV) {
+        config.check_interval_secs = secs
+            .parse()
+            .map_err(|_| invalid_value(CHECK_INTERVAL_SECS_ENV, &secs))?;
+    }
+    if let Some(links_base_url) = var(LINKS_BASE_URL_ENV) {
+        config.links_base_url = Some(links_base_url);
+    }
+
+    Ok(())
+}
+
 fn default_db_path() -> PathBuf {
     let xdg_dirs = xdg::BaseDirectories::with_prefix(PKG_NAME).unwrap();
     xdg_dirs.place_state_file("data.db3").unwrap()
@@ -61,3 +232,77 @@ fn default_db_path() -> PathBuf {
 fn default_skip_initial_send() -> bool {
     true
 }
+
+fn default_catch_up_max_posts() -> u32 {
+    DEFAULT_CATCH_UP_MAX_POSTS
+}
+
+fn default_media_download_timeout_secs() -> u64 {
+    DEFAULT_MEDIA_DOWNLOAD_TIMEOUT_SECS
+}
+
+fn default_video_download_timeout_secs() -> u64 {
+    DEFAULT_VIDEO_DOWNLOAD_TIMEOUT_SECS
+}
+
+fn default_all_time_poll_decay() -> f64 {
+    DEFAULT_ALL_TIME_POLL_DECAY
+}
+
+fn default_all_time_max_poll_interval_secs() -> u64 {
+    DEFAULT_ALL_TIME_MAX_POLL_INTERVAL_SECS
+}
+
+fn default_failure_digest_interval_secs() -> u64 {
+    DEFAULT_FAILURE_DIGEST_INTERVAL_SECS
+}
+
+fn default_failure_digest_min_failures() -> u32 {
+    DEFAULT_FAILURE_DIGEST_MIN_FAILURES
+}
+
+fn default_record_post_retries() -> u32 {
+    DEFAULT_RECORD_POST_RETRIES
+}
+
+fn default_send_concurrency() -> usize {
+    DEFAULT_SEND_CONCURRENCY
+}
+
+fn default_video_workers() -> usize {
+    DEFAULT_VIDEO_WORKERS
+}
+
+fn default_video_queue_capacity() -> usize {
+    DEFAULT_VIDEO_QUEUE_CAPACITY
+}
+
+fn default_link_fallback_on_send_failure() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_env_overrides_config_file() {
+        let toml = r#"
+            authorized_user_ids = [1]
+            db_path = "/from/file.db3"
+            telegram_bot_token = "token"
+            check_interval_secs = 60
+        "#;
+
+        let vars = HashMap::from([
+            (DB_PATH_ENV, "/from/env.db3"),
+            (AUTHORIZED_USER_IDS_ENV, "2, 3"),
+        ]);
+        let config =
+            parse_config(toml, |name| vars.get(name).map(|value| value.to_string())).unwrap();
+        assert_eq!(config.db_path, PathBuf::from("/from/env.db3"));
+        assert_eq!(config.authorized_user_ids, vec![2, 3]);
+        assert_eq!(config.check_interval_secs, 60);
+    }
+}
@@ -0,0 +1,243 @@
+use anyhow::{Context, Result};
+use duct::cmd;
+use image::{
+    codecs::{
+        gif::{GifEncoder, Repeat},
+        webp::WebPDecoder,
+    },
+    AnimationDecoder,
+};
+use log::info;
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+/// Largest width and height Telegram accepts for a video thumbnail.
+const MAX_THUMBNAIL_SIZE: u32 = 320;
+
+/// Format of a downloaded image, as far as it matters for sending it to Telegram.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    StaticWebp,
+    AnimatedWebp,
+    Other,
+}
+
+/// Detects WebP images from the RIFF header. Animated images use the extended `VP8X` format with
+/// the animation flag set.
+pub fn detect_image_format(bytes: &[u8]) -> ImageFormat {
+    if bytes.len() < 16 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WEBP" {
+        return ImageFormat::Other;
+    }
+
+    const ANIMATION_FLAG: u8 = 0x02;
+    match &bytes[12..16] {
+        b"VP8X"
+            if bytes
+                .get(20)
+                .is_some_and(|flags| flags & ANIMATION_FLAG != 0) =>
+        {
+            ImageFormat::AnimatedWebp
+        }
+        b"VP8 " | b"VP8L" | b"VP8X" => ImageFormat::StaticWebp,
+        _ => ImageFormat::Other,
+    }
+}
+
+/// Converts the image to a JPEG next to it, and returns the path of the JPEG. Transparent areas
+/// turn black, as JPEG has no alpha channel.
+pub fn convert_to_jpeg(path: &Path) -> Result<PathBuf> {
+    let output = path.with_extension("jpg");
+    info!("converting {path:?} to jpeg");
+    let decoded = image::io::Reader::open(path)?
+        .with_guessed_format()?
+        .decode()
+        .context("Failed to decode image")?;
+    decoded
+        .to_rgb8()
+        .save_with_format(&output, image::ImageFormat::Jpeg)
+        .context("Failed to write jpeg")?;
+    Ok(output)
+}
+
+/// Converts an animated WebP to a GIF next to it, keeping the timing of its frames, and returns
+/// the path of the GIF. Telegram plays GIFs as videos. ffmpeg isn't used, as its WebP decoder
+/// doesn't support animations.
+pub fn convert_animated_webp_to_gif(path: &Path) -> Result<PathBuf> {
+    let output = path.with_extension("gif");
+    info!("converting {path:?} to gif");
+    let decoder = WebPDecoder::new(BufReader::new(File::open(path)?))
+        .context("Failed to decode animated webp")?;
+    let mut encoder = GifEncoder::new(BufWriter::new(File::create(&output)?));
+    encoder.set_repeat(Repeat::Infinite)?;
+    encoder
+        .try_encode_frames(decoder.into_frames())
+        .context("Failed to write gif")?;
+    Ok(output)
+}
+
+/// Dimensions and length of a video, as reported by ffprobe.
+#[derive(Debug, PartialEq, Eq)]
+pub struct VideoInfo {
+    pub width: u32,
+    pub height: u32,
+    /// Rounded to the nearest second.
+    pub duration_secs: u32,
+}
+
+#[derive(Deserialize)]
+struct FfprobeOutput {
+    #[serde(default)]
+    streams: Vec<FfprobeStream>,
+    format: FfprobeFormat,
+}
+
+#[derive(Deserialize)]
+struct FfprobeStream {
+    width: u32,
+    height: u32,
+}
+
+#[derive(Deserialize)]
+struct FfprobeFormat {
+    /// ffprobe reports the duration as a string of seconds, e.g. "12.345000".
+    duration: String,
+}
+
+/// Parses the JSON output of `ffprobe -show_entries stream=width,height:format=duration`. `None`
+/// if there is no video stream or the duration is missing.
+pub fn parse_ffprobe_output(json: &str) -> Option<VideoInfo> {
+    let output: FfprobeOutput = serde_json::from_str(json).ok()?;
+    let stream = output.streams.first()?;
+    let duration = output.format.duration.parse::<f64>().ok()?;
+    if !duration.is_finite() || duration < 0.0 {
+        return None;
+    }
+    Some(VideoInfo {
+        width: stream.width,
+        height: stream.height,
+        duration_secs: duration.round() as u32,
+    })
+}
+
+/// Reads the dimensions and length of the video with ffprobe.
+pub fn probe_video(path: &Path) -> Result<VideoInfo> {
+    let output = cmd!(
+        "ffprobe",
+        "-v",
+        "error",
+        "-select_streams",
+        "v:0",
+        "-show_entries",
+        "stream=width,height:format=duration",
+        "-of",
+        "json",
+        path
+    )
+    .read()
+    .context("Failed to probe video with ffprobe")?;
+    parse_ffprobe_output(&output).context("ffprobe output has no video dimensions or duration")
+}
+
+/// Extracts the first frame of the video as a JPEG next to it, scaled to fit Telegram's thumbnail
+/// size, and returns the path of the JPEG.
+pub fn extract_thumbnail(path: &Path) -> Result<PathBuf> {
+    let output = path.with_extension("thumb.jpg");
+    let scale = format!(
+        "scale={MAX_THUMBNAIL_SIZE}:{MAX_THUMBNAIL_SIZE}:force_original_aspect_ratio=decrease"
+    );
+    info!("extracting thumbnail of {path:?}");
+    cmd!(
+        "ffmpeg",
+        "-y",
+        "-loglevel",
+        "error",
+        "-i",
+        path,
+        "-frames:v",
+        "1",
+        "-vf",
+        scale,
+        &output
+    )
+    .stderr_to_stdout()
+    .read()
+    .context("Failed to extract thumbnail with ffmpeg")?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ffprobe_output() {
+        let output = r#"{
+            "programs": [],
+            "streams": [{ "width": 1280, "height": 720 }],
+            "format": { "duration": "12.512000" }
+        }"#;
+        assert_eq!(
+            parse_ffprobe_output(output),
+            Some(VideoInfo {
+                width: 1280,
+                height: 720,
+                duration_secs: 13
+            })
+        );
+
+        // Audio only
+        assert_eq!(
+            parse_ffprobe_output(r#"{"streams": [], "format": {"duration": "3.0"}}"#),
+            None
+        );
+        assert_eq!(
+            parse_ffprobe_output(
+                r#"{"streams": [{"width": 640, "height": 480}], "format": {"duration": "N/A"}}"#
+            ),
+            None
+        );
+        assert_eq!(
+            parse_ffprobe_output(r#"{"streams": [{"width": 640, "height": 480}], "format": {}}"#),
+            None
+        );
+        assert_eq!(parse_ffprobe_output(""), None);
+    }
+
+    fn webp(chunk: &[u8; 4], flags: u8) -> Vec<u8> {
+        let mut bytes = b"RIFF\x00\x00\x00\x00WEBP".to_vec();
+        bytes.extend_from_slice(chunk);
+        bytes.extend_from_slice(&[10, 0, 0, 0, flags, 0, 0, 0]);
+        bytes
+    }
+
+    #[test]
+    fn test_detect_image_format() {
+        assert_eq!(
+            detect_image_format(&webp(b"VP8 ", 0)),
+            ImageFormat::StaticWebp
+        );
+        assert_eq!(
+            detect_image_format(&webp(b"VP8L", 0)),
+            ImageFormat::StaticWebp
+        );
+        assert_eq!(
+            detect_image_format(&webp(b"VP8X", 0x10)),
+            ImageFormat::StaticWebp
+        );
+        assert_eq!(
+            detect_image_format(&webp(b"VP8X", 0x12)),
+            ImageFormat::AnimatedWebp
+        );
+        assert_eq!(detect_image_format(&webp(b"ABCD", 0)), ImageFormat::Other);
+        assert_eq!(
+            detect_image_format(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR"),
+            ImageFormat::Other
+        );
+        assert_eq!(detect_image_format(b"GIF89a"), ImageFormat::Other);
+        assert_eq!(detect_image_format(b""), ImageFormat::Other);
+    }
+}
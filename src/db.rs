@@ -1,15 +1,19 @@
 use crate::{config::*, reddit::*, types::*};
 use anyhow::{Context, Result};
+use itertools::Itertools;
+use log::*;
 use rusqlite::{named_params, Connection, Row};
 use rusqlite::{
     types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, Value, ValueRef},
     OptionalExtension,
 };
 use rusqlite_migration::{Migrations, M};
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
 use std::str::FromStr;
 use std::string::ToString;
 use std::{convert::TryFrom, sync::Mutex};
+use thiserror::Error;
 
 const MIGRATIONS: &[&str] = &[
     "
@@ -133,25 +137,212 @@ const MIGRATIONS: &[&str] = &[
     "
     ALTER TABLE telegram_file_new RENAME TO telegram_file;
     ",
+    "
+    alter table chat add column silent integer not null default 0;
+    ",
+    "
+    alter table subscription add column min_score integer;
+    ",
+    "
+    create table repost_channel(
+        chat_id     integer not null,
+        channel_id  integer not null,
+        created_at  text not null,
+        primary key (chat_id, channel_id),
+        foreign key (chat_id) references chat(chat_id)
+    ) strict;
+    ",
+    "
+    insert into repost_channel (chat_id, channel_id, created_at)
+    select chat_id, repost_channel_id, datetime('now') from chat
+    where repost_channel_id is not null;
+    ",
+    "
+    alter table subscription add column dedup_titles integer not null default 0;
+    ",
+    "
+    alter table subscription add column oc_only integer not null default 0;
+    ",
+    "
+    alter table subscription add column send_order text not null default 'top_first';
+    ",
+    "
+    alter table subscription add column allow_nsfw integer not null default 0;
+    ",
+    "
+    alter table subscription add column claimed_by text;
+    alter table subscription add column claimed_until text;
+    ",
+    "
+    alter table chat add column merged_feed integer not null default 0;
+    ",
+    "
+    alter table post add column post_name text;
+    ",
+    "
+    alter table subscription add column consecutive_failures integer not null default 0;
+    alter table subscription add column last_error text;
+    ",
+    "
+    alter table chat add column caption_components text;
+    ",
+    "
+    alter table subscription add column blocked_authors text not null default '';
+    ",
+    "
+    alter table chat add column web_preview text not null default 'thumbnail';
+    ",
+    "
+    alter table chat add column nsfw_spoiler integer not null default 0;
+    ",
+    r#"
+    alter table subscription add column filters_json text not null default '{}';
+    update subscription
+    set filters_json = json_object(
+        'type', filter,
+        'min_score', min_score,
+        'dedup_titles', json(iif(dedup_titles, 'true', 'false')),
+        'oc_only', json(iif(oc_only, 'true', 'false')),
+        'nsfw', json(iif(allow_nsfw, 'true', 'false')),
+        'blocked_authors', iif(
+            blocked_authors = '',
+            json_array(),
+            json('["' || replace(replace(replace(blocked_authors, '\', '\\'), '"', '\"'), ',', '","') || '"]')
+        )
+    );
+    "#,
+    "
+    create table linked_chat(
+        chat_id         integer not null,
+        linked_chat_id  integer not null,
+        created_at      text not null,
+        primary key (chat_id, linked_chat_id),
+        foreign key (chat_id) references chat(chat_id),
+        foreign key (linked_chat_id) references chat(chat_id)
+    ) strict;
+    ",
+    "
+    alter table subscription add column message_thread_id integer;
+    ",
+    "
+    alter table chat add column daily_header text;
+    alter table chat add column last_header_date text;
+    ",
+    "
+    alter table chat add column snooze_until text;
+    ",
+    "
+    create table poll_state(
+        chat_id        integer not null,
+        subreddit      text not null,
+        last_polled_at text not null,
+        next_poll_at   text not null,
+        idle_polls     integer not null default 0,
+        primary key (chat_id, subreddit),
+        foreign key (subreddit, chat_id) references subscription(subreddit, chat_id)
+            on delete cascade on update cascade
+    ) strict;
+    ",
+    "
+    alter table subscription add column group_name text;
+    ",
+    "
+    create table sent_post_id(
+        post_id     text not null,
+        chat_id     integer not null,
+        pruned_at   text not null,
+        primary key (post_id, chat_id)
+    ) strict;
+    ",
+    "
+    alter table chat add column parse_mode text not null default 'html';
+    ",
+    "
+    alter table subscription add column last_delivered_at text;
+    ",
+    "
+    alter table subscription add column sort text;
+    alter table chat add column default_sort text;
+    ",
+    "
+    alter table post add column post_url text;
+    create index post_chat_url on post(chat_id, post_url);
+    ",
+    "
+    -- Marker: databases migrated past this get their subscriptions differing only in casing
+    -- merged once, see Database::migrate
+    ",
 ];
 
+/// Tables whose row counts are reported by /dbstats.
+const COUNTED_TABLES: &[&str] = &["post", "subscription", "chat"];
+
 #[derive(Debug)]
 pub struct Database {
     pub conn: Mutex<Connection>,
+    record_post_retries: u32,
+    max_subscriptions_per_chat: Option<u32>,
+    blocked_subreddits: Vec<String>,
+}
+
+/// Why a subscription could not be added.
+#[derive(Error, Debug)]
+pub enum SubscribeError {
+    #[error("Already subscribed to r/{0}")]
+    AlreadySubscribed(String),
+    #[error("This chat already has the maximum of {0} subscriptions")]
+    QuotaExceeded(u32),
+    #[error("Subscribing to r/{0} is not allowed")]
+    Blocked(String),
+    #[error(transparent)]
+    Db(#[from] anyhow::Error),
+}
+
+impl SubscribeError {
+    /// A conflict with the primary key means the chat is already subscribed to the subreddit.
+    fn from_insert_error(err: rusqlite::Error, subreddit: &str) -> Self {
+        match err {
+            rusqlite::Error::SqliteFailure(err, _)
+                if matches!(
+                    err.extended_code,
+                    rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY
+                        | rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE
+                ) =>
+            {
+                SubscribeError::AlreadySubscribed(subreddit.to_string())
+            }
+            err => {
+                SubscribeError::Db(anyhow::Error::new(err).context("could not add subscription"))
+            }
+        }
+    }
 }
 
 impl Database {
     pub fn open(config: &Config) -> Result<Self> {
         let conn = Self::get_conn(&config.db_path).context("error connecting to database")?;
         conn.pragma_update(None, "foreign_keys", "ON")?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
         Ok(Database {
             conn: Mutex::new(conn),
+            record_post_retries: config.record_post_retries,
+            max_subscriptions_per_chat: config.max_subscriptions_per_chat,
+            blocked_subreddits: config.blocked_subreddits.clone(),
         })
     }
 
+    /// Tests that need several connections to the same database can name a shared in-memory
+    /// database with db_path.
     #[cfg(test)]
-    fn get_conn(_db_path: &Path) -> Result<Connection, rusqlite::Error> {
-        Connection::open_in_memory()
+    fn get_conn(db_path: &Path) -> Result<Connection, rusqlite::Error> {
+        if db_path.as_os_str().is_empty() {
+            Connection::open_in_memory()
+        } else {
+            Connection::open(format!(
+                "file:{}?mode=memory&cache=shared",
+                db_path.display()
+            ))
+        }
     }
 
     #[cfg(not(test))]
@@ -161,9 +352,80 @@ impl Database {
         Connection::open(db_path)
     }
 
-    pub fn migrate(&mut self) -> Result<(), rusqlite_migration::Error> {
+    /// Migrates the schema to the latest version. Subscriptions created before subreddits were
+    /// matched case-insensitively are merged the first time the database is migrated past the
+    /// merge marker.
+    pub fn migrate(&mut self) -> Result<()> {
+        let version_before = self.schema_version()?;
         let migrations = MIGRATIONS.iter().map(|e| M::up(e)).collect();
-        Migrations::new(migrations).to_latest(&mut self.conn.lock().expect("No poison"))
+        Migrations::new(migrations).to_latest(&mut self.conn.lock().expect("No poison"))?;
+
+        if version_before < merge_marker_version() {
+            let merged = self.merge_duplicate_subscriptions()?;
+            if merged > 0 {
+                info!(
+                    "merged {merged} subscriptions differing only in the casing of the subreddit"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs sqlite's integrity and foreign key checks, logging every problem found. Returns whether
+    /// the database passed both.
+    pub fn check_integrity(&self) -> Result<bool> {
+        let conn = self.conn.lock().expect("No poison");
+
+        let mut stmt = conn.prepare("pragma integrity_check")?;
+        let problems = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()
+            .context("could not check database integrity")?;
+        let integrity_ok = problems == ["ok"];
+        if !integrity_ok {
+            for problem in &problems {
+                error!("database integrity check failed: {problem}");
+            }
+        }
+
+        let mut stmt = conn.prepare("pragma foreign_key_check")?;
+        let violations = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>("table")?,
+                    row.get::<_, Option<i64>>("rowid")?,
+                    row.get::<_, String>("parent")?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .context("could not check database foreign keys")?;
+        for (table, rowid, parent) in &violations {
+            error!("foreign key violation in table {table} rowid {rowid:?} referencing {parent}");
+        }
+
+        Ok(integrity_ok && violations.is_empty())
+    }
+
+    /// Number of rows in each of the largest tables.
+    pub fn table_counts(&self) -> Result<HashMap<String, i64>> {
+        let conn = &self.conn.lock().expect("No poison");
+        COUNTED_TABLES
+            .iter()
+            .map(|table| {
+                let count = conn
+                    .query_row(&format!("select count(*) from {table}"), [], |row| {
+                        row.get(0)
+                    })
+                    .with_context(|| format!("could not count rows of {table}"))?;
+                Ok((table.to_string(), count))
+            })
+            .collect()
+    }
+
+    pub fn schema_version(&self) -> Result<i64> {
+        let conn = self.conn.lock().expect("No poison");
+        conn.query_row("pragma user_version", [], |row| row.get(0))
+            .context("could not get schema version")
     }
 
     pub fn record_post<T: Recordable>(
@@ -172,12 +434,25 @@ impl Database {
         post: &T,
         seen_at: Option<chrono::DateTime<chrono::Utc>>,
     ) -> Result<()> {
+        let mut conn = self.conn.lock().expect("No poison");
+        retry_on_busy(self.record_post_retries, || {
+            Self::record_post_tx(&mut conn, chat_id, post, seen_at)
+        })
+        .context("could not record post")
+    }
+
+    fn record_post_tx<T: Recordable>(
+        conn: &mut Connection,
+        chat_id: i64,
+        post: &T,
+        seen_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> rusqlite::Result<()> {
+        let tx = conn.transaction()?;
         // First, attempt to insert a new row with INSERT OR IGNORE
-        let conn = self.conn.lock().expect("No poison");
-        let mut stmt = conn.prepare(
+        let mut stmt = tx.prepare(
             "
-            insert or ignore into post (post_id, chat_id, subreddit, seen_at, post_title)
-            values (:post_id, :chat_id, :subreddit, :seen_at, :post_title)
+            insert or ignore into post (post_id, chat_id, subreddit, seen_at, post_title, post_name, post_url)
+            values (:post_id, :chat_id, :subreddit, :seen_at, :post_title, :post_name, :post_url)
             ",
         )?;
         stmt.execute(named_params! {
@@ -186,10 +461,13 @@ impl Database {
             ":subreddit": &post.subreddit(),
             ":seen_at": seen_at,
             ":post_title": &post.title(),
+            ":post_name": post.fullname(),
+            ":post_url": post.normalized_url(),
         })?;
+        drop(stmt);
 
         // Then, update the seen_at field for the row with the given post_id and chat_id, only if seen_at is null
-        let mut stmt = conn.prepare(
+        let mut stmt = tx.prepare(
             "
             update post
             set seen_at = :seen_at
@@ -200,9 +478,10 @@ impl Database {
             ":seen_at": seen_at,
             ":post_id": post.id(),
             ":chat_id": chat_id,
-        })
-        .context("could not update seen_at")
-        .map(|_| ())
+        })?;
+        drop(stmt);
+
+        tx.commit()
     }
 
     pub fn record_post_seen_with_current_time<T: Recordable>(
@@ -245,6 +524,10 @@ impl Database {
                 select 1 
                   from post
                  where post_id = :post_id and chat_id = :chat_id and seen_at is not null
+            ) or exists(
+                select 1
+                  from sent_post_id
+                 where post_id = :post_id and chat_id = :chat_id
             );
             ",
         )?;
@@ -259,389 +542,4212 @@ impl Database {
         .map_err(anyhow::Error::from)
     }
 
-    pub fn existing_posts_for_subreddit(&self, chat_id: i64, subreddit: &str) -> Result<bool> {
+    /// Chat and post ids of every post considered seen by `is_post_seen`.
+    #[cfg_attr(not(feature = "redis"), allow(dead_code))]
+    pub fn get_seen_post_ids(&self) -> Result<Vec<(i64, String)>> {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
             "
-            select exists(
-                select 1
-                  from post
-                 where chat_id = :chat_id and subreddit = :subreddit
-            );
+            select chat_id, post_id from post where seen_at is not null
+            union
+            select chat_id, post_id from sent_post_id
             ",
         )?;
+        let ids = stmt
+            .query_map([], |row| Ok((row.get("chat_id")?, row.get("post_id")?)))?
+            .collect::<Result<Vec<_>, _>>()
+            .context("could not get seen post ids")?;
+        Ok(ids)
+    }
 
-        stmt.query_row(
+    /// Deletes the posts seen before `seen_before`, along with their Telegram files. With
+    /// `tombstone_retention` set, the ids of pruned posts are kept for that long so that the posts
+    /// are still considered seen if they reappear in listings. Returns the number of pruned posts.
+    pub fn prune_posts(
+        &self,
+        seen_before: chrono::DateTime<chrono::Utc>,
+        tombstone_retention: Option<chrono::Duration>,
+    ) -> Result<usize> {
+        let now = chrono::Utc::now();
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        tx.execute(
+            "delete from sent_post_id where pruned_at < :expired_before",
             named_params! {
-                ":chat_id": chat_id,
-                ":subreddit": subreddit,
+                ":expired_before": now - tombstone_retention.unwrap_or_else(chrono::Duration::zero),
             },
-            |row| row.get(0),
         )
-        .map_err(anyhow::Error::from)
+        .context("could not delete expired tombstones")?;
+        if tombstone_retention.is_some() {
+            tx.execute(
+                "
+                insert or ignore into sent_post_id (post_id, chat_id, pruned_at)
+                select post_id, chat_id, :now
+                from post
+                where seen_at < :seen_before
+                ",
+                named_params! {
+                    ":now": now,
+                    ":seen_before": seen_before,
+                },
+            )
+            .context("could not record tombstones")?;
+        }
+        tx.execute(
+            "
+            delete from telegram_file
+            where (post_id, chat_id) in (
+                select post_id, chat_id from post where seen_at < :seen_before
+            )
+            ",
+            named_params! {
+                ":seen_before": seen_before,
+            },
+        )
+        .context("could not delete telegram files of pruned posts")?;
+        let pruned = tx
+            .execute(
+                "delete from post where seen_at < :seen_before",
+                named_params! {
+                    ":seen_before": seen_before,
+                },
+            )
+            .context("could not prune posts")?;
+        tx.commit()?;
+        Ok(pruned)
     }
 
-    pub fn subscribe(&self, chat_id: i64, args: &SubscriptionArgs) -> Result<()> {
-        self.ensure_chat_exists(chat_id)?;
-
+    /// Whether the post has been seen in any chat linked to the chat.
+    pub fn is_post_seen_in_linked_chats<T: Recordable>(
+        &self,
+        chat_id: i64,
+        post: &T,
+    ) -> Result<bool> {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
             "
-            insert or replace into subscription (chat_id, subreddit, post_limit, time, filter, created_at)
-            values (:chat_id, :subreddit, :limit, :time, :filter, :created_at)
+            select exists(
+                select 1
+                  from post p
+                  join linked_chat l on l.linked_chat_id = p.chat_id
+                 where l.chat_id = :chat_id and p.post_id = :post_id and p.seen_at is not null
+            );
             ",
         )?;
-        stmt.execute(named_params! {
-            ":chat_id": chat_id,
-            ":subreddit": args.subreddit,
-            ":limit": args.limit,
-            ":time": args.time,
-            ":filter": args.filter,
-            ":created_at": chrono::Utc::now()
-        })
-        .context("could not add subscription")?;
-        Ok(())
+
+        stmt.query_row(
+            named_params! {
+                ":post_id": post.id(),
+                ":chat_id": chat_id
+            },
+            |row| row.get(0),
+        )
+        .context("could not check linked chats for post")
     }
 
-    pub fn unsubscribe(&self, chat_id: i64, subreddit: &str) -> Result<String> {
+    /// Titles of the posts most recently seen in the chat, newest first.
+    pub fn get_recent_post_titles(&self, chat_id: i64, limit: u32) -> Result<Vec<String>> {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
             "
-            delete from subscription
-            where chat_id = :chat_id and subreddit LIKE :subreddit
-            returning subreddit
+            select post_title
+            from post
+            where chat_id = :chat_id and seen_at is not null
+            order by seen_at desc
+            limit :limit
             ",
         )?;
-        let deleted_subreddit: String = stmt
-            .query_row(
+
+        let titles = stmt
+            .query_map(
                 named_params! {
                     ":chat_id": chat_id,
-                    ":subreddit": subreddit,
+                    ":limit": limit,
                 },
-                |row| row.get("subreddit"),
-            )
-            .context("could not delete subscription")?;
+                |row| row.get("post_title"),
+            )?
+            .collect::<Result<Vec<String>, _>>()
+            .context("could not retrieve recent post titles")?;
 
-        Ok(deleted_subreddit)
+        Ok(titles)
     }
 
-    pub fn get_subscriptions_for_chat(&self, chat_id: i64) -> Result<Vec<Subscription>> {
+    /// Posts delivered to the chat as CSV with a header row, oldest first. Records are terminated
+    /// by CRLF as described in RFC 4180.
+    pub fn export_history_csv(&self, chat_id: i64) -> Result<String> {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
             "
-            select chat_id, subreddit, post_limit, time, filter, created_at
-            from subscription
-            where chat_id = ?
+            select subreddit, post_id, post_title, seen_at
+            from post
+            where chat_id = :chat_id
+              and seen_at is not null
+              and subreddit != :video_link_subreddit
+            order by seen_at, post_id
             ",
         )?;
 
-        let subs = stmt
-            .query_map([chat_id], |row| Subscription::try_from(row))?
-            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+        let mut csv = csv::WriterBuilder::new()
+            .terminator(csv::Terminator::CRLF)
+            .from_writer(vec![]);
+        csv.write_record(["subreddit", "post_id", "title", "seen_at"])?;
+        let mut rows = stmt.query(named_params! {
+            ":chat_id": chat_id,
+            ":video_link_subreddit": VIDEO_LINK_SUBREDDIT,
+        })?;
+        while let Some(row) = rows.next()? {
+            let seen_at: chrono::DateTime<chrono::Utc> = row.get("seen_at")?;
+            csv.write_record([
+                row.get::<_, String>("subreddit")?,
+                row.get("post_id")?,
+                row.get("post_title")?,
+                seen_at.to_rfc3339(),
+            ])?;
+        }
 
-        Ok(subs)
+        let csv = csv.into_inner().context("could not write history csv")?;
+        String::from_utf8(csv).context("history csv is not valid UTF-8")
     }
 
-    pub fn get_all_subscriptions(&self) -> Result<Vec<Subscription>> {
+    /// Pairs of subreddits that delivered posts of the same content to the chat, e.g. the same
+    /// link or crossposts, with the number of shared urls, the most overlapping first.
+    pub fn subreddit_overlap(&self, chat_id: i64) -> Result<Vec<(String, String, u32)>> {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
             "
-            select chat_id, subreddit, post_limit, time, filter, created_at
-            from subscription
+            select a.subreddit as subreddit_a, b.subreddit as subreddit_b,
+                count(distinct a.post_url) as shared_count
+            from post a
+            join post b
+              on b.chat_id = a.chat_id
+             and b.post_url = a.post_url
+             and b.subreddit > a.subreddit collate nocase
+            where a.chat_id = :chat_id
+              and a.seen_at is not null
+              and b.seen_at is not null
+              and a.subreddit != :video_link_subreddit
+              and b.subreddit != :video_link_subreddit
+            group by a.subreddit, b.subreddit
+            order by shared_count desc, a.subreddit, b.subreddit
             ",
         )?;
 
-        let subs = stmt
-            .query_map([], |row| Subscription::try_from(row))?
-            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+        let overlap = stmt
+            .query_map(
+                named_params! {
+                    ":chat_id": chat_id,
+                    ":video_link_subreddit": VIDEO_LINK_SUBREDDIT,
+                },
+                |row| {
+                    Ok((
+                        row.get("subreddit_a")?,
+                        row.get("subreddit_b")?,
+                        row.get("shared_count")?,
+                    ))
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()
+            .context("could not get subreddit overlap")?;
 
-        Ok(subs)
+        Ok(overlap)
     }
 
-    pub fn ensure_chat_exists(&self, chat_id: i64) -> Result<()> {
+    /// Subreddits that delivered posts to the chat whose content had already been delivered from
+    /// another subreddit, e.g. the same link or a crosspost, the most duplicates first. Posts
+    /// delivered at the same time count as duplicates of the one recorded first.
+    pub fn duplicate_stats(&self, chat_id: i64) -> Result<Vec<DuplicateStats>> {
         let conn = &self.conn.lock().expect("No poison");
-        let chat_exists: bool = conn.query_row(
+        let mut stmt = conn.prepare(
             "
-            select exists(
-                select 1
-                from chat
-                where chat_id = :chat_id
-            );
+            select a.subreddit,
+                count(*) as post_count,
+                sum(exists(
+                    select 1
+                    from post b
+                    where b.chat_id = a.chat_id
+                      and b.post_url = a.post_url
+                      and b.subreddit != a.subreddit collate nocase
+                      and b.subreddit != :video_link_subreddit
+                      and b.seen_at is not null
+                      and (b.seen_at < a.seen_at or (b.seen_at = a.seen_at and b.rowid < a.rowid))
+                )) as duplicate_count
+            from post a
+            where a.chat_id = :chat_id
+              and a.seen_at is not null
+              and a.subreddit != :video_link_subreddit
+            group by a.subreddit
+            having duplicate_count > 0
+            order by duplicate_count desc, a.subreddit
             ",
-            named_params! {
-                ":chat_id": chat_id,
-            },
-            |row| row.get(0),
         )?;
 
-        if !chat_exists {
-            let mut stmt = conn.prepare(
-                "
-                insert into chat (chat_id)
-                values (:chat_id);
-                ",
-            )?;
-
-            stmt.execute(named_params! {
-                ":chat_id": chat_id,
-            })
-            .context("could not create chat")?;
-        }
+        let stats = stmt
+            .query_map(
+                named_params! {
+                    ":chat_id": chat_id,
+                    ":video_link_subreddit": VIDEO_LINK_SUBREDDIT,
+                },
+                |row| {
+                    Ok(DuplicateStats {
+                        subreddit: row.get("subreddit")?,
+                        duplicate_count: row.get("duplicate_count")?,
+                        post_count: row.get("post_count")?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()
+            .context("could not get duplicate stats")?;
 
-        Ok(())
+        Ok(stats)
     }
 
-    pub fn set_repost_channel(&self, chat_id: i64, repost_channel_id: i64) -> Result<()> {
-        self.ensure_chat_exists(chat_id)?;
+    /// What is stored about the post for the chat, if it has been recorded.
+    pub fn get_post_record(&self, chat_id: i64, post_id: &str) -> Result<Option<PostRecord>> {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
             "
-            update chat
-            set repost_channel_id = :repost_channel_id
-            where chat_id = :chat_id;
+            select post_id, subreddit, post_title, seen_at, post_name
+            from post
+            where chat_id = :chat_id and post_id = :post_id
             ",
         )?;
 
-        stmt.execute(named_params! {
-            ":chat_id": chat_id,
-            ":repost_channel_id": repost_channel_id,
-        })
-        .context("could not set repost channel")?;
-
-        Ok(())
+        stmt.query_row(
+            named_params! {
+                ":chat_id": chat_id,
+                ":post_id": post_id,
+            },
+            |row| {
+                Ok(PostRecord {
+                    post_id: row.get("post_id")?,
+                    subreddit: row.get("subreddit")?,
+                    title: row.get("post_title")?,
+                    seen_at: row.get("seen_at")?,
+                    name: row.get("post_name")?,
+                })
+            },
+        )
+        .optional()
+        .context("could not get post record")
     }
 
-    pub fn get_repost_channel(&self, chat_id: i64) -> Result<Option<i64>> {
+    /// Finds posts delivered to the chat whose title contains the query, newest first.
+    pub fn search_history(
+        &self,
+        chat_id: i64,
+        query: &str,
+        limit: u32,
+    ) -> Result<Vec<HistoryEntry>> {
         let conn = &self.conn.lock().expect("No poison");
         let mut stmt = conn.prepare(
-            "
-            select repost_channel_id
-            from chat
-            where chat_id = :chat_id;
+            r"
+            select post_id, subreddit, post_title, seen_at
+            from post
+            where chat_id = :chat_id
+              and seen_at is not null
+              and subreddit != :video_link_subreddit
+              and post_title like :pattern escape '\'
+            order by seen_at desc
+            limit :limit
             ",
         )?;
 
-        let repost_channel_id: Option<i64> = stmt
-            .query_row(
+        let entries = stmt
+            .query_map(
                 named_params! {
                     ":chat_id": chat_id,
+                    ":video_link_subreddit": VIDEO_LINK_SUBREDDIT,
+                    ":pattern": format!("%{}%", escape_like(query)),
+                    ":limit": limit,
                 },
-                |row| row.get("repost_channel_id"),
-            )
-            .optional()
-            .context("could not get repost channel")?;
+                |row| {
+                    Ok(HistoryEntry {
+                        post_id: row.get("post_id")?,
+                        subreddit: row.get("subreddit")?,
+                        title: row.get("post_title")?,
+                        seen_at: row.get("seen_at")?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()
+            .context("could not search history")?;
 
-        Ok(repost_channel_id)
+        Ok(entries)
     }
 
-    pub fn add_telegram_file(
-        &self,
-        post_id: &str,
-        chat_id: i64,
-        telegram_file_id: &str,
-        telegram_unique_file_id: &str,
-    ) -> Result<()> {
+    /// At most `limit` of the posts recorded for the chat that have not been sent yet, oldest
+    /// first, along with how many posts have not been sent in total.
+    pub fn get_pending_posts(&self, chat_id: i64, limit: u32) -> Result<(Vec<QueuedPost>, u32)> {
         let conn = &self.conn.lock().expect("No poison");
+        let total: u32 = conn
+            .query_row(
+                "select count(*) from post where chat_id = :chat_id and seen_at is null",
+                named_params! { ":chat_id": chat_id },
+                |row| row.get(0),
+            )
+            .context("could not count pending posts")?;
         let mut stmt = conn.prepare(
             "
-            insert or ignore into telegram_file (post_id, chat_id, telegram_file_id, telegram_file_unique_id)
-            values (:post_id, :chat_id, :telegram_file_id, :telegram_file_unique_id)
+            select post_id, subreddit, post_title
+            from post
+            where chat_id = :chat_id and seen_at is null
+            order by rowid
+            limit :limit
             ",
         )?;
-        stmt.execute(named_params! {
-            ":post_id": post_id,
+
+        let posts = stmt
+            .query_map(
+                named_params! {
+                    ":chat_id": chat_id,
+                    ":limit": limit,
+                },
+                |row| {
+                    Ok(QueuedPost {
+                        post_id: row.get("post_id")?,
+                        subreddit: row.get("subreddit")?,
+                        title: row.get("post_title")?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()
+            .context("could not get pending posts")?;
+
+        Ok((posts, total))
+    }
+
+    pub fn existing_posts_for_subreddit(&self, chat_id: i64, subreddit: &str) -> Result<bool> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select exists(
+                select 1
+                  from post
+                 where chat_id = :chat_id and subreddit = :subreddit
+            );
+            ",
+        )?;
+
+        stmt.query_row(
+            named_params! {
+                ":chat_id": chat_id,
+                ":subreddit": subreddit,
+            },
+            |row| row.get(0),
+        )
+        .map_err(anyhow::Error::from)
+    }
+
+    /// Number of posts sent to the chat. Posts that were recorded but not sent yet are not counted.
+    pub fn count_posts(&self, chat_id: i64) -> Result<u64> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select count(*)
+              from post
+             where chat_id = :chat_id and seen_at is not null;
+            ",
+        )?;
+
+        stmt.query_row(
+            named_params! {
+                ":chat_id": chat_id,
+            },
+            |row| row.get(0),
+        )
+        .context("could not count posts")
+    }
+
+    /// Adds the subscription. Fails with `SubscribeError::AlreadySubscribed` if the chat is
+    /// already subscribed to the subreddit, so that its options are not replaced by accident.
+    pub fn subscribe(&self, chat_id: i64, args: &SubscriptionArgs) -> Result<(), SubscribeError> {
+        if self
+            .blocked_subreddits
+            .iter()
+            .any(|blocked| blocked.eq_ignore_ascii_case(&args.subreddit))
+        {
+            return Err(SubscribeError::Blocked(args.subreddit.clone()));
+        }
+
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction().context("could not add subscription")?;
+        ensure_chat_exists(&tx, chat_id)?;
+        let sort = subscription_sort(&tx, chat_id, args.sort)?;
+
+        let mut stmt = tx
+            .prepare(
+                "
+            insert into subscription (chat_id, subreddit, post_limit, sort, time, filter, min_score, dedup_titles, oc_only, send_order, allow_nsfw, blocked_authors, filters_json, created_at)
+            values (:chat_id, :subreddit, :limit, :sort, :time, :filter, :min_score, :dedup_titles, :oc_only, :send_order, :allow_nsfw, :blocked_authors, :filters_json, :created_at)
+            ",
+            )
+            .context("could not add subscription")?;
+        stmt.execute(named_params! {
             ":chat_id": chat_id,
-            ":telegram_file_id": telegram_file_id,
-            ":telegram_file_unique_id": telegram_unique_file_id,
+            ":subreddit": args.subreddit,
+            ":limit": args.limit,
+            ":sort": sort,
+            ":time": args.time,
+            ":filter": args.filter,
+            ":min_score": args.min_score,
+            ":dedup_titles": args.dedup_titles,
+            ":oc_only": args.oc_only,
+            ":send_order": args.send_order,
+            ":allow_nsfw": args.allow_nsfw,
+            ":blocked_authors": args.blocked_authors.join(","),
+            ":filters_json": FilterConfig::from(args),
+            ":created_at": chrono::Utc::now()
         })
-        .context("could not add telegram file")
-        .map(|_| ())
+        .map_err(|err| SubscribeError::from_insert_error(err, &args.subreddit))?;
+        drop(stmt);
+
+        // Counted after inserting so that subscribing again reports the existing subscription
+        // rather than the quota
+        if let Some(max) = self.max_subscriptions_per_chat {
+            let count: u32 = tx
+                .query_row(
+                    "select count(*) from subscription where chat_id = :chat_id",
+                    named_params! { ":chat_id": chat_id },
+                    |row| row.get(0),
+                )
+                .context("could not count subscriptions")?;
+            if count > max {
+                return Err(SubscribeError::QuotaExceeded(max));
+            }
+        }
+
+        tx.commit().context("could not add subscription")?;
+        Ok(())
+    }
+
+    /// Replaces the options of the chat's existing subscription with `args`. Unlike unsubscribing
+    /// and subscribing again, the subscription's group, topic and poll state are kept. Returns
+    /// false if the chat is not subscribed to the subreddit.
+    pub fn update_subscription(&self, chat_id: i64, args: &SubscriptionArgs) -> Result<bool> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn
+            .transaction()
+            .context("could not update subscription")?;
+        let sort = subscription_sort(&tx, chat_id, args.sort)?;
+        let updated = tx
+            .execute(
+                r"
+                update subscription
+                set post_limit = :limit, sort = :sort, time = :time, filter = :filter,
+                    min_score = :min_score, dedup_titles = :dedup_titles, oc_only = :oc_only,
+                    send_order = :send_order, allow_nsfw = :allow_nsfw,
+                    blocked_authors = :blocked_authors, filters_json = :filters_json
+                where chat_id = :chat_id and subreddit like :subreddit escape '\'
+                ",
+                named_params! {
+                    ":chat_id": chat_id,
+                    ":subreddit": escape_like(&args.subreddit),
+                    ":limit": args.limit,
+                    ":sort": sort,
+                    ":time": args.time,
+                    ":filter": args.filter,
+                    ":min_score": args.min_score,
+                    ":dedup_titles": args.dedup_titles,
+                    ":oc_only": args.oc_only,
+                    ":send_order": args.send_order,
+                    ":allow_nsfw": args.allow_nsfw,
+                    ":blocked_authors": args.blocked_authors.join(","),
+                    ":filters_json": FilterConfig::from(args),
+                },
+            )
+            .context("could not update subscription")?;
+        tx.commit().context("could not update subscription")?;
+
+        Ok(updated > 0)
+    }
+
+    /// Deletes the chat's subscription, matching the subreddit name case-insensitively. Returns
+    /// the stored name of the deleted subscription, or `None` if there was no such subscription.
+    pub fn unsubscribe(&self, chat_id: i64, subreddit: &str) -> Result<Option<String>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            r"
+            delete from subscription
+            where chat_id = :chat_id and subreddit like :subreddit escape '\'
+            returning subreddit
+            ",
+        )?;
+        let deleted_subreddit: Option<String> = stmt
+            .query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                    ":subreddit": escape_like(subreddit),
+                },
+                |row| row.get("subreddit"),
+            )
+            .optional()
+            .context("could not delete subscription")?;
+
+        Ok(deleted_subreddit)
+    }
+
+    /// Renames the chat's subscription and the posts recorded for it from `from` to `to`, e.g. to
+    /// fix the casing of a stored name. `from` must match the stored name exactly. Returns false if
+    /// there is no such subscription, and fails if the chat is already subscribed to `to`.
+    pub fn rename_subreddit(&self, chat_id: i64, from: &str, to: &str) -> Result<bool> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+
+        let taken = tx
+            .query_row(
+                "
+                select exists(
+                    select 1 from subscription
+                    where chat_id = :chat_id and subreddit = :to collate nocase
+                      and subreddit != :from
+                )
+                ",
+                named_params! {
+                    ":chat_id": chat_id,
+                    ":from": from,
+                    ":to": to,
+                },
+                |row| row.get::<_, bool>(0),
+            )
+            .context("could not check for existing subscription")?;
+        if taken {
+            anyhow::bail!("already subscribed to r/{to}");
+        }
+
+        let renamed = tx.execute(
+            "
+            update subscription
+            set subreddit = :to
+            where chat_id = :chat_id and subreddit = :from
+            ",
+            named_params! {
+                ":chat_id": chat_id,
+                ":from": from,
+                ":to": to,
+            },
+        );
+        let renamed = match renamed {
+            Err(rusqlite::Error::SqliteFailure(err, _))
+                if err.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY =>
+            {
+                anyhow::bail!("already subscribed to r/{to}");
+            }
+            renamed => renamed.context("could not rename subscription")?,
+        };
+        if renamed == 0 {
+            return Ok(false);
+        }
+
+        tx.execute(
+            "
+            update post
+            set subreddit = :to
+            where chat_id = :chat_id and subreddit = :from
+            ",
+            named_params! {
+                ":chat_id": chat_id,
+                ":from": from,
+                ":to": to,
+            },
+        )
+        .context("could not rename subreddit of posts")?;
+
+        tx.commit().context("could not rename subreddit")?;
+        Ok(true)
+    }
+
+    /// Collapses subscriptions of a chat whose subreddit names differ only in casing, e.g.
+    /// `AskReddit` and `askreddit`, into the oldest of them, which keeps its options. The posts
+    /// recorded for the others are moved to it, so that they aren't sent again. Returns how many
+    /// subscriptions were merged away.
+    pub fn merge_duplicate_subscriptions(&self) -> Result<usize> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+
+        let subs = tx
+            .prepare(
+                "
+                select chat_id, subreddit
+                from subscription
+                order by chat_id, lower(subreddit), created_at, rowid
+                ",
+            )?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>("chat_id")?,
+                    row.get::<_, String>("subreddit")?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()
+            .context("could not get subscriptions")?;
+
+        let mut merged = 0;
+        let groups = subs
+            .iter()
+            .group_by(|(chat_id, subreddit)| (*chat_id, subreddit.to_ascii_lowercase()));
+        for (_, duplicates) in &groups {
+            let duplicates = duplicates.collect::<Vec<_>>();
+            let [(chat_id, kept), duplicates @ ..] = duplicates.as_slice() else {
+                continue;
+            };
+            for (_, duplicate) in duplicates {
+                info!("merging subscription to {duplicate} into {kept} chat_id={chat_id}");
+                tx.execute(
+                    "
+                    update post
+                    set subreddit = :kept
+                    where chat_id = :chat_id and subreddit = :duplicate
+                    ",
+                    named_params! {
+                        ":chat_id": chat_id,
+                        ":kept": kept,
+                        ":duplicate": duplicate,
+                    },
+                )
+                .context("could not move posts of duplicate subscription")?;
+                tx.execute(
+                    "
+                    delete from subscription
+                    where chat_id = :chat_id and subreddit = :duplicate
+                    ",
+                    named_params! {
+                        ":chat_id": chat_id,
+                        ":duplicate": duplicate,
+                    },
+                )
+                .context("could not delete duplicate subscription")?;
+                merged += 1;
+            }
+        }
+
+        tx.commit()
+            .context("could not merge duplicate subscriptions")?;
+        Ok(merged)
+    }
+
+    /// Sets only the post limit of the subscription, clamped to what Reddit returns in one listing.
+    /// Returns the limit that was set, or `None` if the chat is not subscribed to the subreddit.
+    pub fn set_subscription_limit(
+        &self,
+        chat_id: i64,
+        subreddit: &str,
+        limit: u32,
+    ) -> Result<Option<u32>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            r"
+            update subscription
+            set post_limit = :post_limit
+            where chat_id = :chat_id and subreddit like :subreddit escape '\'
+            returning post_limit
+            ",
+        )?;
+
+        stmt.query_row(
+            named_params! {
+                ":chat_id": chat_id,
+                ":subreddit": escape_like(subreddit),
+                ":post_limit": limit.clamp(1, MAX_LISTING_LIMIT),
+            },
+            |row| row.get("post_limit"),
+        )
+        .optional()
+        .context("could not set subscription limit")
+    }
+
+    /// Sets the forum topic the subscription's posts are sent to, or the chat's general topic if
+    /// `None`. Returns false if the chat is not subscribed to the subreddit.
+    pub fn set_subscription_thread(
+        &self,
+        chat_id: i64,
+        subreddit: &str,
+        message_thread_id: Option<i32>,
+    ) -> Result<bool> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            r"
+            update subscription
+            set message_thread_id = :message_thread_id
+            where chat_id = :chat_id and subreddit like :subreddit escape '\'
+            ",
+        )?;
+
+        let updated = stmt
+            .execute(named_params! {
+                ":chat_id": chat_id,
+                ":subreddit": escape_like(subreddit),
+                ":message_thread_id": message_thread_id,
+            })
+            .context("could not set subscription thread")?;
+        Ok(updated > 0)
+    }
+
+    /// Labels the subscription with a group, or removes it from its group with `None`. Returns
+    /// false if the chat is not subscribed to the subreddit.
+    pub fn set_subscription_group(
+        &self,
+        chat_id: i64,
+        subreddit: &str,
+        group: Option<&str>,
+    ) -> Result<bool> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            r"
+            update subscription
+            set group_name = :group_name
+            where chat_id = :chat_id and subreddit like :subreddit escape '\'
+            ",
+        )?;
+
+        let updated = stmt
+            .execute(named_params! {
+                ":chat_id": chat_id,
+                ":subreddit": escape_like(subreddit),
+                ":group_name": group,
+            })
+            .context("could not set subscription group")?;
+        Ok(updated > 0)
+    }
+
+    /// Subscriptions of the chat labeled with the group, compared case-insensitively, sorted by
+    /// subreddit.
+    pub fn get_subscriptions_for_chat_in_group(
+        &self,
+        chat_id: i64,
+        group: &str,
+    ) -> Result<Vec<Subscription>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select chat_id, subreddit, post_limit, time, filter, min_score, dedup_titles, oc_only, send_order, allow_nsfw, blocked_authors, filters_json, message_thread_id, group_name, sort, created_at
+            from subscription
+            where chat_id = :chat_id and group_name = :group_name collate nocase
+            order by subreddit collate nocase
+            ",
+        )?;
+
+        let subs = stmt
+            .query_map(
+                named_params! {
+                    ":chat_id": chat_id,
+                    ":group_name": group,
+                },
+                |row| Subscription::try_from(row),
+            )?
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+
+        Ok(subs)
+    }
+
+    /// Stores when the subscription was polled, so that its schedule survives restarts.
+    pub fn set_poll_state(&self, chat_id: i64, subreddit: &str, state: &PollState) -> Result<()> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            insert into poll_state (chat_id, subreddit, last_polled_at, next_poll_at, idle_polls)
+            values (:chat_id, :subreddit, :last_polled_at, :next_poll_at, :idle_polls)
+            on conflict (chat_id, subreddit) do update
+            set last_polled_at = excluded.last_polled_at,
+                next_poll_at = excluded.next_poll_at,
+                idle_polls = excluded.idle_polls;
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":subreddit": subreddit,
+            ":last_polled_at": state.last_polled_at,
+            ":next_poll_at": state.next_poll_at,
+            ":idle_polls": state.idle_polls,
+        })
+        .context("could not set poll state")?;
+        Ok(())
+    }
+
+    /// Returns `None` if the subscription has not been polled yet.
+    pub fn get_poll_state(&self, chat_id: i64, subreddit: &str) -> Result<Option<PollState>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select last_polled_at, next_poll_at, idle_polls
+            from poll_state
+            where chat_id = :chat_id and subreddit = :subreddit;
+            ",
+        )?;
+
+        stmt.query_row(
+            named_params! {
+                ":chat_id": chat_id,
+                ":subreddit": subreddit,
+            },
+            |row| {
+                Ok(PollState {
+                    last_polled_at: row.get("last_polled_at")?,
+                    next_poll_at: row.get("next_poll_at")?,
+                    idle_polls: row.get("idle_polls")?,
+                })
+            },
+        )
+        .optional()
+        .context("could not get poll state")
+    }
+
+    /// The forum topic the subscription's posts are sent to. Returns `None` if the chat is not
+    /// subscribed to the subreddit.
+    pub fn get_subscription_thread(
+        &self,
+        chat_id: i64,
+        subreddit: &str,
+    ) -> Result<Option<Option<i32>>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            r"
+            select message_thread_id
+            from subscription
+            where chat_id = :chat_id and subreddit like :subreddit escape '\'
+            ",
+        )?;
+
+        stmt.query_row(
+            named_params! {
+                ":chat_id": chat_id,
+                ":subreddit": escape_like(subreddit),
+            },
+            |row| row.get("message_thread_id"),
+        )
+        .optional()
+        .context("could not get subscription thread")
+    }
+
+    /// All stored columns of the subscription row, with values rendered as text, for debugging.
+    pub fn get_subscription_raw(
+        &self,
+        chat_id: i64,
+        subreddit: &str,
+    ) -> Result<Option<BTreeMap<String, String>>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            r"
+            select *
+            from subscription
+            where chat_id = :chat_id and subreddit like :subreddit escape '\'
+            ",
+        )?;
+        let column_names = stmt
+            .column_names()
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+
+        stmt.query_row(
+            named_params! {
+                ":chat_id": chat_id,
+                ":subreddit": escape_like(subreddit),
+            },
+            |row| {
+                column_names
+                    .iter()
+                    .enumerate()
+                    .map(|(i, name)| Ok((name.clone(), format_raw_value(row.get_ref(i)?))))
+                    .collect()
+            },
+        )
+        .optional()
+        .context("could not get subscription")
+    }
+
+    /// Counts a failed check of the subscription and stores its error.
+    pub fn record_subscription_failure(
+        &self,
+        chat_id: i64,
+        subreddit: &str,
+        error: &str,
+    ) -> Result<()> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            update subscription
+            set consecutive_failures = consecutive_failures + 1, last_error = :last_error
+            where chat_id = :chat_id and subreddit = :subreddit
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":subreddit": subreddit,
+            ":last_error": error,
+        })
+        .context("could not record subscription failure")?;
+
+        Ok(())
+    }
+
+    /// Resets the failure count of the subscription after a successful check.
+    pub fn record_subscription_success(&self, chat_id: i64, subreddit: &str) -> Result<()> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            update subscription
+            set consecutive_failures = 0, last_error = null
+            where chat_id = :chat_id and subreddit = :subreddit and consecutive_failures > 0
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":subreddit": subreddit,
+        })
+        .context("could not record subscription success")?;
+
+        Ok(())
+    }
+
+    /// Records that a post of the subscription was delivered at `delivered_at`.
+    pub fn touch_subscription(
+        &self,
+        chat_id: i64,
+        subreddit: &str,
+        delivered_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<()> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            update subscription
+            set last_delivered_at = :delivered_at
+            where chat_id = :chat_id and subreddit = :subreddit
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":subreddit": subreddit,
+            ":delivered_at": delivered_at,
+        })
+        .context("could not touch subscription")?;
+
+        Ok(())
+    }
+
+    /// Subscriptions of all chats that have not delivered a post since `inactive_since`.
+    /// Subscriptions that have never delivered anything count from the time they were created.
+    pub fn get_inactive_subscriptions(
+        &self,
+        inactive_since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<Subscription>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select chat_id, subreddit, post_limit, time, filter, min_score, dedup_titles, oc_only, send_order, allow_nsfw, blocked_authors, filters_json, message_thread_id, group_name, sort, created_at
+            from subscription
+            where coalesce(last_delivered_at, created_at) < :inactive_since
+            order by chat_id, subreddit
+            ",
+        )?;
+
+        let subs = stmt
+            .query_map(
+                named_params! {
+                    ":inactive_since": inactive_since,
+                },
+                |row| Subscription::try_from(row),
+            )?
+            .collect::<Result<Vec<_>, _>>()
+            .context("could not get inactive subscriptions")?;
+
+        Ok(subs)
+    }
+
+    /// Subscriptions of all chats whose latest `min_failures` or more checks have failed, the most
+    /// failing first.
+    pub fn get_failing_subscriptions(&self, min_failures: u32) -> Result<Vec<FailingSubscription>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select chat_id, subreddit, consecutive_failures, last_error
+            from subscription
+            where consecutive_failures >= :min_failures and consecutive_failures > 0
+            order by consecutive_failures desc, chat_id, subreddit
+            ",
+        )?;
+
+        let subs = stmt
+            .query_map(
+                named_params! {
+                    ":min_failures": min_failures,
+                },
+                |row| {
+                    Ok(FailingSubscription {
+                        chat_id: row.get("chat_id")?,
+                        subreddit: row.get("subreddit")?,
+                        consecutive_failures: row.get("consecutive_failures")?,
+                        last_error: row.get("last_error")?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()
+            .context("could not get failing subscriptions")?;
+
+        Ok(subs)
+    }
+
+    /// Claims the subscription for `instance_id` until `now + lease`, so that other instances
+    /// using the same database skip it while it is being polled. Succeeds if the subscription is
+    /// unclaimed, its lease has expired or it is already claimed by the same instance, in which
+    /// case the lease is renewed. Returns whether the claim succeeded.
+    pub fn claim_subscription(
+        &self,
+        chat_id: i64,
+        subreddit: &str,
+        instance_id: &str,
+        now: chrono::DateTime<chrono::Utc>,
+        lease: chrono::Duration,
+    ) -> Result<bool> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            update subscription
+            set claimed_by = :instance_id, claimed_until = :claimed_until
+            where chat_id = :chat_id and subreddit = :subreddit
+              and (claimed_by is null or claimed_by = :instance_id or claimed_until <= :now)
+            ",
+        )?;
+        let updated = stmt
+            .execute(named_params! {
+                ":chat_id": chat_id,
+                ":subreddit": subreddit,
+                ":instance_id": instance_id,
+                ":claimed_until": now + lease,
+                ":now": now,
+            })
+            .context("could not claim subscription")?;
+
+        Ok(updated == 1)
+    }
+
+    /// Releases the claim of `instance_id` on the subscription, if it still holds one.
+    pub fn release_subscription(
+        &self,
+        chat_id: i64,
+        subreddit: &str,
+        instance_id: &str,
+    ) -> Result<()> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            update subscription
+            set claimed_by = null, claimed_until = null
+            where chat_id = :chat_id and subreddit = :subreddit and claimed_by = :instance_id
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":subreddit": subreddit,
+            ":instance_id": instance_id,
+        })
+        .context("could not release subscription")?;
+
+        Ok(())
+    }
+
+    /// Subscribes the target chat to the subscriptions of the source chat and copies the source
+    /// chat's settings. Subreddits the target chat is already subscribed to are left as they are.
+    /// Subreddits that can't be added because of the subscription quota or the blocklist are
+    /// skipped, and the rest are still copied.
+    pub fn copy_chat(&self, source_chat_id: i64, target_chat_id: i64) -> Result<ChatCopy> {
+        let mut copy = ChatCopy::default();
+        for sub in self.get_subscriptions_for_chat(source_chat_id)? {
+            if self
+                .get_subscription(target_chat_id, &sub.subreddit)?
+                .is_some()
+            {
+                continue;
+            }
+            let args = SubscriptionArgs {
+                // Pinned so that the target chat's default sort doesn't apply
+                sort: Some(sub.sort.unwrap_or_default()),
+                ..SubscriptionArgs::from(&sub)
+            };
+            match self.subscribe(target_chat_id, &args) {
+                Ok(()) => {}
+                Err(SubscribeError::AlreadySubscribed(_)) => continue,
+                Err(err @ (SubscribeError::QuotaExceeded(_) | SubscribeError::Blocked(_))) => {
+                    copy.skipped.push((sub.subreddit.clone(), err.to_string()));
+                    continue;
+                }
+                Err(SubscribeError::Db(err)) => return Err(err),
+            }
+            self.set_subscription_group(target_chat_id, &sub.subreddit, sub.group.as_deref())?;
+            copy.added += 1;
+        }
+        self.set_silent(target_chat_id, self.get_silent(source_chat_id)?)?;
+        self.set_merged_feed(target_chat_id, self.get_merged_feed(source_chat_id)?)?;
+        self.set_web_preview(target_chat_id, self.get_web_preview(source_chat_id)?)?;
+        self.set_parse_mode(target_chat_id, self.get_parse_mode(source_chat_id)?)?;
+        self.set_default_sort(target_chat_id, self.get_default_sort(source_chat_id)?)?;
+        self.set_nsfw_spoiler(target_chat_id, self.get_nsfw_spoiler(source_chat_id)?)?;
+        self.set_daily_header(
+            target_chat_id,
+            self.get_daily_header(source_chat_id)?.as_deref(),
+        )?;
+        self.set_caption_components(
+            target_chat_id,
+            Some(&self.get_caption_components(source_chat_id)?),
+        )?;
+
+        Ok(copy)
+    }
+
+    /// Deletes the chats that have no subscriptions, repost channels or linked chats, and whose
+    /// settings are all at their defaults. Returns the number of deleted chats.
+    pub fn prune_empty_chats(&self) -> Result<usize> {
+        let conn = self.conn.lock().expect("No poison");
+        conn.execute(
+            "
+            delete from chat
+            where repost_channel_id is null
+              and silent = 0
+              and merged_feed = 0
+              and caption_components is null
+              and web_preview = 'thumbnail'
+              and nsfw_spoiler = 0
+              and daily_header is null
+              and snooze_until is null
+              and parse_mode = 'html'
+              and default_sort is null
+              and not exists (select 1 from subscription s where s.chat_id = chat.chat_id)
+              and not exists (select 1 from repost_channel r where r.chat_id = chat.chat_id)
+              and not exists (
+                  select 1 from linked_chat l
+                  where l.chat_id = chat.chat_id or l.linked_chat_id = chat.chat_id
+              )
+            ",
+            [],
+        )
+        .context("could not prune empty chats")
+    }
+
+    /// Deletes everything stored about the chat.
+    pub fn forget_chat(&self, chat_id: i64) -> Result<()> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        tx.execute(
+            "delete from linked_chat where linked_chat_id = :chat_id",
+            named_params! {
+                ":chat_id": chat_id,
+            },
+        )
+        .context("could not delete chat's rows from linked_chat")?;
+        // Order matters because of foreign keys
+        for table in [
+            "telegram_file",
+            "post",
+            "sent_post_id",
+            "subscription",
+            "repost_channel",
+            "linked_chat",
+            "chat",
+        ] {
+            tx.execute(
+                &format!("delete from {table} where chat_id = :chat_id"),
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+            )
+            .with_context(|| format!("could not delete chat's rows from {table}"))?;
+        }
+        tx.commit().context("could not forget chat")
+    }
+
+    pub fn get_subscriptions_for_chat(&self, chat_id: i64) -> Result<Vec<Subscription>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select chat_id, subreddit, post_limit, time, filter, min_score, dedup_titles, oc_only, send_order, allow_nsfw, blocked_authors, filters_json, message_thread_id, group_name, sort, created_at
+            from subscription
+            where chat_id = ?
+            ",
+        )?;
+
+        let subs = stmt
+            .query_map([chat_id], |row| Subscription::try_from(row))?
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+
+        Ok(subs)
+    }
+
+    pub fn get_subscriptions_for_chat_sorted(
+        &self,
+        chat_id: i64,
+        sort: SubscriptionSort,
+    ) -> Result<Vec<Subscription>> {
+        let order_by = match sort {
+            SubscriptionSort::Name => "s.subreddit collate nocase",
+            SubscriptionSort::Created => "s.created_at, s.rowid",
+            SubscriptionSort::Delivered => "delivered_count desc, s.subreddit collate nocase",
+            SubscriptionSort::Errors => {
+                "s.consecutive_failures desc, s.last_error is null, s.subreddit collate nocase"
+            }
+        };
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(&format!(
+            "
+            select s.chat_id, s.subreddit, s.post_limit, s.time, s.filter, s.min_score, s.dedup_titles, s.oc_only, s.send_order, s.allow_nsfw, s.blocked_authors, s.filters_json, s.message_thread_id, s.group_name, s.sort, s.created_at,
+                (
+                    select count(*)
+                    from post p
+                    where p.chat_id = s.chat_id and p.subreddit = s.subreddit collate nocase and p.seen_at is not null
+                ) as delivered_count
+            from subscription s
+            where s.chat_id = :chat_id
+            order by {order_by}
+            ",
+        ))?;
+
+        let subs = stmt
+            .query_map(named_params! { ":chat_id": chat_id }, |row| {
+                Subscription::try_from(row)
+            })?
+            .collect::<Result<Vec<_>, rusqlite::Error>>()
+            .context("could not get subscriptions")?;
+
+        Ok(subs)
+    }
+
+    /// The chat's subscription to the subreddit. The subreddit is matched case-insensitively.
+    pub fn get_subscription(&self, chat_id: i64, subreddit: &str) -> Result<Option<Subscription>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            r"
+            select chat_id, subreddit, post_limit, time, filter, min_score, dedup_titles, oc_only, send_order, allow_nsfw, blocked_authors, filters_json, message_thread_id, group_name, sort, created_at
+            from subscription
+            where chat_id = :chat_id and subreddit like :subreddit escape '\'
+            ",
+        )?;
+
+        stmt.query_row(
+            named_params! {
+                ":chat_id": chat_id,
+                ":subreddit": escape_like(subreddit),
+            },
+            |row| Subscription::try_from(row),
+        )
+        .optional()
+        .context("could not get subscription")
+    }
+
+    pub fn get_all_subscriptions(&self) -> Result<Vec<Subscription>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select chat_id, subreddit, post_limit, time, filter, min_score, dedup_titles, oc_only, send_order, allow_nsfw, blocked_authors, filters_json, message_thread_id, group_name, sort, created_at
+            from subscription
+            ",
+        )?;
+
+        let subs = stmt
+            .query_map([], |row| Subscription::try_from(row))?
+            .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+
+        Ok(subs)
+    }
+
+    /// Subscriptions keyed by lowercased subreddit name, so that each subreddit can be fetched
+    /// once for all the chats subscribed to it. Subscriptions of a subreddit are ordered by chat.
+    pub fn get_subscriptions_grouped_by_subreddit(
+        &self,
+    ) -> Result<BTreeMap<String, Vec<Subscription>>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select chat_id, subreddit, post_limit, time, filter, min_score, dedup_titles, oc_only, send_order, allow_nsfw, blocked_authors, filters_json, message_thread_id, group_name, sort, created_at
+            from subscription
+            order by subreddit collate nocase, chat_id
+            ",
+        )?;
+
+        let mut groups: BTreeMap<String, Vec<Subscription>> = BTreeMap::new();
+        for sub in stmt.query_map([], |row| Subscription::try_from(row))? {
+            let sub = sub?;
+            groups
+                .entry(sub.subreddit.to_lowercase())
+                .or_default()
+                .push(sub);
+        }
+
+        Ok(groups)
+    }
+
+    pub fn add_repost_channel(&self, chat_id: i64, channel_id: i64) -> Result<()> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        ensure_chat_exists(&tx, chat_id)?;
+        let mut stmt = tx.prepare(
+            "
+            insert or ignore into repost_channel (chat_id, channel_id, created_at)
+            values (:chat_id, :channel_id, :created_at);
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":channel_id": channel_id,
+            ":created_at": chrono::Utc::now(),
+        })
+        .context("could not add repost channel")?;
+        drop(stmt);
+
+        tx.commit().context("could not add repost channel")
+    }
+
+    /// Returns whether the channel was registered for the chat.
+    pub fn remove_repost_channel(&self, chat_id: i64, channel_id: i64) -> Result<bool> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            delete from repost_channel
+            where chat_id = :chat_id and channel_id = :channel_id;
+            ",
+        )?;
+
+        let deleted = stmt
+            .execute(named_params! {
+                ":chat_id": chat_id,
+                ":channel_id": channel_id,
+            })
+            .context("could not remove repost channel")?;
+
+        Ok(deleted > 0)
+    }
+
+    /// Links the chats with each other, so that posts seen in one are skipped in the other.
+    pub fn link_chats(&self, chat_id: i64, other_chat_id: i64) -> Result<()> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        ensure_chat_exists(&tx, chat_id)?;
+        ensure_chat_exists(&tx, other_chat_id)?;
+        let mut stmt = tx.prepare(
+            "
+            insert or ignore into linked_chat (chat_id, linked_chat_id, created_at)
+            values (:chat_id, :linked_chat_id, :created_at);
+            ",
+        )?;
+
+        let created_at = chrono::Utc::now();
+        for (chat_id, linked_chat_id) in [(chat_id, other_chat_id), (other_chat_id, chat_id)] {
+            stmt.execute(named_params! {
+                ":chat_id": chat_id,
+                ":linked_chat_id": linked_chat_id,
+                ":created_at": created_at,
+            })
+            .context("could not link chats")?;
+        }
+        drop(stmt);
+
+        tx.commit().context("could not link chats")
+    }
+
+    /// Returns whether the chats were linked.
+    pub fn unlink_chats(&self, chat_id: i64, other_chat_id: i64) -> Result<bool> {
+        let conn = &self.conn.lock().expect("No poison");
+        let deleted = conn
+            .execute(
+                "
+                delete from linked_chat
+                where (chat_id = :chat_id and linked_chat_id = :other_chat_id)
+                   or (chat_id = :other_chat_id and linked_chat_id = :chat_id);
+                ",
+                named_params! {
+                    ":chat_id": chat_id,
+                    ":other_chat_id": other_chat_id,
+                },
+            )
+            .context("could not unlink chats")?;
+
+        Ok(deleted > 0)
+    }
+
+    pub fn get_linked_chats(&self, chat_id: i64) -> Result<Vec<i64>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select linked_chat_id
+            from linked_chat
+            where chat_id = :chat_id
+            order by created_at, rowid;
+            ",
+        )?;
+
+        let chats = stmt
+            .query_map(named_params! { ":chat_id": chat_id }, |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()
+            .context("could not get linked chats")?;
+
+        Ok(chats)
+    }
+
+    pub fn get_repost_channels(&self, chat_id: i64) -> Result<Vec<i64>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select channel_id
+            from repost_channel
+            where chat_id = :chat_id
+            order by created_at, rowid;
+            ",
+        )?;
+
+        let channels = stmt
+            .query_map(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("channel_id"),
+            )?
+            .collect::<Result<Vec<i64>, _>>()
+            .context("could not get repost channels")?;
+
+        Ok(channels)
+    }
+
+    /// Returns the first registered repost channel, for callers that only support one.
+    #[allow(dead_code)]
+    pub fn get_repost_channel(&self, chat_id: i64) -> Result<Option<i64>> {
+        Ok(self.get_repost_channels(chat_id)?.into_iter().next())
+    }
+
+    pub fn set_silent(&self, chat_id: i64, silent: bool) -> Result<()> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        ensure_chat_exists(&tx, chat_id)?;
+        let mut stmt = tx.prepare(
+            "
+            update chat
+            set silent = :silent
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":silent": silent,
+        })
+        .context("could not set silent")?;
+        drop(stmt);
+
+        tx.commit().context("could not set silent")
+    }
+
+    pub fn get_silent(&self, chat_id: i64) -> Result<bool> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select silent
+            from chat
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        let silent: Option<bool> = stmt
+            .query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("silent"),
+            )
+            .optional()
+            .context("could not get silent")?;
+
+        Ok(silent.unwrap_or(false))
+    }
+
+    pub fn set_merged_feed(&self, chat_id: i64, merged_feed: bool) -> Result<()> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        ensure_chat_exists(&tx, chat_id)?;
+        let mut stmt = tx.prepare(
+            "
+            update chat
+            set merged_feed = :merged_feed
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":merged_feed": merged_feed,
+        })
+        .context("could not set merged feed")?;
+        drop(stmt);
+
+        tx.commit().context("could not set merged feed")
+    }
+
+    pub fn get_merged_feed(&self, chat_id: i64) -> Result<bool> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select merged_feed
+            from chat
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        let merged_feed: Option<bool> = stmt
+            .query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("merged_feed"),
+            )
+            .optional()
+            .context("could not get merged feed")?;
+
+        Ok(merged_feed.unwrap_or(false))
+    }
+
+    pub fn set_nsfw_spoiler(&self, chat_id: i64, nsfw_spoiler: bool) -> Result<()> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        ensure_chat_exists(&tx, chat_id)?;
+        let mut stmt = tx.prepare(
+            "
+            update chat
+            set nsfw_spoiler = :nsfw_spoiler
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":nsfw_spoiler": nsfw_spoiler,
+        })
+        .context("could not set nsfw spoiler")?;
+        drop(stmt);
+
+        tx.commit().context("could not set nsfw spoiler")
+    }
+
+    pub fn get_nsfw_spoiler(&self, chat_id: i64) -> Result<bool> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select nsfw_spoiler
+            from chat
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        let nsfw_spoiler: Option<bool> = stmt
+            .query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("nsfw_spoiler"),
+            )
+            .optional()
+            .context("could not get nsfw spoiler")?;
+
+        Ok(nsfw_spoiler.unwrap_or(false))
+    }
+
+    /// Stops delivering posts to the chat until the given time, or resumes delivery if `None`.
+    pub fn snooze_chat(
+        &self,
+        chat_id: i64,
+        until: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        ensure_chat_exists(&tx, chat_id)?;
+        let mut stmt = tx.prepare(
+            "
+            update chat
+            set snooze_until = :snooze_until
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":snooze_until": until,
+        })
+        .context("could not snooze chat")?;
+        drop(stmt);
+
+        tx.commit().context("could not snooze chat")
+    }
+
+    /// When the chat's snooze ends, if it is snoozed at `now`. An expired snooze is treated as no
+    /// snooze at all.
+    pub fn get_snooze_until(
+        &self,
+        chat_id: i64,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select snooze_until
+            from chat
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        let snooze_until: Option<Option<chrono::DateTime<chrono::Utc>>> = stmt
+            .query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("snooze_until"),
+            )
+            .optional()
+            .context("could not get snooze")?;
+
+        Ok(snooze_until.flatten().filter(|until| *until > now))
+    }
+
+    /// Sets the text of the header sent before the first post of each day, or disables the header
+    /// if `None`.
+    pub fn set_daily_header(&self, chat_id: i64, daily_header: Option<&str>) -> Result<()> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        ensure_chat_exists(&tx, chat_id)?;
+        let mut stmt = tx.prepare(
+            "
+            update chat
+            set daily_header = :daily_header
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":daily_header": daily_header,
+        })
+        .context("could not set daily header")?;
+        drop(stmt);
+
+        tx.commit().context("could not set daily header")
+    }
+
+    pub fn get_daily_header(&self, chat_id: i64) -> Result<Option<String>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select daily_header
+            from chat
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        let daily_header: Option<Option<String>> = stmt
+            .query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("daily_header"),
+            )
+            .optional()
+            .context("could not get daily header")?;
+
+        Ok(daily_header.flatten())
+    }
+
+    /// Sets the sort that subscriptions of the chat get when they are created without one.
+    pub fn set_default_sort(&self, chat_id: i64, sort: Option<ListingSort>) -> Result<()> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        ensure_chat_exists(&tx, chat_id)?;
+        let mut stmt = tx.prepare(
+            "
+            update chat
+            set default_sort = :default_sort
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":default_sort": sort,
+        })
+        .context("could not set default sort")?;
+        drop(stmt);
+
+        tx.commit().context("could not set default sort")
+    }
+
+    pub fn get_default_sort(&self, chat_id: i64) -> Result<Option<ListingSort>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select default_sort
+            from chat
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        let sort: Option<Option<ListingSort>> = stmt
+            .query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("default_sort"),
+            )
+            .optional()
+            .context("could not get default sort")?;
+
+        Ok(sort.flatten())
+    }
+
+    pub fn set_last_header_date(&self, chat_id: i64, date: chrono::NaiveDate) -> Result<()> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        ensure_chat_exists(&tx, chat_id)?;
+        let mut stmt = tx.prepare(
+            "
+            update chat
+            set last_header_date = :last_header_date
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":last_header_date": date,
+        })
+        .context("could not set last header date")?;
+        drop(stmt);
+
+        tx.commit().context("could not set last header date")
+    }
+
+    /// The day the daily header was last sent to the chat, if ever.
+    pub fn get_last_header_date(&self, chat_id: i64) -> Result<Option<chrono::NaiveDate>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select last_header_date
+            from chat
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        let last_header_date: Option<Option<chrono::NaiveDate>> = stmt
+            .query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("last_header_date"),
+            )
+            .optional()
+            .context("could not get last header date")?;
+
+        Ok(last_header_date.flatten())
+    }
+
+    pub fn set_web_preview(&self, chat_id: i64, web_preview: WebPreview) -> Result<()> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        ensure_chat_exists(&tx, chat_id)?;
+        let mut stmt = tx.prepare(
+            "
+            update chat
+            set web_preview = :web_preview
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":web_preview": web_preview,
+        })
+        .context("could not set web preview")?;
+        drop(stmt);
+
+        tx.commit().context("could not set web preview")
+    }
+
+    pub fn get_web_preview(&self, chat_id: i64) -> Result<WebPreview> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select web_preview
+            from chat
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        let web_preview: Option<WebPreview> = stmt
+            .query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("web_preview"),
+            )
+            .optional()
+            .context("could not get web preview")?;
+
+        Ok(web_preview.unwrap_or_default())
+    }
+
+    pub fn set_parse_mode(&self, chat_id: i64, parse_mode: ParseMode) -> Result<()> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        ensure_chat_exists(&tx, chat_id)?;
+        let mut stmt = tx.prepare(
+            "
+            update chat
+            set parse_mode = :parse_mode
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":parse_mode": parse_mode,
+        })
+        .context("could not set parse mode")?;
+        drop(stmt);
+
+        tx.commit().context("could not set parse mode")
+    }
+
+    pub fn get_parse_mode(&self, chat_id: i64) -> Result<ParseMode> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select parse_mode
+            from chat
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        let parse_mode: Option<ParseMode> = stmt
+            .query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("parse_mode"),
+            )
+            .optional()
+            .context("could not get parse mode")?;
+
+        Ok(parse_mode.unwrap_or_default())
+    }
+
+    /// Sets the components shown in captions of posts delivered to the chat, or resets them to the
+    /// default with `None`.
+    pub fn set_caption_components(
+        &self,
+        chat_id: i64,
+        components: Option<&[CaptionComponent]>,
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().expect("No poison");
+        let tx = conn.transaction()?;
+        ensure_chat_exists(&tx, chat_id)?;
+        let mut stmt = tx.prepare(
+            "
+            update chat
+            set caption_components = :caption_components
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        stmt.execute(named_params! {
+            ":chat_id": chat_id,
+            ":caption_components": components.map(|components| components.iter().join(",")),
+        })
+        .context("could not set caption components")?;
+        drop(stmt);
+
+        tx.commit().context("could not set caption components")
+    }
+
+    pub fn get_caption_components(&self, chat_id: i64) -> Result<Vec<CaptionComponent>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select caption_components
+            from chat
+            where chat_id = :chat_id;
+            ",
+        )?;
+
+        let components: Option<String> = stmt
+            .query_row(
+                named_params! {
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("caption_components"),
+            )
+            .optional()
+            .context("could not get caption components")?
+            .flatten();
+
+        match components {
+            Some(components) => components
+                .split(',')
+                .filter(|component| !component.is_empty())
+                .map(|component| {
+                    CaptionComponent::from_str(component)
+                        .with_context(|| format!("invalid caption component {component}"))
+                })
+                .collect(),
+            None => Ok(DEFAULT_CAPTION_COMPONENTS.to_vec()),
+        }
+    }
+
+    pub fn add_telegram_file(
+        &self,
+        post_id: &str,
+        chat_id: i64,
+        telegram_file_id: &str,
+        telegram_unique_file_id: &str,
+    ) -> Result<()> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            insert or ignore into telegram_file (post_id, chat_id, telegram_file_id, telegram_file_unique_id)
+            values (:post_id, :chat_id, :telegram_file_id, :telegram_file_unique_id)
+            ",
+        )?;
+        stmt.execute(named_params! {
+            ":post_id": post_id,
+            ":chat_id": chat_id,
+            ":telegram_file_id": telegram_file_id,
+            ":telegram_file_unique_id": telegram_unique_file_id,
+        })
+        .context("could not add telegram file")
+        .map(|_| ())
+    }
+
+    pub fn get_telegram_files_for_post(&self, post_id: &str, chat_id: i64) -> Result<Vec<String>> {
+        let conn = &self.conn.lock().expect("No poison");
+        let mut stmt = conn.prepare(
+            "
+            select telegram_file_id
+            from telegram_file
+            where post_id = :post_id and chat_id = :chat_id
+            order by telegram_file.id
+            ",
+        )?;
+
+        let rows = stmt
+            .query_map(
+                named_params! {
+                    ":post_id": post_id,
+                    ":chat_id": chat_id,
+                },
+                |row| row.get("telegram_file_id"),
+            )
+            .context("could not retrieve telegram files")?;
+
+        let telegram_files: Result<Vec<String>, _> = rows.collect();
+        Ok(telegram_files?)
+    }
+}
+
+/// The sort a subscription is stored with. A sort given with the subscription takes precedence
+/// over the chat's default sort.
+fn subscription_sort(
+    conn: &Connection,
+    chat_id: i64,
+    sort: Option<ListingSort>,
+) -> Result<Option<ListingSort>> {
+    if sort.is_some() {
+        return Ok(sort);
+    }
+    let default_sort: Option<Option<ListingSort>> = conn
+        .query_row(
+            "select default_sort from chat where chat_id = :chat_id",
+            named_params! { ":chat_id": chat_id },
+            |row| row.get(0),
+        )
+        .optional()
+        .context("could not get default sort")?;
+    Ok(default_sort.flatten())
+}
+
+/// Creates the chat if it doesn't exist yet. A single `insert or ignore` rather than checking
+/// first, so that connections creating the same chat at the same time don't conflict.
+fn ensure_chat_exists(conn: &Connection, chat_id: i64) -> Result<()> {
+    conn.execute(
+        "
+        insert or ignore into chat (chat_id)
+        values (:chat_id);
+        ",
+        named_params! {
+            ":chat_id": chat_id,
+        },
+    )
+    .context("could not create chat")?;
+
+    Ok(())
+}
+
+fn format_raw_value(value: ValueRef) -> String {
+    match value {
+        ValueRef::Null => "null".to_owned(),
+        ValueRef::Integer(i) => i.to_string(),
+        ValueRef::Real(f) => f.to_string(),
+        ValueRef::Text(text) => String::from_utf8_lossy(text).into_owned(),
+        ValueRef::Blob(blob) => format!("<{} bytes>", blob.len()),
+    }
+}
+
+/// Schema version reached by applying the merge marker migration.
+fn merge_marker_version() -> i64 {
+    MIGRATIONS
+        .iter()
+        .position(|m| m.contains("Marker: databases migrated past this"))
+        .expect("merge marker migration exists") as i64
+        + 1
+}
+
+/// Escapes the wildcards of a `like` pattern, to be used with `escape '\'`.
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// How long sqlite waits for another connection to release its lock before failing with
+/// `SQLITE_BUSY`. The wait happens in sqlite, so a busy database doesn't need sleeping while the
+/// connection's mutex is held.
+const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Runs `op` again up to `retries` times while it fails with `SQLITE_BUSY` or `SQLITE_LOCKED`.
+/// Other errors are returned right away. Waiting for locks is left to the connection's busy
+/// timeout, the retries cover a transaction that sqlite gave up on without waiting, e.g. because
+/// it couldn't upgrade its read lock.
+fn retry_on_busy<T>(
+    retries: u32,
+    mut op: impl FnMut() -> rusqlite::Result<T>,
+) -> rusqlite::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(rusqlite::Error::SqliteFailure(err, _))
+                if attempt < retries
+                    && matches!(
+                        err.code,
+                        rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+                    ) =>
+            {
+                attempt += 1;
+                warn!("database is busy, retrying ({attempt}/{retries})");
+            }
+            result => return result,
+        }
+    }
+}
+
+pub trait Recordable {
+    fn id(&self) -> &str;
+    fn title(&self) -> &str;
+    fn subreddit(&self) -> &str;
+
+    /// Reddit fullname of the post, if it is a Reddit post.
+    fn fullname(&self) -> Option<String> {
+        None
+    }
+
+    /// Url of the post's content as returned by `reddit::normalize_url`, if it has one.
+    fn normalized_url(&self) -> Option<String> {
+        None
+    }
+}
+
+impl ToSql for TopPostsTimePeriod {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl ToSql for PostType {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl ToSql for ListingSort {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl FromSql for ListingSort {
+    fn column_result(value: ValueRef) -> FromSqlResult<ListingSort> {
+        let str = String::column_result(value)?;
+        ListingSort::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl FromSql for TopPostsTimePeriod {
+    fn column_result(value: ValueRef) -> FromSqlResult<TopPostsTimePeriod> {
+        let str = String::column_result(value)?;
+        TopPostsTimePeriod::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl FromSql for PostType {
+    fn column_result(value: ValueRef) -> FromSqlResult<PostType> {
+        let str = String::column_result(value)?;
+        PostType::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl ToSql for SendOrder {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl FromSql for SendOrder {
+    fn column_result(value: ValueRef) -> FromSqlResult<SendOrder> {
+        let str = String::column_result(value)?;
+        SendOrder::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl ToSql for WebPreview {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl FromSql for WebPreview {
+    fn column_result(value: ValueRef) -> FromSqlResult<WebPreview> {
+        let str = String::column_result(value)?;
+        WebPreview::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl ToSql for ParseMode {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+    }
+}
+
+impl FromSql for ParseMode {
+    fn column_result(value: ValueRef) -> FromSqlResult<ParseMode> {
+        let str = String::column_result(value)?;
+        ParseMode::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl ToSql for FilterConfig {
+    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput<'_>, rusqlite::Error> {
+        let json = serde_json::to_string(self)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
+        Ok(ToSqlOutput::Owned(Value::Text(json)))
+    }
+}
+
+impl FromSql for FilterConfig {
+    fn column_result(value: ValueRef) -> FromSqlResult<FilterConfig> {
+        let str = String::column_result(value)?;
+        serde_json::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+    }
+}
+
+impl TryFrom<&Row<'_>> for Subscription {
+    type Error = rusqlite::Error;
+
+    /// Filters are read from `filters_json`. The filter columns are still written for now, so that
+    /// the previous release can read subscriptions added by this one.
+    fn try_from(row: &Row<'_>) -> Result<Self, Self::Error> {
+        let filters: FilterConfig = row.get("filters_json")?;
+        Ok(Self {
+            subreddit: row.get_unwrap("subreddit"),
+            chat_id: row.get_unwrap("chat_id"),
+            limit: row.get_unwrap("post_limit"),
+            sort: row.get_unwrap("sort"),
+            time: row.get_unwrap("time"),
+            filter: filters.post_type,
+            min_score: filters.min_score,
+            min_upvote_ratio: filters.min_upvote_ratio,
+            top_percentile: filters.top_percentile,
+            rank: filters.rank,
+            min_rank: filters.min_rank,
+            dedup_titles: filters.dedup_titles,
+            oc_only: filters.oc_only,
+            media_only: filters.media_only,
+            text_only: filters.text_only,
+            catch_up: filters.catch_up,
+            send_order: row.get_unwrap("send_order"),
+            allow_nsfw: filters.nsfw,
+            top_comment: filters.top_comment,
+            blocked_authors: filters.blocked_authors,
+            message_thread_id: row.get_unwrap("message_thread_id"),
+            group: row.get_unwrap("group_name"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reddit::PostType;
+
+    #[test]
+    fn test_check_integrity() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        assert!(db.check_integrity().unwrap());
+    }
+
+    #[test]
+    fn test_db() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = Post {
+            id: "v6nu75".into(),
+            name: "t3_v6nu75".into(),
+            created: 1654581100.0,
+            created_utc: 1654581100.0,
+            post_hint: Some("link".into()),
+            subreddit: "absoluteunit".into(),
+            title: "Tipping a cow to trim its hooves".into(),
+            is_self: false,
+            is_video: false,
+            is_gallery: Some(false),
+            is_original_content: false,
+            over_18: false,
+            stickied: false,
+            spoiler: false,
+            upvote_ratio: 1.0,
+            author: "".into(),
+            link_flair_text: None,
+            selftext: "".into(),
+            gallery_data: None,
+            poll_data: None,
+            media_metadata: None,
+            thumbnail: None,
+            preview: None,
+            top_comment: None,
+            ups: 469,
+            permalink: "/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/".into(),
+            url: "https://i.imgur.com/Zt6f5mB.gifv".into(),
+            post_type: PostType::Video,
+            crosspost_parent_list: None,
+        };
+
+        assert!(!db.existing_posts_for_subreddit(1, "absoluteunit").unwrap());
+        db.record_post_seen_with_current_time(1, &post).unwrap();
+        assert!(db.is_post_seen(1, &post).unwrap());
+        assert!(db.existing_posts_for_subreddit(1, "absoluteunit").unwrap());
+    }
+
+    #[test]
+    fn test_db_prune_posts_keeps_tombstones() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        let post = |id: &str| Post {
+            id: id.into(),
+            subreddit: "test".into(),
+            ..Default::default()
+        };
+        let now = chrono::Utc::now();
+        let retention = chrono::Duration::days(30);
+        let long_ago = now - chrono::Duration::days(60);
+        db.record_post(1, &post("old"), Some(long_ago)).unwrap();
+        db.record_post(1, &post("recent"), Some(now)).unwrap();
+        db.record_post(1, &post("pending"), None).unwrap();
+
+        assert_eq!(
+            db.prune_posts(now - retention, Some(chrono::Duration::days(365)))
+                .unwrap(),
+            1
+        );
+        assert_eq!(db.get_post_title(1, "old").ok(), None);
+        // The pruned post is still seen thanks to its tombstone
+        assert!(db.is_post_seen(1, &post("old")).unwrap());
+        assert!(!db.is_post_seen(2, &post("old")).unwrap());
+        assert!(db.is_post_seen(1, &post("recent")).unwrap());
+        assert!(!db.is_post_seen(1, &post("pending")).unwrap());
+
+        // Tombstones expire after their retention
+        db.prune_posts(now - retention, Some(chrono::Duration::zero()))
+            .unwrap();
+        assert!(!db.is_post_seen(1, &post("old")).unwrap());
+
+        // Without tombstones, pruned posts are forgotten right away
+        db.record_post(1, &post("old"), Some(long_ago)).unwrap();
+        assert_eq!(db.prune_posts(now - retention, None).unwrap(), 1);
+        assert!(!db.is_post_seen(1, &post("old")).unwrap());
+    }
+
+    #[test]
+    fn test_db_prune_empty_chats() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        let chat_ids = |db: &Database| {
+            let conn = db.conn.lock().unwrap();
+            let mut stmt = conn
+                .prepare("select chat_id from chat order by chat_id")
+                .unwrap();
+            let chat_ids = stmt
+                .query_map([], |row| row.get::<_, i64>(0))
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            chat_ids
+        };
+
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "test".into(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        // Unsubscribing keeps the chat and its settings
+        db.subscribe(
+            2,
+            &SubscriptionArgs {
+                subreddit: "test".into(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.unsubscribe(2, "test").unwrap();
+        db.set_silent(3, true).unwrap();
+        db.set_web_preview(4, WebPreview::Thumbnail).unwrap();
+        assert_eq!(chat_ids(&db), vec![1, 2, 3, 4]);
+
+        assert_eq!(db.prune_empty_chats().unwrap(), 2);
+        assert_eq!(chat_ids(&db), vec![1, 3]);
+        assert_eq!(db.prune_empty_chats().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_db_export_history_csv() {
+        use chrono::TimeZone;
+
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        let post = |id: &str, title: &str| Post {
+            id: id.into(),
+            subreddit: "pics".into(),
+            title: title.into(),
+            ..Default::default()
+        };
+        let seen_at = chrono::Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+        db.record_post(1, &post("abc", "Cats, dogs and \"birds\""), Some(seen_at))
+            .unwrap();
+        db.record_post(
+            1,
+            &post("def", "Line\nbreak"),
+            Some(seen_at + chrono::Duration::hours(1)),
+        )
+        .unwrap();
+        db.record_post(1, &post("pending", "Not sent"), None)
+            .unwrap();
+        db.record_post(2, &post("other", "Other chat"), Some(seen_at))
+            .unwrap();
+
+        assert_eq!(
+            db.export_history_csv(1).unwrap(),
+            "subreddit,post_id,title,seen_at\r\n\
+             pics,abc,\"Cats, dogs and \"\"birds\"\"\",2023-05-01T12:00:00+00:00\r\n\
+             pics,def,\"Line\nbreak\",2023-05-01T13:00:00+00:00\r\n"
+        );
+        assert_eq!(
+            db.export_history_csv(3).unwrap(),
+            "subreddit,post_id,title,seen_at\r\n"
+        );
+    }
+
+    #[test]
+    fn test_db_schema_version() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        assert_eq!(db.schema_version().unwrap(), 0);
+        db.migrate().unwrap();
+        assert_eq!(db.schema_version().unwrap(), MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn test_db_subscribe() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let subscription_args = SubscriptionArgs {
+            subreddit: "test".to_string(),
+            limit: Some(1),
+            sort: None,
+            time: Some(TopPostsTimePeriod::Week),
+            filter: Some(PostType::Video),
+            min_score: None,
+            min_upvote_ratio: None,
+            top_percentile: None,
+            rank: None,
+            min_rank: None,
+            dedup_titles: false,
+            oc_only: false,
+            media_only: false,
+            text_only: false,
+            catch_up: None,
+            send_order: SendOrder::TopFirst,
+            allow_nsfw: false,
+            top_comment: false,
+            blocked_authors: vec![],
+        };
+        db.subscribe(1, &subscription_args).unwrap();
+
+        let subs = db.get_subscriptions_for_chat(1).unwrap();
+        assert_eq!(
+            subs,
+            vec![Subscription {
+                chat_id: 1,
+                subreddit: "test".to_string(),
+                limit: Some(1),
+                sort: None,
+                time: Some(TopPostsTimePeriod::Week),
+                filter: Some(PostType::Video),
+                min_score: None,
+                min_upvote_ratio: None,
+                top_percentile: None,
+                rank: None,
+                min_rank: None,
+                dedup_titles: false,
+                oc_only: false,
+                media_only: false,
+                text_only: false,
+                catch_up: None,
+                send_order: SendOrder::TopFirst,
+                allow_nsfw: false,
+                top_comment: false,
+                blocked_authors: vec![],
+                message_thread_id: None,
+                group: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_db_unsubscribe() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let subscription_args = SubscriptionArgs {
+            subreddit: "test".to_string(),
+            limit: Some(1),
+            sort: None,
+            time: Some(TopPostsTimePeriod::Week),
+            filter: Some(PostType::Video),
+            min_score: None,
+            min_upvote_ratio: None,
+            top_percentile: None,
+            rank: None,
+            min_rank: None,
+            dedup_titles: false,
+            oc_only: false,
+            media_only: false,
+            text_only: false,
+            catch_up: None,
+            send_order: SendOrder::TopFirst,
+            allow_nsfw: false,
+            top_comment: false,
+            blocked_authors: vec![],
+        };
+        db.subscribe(1, &subscription_args).unwrap();
+        let subs = db.get_subscriptions_for_chat(1).unwrap();
+        assert_eq!(subs.len(), 1);
+        // Wildcards only match themselves
+        assert_eq!(db.unsubscribe(1, "%").unwrap(), None);
+        assert_eq!(db.unsubscribe(1, "t_st").unwrap(), None);
+        let deleted = db.unsubscribe(1, "TEST").unwrap();
+        assert_eq!(deleted.as_deref(), Some("test"));
+        let subs = db.get_subscriptions_for_chat(1).unwrap();
+        assert_eq!(subs, vec![]);
+        assert_eq!(db.unsubscribe(1, "test").unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_unsubscribe_doesnt_delete_posts() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let subscription_args = SubscriptionArgs {
+            subreddit: "test".to_string(),
+            limit: Some(1),
+            sort: None,
+            time: Some(TopPostsTimePeriod::Week),
+            filter: Some(PostType::Video),
+            min_score: None,
+            min_upvote_ratio: None,
+            top_percentile: None,
+            rank: None,
+            min_rank: None,
+            dedup_titles: false,
+            oc_only: false,
+            media_only: false,
+            text_only: false,
+            catch_up: None,
+            send_order: SendOrder::TopFirst,
+            allow_nsfw: false,
+            top_comment: false,
+            blocked_authors: vec![],
+        };
+        db.subscribe(1, &subscription_args).unwrap();
+        let post = Post {
+            id: "v6nu75".into(),
+            name: "t3_v6nu75".into(),
+            created: 1654581100.0,
+            created_utc: 1654581100.0,
+            post_hint: Some("link".into()),
+            subreddit: "test".into(),
+            title: "Tipping a cow to trim its hooves".into(),
+            is_self: false,
+            is_gallery: Some(false),
+            is_original_content: false,
+            over_18: false,
+            stickied: false,
+            spoiler: false,
+            upvote_ratio: 1.0,
+            author: "".into(),
+            link_flair_text: None,
+            selftext: "".into(),
+            is_video: false,
+            gallery_data: None,
+            poll_data: None,
+            media_metadata: None,
+            thumbnail: None,
+            preview: None,
+            top_comment: None,
+            ups: 469,
+            permalink: "/r/test/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/".into(),
+            url: "https://i.imgur.com/Zt6f5mB.gifv".into(),
+            post_type: PostType::Video,
+            crosspost_parent_list: None,
+        };
+        db.record_post_seen_with_current_time(1, &post).unwrap();
+        assert!(db.is_post_seen(1, &post).unwrap());
+        db.unsubscribe(1, "test").unwrap();
+        assert!(db.is_post_seen(1, &post).unwrap());
+    }
+
+    #[test]
+    fn test_db_silent() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert!(!db.get_silent(1).unwrap());
+        db.set_silent(1, true).unwrap();
+        assert!(db.get_silent(1).unwrap());
+        db.set_silent(1, false).unwrap();
+        assert!(!db.get_silent(1).unwrap());
+    }
+
+    #[test]
+    fn test_db_merged_feed() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert!(!db.get_merged_feed(1).unwrap());
+        db.set_merged_feed(1, true).unwrap();
+        assert!(db.get_merged_feed(1).unwrap());
+        db.set_merged_feed(1, false).unwrap();
+        assert!(!db.get_merged_feed(1).unwrap());
+    }
+
+    #[test]
+    fn test_db_nsfw_spoiler() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert!(!db.get_nsfw_spoiler(1).unwrap());
+        db.set_nsfw_spoiler(1, true).unwrap();
+        assert!(db.get_nsfw_spoiler(1).unwrap());
+        db.set_nsfw_spoiler(1, false).unwrap();
+        assert!(!db.get_nsfw_spoiler(1).unwrap());
+    }
+
+    #[test]
+    fn test_db_web_preview() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.get_web_preview(1).unwrap(), WebPreview::Thumbnail);
+        db.set_web_preview(1, WebPreview::None).unwrap();
+        assert_eq!(db.get_web_preview(1).unwrap(), WebPreview::None);
+        db.set_web_preview(1, WebPreview::Preview).unwrap();
+        assert_eq!(db.get_web_preview(1).unwrap(), WebPreview::Preview);
+        assert_eq!(db.get_web_preview(2).unwrap(), WebPreview::Thumbnail);
+    }
+
+    #[test]
+    fn test_db_caption_components() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(
+            db.get_caption_components(1).unwrap(),
+            DEFAULT_CAPTION_COMPONENTS
+        );
+        let components = [CaptionComponent::Score, CaptionComponent::Author];
+        db.set_caption_components(1, Some(&components)).unwrap();
+        assert_eq!(db.get_caption_components(1).unwrap(), components);
+        db.set_caption_components(1, Some(&[])).unwrap();
+        assert!(db.get_caption_components(1).unwrap().is_empty());
+        db.set_caption_components(1, None).unwrap();
+        assert_eq!(
+            db.get_caption_components(1).unwrap(),
+            DEFAULT_CAPTION_COMPONENTS
+        );
+    }
+
+    #[test]
+    fn test_db_repost_channels() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.get_repost_channels(1).unwrap(), Vec::<i64>::new());
+        assert_eq!(db.get_repost_channel(1).unwrap(), None);
+
+        db.add_repost_channel(1, -100).unwrap();
+        db.add_repost_channel(1, -200).unwrap();
+        db.add_repost_channel(1, -100).unwrap();
+        db.add_repost_channel(2, -300).unwrap();
+        assert_eq!(db.get_repost_channels(1).unwrap(), vec![-100, -200]);
+        assert_eq!(db.get_repost_channel(1).unwrap(), Some(-100));
+
+        assert!(db.remove_repost_channel(1, -100).unwrap());
+        assert!(!db.remove_repost_channel(1, -100).unwrap());
+        assert_eq!(db.get_repost_channels(1).unwrap(), vec![-200]);
+        assert_eq!(db.get_repost_channels(2).unwrap(), vec![-300]);
+    }
+
+    #[test]
+    fn test_db_copy_chat() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        for subreddit in ["foo", "bar"] {
+            db.subscribe(
+                1,
+                &SubscriptionArgs {
+                    subreddit: subreddit.to_string(),
+                    limit: Some(5),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+        db.set_silent(1, true).unwrap();
+        db.subscribe(
+            2,
+            &SubscriptionArgs {
+                subreddit: "Foo".to_string(),
+                limit: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(db.copy_chat(1, 2).unwrap().added, 1);
+        let subs = db.get_subscriptions_for_chat(2).unwrap();
+        assert_eq!(subs.len(), 2);
+        assert!(subs
+            .iter()
+            .any(|sub| sub.subreddit == "Foo" && sub.limit == Some(1)));
+        assert!(subs
+            .iter()
+            .any(|sub| sub.subreddit == "bar" && sub.limit == Some(5)));
+        assert!(db.get_silent(2).unwrap());
+
+        // Copying again adds nothing
+        assert_eq!(db.copy_chat(1, 2).unwrap(), ChatCopy::default());
+        assert_eq!(db.get_subscriptions_for_chat(2).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_db_copy_chat_over_quota() {
+        let config = Config {
+            max_subscriptions_per_chat: Some(2),
+            ..Default::default()
+        };
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        for subreddit in ["bar", "foo"] {
+            db.subscribe(
+                1,
+                &SubscriptionArgs {
+                    subreddit: subreddit.to_string(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+        db.set_silent(1, true).unwrap();
+        db.subscribe(
+            2,
+            &SubscriptionArgs {
+                subreddit: "baz".to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let copy = db.copy_chat(1, 2).unwrap();
+        assert_eq!(copy.added, 1);
+        assert_eq!(
+            copy.skipped,
+            vec![(
+                "foo".to_string(),
+                "This chat already has the maximum of 2 subscriptions".to_string()
+            )]
+        );
+        assert_eq!(db.get_subscriptions_for_chat(2).unwrap().len(), 2);
+        // Settings are copied regardless
+        assert!(db.get_silent(2).unwrap());
+    }
+
+    #[test]
+    fn test_db_linked_chats() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        let post = Post {
+            id: "v6nu75".into(),
+            subreddit: "test".into(),
+            ..Default::default()
+        };
+
+        db.link_chats(1, 2).unwrap();
+        db.link_chats(2, 1).unwrap();
+        db.link_chats(1, 3).unwrap();
+        assert_eq!(db.get_linked_chats(1).unwrap(), [2, 3]);
+        assert_eq!(db.get_linked_chats(2).unwrap(), [1]);
+        assert_eq!(db.get_linked_chats(3).unwrap(), [1]);
+
+        // Recorded but not sent yet
+        db.record_post(2, &post, None).unwrap();
+        assert!(!db.is_post_seen_in_linked_chats(1, &post).unwrap());
+
+        db.record_post_seen_with_current_time(2, &post).unwrap();
+        assert!(db.is_post_seen_in_linked_chats(1, &post).unwrap());
+        assert!(!db.is_post_seen_in_linked_chats(2, &post).unwrap());
+        // Links are not transitive
+        assert!(!db.is_post_seen_in_linked_chats(3, &post).unwrap());
+        assert!(!db.is_post_seen_in_linked_chats(4, &post).unwrap());
+
+        assert!(db.unlink_chats(2, 1).unwrap());
+        assert!(!db.unlink_chats(1, 2).unwrap());
+        assert!(!db.is_post_seen_in_linked_chats(1, &post).unwrap());
+        assert_eq!(db.get_linked_chats(1).unwrap(), [3]);
+        assert!(db.get_linked_chats(2).unwrap().is_empty());
+
+        db.forget_chat(3).unwrap();
+        assert!(db.get_linked_chats(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_db_forget_chat() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        for chat_id in [1, 2] {
+            db.subscribe(
+                chat_id,
+                &SubscriptionArgs {
+                    subreddit: "test".to_string(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let post = Post {
+                id: "v6nu75".into(),
+                subreddit: "test".into(),
+                ..Default::default()
+            };
+            db.record_post_seen_with_current_time(chat_id, &post)
+                .unwrap();
+            db.add_telegram_file("v6nu75", chat_id, "file", "unique")
+                .unwrap();
+            db.add_repost_channel(chat_id, -100).unwrap();
+        }
+
+        db.forget_chat(1).unwrap();
+
+        let conn = db.conn.lock().unwrap();
+        let count_rows = |table: &str, chat_id: i64| -> i64 {
+            conn.query_row(
+                &format!("select count(*) from {table} where chat_id = ?"),
+                [chat_id],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        for table in [
+            "subscription",
+            "post",
+            "chat",
+            "telegram_file",
+            "repost_channel",
+        ] {
+            assert_eq!(
+                count_rows(table, 1),
+                0,
+                "{table} has rows for forgotten chat"
+            );
+            assert_eq!(count_rows(table, 2), 1, "{table} lost rows of another chat");
+        }
+    }
+
+    #[test]
+    fn test_db_subscribe_errors() {
+        let config = Config {
+            max_subscriptions_per_chat: Some(2),
+            blocked_subreddits: vec!["Spam".to_string()],
+            ..Default::default()
+        };
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let args = |subreddit: &str| SubscriptionArgs {
+            subreddit: subreddit.to_string(),
+            ..Default::default()
+        };
+
+        db.subscribe(1, &args("test")).unwrap();
+        assert!(matches!(
+            db.subscribe(1, &args("test")),
+            Err(SubscribeError::AlreadySubscribed(subreddit)) if subreddit == "test"
+        ));
+        assert!(matches!(
+            db.subscribe(1, &args("spam")),
+            Err(SubscribeError::Blocked(_))
+        ));
+        db.subscribe(1, &args("other")).unwrap();
+        assert!(matches!(
+            db.subscribe(1, &args("third")),
+            Err(SubscribeError::QuotaExceeded(2))
+        ));
+        // Still reported as a conflict when the quota is reached
+        assert!(matches!(
+            db.subscribe(1, &args("other")),
+            Err(SubscribeError::AlreadySubscribed(_))
+        ));
+        assert_eq!(db.get_subscriptions_for_chat(1).unwrap().len(), 2);
+        db.subscribe(2, &args("third")).unwrap();
+    }
+
+    #[test]
+    fn test_subscribe_error_from_insert_error() {
+        let constraint_error = |extended_code| {
+            rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(extended_code), None)
+        };
+        assert!(matches!(
+            SubscribeError::from_insert_error(
+                constraint_error(rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY),
+                "test"
+            ),
+            SubscribeError::AlreadySubscribed(_)
+        ));
+        assert!(matches!(
+            SubscribeError::from_insert_error(
+                constraint_error(rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE),
+                "test"
+            ),
+            SubscribeError::AlreadySubscribed(_)
+        ));
+        assert!(matches!(
+            SubscribeError::from_insert_error(
+                constraint_error(rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY),
+                "test"
+            ),
+            SubscribeError::Db(_)
+        ));
+        assert!(matches!(
+            SubscribeError::from_insert_error(constraint_error(rusqlite::ffi::SQLITE_BUSY), "test"),
+            SubscribeError::Db(_)
+        ));
+    }
+
+    #[test]
+    fn test_ensure_chat_exists_is_idempotent() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        db.set_silent(1, true).unwrap();
+        {
+            let conn = db.conn.lock().unwrap();
+            ensure_chat_exists(&conn, 1).unwrap();
+            ensure_chat_exists(&conn, 1).unwrap();
+            ensure_chat_exists(&conn, 2).unwrap();
+            let chat_count: i64 = conn
+                .query_row("select count(*) from chat", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(chat_count, 2);
+        }
+        // The existing chat is left as it was
+        assert!(db.get_silent(1).unwrap());
+
+        // Subscriptions still require their chat to exist
+        db.subscribe(
+            2,
+            &SubscriptionArgs {
+                subreddit: "test".into(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let orphan = db.conn.lock().unwrap().execute(
+            "insert into subscription (chat_id, subreddit, created_at) values (3, 'test', '')",
+            [],
+        );
+        assert!(orphan.unwrap_err().to_string().contains("FOREIGN KEY"));
+    }
+
+    #[test]
+    fn test_db_subscribe_rolls_back_new_chat_on_error() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        db.conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "
+                create trigger fail_subscribe before insert on subscription
+                begin
+                    select raise(abort, 'simulated failure');
+                end;
+                ",
+            )
+            .unwrap();
+
+        let args = SubscriptionArgs {
+            subreddit: "test".to_string(),
+            ..Default::default()
+        };
+        assert!(db.subscribe(1, &args).is_err());
+
+        let chat_count: i64 = db
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("select count(*) from chat", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(chat_count, 0);
+    }
+
+    #[test]
+    fn test_db_record_post_rolls_back_insert_on_error() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        db.conn
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "
+                create trigger fail_record_post before update on post
+                begin
+                    select raise(abort, 'simulated failure');
+                end;
+                ",
+            )
+            .unwrap();
+
+        let post = Post {
+            id: "v6nu75".into(),
+            name: "t3_v6nu75".into(),
+            subreddit: "test".into(),
+            ..Default::default()
+        };
+        assert!(db.record_post(1, &post, None).is_err());
+        assert!(!db.existing_posts_for_subreddit(1, "test").unwrap());
+    }
+
+    #[test]
+    fn test_db_record_post_retries_when_busy() {
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = Post {
+            id: "v6nu75".into(),
+            subreddit: "test".into(),
+            ..Default::default()
+        };
+        let busy = || {
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+                None,
+            )
+        };
+
+        // Fails twice, then records the post
+        let mut attempts = 0;
+        let mut conn = db.conn.lock().unwrap();
+        retry_on_busy(3, || {
+            attempts += 1;
+            if attempts <= 2 {
+                return Err(busy());
+            }
+            Database::record_post_tx(&mut conn, 1, &post, None)
+        })
+        .unwrap();
+        drop(conn);
+        assert_eq!(attempts, 3);
+        assert!(db.existing_posts_for_subreddit(1, "test").unwrap());
+
+        // Gives up after the configured number of retries
+        let mut attempts = 0;
+        let result: rusqlite::Result<()> = retry_on_busy(2, || {
+            attempts += 1;
+            Err(busy())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+
+        // Other errors are not retried
+        let mut attempts = 0;
+        let result: rusqlite::Result<()> = retry_on_busy(2, || {
+            attempts += 1;
+            Err(rusqlite::Error::QueryReturnedNoRows)
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_db_busy_timeout() {
+        let db = Database::open(&Config::default()).unwrap();
+        let conn = db.conn.lock().unwrap();
+        let timeout_ms: u64 = conn
+            .query_row("pragma busy_timeout", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(timeout_ms, BUSY_TIMEOUT.as_millis() as u64);
+    }
+
+    #[test]
+    fn test_escape_like() {
+        assert_eq!(escape_like("100%"), r"100\%");
+        assert_eq!(escape_like("snake_case"), r"snake\_case");
+        assert_eq!(escape_like(r"back\slash"), r"back\\slash");
+    }
+
+    #[test]
+    fn test_db_search_history() {
+        use itertools::Itertools;
+
+        let config = Config::default();
+        let mut db = Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let post = |id: &str, title: &str| Post {
+            id: id.into(),
+            subreddit: "test".into(),
+            title: title.into(),
+            ..Default::default()
+        };
+        for p in [
+            post("a", "100% real cat"),
+            post("b", "1000 cats"),
+            post("c", "snake_case dog"),
+            post("d", "snakescase dog"),
+        ] {
+            db.record_post_seen_with_current_time(1, &p).unwrap();
+        }
+        db.record_post_seen_with_current_time(2, &post("e", "another cat"))
+            .unwrap();
+
+        let ids = |query: &str| {
+            db.search_history(1, query, 10)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.post_id)
+                .sorted()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids("CAT"), vec!["a", "b"]);
+        assert_eq!(ids("100%"), vec!["a"]);
+        assert_eq!(ids("snake_case"), vec!["c"]);
+        assert!(ids("horse").is_empty());
+        assert_eq!(db.search_history(1, "dog", 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_db_claim_subscription() {
+        use chrono::TimeZone;
+
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "test".into(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let lease = chrono::Duration::seconds(60);
+        let now = chrono::Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap();
+        let claim = |instance_id: &str, at: chrono::DateTime<chrono::Utc>| {
+            db.claim_subscription(1, "test", instance_id, at, lease)
+                .unwrap()
+        };
+
+        assert!(claim("a", now));
+        // Claimed by another instance until the lease expires
+        assert!(!claim("b", now + chrono::Duration::seconds(30)));
+        assert!(!claim("b", now + chrono::Duration::milliseconds(59_500)));
+        // The claiming instance can renew the lease
+        assert!(claim("a", now + chrono::Duration::seconds(30)));
+        assert!(!claim("b", now + chrono::Duration::seconds(60)));
+        assert!(claim("b", now + chrono::Duration::seconds(90)));
+        assert!(!claim("a", now + chrono::Duration::seconds(100)));
+
+        // Releasing only works for the instance holding the claim
+        db.release_subscription(1, "test", "a").unwrap();
+        assert!(!claim("a", now + chrono::Duration::seconds(100)));
+        db.release_subscription(1, "test", "b").unwrap();
+        assert!(claim("a", now + chrono::Duration::seconds(100)));
+
+        assert!(!db.claim_subscription(1, "other", "a", now, lease).unwrap());
+    }
+
+    #[test]
+    fn test_db_get_subscription_raw() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "test".into(),
+                limit: Some(5),
+                min_score: Some(100),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let row = db.get_subscription_raw(1, "TEST").unwrap().unwrap();
+        for key in [
+            "chat_id",
+            "subreddit",
+            "created_at",
+            "post_limit",
+            "time",
+            "filter",
+            "min_score",
+            "dedup_titles",
+            "oc_only",
+            "send_order",
+            "allow_nsfw",
+            "claimed_by",
+            "claimed_until",
+        ] {
+            assert!(row.contains_key(key), "missing {key}");
+        }
+        assert_eq!(row["subreddit"], "test");
+        assert_eq!(row["post_limit"], "5");
+        assert_eq!(row["time"], "null");
+        assert_eq!(row["send_order"], "top_first");
+        assert_eq!(db.get_subscription_raw(1, "other").unwrap(), None);
+        assert_eq!(db.get_subscription_raw(1, "t%").unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_get_subscription() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "rust_gamedev".into(),
+                limit: Some(3),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let sub = db.get_subscription(1, "Rust_GameDev").unwrap().unwrap();
+        assert_eq!(sub.chat_id, 1);
+        assert_eq!(sub.subreddit, "rust_gamedev");
+        assert_eq!(sub.limit, Some(3));
+
+        assert_eq!(db.get_subscription(1, "rustXgamedev").unwrap(), None);
+        assert_eq!(db.get_subscription(1, "rust%").unwrap(), None);
+        assert_eq!(db.get_subscription(2, "rust_gamedev").unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_filters_json_backfill() {
+        let db = Database::open(&Config::default()).unwrap();
+        let migrations = Migrations::new(MIGRATIONS.iter().map(|e| M::up(e)).collect());
+        let filters_json_migration = MIGRATIONS
+            .iter()
+            .position(|m| m.contains("add column filters_json"))
+            .unwrap();
+        let mut conn = db.conn.lock().unwrap();
+        migrations
+            .to_version(&mut conn, filters_json_migration)
+            .unwrap();
+        conn.execute_batch(
+            r#"
+            insert into chat (chat_id) values (1);
+            insert into subscription (chat_id, subreddit, filter, min_score, dedup_titles, oc_only, allow_nsfw, blocked_authors, created_at)
+            values
+                (1, 'pics', 'image', 500, 1, 0, 1, 'spammer,[deleted],quo"te\', '2023-05-01T00:00:00Z'),
+                (1, 'rust', null, null, 0, 0, 0, '', '2023-05-01T00:00:00Z');
+            "#,
+        )
+        .unwrap();
+        migrations.to_latest(&mut conn).unwrap();
+        drop(conn);
+
+        let sub = db.get_subscription(1, "pics").unwrap().unwrap();
+        assert_eq!(sub.filter, Some(PostType::Image));
+        assert_eq!(sub.min_score, Some(500));
+        assert!(sub.dedup_titles);
+        assert!(!sub.oc_only);
+        assert!(sub.allow_nsfw);
+        assert_eq!(sub.blocked_authors, ["spammer", "[deleted]", r#"quo"te\"#]);
+
+        let sub = db.get_subscription(1, "rust").unwrap().unwrap();
+        assert_eq!(sub.filter, None);
+        assert_eq!(sub.min_score, None);
+        assert!(!sub.dedup_titles);
+        assert!(!sub.allow_nsfw);
+        assert!(sub.blocked_authors.is_empty());
+    }
+
+    #[test]
+    fn test_db_table_counts() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        let counts = db.table_counts().unwrap();
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts["post"], 0);
+        assert_eq!(counts["subscription"], 0);
+        assert_eq!(counts["chat"], 0);
+
+        for (chat_id, subreddit) in [(1, "pics"), (1, "rust"), (2, "pics")] {
+            db.subscribe(
+                chat_id,
+                &SubscriptionArgs {
+                    subreddit: subreddit.into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+        for id in ["a", "b", "c", "d"] {
+            let post = Post {
+                id: id.into(),
+                subreddit: "pics".into(),
+                ..Default::default()
+            };
+            db.record_post_seen_with_current_time(1, &post).unwrap();
+        }
+        db.set_silent(3, true).unwrap();
+
+        let counts = db.table_counts().unwrap();
+        assert_eq!(counts["post"], 4);
+        assert_eq!(counts["subscription"], 3);
+        assert_eq!(counts["chat"], 3);
+    }
+
+    #[test]
+    fn test_db_count_posts() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.count_posts(1).unwrap(), 0);
+
+        let post = |id: &str| Post {
+            id: id.into(),
+            subreddit: "test".into(),
+            ..Default::default()
+        };
+        db.record_post_seen_with_current_time(1, &post("a"))
+            .unwrap();
+        db.record_post_seen_with_current_time(1, &post("b"))
+            .unwrap();
+        db.record_post(1, &post("c"), None).unwrap();
+        db.record_post(1, &post("d"), None).unwrap();
+        db.record_post_seen_with_current_time(2, &post("a"))
+            .unwrap();
+        assert_eq!(db.count_posts(1).unwrap(), 2);
+
+        db.record_post_seen_with_current_time(1, &post("c"))
+            .unwrap();
+        assert_eq!(db.count_posts(1).unwrap(), 3);
+        assert_eq!(db.count_posts(2).unwrap(), 1);
+        assert_eq!(db.count_posts(3).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_db_get_subscriptions_for_chat_sorted() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        for subreddit in ["pics", "Aww", "rust"] {
+            db.subscribe(
+                1,
+                &SubscriptionArgs {
+                    subreddit: subreddit.into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+        db.subscribe(
+            2,
+            &SubscriptionArgs {
+                subreddit: "other".into(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        for (id, subreddit) in [("a", "rust"), ("b", "rust"), ("c", "Aww")] {
+            let post = Post {
+                id: id.into(),
+                subreddit: subreddit.into(),
+                ..Default::default()
+            };
+            db.record_post_seen_with_current_time(1, &post).unwrap();
+        }
+        // Not delivered yet
+        let post = Post {
+            id: "d".into(),
+            subreddit: "pics".into(),
+            ..Default::default()
+        };
+        db.record_post(1, &post, None).unwrap();
+        db.record_subscription_failure(1, "pics", "forbidden")
+            .unwrap();
+        db.record_subscription_failure(1, "pics", "forbidden")
+            .unwrap();
+        db.record_subscription_failure(1, "rust", "timeout")
+            .unwrap();
+
+        let sorted = |sort| {
+            db.get_subscriptions_for_chat_sorted(1, sort)
+                .unwrap()
+                .into_iter()
+                .map(|sub| sub.subreddit)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sorted(SubscriptionSort::Name), ["Aww", "pics", "rust"]);
+        assert_eq!(sorted(SubscriptionSort::Created), ["pics", "Aww", "rust"]);
+        assert_eq!(sorted(SubscriptionSort::Delivered), ["rust", "Aww", "pics"]);
+        assert_eq!(sorted(SubscriptionSort::Errors), ["pics", "rust", "Aww"]);
+    }
+
+    #[test]
+    fn test_db_set_subscription_limit() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "test".into(),
+                limit: Some(3),
+                time: Some(TopPostsTimePeriod::Week),
+                filter: Some(PostType::Video),
+                min_score: Some(100),
+                oc_only: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let before = db.get_subscription(1, "test").unwrap().unwrap();
+
+        assert_eq!(db.set_subscription_limit(1, "TEST", 10).unwrap(), Some(10));
+        let after = db.get_subscription(1, "test").unwrap().unwrap();
+        assert_eq!(
+            after,
+            Subscription {
+                limit: Some(10),
+                ..before
+            }
+        );
+
+        assert_eq!(
+            db.set_subscription_limit(1, "test", 1000).unwrap(),
+            Some(MAX_LISTING_LIMIT)
+        );
+        assert_eq!(db.set_subscription_limit(1, "test", 0).unwrap(), Some(1));
+        assert_eq!(db.set_subscription_limit(1, "other", 5).unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_snooze_chat() {
+        use chrono::TimeZone;
+
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        let now = chrono::Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+        let until = now + chrono::Duration::hours(2);
+        assert_eq!(db.get_snooze_until(1, now).unwrap(), None);
+
+        db.snooze_chat(1, Some(until)).unwrap();
+        assert_eq!(db.get_snooze_until(1, now).unwrap(), Some(until));
+        assert_eq!(
+            db.get_snooze_until(1, until - chrono::Duration::seconds(1))
+                .unwrap(),
+            Some(until)
+        );
+        // Delivery resumes once the snooze has expired
+        assert_eq!(db.get_snooze_until(1, until).unwrap(), None);
+        assert_eq!(
+            db.get_snooze_until(1, until + chrono::Duration::days(1))
+                .unwrap(),
+            None
+        );
+        assert_eq!(db.get_snooze_until(2, now).unwrap(), None);
+
+        db.snooze_chat(1, None).unwrap();
+        assert_eq!(db.get_snooze_until(1, now).unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_daily_header() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.get_daily_header(1).unwrap(), None);
+        assert_eq!(db.get_last_header_date(1).unwrap(), None);
+
+        db.set_daily_header(1, Some("News of {date}")).unwrap();
+        let date = chrono::NaiveDate::from_ymd_opt(2023, 5, 1).unwrap();
+        db.set_last_header_date(1, date).unwrap();
+        assert_eq!(
+            db.get_daily_header(1).unwrap().as_deref(),
+            Some("News of {date}")
+        );
+        assert_eq!(db.get_last_header_date(1).unwrap(), Some(date));
+
+        db.set_daily_header(1, None).unwrap();
+        assert_eq!(db.get_daily_header(1).unwrap(), None);
+    }
+
+    #[test]
+    fn test_db_rename_subreddit() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        for (chat_id, subreddit) in [(1, "rust"), (1, "pics"), (2, "rust")] {
+            db.subscribe(
+                chat_id,
+                &SubscriptionArgs {
+                    subreddit: subreddit.into(),
+                    limit: Some(5),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+        let post = |id: &str, subreddit: &str| Post {
+            id: id.into(),
+            subreddit: subreddit.into(),
+            ..Default::default()
+        };
+        db.record_post(1, &post("a", "rust"), None).unwrap();
+        db.record_post(2, &post("b", "rust"), None).unwrap();
+
+        assert!(db.rename_subreddit(1, "rust", "Rust").unwrap());
+        let renamed = db.get_subscription(1, "Rust").unwrap().unwrap();
+        assert_eq!(renamed.subreddit, "Rust");
+        assert_eq!(renamed.limit, Some(5));
+        assert!(db.existing_posts_for_subreddit(1, "Rust").unwrap());
+        assert!(!db.existing_posts_for_subreddit(1, "rust").unwrap());
+        // Other chats are not affected
+        assert_eq!(
+            db.get_subscription(2, "rust").unwrap().unwrap().subreddit,
+            "rust"
+        );
+        assert!(db.existing_posts_for_subreddit(2, "rust").unwrap());
+
+        // Unknown names must match exactly
+        assert!(!db.rename_subreddit(1, "rust", "rustlang").unwrap());
+        assert!(!db.rename_subreddit(1, "nope", "rustlang").unwrap());
+
+        // Renaming to an existing subscription fails and changes nothing
+        let err = db.rename_subreddit(1, "Rust", "pics").unwrap_err();
+        assert_eq!(err.to_string(), "already subscribed to r/pics");
+        assert!(db.get_subscription(1, "Rust").unwrap().is_some());
+        assert!(db.existing_posts_for_subreddit(1, "Rust").unwrap());
+        assert_eq!(db.get_subscriptions_for_chat(1).unwrap().len(), 2);
+
+        // Also when the existing subscription differs only in casing
+        let err = db.rename_subreddit(1, "Rust", "PICS").unwrap_err();
+        assert_eq!(err.to_string(), "already subscribed to r/PICS");
+        assert!(db.get_subscription(1, "Rust").unwrap().is_some());
+        assert_eq!(db.get_subscriptions_for_chat(1).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_db_subscription_groups() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        for subreddit in ["worldnews", "Europe", "aww"] {
+            db.subscribe(
+                1,
+                &SubscriptionArgs {
+                    subreddit: subreddit.into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+        db.subscribe(
+            2,
+            &SubscriptionArgs {
+                subreddit: "worldnews".into(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(db
+            .set_subscription_group(1, "worldnews", Some("news"))
+            .unwrap());
+        assert!(db
+            .set_subscription_group(1, "europe", Some("News"))
+            .unwrap());
+        assert!(db
+            .set_subscription_group(2, "worldnews", Some("news"))
+            .unwrap());
+        assert!(!db.set_subscription_group(1, "pics", Some("news")).unwrap());
+
+        let subreddits = |subs: Vec<Subscription>| {
+            subs.into_iter()
+                .map(|sub| sub.subreddit)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            subreddits(db.get_subscriptions_for_chat_in_group(1, "NEWS").unwrap()),
+            vec!["Europe", "worldnews"]
+        );
+        assert!(db
+            .get_subscriptions_for_chat_in_group(1, "animals")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            db.get_subscription(1, "worldnews").unwrap().unwrap().group,
+            Some("news".to_string())
+        );
+
+        assert!(db.set_subscription_group(1, "worldnews", None).unwrap());
+        assert_eq!(
+            subreddits(db.get_subscriptions_for_chat_in_group(1, "news").unwrap()),
+            vec!["Europe"]
+        );
+        assert_eq!(db.get_subscription(1, "aww").unwrap().unwrap().group, None);
+    }
+
+    #[test]
+    fn test_db_poll_state() {
+        use chrono::TimeZone;
+
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "test".into(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(db.get_poll_state(1, "test").unwrap(), None);
+
+        let polled_at = chrono::Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+        let state = PollState {
+            last_polled_at: polled_at,
+            next_poll_at: polled_at + chrono::Duration::minutes(10),
+            idle_polls: 0,
+        };
+        db.set_poll_state(1, "test", &state).unwrap();
+        assert_eq!(db.get_poll_state(1, "test").unwrap(), Some(state));
+
+        let state = PollState {
+            last_polled_at: polled_at + chrono::Duration::minutes(10),
+            next_poll_at: polled_at + chrono::Duration::minutes(30),
+            idle_polls: 1,
+        };
+        db.set_poll_state(1, "test", &state).unwrap();
+        assert_eq!(db.get_poll_state(1, "test").unwrap(), Some(state));
+        assert_eq!(db.get_poll_state(2, "test").unwrap(), None);
+
+        // The state follows the subscription
+        assert!(db.rename_subreddit(1, "test", "Test").unwrap());
+        assert!(db.get_poll_state(1, "Test").unwrap().is_some());
+        db.unsubscribe(1, "Test").unwrap();
+        assert_eq!(db.get_poll_state(1, "Test").unwrap(), None);
     }
 
-    pub fn get_telegram_files_for_post(&self, post_id: &str, chat_id: i64) -> Result<Vec<String>> {
-        let conn = &self.conn.lock().expect("No poison");
-        let mut stmt = conn.prepare(
-            "
-            select telegram_file_id
-            from telegram_file
-            where post_id = :post_id and chat_id = :chat_id
-            order by telegram_file.id
-            ",
-        )?;
+    #[test]
+    fn test_db_set_subscription_thread() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "test".into(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(db.get_subscription_thread(1, "test").unwrap(), Some(None));
 
-        let rows = stmt
-            .query_map(
-                named_params! {
-                    ":post_id": post_id,
-                    ":chat_id": chat_id,
-                },
-                |row| row.get("telegram_file_id"),
-            )
-            .context("could not retrieve telegram files")?;
+        assert!(db.set_subscription_thread(1, "TEST", Some(42)).unwrap());
+        assert_eq!(
+            db.get_subscription_thread(1, "test").unwrap(),
+            Some(Some(42))
+        );
+        let sub = db.get_subscription(1, "test").unwrap().unwrap();
+        assert_eq!(sub.message_thread_id, Some(42));
+        let row = db.get_subscription_raw(1, "test").unwrap().unwrap();
+        assert_eq!(row["message_thread_id"], "42");
 
-        let telegram_files: Result<Vec<String>, _> = rows.collect();
-        Ok(telegram_files?)
+        assert!(db.set_subscription_thread(1, "test", None).unwrap());
+        assert_eq!(db.get_subscription_thread(1, "test").unwrap(), Some(None));
+        assert!(!db.set_subscription_thread(1, "other", Some(42)).unwrap());
+        assert_eq!(db.get_subscription_thread(1, "other").unwrap(), None);
     }
-}
 
-pub trait Recordable {
-    fn id(&self) -> &str;
-    fn title(&self) -> &str;
-    fn subreddit(&self) -> &str;
-}
+    #[test]
+    fn test_db_record_post_name() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        let post: Post = serde_json::from_str(
+            r#"{
+                "id": "abc123",
+                "name": "t3_abc123",
+                "created": 1684000000.0,
+                "created_utc": 1684000000.0,
+                "subreddit": "pics",
+                "title": "I made this",
+                "is_video": false,
+                "ups": 100,
+                "permalink": "/r/pics/comments/abc123/i_made_this/",
+                "url": "https://i.redd.it/abc123.jpg",
+                "is_self": false
+            }"#,
+        )
+        .unwrap();
+        db.record_post_seen_with_current_time(1, &post).unwrap();
+        let unnamed = Post {
+            id: "def456".into(),
+            subreddit: "pics".into(),
+            ..Default::default()
+        };
+        db.record_post_seen_with_current_time(1, &unnamed).unwrap();
 
-impl ToSql for TopPostsTimePeriod {
-    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput, rusqlite::Error> {
-        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
+        let post_name = |post_id: &str| -> Option<String> {
+            db.conn
+                .lock()
+                .unwrap()
+                .query_row(
+                    "select post_name from post where post_id = ?",
+                    [post_id],
+                    |row| row.get(0),
+                )
+                .unwrap()
+        };
+        assert_eq!(post_name("abc123").as_deref(), Some("t3_abc123"));
+        assert_eq!(post_name("def456").as_deref(), Some("t3_def456"));
     }
-}
 
-impl ToSql for PostType {
-    fn to_sql(&self) -> Result<rusqlite::types::ToSqlOutput, rusqlite::Error> {
-        Ok(ToSqlOutput::Owned(Value::Text(self.to_string())))
-    }
-}
+    #[test]
+    fn test_db_update_subscription_keeps_seen_posts() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "Pics".into(),
+                limit: Some(5),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.set_subscription_group(1, "Pics", Some("Art")).unwrap();
+        let post = Post {
+            id: "seen".into(),
+            subreddit: "Pics".into(),
+            ..Default::default()
+        };
+        db.record_post_seen_with_current_time(1, &post).unwrap();
 
-impl FromSql for TopPostsTimePeriod {
-    fn column_result(value: ValueRef) -> FromSqlResult<TopPostsTimePeriod> {
-        let str = String::column_result(value)?;
-        TopPostsTimePeriod::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
-    }
-}
+        let args = SubscriptionArgs {
+            subreddit: "pics".into(),
+            min_score: Some(100),
+            oc_only: true,
+            ..Default::default()
+        };
+        assert!(db.update_subscription(1, &args).unwrap());
 
-impl FromSql for PostType {
-    fn column_result(value: ValueRef) -> FromSqlResult<PostType> {
-        let str = String::column_result(value)?;
-        PostType::from_str(&str).map_err(|e| FromSqlError::Other(From::from(e)))
+        let sub = db.get_subscription(1, "Pics").unwrap().unwrap();
+        assert_eq!(sub.limit, None);
+        assert_eq!(sub.min_score, Some(100));
+        assert!(sub.oc_only);
+        assert_eq!(sub.group.as_deref(), Some("Art"));
+        assert!(db.is_post_seen(1, &post).unwrap());
+        assert!(db.existing_posts_for_subreddit(1, "Pics").unwrap());
+
+        assert!(!db
+            .update_subscription(
+                1,
+                &SubscriptionArgs {
+                    subreddit: "missing".into(),
+                    ..Default::default()
+                }
+            )
+            .unwrap());
+        assert!(!db.update_subscription(2, &args).unwrap());
+
+        // `_` is matched literally, not as a wildcard
+        assert!(!db
+            .update_subscription(
+                1,
+                &SubscriptionArgs {
+                    subreddit: "p_cs".into(),
+                    ..Default::default()
+                }
+            )
+            .unwrap());
     }
-}
 
-impl TryFrom<&Row<'_>> for Subscription {
-    type Error = rusqlite::Error;
+    #[test]
+    fn test_db_subreddit_overlap() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        let post = |id: &str, subreddit: &str, url: &str| Post {
+            id: id.into(),
+            subreddit: subreddit.into(),
+            url: url.into(),
+            ..Default::default()
+        };
+        for (chat_id, post) in [
+            (1, post("a1", "pics", "https://i.imgur.com/cat.jpg")),
+            (1, post("b1", "aww", "http://i.imgur.com/cat.jpg/")),
+            (1, post("c1", "cats", "https://i.imgur.com/cat.jpg")),
+            (1, post("a2", "pics", "https://www.youtube.com/watch?v=abc")),
+            (1, post("b2", "aww", "https://youtube.com/watch?v=abc#t=10")),
+            (1, post("b3", "aww", "https://youtube.com/watch?v=other")),
+            (1, post("c2", "cats", "https://example.com/unrelated")),
+            // Other chats are not counted
+            (2, post("a3", "pics", "https://example.com/unrelated")),
+        ] {
+            db.record_post_seen_with_current_time(chat_id, &post)
+                .unwrap();
+        }
+        // Pending posts are not counted
+        db.record_post(
+            1,
+            &post("c3", "cats", "https://youtube.com/watch?v=abc"),
+            None,
+        )
+        .unwrap();
 
-    fn try_from(row: &Row<'_>) -> Result<Self, Self::Error> {
-        Ok(Self {
-            subreddit: row.get_unwrap("subreddit"),
-            chat_id: row.get_unwrap("chat_id"),
-            limit: row.get_unwrap("post_limit"),
-            time: row.get_unwrap("time"),
-            filter: row.get_unwrap("filter"),
-        })
+        assert_eq!(
+            db.subreddit_overlap(1).unwrap(),
+            vec![
+                ("aww".to_string(), "pics".to_string(), 2),
+                ("aww".to_string(), "cats".to_string(), 1),
+                ("cats".to_string(), "pics".to_string(), 1),
+            ]
+        );
+        assert_eq!(db.subreddit_overlap(2).unwrap(), vec![]);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::reddit::PostType;
+    #[test]
+    fn test_db_get_subscriptions_grouped_by_subreddit() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        for (chat_id, subreddit, min_score) in [
+            (2, "pics", None),
+            (1, "Pics", Some(100)),
+            (1, "aww", None),
+            (3, "rust", None),
+            (3, "aww", Some(10)),
+        ] {
+            db.subscribe(
+                chat_id,
+                &SubscriptionArgs {
+                    subreddit: subreddit.to_string(),
+                    min_score,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+
+        let groups = db.get_subscriptions_grouped_by_subreddit().unwrap();
+        let summary = groups
+            .iter()
+            .map(|(subreddit, subs)| {
+                (
+                    subreddit.as_str(),
+                    subs.iter()
+                        .map(|sub| (sub.chat_id, sub.min_score))
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("aww", vec![(1, None), (3, Some(10))]),
+                ("pics", vec![(1, Some(100)), (2, None)]),
+                ("rust", vec![(3, None)]),
+            ]
+        );
+        // The subscriptions keep the names they were subscribed with
+        assert_eq!(groups["pics"][0].subreddit, "Pics");
+    }
 
     #[test]
-    fn test_db() {
-        let config = Config::default();
-        let mut db = Database::open(&config).unwrap();
+    fn test_db_duplicate_stats() {
+        use chrono::TimeZone;
+
+        let mut db = Database::open(&Config::default()).unwrap();
         db.migrate().unwrap();
-        let post = Post {
-            id: "v6nu75".into(),
-            created: 1654581100.0,
-            post_hint: Some("link".into()),
-            subreddit: "absoluteunit".into(),
-            title: "Tipping a cow to trim its hooves".into(),
-            is_self: false,
-            is_video: false,
-            is_gallery: Some(false),
-            gallery_data: None,
-            media_metadata: None,
-            ups: 469,
-            permalink: "/r/absoluteunit/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/".into(),
-            url: "https://i.imgur.com/Zt6f5mB.gifv".into(),
-            post_type: PostType::Video,
-            crosspost_parent_list: None,
+        let post = |id: &str, subreddit: &str, url: &str| Post {
+            id: id.into(),
+            subreddit: subreddit.into(),
+            url: url.into(),
+            ..Default::default()
+        };
+        let at = |minute: u32| {
+            Some(
+                chrono::Utc
+                    .with_ymd_and_hms(2023, 5, 1, 12, minute, 0)
+                    .unwrap(),
+            )
         };
+        for (chat_id, post, seen_at) in [
+            (1, post("a1", "pics", "https://i.imgur.com/cat.jpg"), at(0)),
+            (1, post("b1", "aww", "http://i.imgur.com/cat.jpg/"), at(1)),
+            (1, post("c1", "cats", "https://i.imgur.com/cat.jpg"), at(2)),
+            (
+                1,
+                post("b2", "aww", "https://youtube.com/watch?v=abc"),
+                at(3),
+            ),
+            (
+                1,
+                post("a2", "pics", "https://www.youtube.com/watch?v=abc"),
+                at(4),
+            ),
+            (
+                1,
+                post("b3", "aww", "https://youtube.com/watch?v=other"),
+                at(5),
+            ),
+            // Reposts within the same subreddit are not duplicates from another sub
+            (
+                1,
+                post("b4", "aww", "https://youtube.com/watch?v=other"),
+                at(6),
+            ),
+            // Pending posts are not counted
+            (
+                1,
+                post("c2", "cats", "https://youtube.com/watch?v=other"),
+                None,
+            ),
+            // Other chats are not counted
+            (
+                2,
+                post("a3", "pics", "https://youtube.com/watch?v=other"),
+                at(0),
+            ),
+        ] {
+            db.record_post(chat_id, &post, seen_at).unwrap();
+        }
 
-        assert!(!db.existing_posts_for_subreddit(1, "absoluteunit").unwrap());
-        db.record_post_seen_with_current_time(1, &post).unwrap();
-        assert!(db.is_post_seen(1, &post).unwrap());
-        assert!(db.existing_posts_for_subreddit(1, "absoluteunit").unwrap());
+        let stats = |subreddit: &str, duplicate_count, post_count| DuplicateStats {
+            subreddit: subreddit.to_string(),
+            duplicate_count,
+            post_count,
+        };
+        assert_eq!(
+            db.duplicate_stats(1).unwrap(),
+            vec![stats("aww", 1, 4), stats("cats", 1, 1), stats("pics", 1, 2)]
+        );
+        assert_eq!(db.duplicate_stats(3).unwrap(), vec![]);
     }
 
     #[test]
-    fn test_db_subscribe() {
-        let config = Config::default();
-        let mut db = Database::open(&config).unwrap();
-        db.migrate().unwrap();
-        let subscription_args = SubscriptionArgs {
-            subreddit: "test".to_string(),
-            limit: Some(1),
-            time: Some(TopPostsTimePeriod::Week),
-            filter: Some(PostType::Video),
+    fn test_db_merge_duplicate_subscriptions() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        let migrations = Migrations::new(MIGRATIONS.iter().map(|e| M::up(e)).collect());
+        migrations
+            .to_version(
+                &mut db.conn.lock().unwrap(),
+                merge_marker_version() as usize - 1,
+            )
+            .unwrap();
+        for (chat_id, subreddit, min_score) in [
+            (1, "AskReddit", Some(100)),
+            (1, "askreddit", None),
+            (1, "ASKREDDIT", Some(5)),
+            (1, "rust", None),
+            (2, "askreddit", None),
+        ] {
+            db.subscribe(
+                chat_id,
+                &SubscriptionArgs {
+                    subreddit: subreddit.to_string(),
+                    min_score,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+        let post = |id: &str, subreddit: &str| Post {
+            id: id.into(),
+            subreddit: subreddit.into(),
+            ..Default::default()
         };
-        db.subscribe(1, &subscription_args).unwrap();
+        for (chat_id, post) in [
+            (1, post("a", "AskReddit")),
+            (1, post("b", "askreddit")),
+            (1, post("c", "ASKREDDIT")),
+            (2, post("d", "askreddit")),
+        ] {
+            db.record_post_seen_with_current_time(chat_id, &post)
+                .unwrap();
+        }
+
+        db.migrate().unwrap();
 
         let subs = db.get_subscriptions_for_chat(1).unwrap();
         assert_eq!(
-            subs,
-            vec![Subscription {
-                chat_id: 1,
-                subreddit: "test".to_string(),
-                limit: Some(1),
-                time: Some(TopPostsTimePeriod::Week),
-                filter: Some(PostType::Video),
-            }]
+            subs.iter()
+                .map(|sub| (sub.subreddit.as_str(), sub.min_score))
+                .collect::<Vec<_>>(),
+            vec![("AskReddit", Some(100)), ("rust", None)]
         );
+        assert_eq!(db.get_subscriptions_for_chat(2).unwrap().len(), 1);
+        for id in ["a", "b", "c"] {
+            let record = db.get_post_record(1, id).unwrap().unwrap();
+            assert_eq!(record.subreddit, "AskReddit");
+            assert!(db.is_post_seen(1, &post(id, "AskReddit")).unwrap());
+        }
+        assert_eq!(
+            db.get_post_record(2, "d").unwrap().unwrap().subreddit,
+            "askreddit"
+        );
+
+        // Later migrations don't merge again
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "RUST".to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        db.migrate().unwrap();
+        assert_eq!(db.get_subscriptions_for_chat(1).unwrap().len(), 3);
+        assert_eq!(db.merge_duplicate_subscriptions().unwrap(), 1);
     }
 
     #[test]
-    fn test_db_unsubscribe() {
-        let config = Config::default();
-        let mut db = Database::open(&config).unwrap();
+    fn test_db_get_post_record() {
+        use chrono::TimeZone;
+
+        let mut db = Database::open(&Config::default()).unwrap();
         db.migrate().unwrap();
-        let subscription_args = SubscriptionArgs {
-            subreddit: "test".to_string(),
-            limit: Some(1),
-            time: Some(TopPostsTimePeriod::Week),
-            filter: Some(PostType::Video),
+        let seen_at = chrono::Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+        let post = |id: &str| Post {
+            id: id.into(),
+            subreddit: "pics".into(),
+            title: format!("Post {id}"),
+            ..Default::default()
         };
-        db.subscribe(1, &subscription_args).unwrap();
-        let subs = db.get_subscriptions_for_chat(1).unwrap();
-        assert_eq!(subs.len(), 1);
-        let deleted = db.unsubscribe(1, "test").unwrap();
-        assert_eq!(deleted, "test");
-        let subs = db.get_subscriptions_for_chat(1).unwrap();
-        assert_eq!(subs, vec![]);
+        db.record_post(1, &post("sent"), Some(seen_at)).unwrap();
+        db.record_post(1, &post("pending"), None).unwrap();
+
+        assert_eq!(
+            db.get_post_record(1, "sent").unwrap(),
+            Some(PostRecord {
+                post_id: "sent".into(),
+                subreddit: "pics".into(),
+                title: "Post sent".into(),
+                seen_at: Some(seen_at),
+                name: Some("t3_sent".into()),
+            })
+        );
+        assert_eq!(
+            db.get_post_record(1, "pending").unwrap().unwrap().seen_at,
+            None
+        );
+        assert_eq!(db.get_post_record(1, "missing").unwrap(), None);
+        assert_eq!(db.get_post_record(2, "sent").unwrap(), None);
     }
 
     #[test]
-    fn test_db_unsubscribe_doesnt_delete_posts() {
-        let config = Config::default();
-        let mut db = Database::open(&config).unwrap();
+    fn test_db_subscribe_falls_back_to_default_sort() {
+        let mut db = Database::open(&Config::default()).unwrap();
         db.migrate().unwrap();
-        let subscription_args = SubscriptionArgs {
-            subreddit: "test".to_string(),
-            limit: Some(1),
-            time: Some(TopPostsTimePeriod::Week),
-            filter: Some(PostType::Video),
+        let subscribe = |chat_id, subreddit: &str, sort| {
+            db.subscribe(
+                chat_id,
+                &SubscriptionArgs {
+                    subreddit: subreddit.into(),
+                    sort,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
         };
-        db.subscribe(1, &subscription_args).unwrap();
-        let post = Post {
-            id: "v6nu75".into(),
-            created: 1654581100.0,
-            post_hint: Some("link".into()),
+        let sort_of = |chat_id, subreddit: &str| {
+            db.get_subscription(chat_id, subreddit)
+                .unwrap()
+                .unwrap()
+                .sort
+        };
+
+        assert_eq!(db.get_default_sort(1).unwrap(), None);
+        subscribe(1, "unsorted", None);
+        db.set_default_sort(1, Some(ListingSort::New)).unwrap();
+        subscribe(1, "defaulted", None);
+        subscribe(1, "explicit", Some(ListingSort::Hot));
+
+        assert_eq!(sort_of(1, "unsorted"), None);
+        assert_eq!(sort_of(1, "defaulted"), Some(ListingSort::New));
+        assert_eq!(sort_of(1, "explicit"), Some(ListingSort::Hot));
+
+        // Copied subscriptions keep their sort rather than taking the target chat's default
+        db.set_default_sort(2, Some(ListingSort::Rising)).unwrap();
+        db.copy_chat(1, 2).unwrap();
+        assert_eq!(sort_of(2, "unsorted"), Some(ListingSort::Top));
+        assert_eq!(sort_of(2, "defaulted"), Some(ListingSort::New));
+        assert_eq!(db.get_default_sort(2).unwrap(), Some(ListingSort::New));
+    }
+
+    #[test]
+    fn test_db_get_inactive_subscriptions() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        for subreddit in ["active", "dormant", "new"] {
+            db.subscribe(
+                1,
+                &SubscriptionArgs {
+                    subreddit: subreddit.into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+
+        let now = chrono::Utc::now();
+        let week_ago = now - chrono::Duration::days(7);
+        db.touch_subscription(1, "active", now).unwrap();
+        db.touch_subscription(1, "dormant", week_ago - chrono::Duration::days(1))
+            .unwrap();
+        db.touch_subscription(1, "missing", now).unwrap();
+
+        let subreddits = |since| {
+            db.get_inactive_subscriptions(since)
+                .unwrap()
+                .into_iter()
+                .map(|sub| sub.subreddit)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(subreddits(week_ago), vec!["dormant"]);
+        // Never delivered subscriptions count from their creation
+        assert_eq!(
+            subreddits(now + chrono::Duration::seconds(1)),
+            vec!["active", "dormant", "new"]
+        );
+    }
+
+    #[test]
+    fn test_db_get_failing_subscriptions() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        for (chat_id, subreddit) in [
+            (1, "healthy"),
+            (1, "broken"),
+            (2, "flaky"),
+            (2, "recovered"),
+        ] {
+            db.subscribe(
+                chat_id,
+                &SubscriptionArgs {
+                    subreddit: subreddit.into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+        for _ in 0..4 {
+            db.record_subscription_failure(1, "broken", "404 Not Found")
+                .unwrap();
+            db.record_subscription_failure(2, "recovered", "timeout")
+                .unwrap();
+        }
+        db.record_subscription_success(2, "recovered").unwrap();
+        db.record_subscription_failure(2, "flaky", "first").unwrap();
+        db.record_subscription_failure(2, "flaky", "second")
+            .unwrap();
+
+        assert_eq!(
+            db.get_failing_subscriptions(3).unwrap(),
+            vec![FailingSubscription {
+                chat_id: 1,
+                subreddit: "broken".into(),
+                consecutive_failures: 4,
+                last_error: Some("404 Not Found".into()),
+            }]
+        );
+        assert_eq!(
+            db.get_failing_subscriptions(2).unwrap(),
+            vec![
+                FailingSubscription {
+                    chat_id: 1,
+                    subreddit: "broken".into(),
+                    consecutive_failures: 4,
+                    last_error: Some("404 Not Found".into()),
+                },
+                FailingSubscription {
+                    chat_id: 2,
+                    subreddit: "flaky".into(),
+                    consecutive_failures: 2,
+                    last_error: Some("second".into()),
+                },
+            ]
+        );
+        assert_eq!(db.get_failing_subscriptions(0).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_db_get_pending_posts() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        let post = |id: &str| Post {
+            id: id.into(),
             subreddit: "test".into(),
-            title: "Tipping a cow to trim its hooves".into(),
-            is_self: false,
-            is_gallery: Some(false),
-            is_video: false,
-            gallery_data: None,
-            media_metadata: None,
-            ups: 469,
-            permalink: "/r/test/comments/v6nu75/tipping_a_cow_to_trim_its_hooves/".into(),
-            url: "https://i.imgur.com/Zt6f5mB.gifv".into(),
-            post_type: PostType::Video,
-            crosspost_parent_list: None,
+            title: format!("Post {id}"),
+            ..Default::default()
         };
-        db.record_post_seen_with_current_time(1, &post).unwrap();
-        assert!(db.is_post_seen(1, &post).unwrap());
-        db.unsubscribe(1, "test").unwrap();
-        assert!(db.is_post_seen(1, &post).unwrap());
+        db.record_post(1, &post("pending"), None).unwrap();
+        db.record_post_seen_with_current_time(1, &post("sent"))
+            .unwrap();
+        db.record_post(2, &post("other_chat"), None).unwrap();
+        db.record_post(1, &post("later"), None).unwrap();
+
+        assert_eq!(
+            db.get_pending_posts(1, 1).unwrap(),
+            (
+                vec![QueuedPost {
+                    post_id: "pending".into(),
+                    subreddit: "test".into(),
+                    title: "Post pending".into(),
+                }],
+                2
+            )
+        );
+        assert_eq!(db.get_pending_posts(1, 10).unwrap().0.len(), 2);
+
+        for id in ["pending", "later"] {
+            db.record_post_seen_with_current_time(1, &post(id)).unwrap();
+        }
+        assert_eq!(db.get_pending_posts(1, 10).unwrap(), (vec![], 0));
     }
 }
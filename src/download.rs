@@ -4,11 +4,174 @@ use log::*;
 use std::io::Write;
 use std::{
     fs::File,
+    future::Future,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
 };
 use tempdir::TempDir;
+use thiserror::Error;
+use tokio::sync::Semaphore;
 use url::Url;
 
+use crate::{config::Config, convert, types::Video, ytdlp};
+
+/// Hosts of images that are fetched through `image_proxy_base` if one is configured.
+const PROXIED_HOSTS: &[&str] = &["imgur.com", "i.imgur.com", "i.redd.it", "preview.redd.it"];
+
+/// Returned when downloading media does not finish in time.
+#[derive(Error, Debug)]
+#[error("media download timed out after {0:?}")]
+pub struct MediaTimeout(pub Duration);
+
+/// Fails with `MediaTimeout` if the download does not finish within `timeout`.
+pub async fn with_timeout<T>(
+    timeout: Duration,
+    download: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    tokio::time::timeout(timeout, download)
+        .await
+        .map_err(|_| MediaTimeout(timeout))?
+}
+
+/// Rewrites an Imgur or Reddit image url to `{proxy_base}/{host}{path}?{query}`. Other urls, and
+/// all urls when no proxy is configured, are returned unchanged.
+pub fn proxied_url(url: &str, proxy_base: Option<&str>) -> String {
+    let (Some(proxy_base), Ok(parsed_url)) = (proxy_base, Url::parse(url)) else {
+        return url.to_owned();
+    };
+    match parsed_url.host_str() {
+        Some(host) if PROXIED_HOSTS.contains(&host) => {
+            let mut proxied = format!(
+                "{}/{host}{}",
+                proxy_base.trim_end_matches('/'),
+                parsed_url.path()
+            );
+            if let Some(query) = parsed_url.query() {
+                proxied.push('?');
+                proxied.push_str(query);
+            }
+            proxied
+        }
+        _ => url.to_owned(),
+    }
+}
+
+/// Downloads an image through the configured image proxy, failing with `MediaTimeout` if it
+/// takes longer than the configured media download timeout.
+pub async fn download_media(config: &Config, url: &str) -> Result<(PathBuf, TempDir)> {
+    let url = proxied_url(url, config.image_proxy_base.as_deref());
+    with_timeout(config.media_download_timeout(), download_url_to_tmp(&url)).await
+}
+
+/// Returned when a video is not downloaded because too many videos are already waiting for a
+/// worker.
+#[derive(Error, Debug)]
+#[error("video queue is full with {0} videos waiting")]
+pub struct VideoQueueFull(pub usize);
+
+/// Whether another video may wait for a worker, given how many are already waiting.
+fn admits_video(waiting: usize, capacity: usize) -> bool {
+    waiting < capacity
+}
+
+/// Workers that download and transcode videos with yt-dlp, so that only a bounded number of
+/// transcodes run at a time. Videos wait for a free worker in a queue of bounded capacity, and are
+/// rejected with `VideoQueueFull` when it is full. The poll loop doesn't wait for its videos, as
+/// it sends them with `BotState::queue_send`.
+pub struct VideoQueue {
+    workers: Arc<Semaphore>,
+    capacity: usize,
+    waiting: Arc<AtomicUsize>,
+}
+
+/// A video's place in the queue, given up when it gets a worker or its download is dropped.
+struct QueuePlace(Arc<AtomicUsize>);
+
+impl Drop for QueuePlace {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl VideoQueue {
+    pub fn new(workers: usize, capacity: usize) -> Self {
+        VideoQueue {
+            workers: Arc::new(Semaphore::new(workers.max(1))),
+            capacity,
+            waiting: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// The queue of all video downloads, sized by the config it is first requested with.
+    pub fn shared(config: &Config) -> &'static VideoQueue {
+        static QUEUE: OnceLock<VideoQueue> = OnceLock::new();
+        QUEUE.get_or_init(|| VideoQueue::new(config.video_workers, config.video_queue_capacity))
+    }
+
+    fn enqueue(&self) -> Result<QueuePlace, VideoQueueFull> {
+        self.waiting
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |waiting| {
+                admits_video(waiting, self.capacity).then_some(waiting + 1)
+            })
+            .map(|_| QueuePlace(self.waiting.clone()))
+            .map_err(VideoQueueFull)
+    }
+
+    /// Downloads and transcodes a video once a worker is free. On timeout yt-dlp is left to finish
+    /// in the background, keeping its worker, and the downloaded file is deleted when it does. The
+    /// timeout doesn't include waiting for a worker.
+    pub async fn download(&self, url: &str, timeout: Duration) -> Result<Video> {
+        let url = url.to_owned();
+        self.run(timeout, move || ytdlp::download(&url)).await
+    }
+
+    /// Runs a blocking conversion once a worker is free, like `download`.
+    async fn run<T: Send + 'static>(
+        &self,
+        timeout: Duration,
+        job: impl FnOnce() -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let place = self.enqueue()?;
+        let worker = self
+            .workers
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Semaphore is never closed");
+        drop(place);
+
+        with_timeout(timeout, async move {
+            tokio::task::spawn_blocking(move || {
+                let _worker = worker;
+                job()
+            })
+            .await?
+        })
+        .await
+    }
+}
+
+/// Downloads and transcodes a video with yt-dlp on the shared video queue.
+pub async fn download_video(config: &Config, url: &str) -> Result<Video> {
+    VideoQueue::shared(config)
+        .download(url, config.video_download_timeout())
+        .await
+}
+
+/// Converts a downloaded animated WebP to a GIF on the shared video queue, as converting takes
+/// about as long as transcoding a short video.
+pub async fn convert_animated_webp(config: &Config, path: PathBuf) -> Result<PathBuf> {
+    VideoQueue::shared(config)
+        .run(config.video_download_timeout(), move || {
+            convert::convert_animated_webp_to_gif(&path)
+        })
+        .await
+}
+
 /// Downloads url to a file and returns the path along with handle to temp dir in which the file is.
 /// Whe the temp dir value is dropped, the contents in file system are deleted.
 pub async fn download_url_to_tmp(url: &str) -> Result<(PathBuf, TempDir)> {
@@ -31,3 +194,74 @@ pub async fn download_url_to_tmp(url: &str) -> Result<(PathBuf, TempDir)> {
     info!("downloaded {url} to {}", tmp_path.to_string_lossy());
     Ok((tmp_path, tmp_dir))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_video_queue_backpressure() {
+        assert!(admits_video(0, 1));
+        assert!(!admits_video(1, 1));
+        assert!(!admits_video(0, 0));
+
+        let queue = VideoQueue::new(1, 2);
+        let first = queue.enqueue().unwrap();
+        let _second = queue.enqueue().unwrap();
+        assert!(matches!(queue.enqueue(), Err(VideoQueueFull(2))));
+        // Getting a worker frees the place in the queue
+        drop(first);
+        assert!(queue.enqueue().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_with_timeout() {
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(())
+        };
+        let err = with_timeout(Duration::from_millis(10), slow)
+            .await
+            .unwrap_err();
+        assert!(err.is::<MediaTimeout>());
+
+        let fast = async { Ok(1) };
+        assert_eq!(
+            with_timeout(Duration::from_secs(10), fast).await.unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_proxied_url() {
+        let proxy = Some("https://proxy.example.com/");
+        assert_eq!(
+            proxied_url("https://i.imgur.com/abc.jpg", proxy),
+            "https://proxy.example.com/i.imgur.com/abc.jpg"
+        );
+        assert_eq!(
+            proxied_url("https://imgur.com/abc.png", proxy),
+            "https://proxy.example.com/imgur.com/abc.png"
+        );
+        assert_eq!(
+            proxied_url("https://i.redd.it/xyz.jpg", proxy),
+            "https://proxy.example.com/i.redd.it/xyz.jpg"
+        );
+        assert_eq!(
+            proxied_url(
+                "https://preview.redd.it/xyz.jpg?width=640&s=abc",
+                Some("https://proxy.example.com")
+            ),
+            "https://proxy.example.com/preview.redd.it/xyz.jpg?width=640&s=abc"
+        );
+        assert_eq!(
+            proxied_url("https://example.com/abc.jpg", proxy),
+            "https://example.com/abc.jpg"
+        );
+        assert_eq!(
+            proxied_url("https://i.imgur.com/abc.jpg", None),
+            "https://i.imgur.com/abc.jpg"
+        );
+        assert_eq!(proxied_url("not a url", proxy), "not a url");
+    }
+}
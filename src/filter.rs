@@ -0,0 +1,460 @@
+use anyhow::Result;
+use std::collections::HashSet;
+
+use crate::{
+    config::Config,
+    db::Database,
+    handle_post::resolves_to_media,
+    reddit::{Post, PostType},
+    types::{Ranking, Subscription},
+};
+
+/// How many of the chat's most recently seen posts are compared against when deduplicating titles.
+const DEDUP_TITLES_RECENT_POSTS: u32 = 200;
+
+/// Share of words two normalized titles must have in common to be considered the same post.
+const TITLE_SIMILARITY_THRESHOLD: f64 = 0.8;
+
+/// Whether the ratio is a valid minimum upvote ratio, i.e. within 0.0 to 1.0.
+pub fn is_valid_upvote_ratio(ratio: f64) -> bool {
+    (0.0..=1.0).contains(&ratio)
+}
+
+/// Whether the value is a valid top percentile, i.e. from 1 to 100.
+pub fn is_valid_top_percentile(percentile: u8) -> bool {
+    (1..=100).contains(&percentile)
+}
+
+/// Lowest score among the `top_percentile` percent highest scores, or `None` without scores. At
+/// least one score is always within the percentile, so that small listings aren't filtered out
+/// entirely.
+pub fn percentile_threshold(
+    scores: impl IntoIterator<Item = u32>,
+    top_percentile: u8,
+) -> Option<u32> {
+    let mut scores = scores.into_iter().collect::<Vec<_>>();
+    scores.sort_unstable_by(|a, b| b.cmp(a));
+    let share = scores.len() * usize::from(top_percentile);
+    let count = (share / 100 + usize::from(share % 100 != 0)).clamp(1, scores.len().max(1));
+    scores.get(count - 1).copied()
+}
+
+/// Orders the posts by their rank, highest first, and drops posts ranked below `min_rank`. Posts
+/// are left as they are without a ranking.
+pub fn rank_posts(
+    posts: Vec<Post>,
+    ranking: Option<Ranking>,
+    min_rank: Option<f64>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<Post> {
+    let Some(ranking) = ranking else {
+        return posts;
+    };
+    let mut ranked = posts
+        .into_iter()
+        .map(|post| (ranking.rank(&post, now), post))
+        .filter(|(rank, _)| min_rank.map_or(true, |min_rank| *rank >= min_rank))
+        .collect::<Vec<_>>();
+    ranked.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    ranked.into_iter().map(|(_, post)| post).collect()
+}
+
+/// Conditions a post has to meet to be delivered for a subscription.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PostFilter {
+    pub post_type: Option<PostType>,
+    pub min_score: Option<u32>,
+    pub min_upvote_ratio: Option<f64>,
+    pub dedup_titles: bool,
+    pub oc_only: bool,
+    pub media_only: bool,
+    /// Whether links to Imgur albums are sent as albums, which `media_only` counts as media.
+    pub expand_imgur_albums: bool,
+    pub blocked_authors: Vec<String>,
+}
+
+impl PostFilter {
+    pub fn for_subscription(sub: &Subscription, config: &Config) -> Self {
+        PostFilter {
+            post_type: sub.filter.or(config.default_filter),
+            min_score: sub.min_score,
+            min_upvote_ratio: sub.min_upvote_ratio,
+            dedup_titles: sub.dedup_titles,
+            oc_only: sub.oc_only,
+            media_only: sub.media_only,
+            expand_imgur_albums: config.imgur_client_id.is_some(),
+            blocked_authors: sub.blocked_authors.clone(),
+        }
+    }
+
+    /// Raises the minimum score to the threshold of the top percentile of the fetched posts. The
+    /// threshold adapts to the subreddit, unlike a fixed minimum score.
+    pub fn with_top_percentile(mut self, posts: &[Post], top_percentile: Option<u8>) -> Self {
+        let Some(top_percentile) = top_percentile else {
+            return self;
+        };
+        let threshold = percentile_threshold(posts.iter().map(|post| post.ups), top_percentile);
+        self.min_score = self.min_score.max(threshold);
+        self
+    }
+
+    pub fn matches(&self, post: &Post) -> bool {
+        if let Some(post_type) = self.post_type {
+            if post.post_type != post_type {
+                return false;
+            }
+        }
+
+        if let Some(min_score) = self.min_score {
+            if post.ups < min_score {
+                return false;
+            }
+        }
+
+        if let Some(min_upvote_ratio) = self.min_upvote_ratio {
+            if post.upvote_ratio < min_upvote_ratio {
+                return false;
+            }
+        }
+
+        if self.oc_only && !post.is_original_content {
+            return false;
+        }
+
+        if self.media_only && !resolves_to_media(post, self.expand_imgur_albums) {
+            return false;
+        }
+
+        if self.is_blocked_author(&post.author) {
+            return false;
+        }
+
+        true
+    }
+
+    /// Usernames are compared case-insensitively. Posts of deleted accounts have `[deleted]` as
+    /// their author, which is only blocked if it is listed as is.
+    fn is_blocked_author(&self, author: &str) -> bool {
+        !author.is_empty()
+            && self
+                .blocked_authors
+                .iter()
+                .any(|blocked| blocked.eq_ignore_ascii_case(author))
+    }
+
+    /// Whether title deduplication is enabled and a post with a very similar title was recently
+    /// delivered to the chat.
+    pub fn is_duplicate_title(&self, db: &Database, chat_id: i64, post: &Post) -> Result<bool> {
+        if !self.dedup_titles {
+            return Ok(false);
+        }
+
+        let recent_titles = db.get_recent_post_titles(chat_id, DEDUP_TITLES_RECENT_POSTS)?;
+        Ok(recent_titles
+            .iter()
+            .any(|title| is_similar_title(title, &post.title)))
+    }
+}
+
+/// Lowercases the title, strips punctuation and collapses whitespace.
+fn normalize_title(title: &str) -> String {
+    title
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .to_lowercase()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Compares the words of the normalized titles. Titles are similar if they normalize to the same
+/// string or share most of their words.
+fn is_similar_title(a: &str, b: &str) -> bool {
+    let a = normalize_title(a);
+    let b = normalize_title(b);
+    if a.is_empty() || b.is_empty() {
+        return false;
+    }
+    if a == b {
+        return true;
+    }
+
+    let a_words = a.split(' ').collect::<HashSet<_>>();
+    let b_words = b.split(' ').collect::<HashSet<_>>();
+    let common = a_words.intersection(&b_words).count();
+    let all = a_words.union(&b_words).count();
+    common as f64 / all as f64 >= TITLE_SIMILARITY_THRESHOLD
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_matches() {
+        let post = Post {
+            post_type: PostType::Video,
+            ups: 500,
+            ..Default::default()
+        };
+
+        assert!(PostFilter::default().matches(&post));
+        assert!(PostFilter {
+            post_type: Some(PostType::Video),
+            min_score: Some(500),
+            ..Default::default()
+        }
+        .matches(&post));
+        assert!(!PostFilter {
+            post_type: Some(PostType::Image),
+            ..Default::default()
+        }
+        .matches(&post));
+        assert!(!PostFilter {
+            post_type: Some(PostType::Video),
+            min_score: Some(1000),
+            ..Default::default()
+        }
+        .matches(&post));
+    }
+
+    #[test]
+    fn test_filter_oc_only() {
+        let filter = PostFilter {
+            oc_only: true,
+            ..Default::default()
+        };
+        assert!(filter.matches(&Post {
+            is_original_content: true,
+            ..Default::default()
+        }));
+        assert!(!filter.matches(&Post::default()));
+    }
+
+    #[test]
+    fn test_filter_media_only() {
+        let filter = PostFilter {
+            media_only: true,
+            ..Default::default()
+        };
+        let post = |post_type| Post {
+            post_type,
+            ..Default::default()
+        };
+        assert!(filter.matches(&post(PostType::Image)));
+        assert!(filter.matches(&post(PostType::Video)));
+        assert!(filter.matches(&post(PostType::Gallery)));
+        assert!(!filter.matches(&post(PostType::SelfText)));
+        assert!(!filter.matches(&post(PostType::Link)));
+        assert!(!filter.matches(&post(PostType::Unknown)));
+        assert!(PostFilter::default().matches(&post(PostType::SelfText)));
+
+        // Links are resolved to media when the media can be downloaded, e.g. imgur gifv
+        let json = r#"{
+            "id": "abc123",
+            "created": 1684000000.0,
+            "created_utc": 1684000000.0,
+            "subreddit": "gifs",
+            "title": "Cat",
+            "is_video": false,
+            "ups": 100,
+            "permalink": "/r/gifs/comments/abc123/cat/",
+            "url": "https://i.imgur.com/abc123.gifv",
+            "post_hint": "link",
+            "is_self": false
+        }"#;
+        let gifv_link: Post = serde_json::from_str(json).unwrap();
+        assert!(filter.matches(&gifv_link));
+        let bare_link: Post = serde_json::from_str(&json.replace(
+            "https://i.imgur.com/abc123.gifv",
+            "https://example.com/article",
+        ))
+        .unwrap();
+        assert!(!filter.matches(&bare_link));
+
+        // Imgur albums are sent as albums when they are expanded
+        let album_link: Post = serde_json::from_str(&json.replace(
+            "https://i.imgur.com/abc123.gifv",
+            "https://imgur.com/a/abc123",
+        ))
+        .unwrap();
+        assert!(!filter.matches(&album_link));
+        assert!(PostFilter {
+            expand_imgur_albums: true,
+            ..filter.clone()
+        }
+        .matches(&album_link));
+
+        // Polls are sent as polls whatever their type
+        assert!(!filter.matches(&Post {
+            post_type: PostType::Image,
+            poll_data: Some(crate::reddit::PollData {
+                options: vec![],
+                total_vote_count: 0,
+            }),
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn test_filter_min_upvote_ratio() {
+        let filter = PostFilter {
+            min_upvote_ratio: Some(0.8),
+            ..Default::default()
+        };
+        let post = |upvote_ratio| Post {
+            upvote_ratio,
+            ..Default::default()
+        };
+        assert!(filter.matches(&post(0.95)));
+        assert!(filter.matches(&post(0.8)));
+        assert!(!filter.matches(&post(0.79)));
+        assert!(PostFilter::default().matches(&post(0.1)));
+
+        assert!(is_valid_upvote_ratio(0.0));
+        assert!(is_valid_upvote_ratio(1.0));
+        assert!(!is_valid_upvote_ratio(1.5));
+        assert!(!is_valid_upvote_ratio(-0.1));
+        assert!(!is_valid_upvote_ratio(f64::NAN));
+    }
+
+    #[test]
+    fn test_rank_posts() {
+        use chrono::TimeZone;
+
+        let now = chrono::Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+        let post = |id: &str, ups, upvote_ratio, age_minutes: i64| Post {
+            id: id.into(),
+            ups,
+            upvote_ratio,
+            created_utc: (now - chrono::Duration::minutes(age_minutes)).timestamp() as f64,
+            ..Default::default()
+        };
+        let posts = vec![
+            post("old", 1000, 0.5, 10 * 60),
+            // Younger than an hour, so ranked as if it was an hour old
+            post("fresh", 200, 0.5, 30),
+            post("steady", 600, 0.9, 4 * 60),
+        ];
+        let ranked = |ranking, min_rank| {
+            rank_posts(posts.clone(), ranking, min_rank, now)
+                .into_iter()
+                .map(|post| post.id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(ranked(None, Some(1e9)), vec!["old", "fresh", "steady"]);
+        assert_eq!(
+            ranked(Some(Ranking::Score), None),
+            vec!["old", "steady", "fresh"]
+        );
+        assert_eq!(
+            ranked(Some(Ranking::Ratio), None),
+            vec!["steady", "old", "fresh"]
+        );
+        assert_eq!(
+            ranked(Some(Ranking::Velocity), None),
+            vec!["fresh", "steady", "old"]
+        );
+        assert_eq!(
+            ranked(Some(Ranking::Quality), None),
+            vec!["steady", "fresh", "old"]
+        );
+        assert_eq!(
+            ranked(Some(Ranking::Quality), Some(100.0)),
+            vec!["steady", "fresh"]
+        );
+    }
+
+    #[test]
+    fn test_percentile_threshold() {
+        let scores = [10, 500, 40, 1000, 20, 300, 50, 70, 90, 30];
+        assert_eq!(percentile_threshold(scores, 100), Some(10));
+        assert_eq!(percentile_threshold(scores, 50), Some(70));
+        assert_eq!(percentile_threshold(scores, 20), Some(500));
+        // Rounded up to whole posts
+        assert_eq!(percentile_threshold(scores, 25), Some(300));
+        assert_eq!(percentile_threshold(scores, 1), Some(1000));
+        // Equal scores are all within the percentile
+        assert_eq!(percentile_threshold([5, 5, 5, 1], 25), Some(5));
+        assert_eq!(percentile_threshold([7], 10), Some(7));
+        assert_eq!(percentile_threshold([], 10), None);
+
+        let post = |ups| Post {
+            ups,
+            ..Default::default()
+        };
+        let posts = scores.map(post);
+        let filter = PostFilter::default().with_top_percentile(&posts, Some(20));
+        assert_eq!(posts.iter().filter(|post| filter.matches(post)).count(), 2);
+        // A higher fixed minimum still applies
+        let filter = PostFilter {
+            min_score: Some(800),
+            ..Default::default()
+        }
+        .with_top_percentile(&posts, Some(20));
+        assert_eq!(filter.min_score, Some(800));
+        assert_eq!(
+            PostFilter::default().with_top_percentile(&posts, None),
+            PostFilter::default()
+        );
+
+        assert!(is_valid_top_percentile(1));
+        assert!(is_valid_top_percentile(100));
+        assert!(!is_valid_top_percentile(0));
+        assert!(!is_valid_top_percentile(101));
+    }
+
+    #[test]
+    fn test_filter_blocked_authors() {
+        let filter = PostFilter {
+            blocked_authors: vec!["Reposter".to_string(), "[deleted]".to_string()],
+            ..Default::default()
+        };
+        let post = |author: &str| Post {
+            author: author.to_string(),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&post("reposter")));
+        assert!(!filter.matches(&post("REPOSTER")));
+        assert!(!filter.matches(&post("[deleted]")));
+        assert!(filter.matches(&post("reposter2")));
+        assert!(filter.matches(&post("")));
+
+        let filter = PostFilter {
+            blocked_authors: vec!["deleted".to_string()],
+            ..Default::default()
+        };
+        assert!(filter.matches(&post("[deleted]")));
+        assert!(!filter.matches(&post("deleted")));
+        assert!(PostFilter::default().matches(&post("[deleted]")));
+    }
+
+    #[test]
+    fn test_normalize_title() {
+        assert_eq!(
+            normalize_title("  This cat is  AMAZING!!! (OC) "),
+            "this cat is amazing oc"
+        );
+        assert_eq!(normalize_title("It's... a dog?"), "it s a dog");
+        assert_eq!(normalize_title("!!!"), "");
+    }
+
+    #[test]
+    fn test_is_similar_title() {
+        assert!(is_similar_title(
+            "This cat is amazing!",
+            "this cat is AMAZING"
+        ));
+        assert!(is_similar_title(
+            "My dog learned to open the fridge by himself today",
+            "My dog learned to open the fridge by himself today [OC]"
+        ));
+        assert!(!is_similar_title(
+            "This cat is amazing",
+            "This dog is amazing"
+        ));
+        assert!(!is_similar_title("!!!", "???"));
+    }
+}
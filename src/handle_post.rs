@@ -1,38 +1,350 @@
 use crate::reddit::{self};
-use crate::{config, db, download::*, messages, ytdlp};
+use crate::{
+    config, convert, db,
+    download::*,
+    imgur, messages,
+    types::{CaptionComponent, ParseMode, RedditSpoilers, Video, WebPreview},
+};
 use anyhow::{Context, Result};
 use log::*;
 use url::Url;
 
+use std::future::Future;
 use std::string::ToString;
 use std::{borrow::Cow, path::PathBuf};
 use std::{collections::HashMap, path::Path};
 use teloxide::types::{InputFile, InputMediaVideo};
 use teloxide::{
-    payloads::{SendMessageSetters, SendPhotoSetters, SendVideoSetters},
+    payloads::{self, SendMessageSetters, SendPhotoSetters, SendVideoSetters},
     types::InputMediaPhoto,
 };
-use teloxide::{prelude::*, types::InputMedia};
+use teloxide::{prelude::*, requests::HasPayload, types::InputMedia};
 use tempdir::TempDir;
 
+/// Per-chat options applied to every message sent for a post.
+#[derive(Debug, Clone)]
+pub struct SendOptions {
+    pub disable_notification: bool,
+    pub caption_components: Vec<CaptionComponent>,
+    pub web_preview: WebPreview,
+    pub nsfw_spoiler: bool,
+    pub reddit_spoilers: RedditSpoilers,
+    pub parse_mode: ParseMode,
+    /// Forum topic the messages are sent to.
+    pub message_thread_id: Option<i32>,
+}
+
+impl SendOptions {
+    pub fn for_chat(db: &db::Database, chat_id: i64) -> Result<Self> {
+        Ok(SendOptions {
+            disable_notification: db.get_silent(chat_id)?,
+            caption_components: db.get_caption_components(chat_id)?,
+            web_preview: db.get_web_preview(chat_id)?,
+            nsfw_spoiler: db.get_nsfw_spoiler(chat_id)?,
+            reddit_spoilers: RedditSpoilers::default(),
+            parse_mode: db.get_parse_mode(chat_id)?,
+            message_thread_id: None,
+        })
+    }
+
+    /// Whether the post's media should be covered with a spoiler.
+    pub fn spoiler(&self, post: &reddit::Post) -> bool {
+        (self.nsfw_spoiler && post.over_18)
+            || (self.reddit_spoilers == RedditSpoilers::SpoilerMedia && post.spoiler)
+    }
+}
+
+/// Payloads of messages that can be sent to a forum topic.
+trait ThreadedPayload {
+    fn message_thread_id_mut(&mut self) -> &mut Option<i32>;
+}
+
+impl ThreadedPayload for payloads::SendMessage {
+    fn message_thread_id_mut(&mut self) -> &mut Option<i32> {
+        &mut self.message_thread_id
+    }
+}
+
+impl ThreadedPayload for payloads::SendPhoto {
+    fn message_thread_id_mut(&mut self) -> &mut Option<i32> {
+        &mut self.message_thread_id
+    }
+}
+
+impl ThreadedPayload for payloads::SendVideo {
+    fn message_thread_id_mut(&mut self) -> &mut Option<i32> {
+        &mut self.message_thread_id
+    }
+}
+
+impl ThreadedPayload for payloads::SendMediaGroup {
+    fn message_thread_id_mut(&mut self) -> &mut Option<i32> {
+        &mut self.message_thread_id
+    }
+}
+
+impl ThreadedPayload for payloads::SendPoll {
+    fn message_thread_id_mut(&mut self) -> &mut Option<i32> {
+        &mut self.message_thread_id
+    }
+}
+
+trait InThread: HasPayload + Sized {
+    /// Sends the message to the forum topic, or to the chat's general topic if `None`.
+    fn in_thread(mut self, message_thread_id: Option<i32>) -> Self
+    where
+        Self::Payload: ThreadedPayload,
+    {
+        *self.payload_mut().message_thread_id_mut() = message_thread_id;
+        self
+    }
+}
+
+impl<R: HasPayload> InThread for R {}
+
+/// Payloads of messages with formatted text.
+trait FormattedPayload {
+    fn parse_mode_mut(&mut self) -> &mut Option<teloxide::types::ParseMode>;
+}
+
+impl FormattedPayload for payloads::SendMessage {
+    fn parse_mode_mut(&mut self) -> &mut Option<teloxide::types::ParseMode> {
+        &mut self.parse_mode
+    }
+}
+
+impl FormattedPayload for payloads::SendPhoto {
+    fn parse_mode_mut(&mut self) -> &mut Option<teloxide::types::ParseMode> {
+        &mut self.parse_mode
+    }
+}
+
+impl FormattedPayload for payloads::SendVideo {
+    fn parse_mode_mut(&mut self) -> &mut Option<teloxide::types::ParseMode> {
+        &mut self.parse_mode
+    }
+}
+
+trait WithParseMode: HasPayload + Sized {
+    /// Sends the text with the chat's parse mode, or as plain text for `ParseMode::None`.
+    fn with_parse_mode(mut self, parse_mode: ParseMode) -> Self
+    where
+        Self::Payload: FormattedPayload,
+    {
+        *self.payload_mut().parse_mode_mut() = parse_mode.telegram_parse_mode();
+        self
+    }
+}
+
+impl<R: HasPayload> WithParseMode for R {}
+
+/// Largest file a bot can upload to Telegram.
+const TELEGRAM_MAX_UPLOAD_BYTES: u64 = 50 * 1024 * 1024;
+
+/// Telegram accepts between 2 and 10 items in a media group.
+const TELEGRAM_MAX_MEDIA_GROUP_SIZE: usize = 10;
+
+/// How a post would be sent to a chat.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendPlan {
+    Video { size_bytes: u64 },
+    Photo,
+    Animation,
+    Album { count: usize },
+    Link { with_thumbnail: bool },
+    Text,
+    Poll { options: usize },
+}
+
+/// Whether `handle_new_post` may transcode a video to send the post, which can take a while.
+/// WebP images are counted when they are converted, as whether one is animated is only known once
+/// it is downloaded.
+pub fn sends_video(config: &config::Config, post: &reddit::Post, text_only: bool) -> bool {
+    if text_only || post.poll_data.is_some() {
+        return false;
+    }
+    match post.post_type {
+        reddit::PostType::Video => true,
+        reddit::PostType::Image => {
+            config.convert_webp_to_jpeg
+                && Url::parse(&post.url).is_ok_and(|url| url.path().ends_with(".webp"))
+        }
+        _ => false,
+    }
+}
+
+/// Whether `handle_new_post` sends the post as media rather than as a link, text or poll, decided
+/// without downloading anything. With `expand_imgur_albums`, links to Imgur albums count as media,
+/// as they are sent as an album.
+pub fn resolves_to_media(post: &reddit::Post, expand_imgur_albums: bool) -> bool {
+    if post.poll_data.is_some() {
+        return false;
+    }
+    match post.post_type {
+        reddit::PostType::Link | reddit::PostType::Unknown => {
+            expand_imgur_albums && imgur::parse_album_id(&post.url).is_some()
+        }
+        post_type => post_type.has_media(),
+    }
+}
+
+/// Resolves the media of a post the same way as `handle_new_post` but without sending or
+/// recording anything. Videos are downloaded to find out whether they fit in Telegram's upload
+/// limit.
+pub async fn plan_post_send(config: &config::Config, post: &reddit::Post) -> Result<SendPlan> {
+    if let Some(options) = post
+        .poll_data
+        .as_ref()
+        .and_then(messages::format_poll_options)
+    {
+        return Ok(SendPlan::Poll {
+            options: options.len(),
+        });
+    }
+    match post.post_type {
+        reddit::PostType::Video => {
+            let video = download_video(config, &post.url)
+                .await
+                .context("Failed to download video from post")?;
+            let size_bytes = std::fs::metadata(&video.path)?.len();
+            if size_bytes > TELEGRAM_MAX_UPLOAD_BYTES {
+                anyhow::bail!(
+                    "Video is too large to upload ({} MB, the limit is {} MB)",
+                    size_bytes / 1024 / 1024,
+                    TELEGRAM_MAX_UPLOAD_BYTES / 1024 / 1024
+                );
+            }
+            Ok(SendPlan::Video { size_bytes })
+        }
+        reddit::PostType::Image => {
+            let url = Url::parse(&post.url).context("Image post has an invalid url")?;
+            if is_gif(Path::new(url.path())) {
+                Ok(SendPlan::Animation)
+            } else {
+                Ok(SendPlan::Photo)
+            }
+        }
+        reddit::PostType::Gallery => {
+            let (items, _) = capped_gallery_items(
+                &post
+                    .gallery_data
+                    .as_ref()
+                    .context("Gallery post has no gallery data")?
+                    .items,
+                config.max_gallery_images,
+            );
+            let media_metadata = post
+                .media_metadata
+                .as_ref()
+                .context("Gallery post has no media metadata")?;
+            let count = items
+                .iter()
+                .filter(|item| {
+                    media_metadata
+                        .get(&item.media_id)
+                        .and_then(|metadata| metadata.s.as_ref())
+                        .is_some()
+                })
+                .count();
+            if count == 0 {
+                anyhow::bail!("Gallery has no downloadable images");
+            }
+            Ok(SendPlan::Album { count })
+        }
+        reddit::PostType::SelfText => Ok(SendPlan::Text),
+        reddit::PostType::Link | reddit::PostType::Unknown => {
+            if let (Some(client_id), Some(album_id)) = (
+                config.imgur_client_id.as_ref(),
+                imgur::parse_album_id(&post.url),
+            ) {
+                match imgur::get_album_image_urls(client_id.expose_secret(), &album_id).await {
+                    Ok(urls) if !urls.is_empty() => {
+                        let (urls, _) = capped_gallery_items(&urls, config.max_gallery_images);
+                        return Ok(SendPlan::Album { count: urls.len() });
+                    }
+                    Ok(_) => warn!("imgur album is empty, would send as link"),
+                    Err(e) => warn!("failed to expand imgur album, would send as link: {e:?}"),
+                }
+            }
+            Ok(SendPlan::Link {
+                with_thumbnail: post.thumbnail_url().is_some(),
+            })
+        }
+    }
+}
+
+/// Dimensions, length and thumbnail of a downloaded video, so that Telegram shows the player at the
+/// right size with a proper preview. Whatever couldn't be found out is left for Telegram to work
+/// out itself.
+#[derive(Debug, Default)]
+struct VideoDetails {
+    info: Option<convert::VideoInfo>,
+    /// The video's first frame, next to the video so that it is deleted along with it.
+    thumbnail: Option<PathBuf>,
+}
+
+/// Probes the video with ffprobe and extracts its first frame with ffmpeg. Failures are logged, as
+/// the video can be sent without them.
+async fn video_details(path: &Path) -> VideoDetails {
+    let path = path.to_owned();
+    let details = tokio::task::spawn_blocking(move || VideoDetails {
+        info: convert::probe_video(&path)
+            .map_err(|e| warn!("failed to probe video: {e:?}"))
+            .ok(),
+        thumbnail: convert::extract_thumbnail(&path)
+            .map_err(|e| warn!("failed to extract video thumbnail: {e:?}"))
+            .ok(),
+    })
+    .await;
+    details.unwrap_or_else(|e| {
+        warn!("failed to get video details: {e:?}");
+        VideoDetails::default()
+    })
+}
+
+trait WithVideoDetails: SendVideoSetters {
+    /// Sets the dimensions, duration and thumbnail of the video that are known. Without ffprobe,
+    /// the dimensions yt-dlp reported are used.
+    fn with_video_details(self, video: &Video, details: VideoDetails) -> Self {
+        let (width, height) = match &details.info {
+            Some(info) => (info.width, info.height),
+            None => (video.width.into(), video.height.into()),
+        };
+        let mut request = self.width(width).height(height);
+        if let Some(info) = &details.info {
+            request = request.duration(info.duration_secs);
+        }
+        if let Some(thumbnail) = details.thumbnail {
+            request = request.thumb(InputFile::file(thumbnail));
+        }
+        request
+    }
+}
+
+impl<R: SendVideoSetters> WithVideoDetails for R {}
+
 pub async fn handle_video_link(
+    config: &config::Config,
     db: &db::Database,
     tg: &Bot,
     chat_id: i64,
     link: &Url,
 ) -> Result<()> {
-    let video = tokio::task::block_in_place(|| ytdlp::download(link.as_str()))
+    let video = download_video(config, link.as_str())
+        .await
         .context("Failed to download video from link")?;
 
     db.record_post_seen_with_current_time(chat_id, &video)?;
+    let options = SendOptions::for_chat(db, chat_id)?;
 
     info!("got a video: {video:?}");
     let caption = messages::format_link_video_caption_html(&video);
     tg.send_video(ChatId(chat_id), InputFile::file(&video.path))
         .parse_mode(teloxide::types::ParseMode::Html)
         .caption(&caption)
-        .height(video.height.into())
-        .width(video.width.into())
+        .disable_notification(options.disable_notification)
+        .in_thread(options.message_thread_id)
+        .with_video_details(&video, video_details(&video.path).await)
         .reply_markup(messages::format_repost_buttons(&video))
         .await?;
     info!(
@@ -47,17 +359,26 @@ async fn handle_new_video_post(
     tg: &Bot,
     chat_id: i64,
     post: &reddit::Post,
+    options: &SendOptions,
 ) -> Result<()> {
-    let video = tokio::task::block_in_place(|| ytdlp::download(&post.url))
+    let video = download_video(config, &post.url)
+        .await
         .context("Failed to download video from post")?;
 
     info!("got a video: {video:?}");
-    let caption = messages::format_media_caption_html(post, config.links_base_url.as_deref());
+    let caption = messages::format_media_caption(
+        post,
+        config,
+        &options.caption_components,
+        options.parse_mode,
+    );
     tg.send_video(ChatId(chat_id), InputFile::file(&video.path))
-        .parse_mode(teloxide::types::ParseMode::Html)
+        .with_parse_mode(options.parse_mode)
         .caption(&caption)
-        .height(video.height.into())
-        .width(video.width.into())
+        .disable_notification(options.disable_notification)
+        .in_thread(options.message_thread_id)
+        .has_spoiler(options.spoiler(post))
+        .with_video_details(&video, video_details(&video.path).await)
         .reply_markup(messages::format_repost_buttons(post))
         .await?;
     info!(
@@ -72,30 +393,33 @@ async fn handle_new_image_post(
     tg: &Bot,
     chat_id: i64,
     post: &reddit::Post,
+    options: &SendOptions,
 ) -> Result<()> {
-    match download_url_to_tmp(&post.url).await {
+    match download_media(config, &post.url).await {
         Ok((path, _tmp_dir)) => {
             // path will be deleted when _tmp_dir when goes out of scope
-            let caption =
-                messages::format_media_caption_html(post, config.links_base_url.as_deref());
-            if is_gif(&path) {
-                tg.send_video(ChatId(chat_id), InputFile::file(path))
-                    .parse_mode(teloxide::types::ParseMode::Html)
-                    .caption(&caption)
-                    .reply_markup(messages::format_repost_buttons(post))
-                    .await?;
-
-                info!("gif uploaded post_id={} chat_id={chat_id}", post.id);
+            let path = if config.convert_webp_to_jpeg {
+                match convert::detect_image_format(&std::fs::read(&path)?) {
+                    convert::ImageFormat::StaticWebp => {
+                        tokio::task::spawn_blocking(move || convert::convert_to_jpeg(&path))
+                            .await??
+                    }
+                    convert::ImageFormat::AnimatedWebp => {
+                        info!("sending animated webp as gif post_id={}", post.id);
+                        convert_animated_webp(config, path).await?
+                    }
+                    convert::ImageFormat::Other => path,
+                }
             } else {
-                tg.send_photo(ChatId(chat_id), InputFile::file(path))
-                    .parse_mode(teloxide::types::ParseMode::Html)
-                    .caption(&caption)
-                    .reply_markup(messages::format_repost_buttons(post))
-                    .await?;
-
-                info!("image uploaded post_id={} chat_id={chat_id}", post.id);
-            }
-            Ok(())
+                path
+            };
+            let caption = messages::format_media_caption(
+                post,
+                config,
+                &options.caption_components,
+                options.parse_mode,
+            );
+            send_image(tg, chat_id, post, options, path, &caption).await
         }
         Err(e) => {
             error!("failed to download image: {e:?}");
@@ -104,31 +428,127 @@ async fn handle_new_image_post(
     }
 }
 
+/// Sends a single downloaded image, or a GIF as a video.
+async fn send_image(
+    tg: &Bot,
+    chat_id: i64,
+    post: &reddit::Post,
+    options: &SendOptions,
+    path: PathBuf,
+    caption: &str,
+) -> Result<()> {
+    if is_gif(&path) {
+        tg.send_video(ChatId(chat_id), InputFile::file(path))
+            .with_parse_mode(options.parse_mode)
+            .caption(caption)
+            .disable_notification(options.disable_notification)
+            .in_thread(options.message_thread_id)
+            .has_spoiler(options.spoiler(post))
+            .reply_markup(messages::format_repost_buttons(post))
+            .await?;
+
+        info!("gif uploaded post_id={} chat_id={chat_id}", post.id);
+    } else {
+        tg.send_photo(ChatId(chat_id), InputFile::file(path))
+            .with_parse_mode(options.parse_mode)
+            .caption(caption)
+            .disable_notification(options.disable_notification)
+            .in_thread(options.message_thread_id)
+            .has_spoiler(options.spoiler(post))
+            .reply_markup(messages::format_repost_buttons(post))
+            .await?;
+
+        info!("image uploaded post_id={} chat_id={chat_id}", post.id);
+    }
+    Ok(())
+}
+
 async fn handle_new_link_post(
     config: &config::Config,
     tg: &Bot,
     chat_id: i64,
     post: &reddit::Post,
+    options: &SendOptions,
 ) -> Result<()> {
-    let message_html = messages::format_link_message_html(post, config.links_base_url.as_deref());
-    tg.send_message(ChatId(chat_id), message_html)
-        .parse_mode(teloxide::types::ParseMode::Html)
-        .disable_web_page_preview(false)
+    let thumbnail_url = match options.web_preview {
+        WebPreview::Thumbnail => post.thumbnail_url(),
+        WebPreview::Preview | WebPreview::None => None,
+    };
+    if let Some(thumbnail_url) = thumbnail_url {
+        match send_link_post_with_thumbnail(config, tg, chat_id, post, options, thumbnail_url).await
+        {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                warn!("failed to send link post with thumbnail, sending as link: {e:?}");
+            }
+        }
+    }
+
+    let message = messages::format_link_message(
+        post,
+        config,
+        &options.caption_components,
+        options.parse_mode,
+    );
+    tg.send_message(ChatId(chat_id), message)
+        .with_parse_mode(options.parse_mode)
+        .disable_notification(options.disable_notification)
+        .in_thread(options.message_thread_id)
+        .disable_web_page_preview(options.web_preview == WebPreview::None)
         .reply_markup(messages::format_repost_buttons(post))
         .await?;
     info!("message sent post_id={} chat_id={chat_id}", post.id);
     Ok(())
 }
 
+async fn send_link_post_with_thumbnail(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    post: &reddit::Post,
+    options: &SendOptions,
+    thumbnail_url: &str,
+) -> Result<()> {
+    // path will be deleted when _tmp_dir when goes out of scope
+    let (path, _tmp_dir) = download_media(config, thumbnail_url).await?;
+    let caption = messages::format_link_message(
+        post,
+        config,
+        &options.caption_components,
+        options.parse_mode,
+    );
+    tg.send_photo(ChatId(chat_id), InputFile::file(path))
+        .with_parse_mode(options.parse_mode)
+        .caption(&caption)
+        .disable_notification(options.disable_notification)
+        .in_thread(options.message_thread_id)
+        .has_spoiler(options.spoiler(post))
+        .reply_markup(messages::format_repost_buttons(post))
+        .await?;
+    info!(
+        "link with thumbnail uploaded post_id={} chat_id={chat_id}",
+        post.id
+    );
+    Ok(())
+}
+
 async fn handle_new_self_post(
     config: &config::Config,
     tg: &Bot,
     chat_id: i64,
     post: &reddit::Post,
+    options: &SendOptions,
 ) -> Result<()> {
-    let message_html = messages::format_media_caption_html(post, config.links_base_url.as_deref());
-    tg.send_message(ChatId(chat_id), message_html)
-        .parse_mode(teloxide::types::ParseMode::Html)
+    let message = messages::format_self_post(
+        post,
+        config,
+        &options.caption_components,
+        options.parse_mode,
+    );
+    tg.send_message(ChatId(chat_id), message)
+        .with_parse_mode(options.parse_mode)
+        .disable_notification(options.disable_notification)
+        .in_thread(options.message_thread_id)
         .disable_web_page_preview(true)
         .reply_markup(messages::format_repost_buttons(post))
         .await?;
@@ -136,21 +556,89 @@ async fn handle_new_self_post(
     Ok(())
 }
 
-async fn download_gallery(post: &reddit::Post) -> Result<HashMap<String, (PathBuf, TempDir)>> {
+/// Sends a poll post as a Telegram poll with the same options, followed by the post's caption with
+/// the votes on Reddit. Fails without sending anything if Telegram can't show the poll.
+async fn handle_new_poll_post(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    post: &reddit::Post,
+    poll: &reddit::PollData,
+    options: &SendOptions,
+) -> Result<()> {
+    let poll_options = messages::format_poll_options(poll).with_context(|| {
+        format!(
+            "Poll with {} options can't be sent as a Telegram poll",
+            poll.options.len()
+        )
+    })?;
+    let sent = tg
+        .send_poll(
+            ChatId(chat_id),
+            messages::format_poll_question(post),
+            poll_options,
+        )
+        .disable_notification(options.disable_notification)
+        .in_thread(options.message_thread_id)
+        .reply_markup(messages::format_repost_buttons(post))
+        .await?;
+    info!("poll sent post_id={} chat_id={chat_id}", post.id);
+
+    // The poll is already delivered, so failing to send the caption doesn't fail the post
+    let caption = messages::format_poll_caption(
+        post,
+        poll,
+        config,
+        &options.caption_components,
+        options.parse_mode,
+    );
+    if let Err(e) = tg
+        .send_message(ChatId(chat_id), caption)
+        .with_parse_mode(options.parse_mode)
+        .disable_notification(options.disable_notification)
+        .in_thread(options.message_thread_id)
+        .disable_web_page_preview(true)
+        .reply_to_message_id(sent.id)
+        .await
+    {
+        warn!("failed to send caption of poll post_id={}: {e:?}", post.id);
+    }
+    Ok(())
+}
+
+/// The items of a gallery or album to send, in order, capped at `max_images`, along with how many
+/// items were left out.
+fn capped_gallery_items<T>(items: &[T], max_images: Option<usize>) -> (&[T], usize) {
+    let count = max_images.map_or(items.len(), |max| items.len().min(max));
+    (&items[..count], items.len() - count)
+}
+
+/// Downloads the images of the given gallery items, keyed by media id.
+async fn download_gallery(
+    config: &config::Config,
+    post: &reddit::Post,
+    items: &[reddit::GalleryDataItem],
+) -> Result<HashMap<String, (PathBuf, TempDir)>> {
     let media_metadata_map = post
         .media_metadata
         .as_ref()
         .expect("expected media_metadata to exist in gallery post");
 
     let mut map: HashMap<String, (PathBuf, TempDir)> = HashMap::new();
-    for (id, media_metadata) in media_metadata_map {
+    for item in items {
+        let Some(media_metadata) = media_metadata_map.get(&item.media_id) else {
+            continue;
+        };
         let s = media_metadata
             .s
             .as_ref()
             .context("Media metadata not available")?;
-        let url = &s.url.replace("&amp;", "&");
-        info!("got media id={id} x={} y={} url={}", &s.x, &s.y, url);
-        map.insert(id.to_string(), download_url_to_tmp(url).await?);
+        let url = &s.url;
+        info!(
+            "got media id={} x={} y={} url={}",
+            item.media_id, &s.x, &s.y, url
+        );
+        map.insert(item.media_id.clone(), download_media(config, url).await?);
     }
 
     Ok(map)
@@ -161,59 +649,127 @@ async fn handle_new_gallery_post(
     tg: &Bot,
     chat_id: i64,
     post: &reddit::Post,
+    options: &SendOptions,
 ) -> Result<()> {
     // post.gallery_data is an array that describes the order of photos in the gallery, while
     // post.media_metadata is a map that contains the URL for each photo
-    let gallery_data_items = &post
-        .gallery_data
-        .as_ref()
-        .expect("expected media_metadata to exist in gallery post")
-        .items;
-    let gallery_files_map = download_gallery(post).await?;
-    let mut media_group = vec![];
-    let mut first = true;
+    let (gallery_data_items, more_images) = capped_gallery_items(
+        &post
+            .gallery_data
+            .as_ref()
+            .expect("expected media_metadata to exist in gallery post")
+            .items,
+        config.max_gallery_images,
+    );
+    let gallery_files_map = download_gallery(config, post, gallery_data_items).await?;
+    let mut image_paths = vec![];
 
     for item in gallery_data_items {
-        let file = gallery_files_map.get(&item.media_id);
-        match file {
-            Some((image_path, _tempdir)) => {
-                if is_gif(image_path) {
-                    let mut input_media_video = InputMediaVideo::new(InputFile::file(image_path));
-                    if first {
-                        let caption = messages::format_media_caption_html(
-                            post,
-                            config.links_base_url.as_deref(),
-                        );
-                        input_media_video = input_media_video
-                            .caption(&caption)
-                            .parse_mode(teloxide::types::ParseMode::Html);
-                        first = false;
-                    }
-                    media_group.push(InputMedia::Video(input_media_video));
-                } else {
-                    let mut input_media_photo = InputMediaPhoto::new(InputFile::file(image_path));
-                    if first {
-                        let caption = messages::format_media_caption_html(
-                            post,
-                            config.links_base_url.as_deref(),
-                        );
-                        input_media_photo = input_media_photo
-                            .caption(&caption)
-                            .parse_mode(teloxide::types::ParseMode::Html);
-                        first = false;
-                    }
-                    media_group.push(InputMedia::Photo(input_media_photo));
-                }
-            }
+        match gallery_files_map.get(&item.media_id) {
+            Some((image_path, _tempdir)) => image_paths.push(image_path.as_path()),
             None => {
                 error!("could not find downloaded image for gallery data item: {item:?}");
             }
         }
     }
 
-    let gallery_msg = tg.send_media_group(ChatId(chat_id), media_group).await?;
+    send_media_group(
+        config,
+        tg,
+        chat_id,
+        post,
+        options,
+        &image_paths,
+        more_images,
+    )
+    .await?;
+    info!("gallery uploaded post_id={} chat_id={chat_id}", post.id);
+
+    Ok(())
+}
+
+async fn handle_new_imgur_album_post(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    post: &reddit::Post,
+    options: &SendOptions,
+    client_id: &str,
+    album_id: &str,
+) -> Result<()> {
+    let image_urls = imgur::get_album_image_urls(client_id, album_id).await?;
+    let (image_urls, more_images) = capped_gallery_items(&image_urls, config.max_gallery_images);
+    let mut downloads = vec![];
+    for url in image_urls {
+        downloads.push(download_media(config, url).await?);
+    }
+    // Keep the temp dirs alive until the album is sent
+    let image_paths = downloads
+        .iter()
+        .map(|(path, _tempdir)| path.as_path())
+        .collect::<Vec<_>>();
+
+    // Telegram rejects media groups of a single item
+    if let [path] = image_paths.as_slice() {
+        let caption = messages::format_album_caption(
+            post,
+            config,
+            &options.caption_components,
+            more_images,
+            options.parse_mode,
+        );
+        send_image(tg, chat_id, post, options, path.to_path_buf(), &caption).await?;
+        info!("imgur album uploaded post_id={} chat_id={chat_id}", post.id);
+        return Ok(());
+    }
+
+    send_media_group(
+        config,
+        tg,
+        chat_id,
+        post,
+        options,
+        &image_paths,
+        more_images,
+    )
+    .await?;
+    info!("imgur album uploaded post_id={} chat_id={chat_id}", post.id);
+
+    Ok(())
+}
+
+/// Sends files as a media group with the post's caption on the first item, and records the
+/// uploaded files so that the group can be reposted later. `more_images` is the number of the
+/// post's images that were left out, which the caption mentions.
+async fn send_media_group(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    post: &reddit::Post,
+    options: &SendOptions,
+    paths: &[&Path],
+    more_images: usize,
+) -> Result<()> {
+    let caption = messages::format_album_caption(
+        post,
+        config,
+        &options.caption_components,
+        more_images,
+        options.parse_mode,
+    );
+    let media_group = build_media_group(paths, &caption, options.spoiler(post), options.parse_mode);
+
+    let mut gallery_msgs = vec![];
+    for media_group in split_media_group(media_group) {
+        let msgs = tg
+            .send_media_group(ChatId(chat_id), media_group)
+            .disable_notification(options.disable_notification)
+            .in_thread(options.message_thread_id)
+            .await?;
+        gallery_msgs.extend(msgs);
+    }
     let db = db::Database::open(config)?;
-    for msg in gallery_msg {
+    for msg in gallery_msgs {
         let file_meta = if let Some(video) = msg.video() {
             &video.file
         } else if let Some(photo) = msg.photo() {
@@ -229,36 +785,238 @@ async fn handle_new_gallery_post(
     }
 
     tg.send_message(ChatId(chat_id), "To repost:")
+        .disable_notification(options.disable_notification)
+        .in_thread(options.message_thread_id)
         .reply_markup(messages::format_repost_buttons_gallery(post, true))
         .send()
         .await?;
 
-    info!("gallery uploaded post_id={} chat_id={chat_id}", post.id);
-
     Ok(())
 }
 
+/// Splits media into groups of at most 10 items that Telegram accepts. The items are spread evenly
+/// over the groups so that the last one isn't left with a single item.
+pub fn split_media_group<T>(mut items: Vec<T>) -> Vec<Vec<T>> {
+    let group_count =
+        (items.len() + TELEGRAM_MAX_MEDIA_GROUP_SIZE - 1) / TELEGRAM_MAX_MEDIA_GROUP_SIZE;
+    (0..group_count)
+        .map(|i| {
+            let groups_left = group_count - i;
+            let size = (items.len() + groups_left - 1) / groups_left;
+            items.drain(..size).collect()
+        })
+        .collect()
+}
+
+/// The caption is set on the first item only, so that Telegram shows it for the whole group.
+fn build_media_group(
+    paths: &[&Path],
+    caption: &str,
+    spoiler: bool,
+    parse_mode: ParseMode,
+) -> Vec<InputMedia> {
+    paths
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            let caption = (i == 0).then(|| caption.to_string());
+            if is_gif(path) {
+                let mut media = InputMediaVideo::new(InputFile::file(path));
+                media.parse_mode = caption
+                    .as_ref()
+                    .and_then(|_| parse_mode.telegram_parse_mode());
+                media.caption = caption;
+                media.has_spoiler = spoiler;
+                InputMedia::Video(media)
+            } else {
+                let mut media = InputMediaPhoto::new(InputFile::file(path));
+                media.parse_mode = caption
+                    .as_ref()
+                    .and_then(|_| parse_mode.telegram_parse_mode());
+                media.caption = caption;
+                media.has_spoiler = spoiler;
+                InputMedia::Photo(media)
+            }
+        })
+        .collect()
+}
+
+/// Link posts pointing to an Imgur album are expanded into a media group when an Imgur client id
+/// is configured. Otherwise, or if expanding fails, the post is sent as a link.
+async fn handle_new_link_or_album_post(
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    post: &reddit::Post,
+    options: &SendOptions,
+) -> Result<()> {
+    if let (Some(client_id), Some(album_id)) = (
+        config.imgur_client_id.as_ref(),
+        imgur::parse_album_id(&post.url),
+    ) {
+        match handle_new_imgur_album_post(
+            config,
+            tg,
+            chat_id,
+            post,
+            options,
+            client_id.expose_secret(),
+            &album_id,
+        )
+        .await
+        {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                warn!("failed to expand imgur album, sending as link: {e:?}");
+            }
+        }
+    }
+
+    handle_new_link_post(config, tg, chat_id, post, options).await
+}
+
+/// Stage of delivering a post with media.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendStage {
+    Media,
+    /// The post as a link with its caption.
+    Link,
+    /// Nothing more is sent. The post stays recorded as seen, so that it doesn't fail on every
+    /// check.
+    Skip,
+}
+
+impl SendStage {
+    /// Stage to continue with after this stage failed with `err`. Posts whose media download timed
+    /// out, or whose video didn't fit in the video queue, are always sent as a link, so that a slow
+    /// media host or a backlog of videos doesn't prevent the post from being delivered at all.
+    /// Other failures, e.g. Telegram rejecting the image, fall back to a link if
+    /// `link_fallback` is enabled.
+    fn after_failure(self, err: &anyhow::Error, link_fallback: bool) -> SendStage {
+        match self {
+            SendStage::Media
+                if link_fallback || err.is::<MediaTimeout>() || err.is::<VideoQueueFull>() =>
+            {
+                SendStage::Link
+            }
+            SendStage::Media | SendStage::Link | SendStage::Skip => SendStage::Skip,
+        }
+    }
+}
+
+/// Sends the post with `send_media`, falling back to `send_link` as decided by
+/// `SendStage::after_failure`. Fails with the last error if the post is skipped.
+async fn send_with_fallback<L, F>(
+    send_media: impl Future<Output = Result<()>>,
+    send_link: L,
+    link_fallback: bool,
+) -> Result<()>
+where
+    L: FnOnce() -> F,
+    F: Future<Output = Result<()>>,
+{
+    let Err(err) = send_media.await else {
+        return Ok(());
+    };
+    match SendStage::Media.after_failure(&err, link_fallback) {
+        SendStage::Link => {
+            warn!("{err:#}, sending as link");
+            send_link().await
+        }
+        SendStage::Media | SendStage::Skip => Err(err),
+    }
+}
+
 pub async fn process_post(
     db: &db::Database,
     chat_id: i64,
+    message_thread_id: Option<i32>,
+    text_only: bool,
     post: &reddit::Post,
     config: &config::Config,
     tg: &Bot,
 ) -> Result<()> {
     db.record_post_seen_with_current_time(chat_id, post)?;
-    if let Err(e) = handle_new_post(config, tg, chat_id, post).await {
+    if let Err(e) = handle_new_post(config, tg, chat_id, message_thread_id, text_only, post).await {
         error!("failed to handle new post: {e:?}");
     };
     Ok(())
 }
 
+/// Whether the daily header is yet to be sent today, given the day it was last sent on.
+fn is_daily_header_due(
+    last_header_date: Option<chrono::NaiveDate>,
+    today: chrono::NaiveDate,
+) -> bool {
+    last_header_date.map_or(true, |last| last < today)
+}
+
+/// Sends the chat's daily header, if it has one, unless it was already sent `today`. Meant to be
+/// called before delivering a post. Failing to send the header is logged and doesn't prevent the
+/// post from being delivered.
+pub async fn send_daily_header_if_due(
+    db: &db::Database,
+    tg: &Bot,
+    chat_id: i64,
+    message_thread_id: Option<i32>,
+    today: chrono::NaiveDate,
+) -> Result<()> {
+    let Some(header) = db.get_daily_header(chat_id)? else {
+        return Ok(());
+    };
+    if !is_daily_header_due(db.get_last_header_date(chat_id)?, today) {
+        return Ok(());
+    }
+
+    let sent = tg
+        .send_message(
+            ChatId(chat_id),
+            messages::format_daily_header(&header, today),
+        )
+        .disable_notification(db.get_silent(chat_id)?)
+        .in_thread(message_thread_id)
+        .await;
+    match sent {
+        Ok(_) => db.set_last_header_date(chat_id, today),
+        Err(e) => {
+            error!("failed to send daily header chat_id={chat_id}: {e:?}");
+            Ok(())
+        }
+    }
+}
+
+/// Sends the post to the chat, in the forum topic `message_thread_id` if given. With `text_only`,
+/// the post is sent as a text message without resolving or downloading its media.
 pub async fn handle_new_post(
     config: &config::Config,
     tg: &Bot,
     chat_id: i64,
+    message_thread_id: Option<i32>,
+    text_only: bool,
     post: &reddit::Post,
 ) -> Result<()> {
     info!("got new {post:#?}");
+    let options = SendOptions {
+        message_thread_id,
+        reddit_spoilers: config.reddit_spoilers,
+        ..SendOptions::for_chat(&db::Database::open(config)?, chat_id)?
+    };
+
+    if text_only {
+        // Without a preview, link posts are sent without downloading their thumbnail either
+        let options = SendOptions {
+            web_preview: WebPreview::None,
+            ..options
+        };
+        return match post.post_type {
+            reddit::PostType::SelfText => handle_new_self_post(config, tg, chat_id, post, &options)
+                .await
+                .context("Failed handling new self"),
+            _ => handle_new_link_post(config, tg, chat_id, post, &options)
+                .await
+                .context("Failed handling new text only post"),
+        };
+    }
     let mut post = Cow::Borrowed(post);
 
     // Sometimes post_hint is not in top list response but exists when getting the link directly,
@@ -266,31 +1024,61 @@ pub async fn handle_new_post(
     // TODO: It appears that post with is_gallery=true will never have post_hint set
     if post.post_hint.is_none() {
         info!("post missing post_hint, getting like directly");
-        post = Cow::Owned(reddit::get_link(&post.id).await.unwrap());
+        post = Cow::Owned(
+            reddit::RedditClient::new(config)?
+                .get_link(&post.id)
+                .await
+                .unwrap(),
+        );
+    }
+
+    if let Some(poll) = &post.poll_data {
+        // Polls Telegram can't show are always sent as a link instead
+        return send_with_fallback(
+            handle_new_poll_post(config, tg, chat_id, &post, poll, &options),
+            || handle_new_link_post(config, tg, chat_id, &post, &options),
+            true,
+        )
+        .await
+        .context("Failed handling new poll");
     }
 
     match post.post_type {
-        reddit::PostType::Image => handle_new_image_post(config, tg, chat_id, &post)
-            .await
-            .context("Failed handling new image"),
-        reddit::PostType::Video => handle_new_video_post(config, tg, chat_id, &post)
-            .await
-            .context("Failed handling new video"),
-        reddit::PostType::Link => handle_new_link_post(config, tg, chat_id, &post)
-            .await
-            .context("Failed handling new link post"),
-        reddit::PostType::SelfText => handle_new_self_post(config, tg, chat_id, &post)
+        reddit::PostType::Image => send_with_fallback(
+            handle_new_image_post(config, tg, chat_id, &post, &options),
+            || handle_new_link_post(config, tg, chat_id, &post, &options),
+            config.link_fallback_on_send_failure,
+        )
+        .await
+        .context("Failed handling new image"),
+        reddit::PostType::Video => send_with_fallback(
+            handle_new_video_post(config, tg, chat_id, &post, &options),
+            || handle_new_link_post(config, tg, chat_id, &post, &options),
+            config.link_fallback_on_send_failure,
+        )
+        .await
+        .context("Failed handling new video"),
+        reddit::PostType::Link => {
+            handle_new_link_or_album_post(config, tg, chat_id, &post, &options)
+                .await
+                .context("Failed handling new link post")
+        }
+        reddit::PostType::SelfText => handle_new_self_post(config, tg, chat_id, &post, &options)
             .await
             .context("Failed handling new self"),
-        reddit::PostType::Gallery => handle_new_gallery_post(config, tg, chat_id, &post)
-            .await
-            .context("Failed handling new gallery"),
+        reddit::PostType::Gallery => send_with_fallback(
+            handle_new_gallery_post(config, tg, chat_id, &post, &options),
+            || handle_new_link_post(config, tg, chat_id, &post, &options),
+            config.link_fallback_on_send_failure,
+        )
+        .await
+        .context("Failed handling new gallery"),
         // /r/bestof posts have no characteristics like post_hint that could be used to
         // determine them as a type of Link; as a workaround, post Unknown post types the same way
         // as a link
         reddit::PostType::Unknown => {
             warn!("unknown post type, post={post:?}");
-            handle_new_link_post(config, tg, chat_id, &post).await
+            handle_new_link_or_album_post(config, tg, chat_id, &post, &options).await
         }
     }
 }
@@ -300,3 +1088,282 @@ fn is_gif(path: &Path) -> bool {
         .and_then(|x| x.to_str().map(|x| x == "gif"))
         .unwrap_or(false)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_split_media_group() {
+        let sizes = |len: usize| {
+            split_media_group((0..len).collect())
+                .iter()
+                .map(Vec::len)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sizes(0), Vec::<usize>::new());
+        assert_eq!(sizes(2), vec![2]);
+        assert_eq!(sizes(10), vec![10]);
+        assert_eq!(sizes(11), vec![6, 5]);
+        assert_eq!(sizes(20), vec![10, 10]);
+        assert_eq!(sizes(91), vec![10, 9, 9, 9, 9, 9, 9, 9, 9, 9]);
+        assert_eq!(
+            split_media_group((0..12).collect()).concat(),
+            (0..12).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_send_stage_after_failure() {
+        let timeout = anyhow::Error::new(MediaTimeout(std::time::Duration::from_secs(1)));
+        let rejected = anyhow::anyhow!("Bad Request: IMAGE_PROCESS_FAILED");
+
+        assert_eq!(
+            SendStage::Media.after_failure(&timeout, false),
+            SendStage::Link
+        );
+        assert_eq!(
+            SendStage::Media.after_failure(&rejected, true),
+            SendStage::Link
+        );
+        assert_eq!(
+            SendStage::Media.after_failure(&rejected, false),
+            SendStage::Skip
+        );
+        assert_eq!(
+            SendStage::Media.after_failure(&anyhow::Error::new(VideoQueueFull(8)), false),
+            SendStage::Link
+        );
+        assert_eq!(
+            SendStage::Link.after_failure(&timeout, true),
+            SendStage::Skip
+        );
+        assert_eq!(
+            SendStage::Link.after_failure(&rejected, true),
+            SendStage::Skip
+        );
+        assert_eq!(
+            SendStage::Skip.after_failure(&rejected, true),
+            SendStage::Skip
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_with_fallback() {
+        let slow_download = || {
+            with_timeout(std::time::Duration::from_millis(10), async {
+                tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+                Ok(())
+            })
+        };
+
+        let sent_link = AtomicBool::new(false);
+        let send_link = || async {
+            sent_link.store(true, Ordering::SeqCst);
+            Ok(())
+        };
+        send_with_fallback(slow_download(), send_link, false)
+            .await
+            .unwrap();
+        assert!(sent_link.load(Ordering::SeqCst));
+
+        // Other errors are not handled by sending a link unless the fallback is enabled
+        let sent_link = AtomicBool::new(false);
+        let send_link = || async {
+            sent_link.store(true, Ordering::SeqCst);
+            Ok(())
+        };
+        let failing_send = async { Err(anyhow::anyhow!("no such file")) };
+        assert!(send_with_fallback(failing_send, send_link, false)
+            .await
+            .is_err());
+        assert!(!sent_link.load(Ordering::SeqCst));
+
+        let sent_link = AtomicBool::new(false);
+        let send_link = || async {
+            sent_link.store(true, Ordering::SeqCst);
+            Ok(())
+        };
+        let failing_send = async { Err(anyhow::anyhow!("IMAGE_PROCESS_FAILED")) };
+        send_with_fallback(failing_send, send_link, true)
+            .await
+            .unwrap();
+        assert!(sent_link.load(Ordering::SeqCst));
+
+        // The post is skipped if the link fails as well
+        let failing_send = async { Err(anyhow::anyhow!("IMAGE_PROCESS_FAILED")) };
+        let failing_link = || async { Err(anyhow::anyhow!("message is too long")) };
+        let err = send_with_fallback(failing_send, failing_link, true)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "message is too long");
+
+        // Nothing else is sent if the media is sent
+        let send_link = || async { panic!("should not be sent as link") };
+        send_with_fallback(async { Ok(()) }, send_link, true)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_capped_gallery_items() {
+        let items = (0..25)
+            .map(|id| reddit::GalleryDataItem {
+                caption: None,
+                media_id: format!("media{id}"),
+                id,
+            })
+            .collect::<Vec<_>>();
+
+        let (capped, more) = capped_gallery_items(&items, Some(10));
+        assert_eq!(capped.len(), 10);
+        assert_eq!(capped[0].media_id, "media0");
+        assert_eq!(capped[9].media_id, "media9");
+        assert_eq!(more, 15);
+
+        assert_eq!(capped_gallery_items(&items, Some(30)).1, 0);
+        assert_eq!(capped_gallery_items(&items, Some(25)).1, 0);
+        let (capped, more) = capped_gallery_items(&items, None);
+        assert_eq!((capped.len(), more), (25, 0));
+
+        // Imgur albums are capped the same way
+        let urls = (0..12)
+            .map(|i| format!("https://i.imgur.com/{i}.jpg"))
+            .collect::<Vec<_>>();
+        let (capped, more) = capped_gallery_items(&urls, Some(10));
+        assert_eq!(capped.last().unwrap(), "https://i.imgur.com/9.jpg");
+        assert_eq!(more, 2);
+    }
+
+    #[test]
+    fn test_is_daily_header_due() {
+        let today = chrono::NaiveDate::from_ymd_opt(2023, 5, 2).unwrap();
+        assert!(is_daily_header_due(None, today));
+        assert!(is_daily_header_due(today.pred_opt(), today));
+        assert!(!is_daily_header_due(Some(today), today));
+        // The clock going backwards doesn't send the header again
+        assert!(!is_daily_header_due(today.succ_opt(), today));
+    }
+
+    #[tokio::test]
+    async fn test_text_only_post_skips_media() {
+        use crate::bot::tests::{spawn_mock_telegram, SentRequests};
+
+        let sent = SentRequests::default();
+        let tg = Bot::new("token").set_api_url(spawn_mock_telegram(sent.clone()).await);
+        let config = config::Config {
+            db_path: "test_text_only_post_skips_media".into(),
+            ..Default::default()
+        };
+        // Keeps the shared in-memory database alive for the duration of the test
+        let mut db = db::Database::open(&config).unwrap();
+        db.migrate().unwrap();
+
+        // Sending the video would first fetch the post again for its missing post hint, and then
+        // fail to download the video from a closed port
+        let post = reddit::Post {
+            id: "abc123".to_string(),
+            subreddit: "videos".to_string(),
+            title: "Watch this".to_string(),
+            post_type: reddit::PostType::Video,
+            url: "http://127.0.0.1:9/video.mp4".to_string(),
+            permalink: "/r/videos/comments/abc123/watch_this/".to_string(),
+            post_hint: None,
+            ..Default::default()
+        };
+        handle_new_post(&config, &tg, 42, None, true, &post)
+            .await
+            .unwrap();
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        let (method, body) = &sent[0];
+        assert_eq!(method, "SendMessage");
+        assert_eq!(body["disable_web_page_preview"], true);
+        assert!(body["text"].as_str().unwrap().contains("Watch this"));
+    }
+
+    #[test]
+    fn test_sends_video() {
+        let config = config::Config {
+            convert_webp_to_jpeg: true,
+            ..Default::default()
+        };
+        let post = |post_type, url: &str| reddit::Post {
+            post_type,
+            url: url.to_string(),
+            ..Default::default()
+        };
+        let video = post(reddit::PostType::Video, "https://v.redd.it/abc");
+        let webp = post(reddit::PostType::Image, "https://i.redd.it/abc.webp");
+        let jpeg = post(reddit::PostType::Image, "https://i.redd.it/abc.jpg");
+
+        assert!(sends_video(&config, &video, false));
+        assert!(!sends_video(&config, &video, true));
+        assert!(sends_video(&config, &webp, false));
+        assert!(!sends_video(&config, &jpeg, false));
+        assert!(!sends_video(&config::Config::default(), &webp, false));
+    }
+
+    #[test]
+    fn test_nsfw_spoiler_is_propagated() {
+        let options = SendOptions {
+            disable_notification: false,
+            caption_components: vec![],
+            web_preview: WebPreview::default(),
+            nsfw_spoiler: true,
+            reddit_spoilers: RedditSpoilers::default(),
+            parse_mode: ParseMode::default(),
+            message_thread_id: None,
+        };
+        let nsfw_post = reddit::Post {
+            over_18: true,
+            ..Default::default()
+        };
+        assert!(options.spoiler(&nsfw_post));
+        assert!(!options.spoiler(&reddit::Post::default()));
+        assert!(!SendOptions {
+            nsfw_spoiler: false,
+            ..options.clone()
+        }
+        .spoiler(&nsfw_post));
+
+        let spoiler_post = reddit::Post {
+            spoiler: true,
+            ..Default::default()
+        };
+        assert!(!options.spoiler(&spoiler_post));
+        assert!(SendOptions {
+            reddit_spoilers: RedditSpoilers::SpoilerMedia,
+            ..options.clone()
+        }
+        .spoiler(&spoiler_post));
+
+        let paths = [Path::new("a.jpg"), Path::new("b.gif"), Path::new("c.png")];
+        let media_group = build_media_group(
+            &paths,
+            "caption",
+            options.spoiler(&nsfw_post),
+            ParseMode::Html,
+        );
+        assert_eq!(media_group.len(), 3);
+        for (i, media) in media_group.iter().enumerate() {
+            let (caption, has_spoiler) = match media {
+                InputMedia::Photo(photo) => (&photo.caption, photo.has_spoiler),
+                InputMedia::Video(video) => (&video.caption, video.has_spoiler),
+                _ => panic!("unexpected media {media:?}"),
+            };
+            assert!(has_spoiler);
+            assert_eq!(caption.is_some(), i == 0);
+        }
+        assert!(matches!(media_group[1], InputMedia::Video(_)));
+
+        let media_group = build_media_group(&paths, "caption", false, ParseMode::Html);
+        assert!(media_group.iter().all(|media| match media {
+            InputMedia::Photo(photo) => !photo.has_spoiler,
+            InputMedia::Video(video) => !video.has_spoiler,
+            _ => false,
+        }));
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use log::info;
+use regex::Regex;
+use serde::Deserialize;
+use url::Url;
+
+static IMGUR_API_BASE_URL: &str = "https://api.imgur.com/3";
+
+#[derive(Deserialize, Debug)]
+struct AlbumImagesResponse {
+    data: Vec<AlbumImage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AlbumImage {
+    link: String,
+}
+
+/// Returns the album id if url points to an Imgur album or gallery, e.g. `imgur.com/a/<id>` or
+/// `imgur.com/gallery/<id>`.
+pub fn parse_album_id(url: &str) -> Option<String> {
+    lazy_static! {
+        // Newer gallery urls have a title slug before the id, e.g. /gallery/some-title-<id>
+        static ref RE_ALBUM_PATH: Regex =
+            Regex::new(r"^/(?:a|gallery)/(?:[\w-]*-)?(\w+)/?$").unwrap();
+    }
+
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    if host != "imgur.com" && host != "www.imgur.com" && host != "m.imgur.com" {
+        return None;
+    }
+
+    RE_ALBUM_PATH
+        .captures(url.path())
+        .and_then(|caps| caps.get(1))
+        .map(|m| m.as_str().to_string())
+}
+
+/// Parses the response of album images endpoint into image urls, in the album's order.
+fn parse_album_images(json: &str) -> Result<Vec<String>> {
+    let res: AlbumImagesResponse =
+        serde_json::from_str(json).context("failed to parse imgur album response")?;
+    Ok(res.data.into_iter().map(|image| image.link).collect())
+}
+
+pub async fn get_album_image_urls(client_id: &str, album_id: &str) -> Result<Vec<String>> {
+    info!("getting imgur album {album_id}");
+    let url = format!("{IMGUR_API_BASE_URL}/album/{album_id}/images");
+    let body = reqwest::Client::new()
+        .get(url)
+        .header("Authorization", format!("Client-ID {client_id}"))
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    parse_album_images(&body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_album_id() {
+        assert_eq!(
+            parse_album_id("https://imgur.com/a/Zt6f5mB"),
+            Some("Zt6f5mB".to_string())
+        );
+        assert_eq!(
+            parse_album_id("https://imgur.com/gallery/Zt6f5mB"),
+            Some("Zt6f5mB".to_string())
+        );
+        assert_eq!(
+            parse_album_id("https://imgur.com/gallery/tipping-cow-Zt6f5mB"),
+            Some("Zt6f5mB".to_string())
+        );
+        assert_eq!(parse_album_id("https://i.imgur.com/Zt6f5mB.gifv"), None);
+        assert_eq!(parse_album_id("https://example.com/a/Zt6f5mB"), None);
+    }
+
+    #[test]
+    fn test_parse_album_images() {
+        let json = r#"{
+            "data": [
+                {"id": "first", "type": "image/jpeg", "animated": false, "link": "https://i.imgur.com/first.jpg"},
+                {"id": "second", "type": "image/gif", "animated": true, "link": "https://i.imgur.com/second.gif"},
+                {"id": "third", "type": "image/png", "animated": false, "link": "https://i.imgur.com/third.png"}
+            ],
+            "success": true,
+            "status": 200
+        }"#;
+        assert_eq!(
+            parse_album_images(json).unwrap(),
+            vec![
+                "https://i.imgur.com/first.jpg",
+                "https://i.imgur.com/second.gif",
+                "https://i.imgur.com/third.png",
+            ]
+        );
+    }
+}
@@ -1,20 +1,30 @@
-use crate::{handle_post::process_post, types::*};
+use crate::{
+    filter::PostFilter,
+    handle_post::{process_post, send_daily_header_if_due, sends_video},
+    metrics::CycleSummary,
+    schedule::{decayed_interval, PollSchedule},
+    state::BotState,
+    types::*,
+};
 use anyhow::{Context, Result};
 use handle_post::handle_new_post;
+use itertools::Itertools;
 use log::*;
-use reddit::{PostType, TopPostsTimePeriod};
+use reddit::{ListingSort, PostType, RedditApi, TopPostsTimePeriod};
 use signal_hook::{
-    consts::signal::{SIGINT, SIGTERM},
+    consts::signal::{SIGINT, SIGTERM, SIGUSR1},
     iterator::Signals,
 };
 
 use std::string::ToString;
 use std::{
+    collections::HashMap,
+    future::Future,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 use teloxide::types::InputMediaPhoto;
 use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile};
@@ -22,61 +32,188 @@ use teloxide::{prelude::*, types::InputMedia};
 
 use tokio::sync::broadcast;
 
+mod api;
 mod args;
 mod bot;
 mod config;
+mod convert;
 mod db;
 mod download;
+mod filter;
 mod handle_post;
+mod imgur;
 mod messages;
+mod metrics;
 mod reddit;
+#[cfg(feature = "redis")]
+mod redis;
+mod schedule;
+mod seen;
+mod state;
 mod types;
 mod ytdlp;
 
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
+const MIN_POLL_SLEEP: Duration = Duration::from_secs(1);
+/// How long a subscription stays claimed by an instance polling it unless the claim is renewed.
+const CLAIM_LEASE_SECS: i64 = 60;
+/// How often old posts and empty chats are pruned, if enabled.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
     let config = Arc::new(config::read_config());
     info!("starting with config: {config:#?}");
+    let opts = args::parse_args();
     let mut db = db::Database::open(&config)?;
+    // Usage: tgreddit --check-db => Check database integrity and exit
+    if opts.opt_present("check-db") {
+        let ok = db.check_integrity()?;
+        info!(
+            "database integrity check {}",
+            if ok { "passed" } else { "failed" }
+        );
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+    if config.check_db_integrity && !db.check_integrity()? {
+        anyhow::bail!("database integrity check failed, refusing to start");
+    }
     db.migrate()?;
+    let seen_posts = seen::SeenPosts::open(&config, &db).await?;
     drop(db);
 
     let (shutdown_tx, mut shutdown_rx) = broadcast::channel::<()>(1);
     let shutdown = Arc::new(AtomicBool::new(false));
-    let bot = bot::MyBot::new(config.clone()).await?;
+    let state = Arc::new(BotState::new().with_seen_posts(seen_posts));
+    let bot = bot::MyBot::new(config.clone(), state.clone()).await?;
 
     // Any arguments are for things that help with debugging and development
     // Not optimized for usability.
     //
     // Usage: tgreddit --debug-post <linkid>                    => Fetch post and print deserialized post
     //        tgreddit --debug-post <linkid> --chat-id <chatid> => Also send to telegram
-    let opts = args::parse_args();
     if let Some(post_id) = opts.opt_str("debug-post") {
-        let post = reddit::get_link(&post_id).await.unwrap();
+        let post = reddit::RedditClient::new(&config)?
+            .get_link(&post_id)
+            .await
+            .unwrap();
         info!("{:#?}", post);
         if let Some(chat_id) = opts.opt_str("chat-id") {
             let db = db::Database::open(&config)?;
             let chat_id = chat_id.parse().unwrap();
             db.record_post(chat_id, &post, None)?;
-            return handle_new_post(&config, &bot.tg, chat_id, &post).await;
+            return handle_new_post(&config, &bot.tg, chat_id, None, false, &post).await;
         }
         return Ok(());
     }
 
+    let api_handle = {
+        let config = config.clone();
+        let state = state.clone();
+        let mut shutdown_rx = shutdown_tx.subscribe();
+        tokio::task::spawn(async move {
+            let Some(addr) = config.api_addr else {
+                return;
+            };
+            if config.api_token.is_none() {
+                error!("api_addr is set but api_token is not, not serving api");
+                return;
+            }
+            let shutdown = async move {
+                let _ = shutdown_rx.recv().await;
+            };
+            if let Err(err) = api::serve(config.clone(), state, addr, shutdown).await {
+                error!("failed to serve api: {err:?}");
+            }
+        })
+    };
+    let failure_digest_handle = {
+        let config = config.clone();
+        let tg = bot.tg.clone();
+        let mut shutdown_rx = shutdown_tx.subscribe();
+        tokio::task::spawn(async move {
+            let Some(admin_chat_id) = config.admin_chat_id else {
+                return;
+            };
+            let mut interval = tokio::time::interval(Duration::from_secs(
+                config.failure_digest_interval_secs.max(1),
+            ));
+            // The first tick completes immediately, don't send a digest on startup
+            interval.tick().await;
+            loop {
+                tokio::select! {
+                   _ = interval.tick() => {}
+                   _ = shutdown_rx.recv() => {
+                       break
+                   }
+                }
+                if let Err(err) = send_failure_digest(&config, &tg, admin_chat_id).await {
+                    error!("failed to send failure digest: {err:?}");
+                }
+            }
+        })
+    };
+    let maintenance_handle = {
+        let config = config.clone();
+        let mut shutdown_rx = shutdown_tx.subscribe();
+        tokio::task::spawn(async move {
+            if config.post_retention().is_none() && !config.prune_empty_chats {
+                return;
+            }
+            let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+            loop {
+                tokio::select! {
+                   _ = interval.tick() => {}
+                   _ = shutdown_rx.recv() => {
+                       break
+                   }
+                }
+                if let Err(err) = run_maintenance(&config) {
+                    error!("failed to run database maintenance: {err:?}");
+                }
+            }
+        })
+    };
     let sub_check_loop_handle = {
+        let config = config.clone();
+        let state = state.clone();
         let shutdown = shutdown.clone();
         let tg = bot.tg.clone();
+        let reddit = reddit::RedditClient::new(&config)?;
         tokio::task::spawn(async move {
+            let mut schedule = PollSchedule::new(
+                chrono::Duration::seconds(config.poll_jitter_secs as i64),
+                None,
+            )
+            .with_first_poll_stagger(chrono::Duration::seconds(
+                config.first_poll_stagger_secs as i64,
+            ))
+            .with_aligned_polls(config.align_polls_to_interval);
+            if let Err(err) = restore_schedule(&config, &mut schedule) {
+                error!("failed to restore poll schedule: {err:?}");
+            }
             while !shutdown.load(Ordering::Acquire) {
-                check_new_posts(&config, &tg).await.unwrap_or_else(|err| {
-                    error!("failed to check for new posts: {err}");
-                });
+                match state.try_start_poll() {
+                    Some(_guard) => {
+                        match check_new_posts(&config, &tg, &reddit, &mut schedule, &state).await {
+                            Ok(()) => state.record_successful_poll(),
+                            Err(err) => error!("failed to check for new posts: {err}"),
+                        }
+                        state.record_reddit_rate_limit(reddit.rate_limit());
+                    }
+                    None => info!("a requested poll is in progress, skipping scheduled poll"),
+                }
+
+                // Sleep until the next subscription is due
+                let sleep_duration = schedule
+                    .next_due_in(chrono::Utc::now())
+                    .and_then(|d| d.to_std().ok())
+                    .unwrap_or(Duration::from_secs(config.check_interval_secs))
+                    .max(MIN_POLL_SLEEP);
 
                 tokio::select! {
-                   _ = tokio::time::sleep(Duration::from_secs(config.check_interval_secs)) => {}
+                   _ = tokio::time::sleep(sleep_duration) => {}
                    _ = shutdown_rx.recv() => {
                        break
                    }
@@ -84,15 +221,25 @@ async fn main() -> Result<()> {
             }
         })
     };
-    let (bot_handle, bot_shutdown_token) = bot.spawn();
+    let tg = bot.tg.clone();
+    let (bot_handle, bot_shutdown_token) = bot.spawn().await?;
 
     {
+        let state = state.clone();
         let shutdown = shutdown.clone();
+        let runtime = tokio::runtime::Handle::current();
         std::thread::spawn(move || {
+            let _runtime = runtime.enter();
             let mut forward_signals =
-                Signals::new([SIGINT, SIGTERM]).expect("unable to watch for signals");
+                Signals::new([SIGINT, SIGTERM, SIGUSR1]).expect("unable to watch for signals");
 
             for signal in forward_signals.forever() {
+                if signal == SIGUSR1 {
+                    if !spawn_poll_now(config.clone(), tg.clone(), state.clone()) {
+                        info!("got SIGUSR1, but a poll is already in progress");
+                    }
+                    continue;
+                }
                 info!("got signal {signal}, shutting down...");
                 shutdown.swap(true, Ordering::Relaxed);
                 let _res = bot_shutdown_token.shutdown();
@@ -104,104 +251,1465 @@ async fn main() -> Result<()> {
         });
     }
 
-    if let Err(err) = tokio::try_join!(bot_handle, sub_check_loop_handle) {
+    if let Err(err) = tokio::try_join!(
+        bot_handle,
+        sub_check_loop_handle,
+        api_handle,
+        failure_digest_handle,
+        maintenance_handle
+    ) {
         panic!("{err}")
     }
+    // Posts already marked seen are still waiting to be sent
+    state.finish_queued_sends().await;
 
     Ok(())
 }
 
-async fn check_post_newness(
-    config: &config::Config,
+/// Prunes seen posts older than the configured retention and chats left without subscriptions or
+/// settings, as enabled.
+fn run_maintenance(config: &config::Config) -> Result<()> {
+    let db = db::Database::open(config)?;
+    if let Some(post_retention) = config.post_retention() {
+        let pruned = db.prune_posts(
+            chrono::Utc::now() - post_retention,
+            config.tombstone_retention(),
+        )?;
+        if pruned > 0 {
+            info!("pruned {pruned} seen posts");
+        }
+    }
+    if config.prune_empty_chats {
+        let pruned = db.prune_empty_chats()?;
+        if pruned > 0 {
+            info!("pruned {pruned} chats without subscriptions or settings");
+        }
+    }
+    Ok(())
+}
+
+/// Checks all subscriptions right away, whether they are due or not, without affecting when they
+/// are checked next. Returns false without doing anything if a poll is already in progress.
+fn spawn_poll_now(config: Arc<config::Config>, tg: Arc<Bot>, state: Arc<BotState>) -> bool {
+    let Some(guard) = state.try_start_poll() else {
+        return false;
+    };
+    tokio::task::spawn(async move {
+        let _guard = guard;
+        info!("polling all subscriptions now");
+        let result = async {
+            let reddit = reddit::RedditClient::new(&config)?;
+            // All subscriptions are due in a new schedule, which is thrown away afterwards
+            let mut schedule =
+                PollSchedule::new(chrono::Duration::zero(), None).with_persistence(false);
+            let result = check_new_posts(&config, &tg, &reddit, &mut schedule, &state).await;
+            state.record_reddit_rate_limit(reddit.rate_limit());
+            result
+        };
+        match result.await {
+            Ok(()) => state.record_successful_poll(),
+            Err(err) => error!("failed to check for new posts: {err}"),
+        }
+    });
+    true
+}
+
+/// Sends the admin chat a summary of subscriptions whose checks keep failing, if there are any.
+async fn send_failure_digest(config: &config::Config, tg: &Bot, admin_chat_id: i64) -> Result<()> {
+    let db = db::Database::open(config)?;
+    let failing = db.get_failing_subscriptions(config.failure_digest_min_failures)?;
+    if failing.is_empty() {
+        return Ok(());
+    }
+    tg.send_message(
+        ChatId(admin_chat_id),
+        messages::format_failure_digest(&failing),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Sends the post unless it was already seen or should be skipped, and marks it seen. Returns
+/// whether the post was sent, or queued to be sent.
+async fn check_post_newness<R: RedditApi>(
+    config: &Arc<config::Config>,
     tg: &Bot,
+    reddit: &R,
+    state: &BotState,
     chat_id: i64,
-    filter: Option<reddit::PostType>,
-    post: &reddit::Post,
-    only_mark_seen: bool,
-) -> Result<()> {
+    pending: &PendingPost,
+) -> Result<bool> {
+    let &PendingPost {
+        message_thread_id,
+        ref filter,
+        top_comment,
+        text_only,
+        only_mark_seen,
+        ref post,
+        ..
+    } = pending;
     let db = db::Database::open(config)?;
-    if filter.is_some() && filter.as_ref() != Some(&post.post_type) {
-        debug!("filter set and post does not match filter, skipping");
-        return Ok(());
+    if !filter.matches(post) {
+        debug!("post does not match filter, skipping");
+        return Ok(false);
     }
 
-    if db
-        .is_post_seen(chat_id, post)
-        .expect("failed to query if post is seen")
-    {
+    if state.seen_posts.is_post_seen(&db, chat_id, post).await? {
         debug!("post already seen, skipping...");
-        return Ok(());
+        return Ok(false);
     }
 
-    if !only_mark_seen {
+    let mut sent = false;
+    if filter.is_duplicate_title(&db, chat_id, post)? {
+        debug!("post with a similar title already seen, skipping");
+    } else if db.is_post_seen_in_linked_chats(chat_id, post)? {
+        debug!("post already seen in a linked chat, skipping");
+    } else if db.get_snooze_until(chat_id, chrono::Utc::now())?.is_some() {
+        debug!("chat is snoozed, skipping");
+    } else if !only_mark_seen {
+        if !state.try_acquire_send(chat_id, config.max_messages_per_minute) {
+            // Left unseen and queued to be sent first on a later cycle, as the post may no longer
+            // be in the listing by then
+            debug!("chat reached its send rate limit, deferring post");
+            db.record_post(chat_id, post, None)?;
+            state.defer_post(chat_id, pending.clone());
+            return Ok(false);
+        }
+
         // Intentionally marking post as seen if handling it fails. It's preferable to not have it
         // fail continuously.
-        process_post(&db, chat_id, post, config, tg).await?;
+        let post = with_top_comment(reddit, post, top_comment).await;
+        if sends_video(config, &post, text_only) || state.has_queued_sends(chat_id) {
+            // Videos are sent once transcoded without holding up the poll loop, and the chat's
+            // later posts are sent after them
+            let (config, tg) = (config.clone(), tg.clone());
+            state.queue_send(chat_id, async move {
+                let sent = async {
+                    let db = db::Database::open(&config)?;
+                    send_post(
+                        &db,
+                        &config,
+                        &tg,
+                        chat_id,
+                        message_thread_id,
+                        text_only,
+                        &post,
+                    )
+                    .await
+                };
+                if let Err(err) = sent.await {
+                    error!("failed to send queued post: {err:?}");
+                }
+            });
+        } else {
+            send_post(
+                &db,
+                config,
+                tg,
+                chat_id,
+                message_thread_id,
+                text_only,
+                &post,
+            )
+            .await?;
+        }
+        db.touch_subscription(chat_id, &pending.subreddit, chrono::Utc::now())?;
+        sent = true;
     }
 
-    db.record_post_seen_with_current_time(chat_id, post)?;
+    state
+        .seen_posts
+        .record_post_seen(&db, chat_id, post)
+        .await?;
     info!("marked post seen: {}", post.id);
 
-    Ok(())
+    Ok(sent)
 }
 
-async fn check_new_posts(config: &config::Config, tg: &Bot) -> Result<()> {
+/// Sends the chat's daily header if it is due, then the post.
+async fn send_post(
+    db: &db::Database,
+    config: &config::Config,
+    tg: &Bot,
+    chat_id: i64,
+    message_thread_id: Option<i32>,
+    text_only: bool,
+    post: &reddit::Post,
+) -> Result<()> {
+    let today = chrono::Utc::now().date_naive();
+    send_daily_header_if_due(db, tg, chat_id, message_thread_id, today).await?;
+    process_post(db, chat_id, message_thread_id, text_only, post, config, tg).await
+}
+
+async fn check_new_posts<R: RedditApi + Clone + 'static>(
+    config: &Arc<config::Config>,
+    tg: &Bot,
+    reddit: &R,
+    schedule: &mut PollSchedule,
+    state: &Arc<BotState>,
+) -> Result<()> {
     info!("checking subscriptions for new posts");
+    let cycle_started_at = Instant::now();
+    let mut fetch_durations = vec![];
     let db = db::Database::open(config)?;
-    let subs = db.get_all_subscriptions()?;
-    for sub in subs {
-        check_new_posts_for_subscription(config, tg, &sub)
-            .await
-            .unwrap_or_else(|err| {
+    let groups = db.get_subscriptions_grouped_by_subreddit()?;
+    let subs = groups.into_values().flatten().collect::<Vec<_>>();
+    schedule.retain(&subs);
+    let interval = chrono::Duration::seconds(config.check_interval_secs as i64);
+    let mut merged_feeds: HashMap<i64, Vec<(String, Vec<PendingPost>)>> = HashMap::new();
+    let mut deliveries = vec![];
+    // Subscriptions of the same subreddit are polled one after another so that they can share
+    // fetched listings
+    let mut listings = CycleListings::new();
+    for sub in &subs {
+        let now = chrono::Utc::now();
+        schedule.schedule_first_poll(sub, now);
+        if !schedule.is_due(sub, now) {
+            continue;
+        }
+
+        if !db.claim_subscription(
+            sub.chat_id,
+            &sub.subreddit,
+            &state.instance_id,
+            now,
+            claim_lease(),
+        )? {
+            debug!("subscription is claimed by another instance, skipping");
+            schedule.schedule_next(sub, now, interval);
+            continue;
+        }
+
+        let fetched =
+            fetch_new_posts_for_subscription(config, reddit, sub, state, &mut listings).await;
+        let pending = match fetched {
+            Ok((fetch_duration, pending)) => {
+                if let Some(fetch_duration) = fetch_duration {
+                    fetch_durations.push((sub.subreddit.clone(), fetch_duration));
+                }
+                Some(pending)
+            }
+            Err(err) => {
                 error!("failed to check subscription for new posts: {err:?}");
-            });
+                None
+            }
+        };
+
+        // The all-time top list rarely changes, so it is checked less often the longer it has
+        // had no new posts. One-off polls leave the schedule alone.
+        let sub_interval = match &pending {
+            Some(pending)
+                if schedule.is_persisted()
+                    && sub.sort.unwrap_or_default() == ListingSort::Top
+                    && subscription_time(sub, config) == TopPostsTimePeriod::All =>
+            {
+                let mut found_new_posts = false;
+                for pending in pending {
+                    if !pending.filter.matches(&pending.post) {
+                        continue;
+                    }
+                    let seen = state
+                        .seen_posts
+                        .is_post_seen(&db, sub.chat_id, &pending.post)
+                        .await
+                        .unwrap_or_else(|err| {
+                            warn!("failed to check if post was seen, counting it as new: {err:?}");
+                            false
+                        });
+                    if !seen {
+                        found_new_posts = true;
+                        break;
+                    }
+                }
+                schedule.record_poll(sub, found_new_posts);
+                decayed_interval(
+                    interval,
+                    schedule.idle_polls(sub),
+                    config.all_time_poll_decay,
+                    config.all_time_max_poll_interval(),
+                )
+            }
+            _ => interval,
+        };
+        let next_poll_at = schedule.schedule_next(sub, now, sub_interval);
+        if schedule.is_persisted() {
+            db.set_poll_state(
+                sub.chat_id,
+                &sub.subreddit,
+                &PollState {
+                    last_polled_at: now,
+                    next_poll_at,
+                    idle_polls: schedule.idle_polls(sub),
+                },
+            )?;
+        }
+        let pending = pending.unwrap_or_default();
+
+        if db.get_merged_feed(sub.chat_id)? {
+            // Sent after every due subscription of the chat has been fetched. The subscription
+            // stays claimed until then.
+            merged_feeds
+                .entry(sub.chat_id)
+                .or_default()
+                .push((sub.subreddit.clone(), pending));
+        } else {
+            deliveries.push((sub.chat_id, (vec![sub.subreddit.clone()], pending)));
+        }
     }
 
+    for (chat_id, feeds) in merged_feeds {
+        let subreddits = feeds
+            .iter()
+            .map(|(subreddit, _)| subreddit.clone())
+            .collect::<Vec<_>>();
+        let pending = merge_feeds(feeds.into_iter().map(|(_, pending)| pending).collect());
+        deliveries.push((chat_id, (subreddits, pending)));
+    }
+
+    // Chats with deferred posts get them even without new posts of their own
+    for chat_id in state.chats_with_deferred_posts() {
+        if !deliveries.iter().any(|(id, _)| *id == chat_id) {
+            deliveries.push((chat_id, (vec![], vec![])));
+        }
+    }
+
+    // Subscriptions stay claimed until their posts have been sent
+    deliver_concurrently(
+        deliveries,
+        config.send_concurrency,
+        |chat_id, (subreddits, pending)| {
+            let (config, tg, reddit, state) =
+                (config.clone(), tg.clone(), reddit.clone(), state.clone());
+            async move {
+                // The posts deferred on earlier cycles are sent before the new ones, by the
+                // first delivery of the chat
+                let deferred = state.take_deferred_posts(chat_id);
+                let subreddits = subreddits
+                    .into_iter()
+                    .chain(deferred.iter().map(|pending| pending.subreddit.clone()))
+                    .unique()
+                    .collect::<Vec<_>>();
+                let pending = deferred.into_iter().chain(pending).collect();
+                send_pending_posts(&config, &tg, &reddit, &state, chat_id, pending).await;
+                if let Err(err) = release_subscriptions(&config, &state, chat_id, &subreddits) {
+                    error!("failed to release subscriptions: {err:?}");
+                }
+            }
+        },
+    )
+    .await;
+
+    let summary = CycleSummary::new(cycle_started_at.elapsed(), &fetch_durations);
+    info!("{summary}");
+    state.record_cycle(summary);
+
     Ok(())
 }
 
-async fn check_new_posts_for_subscription(
+/// Resumes polling subscriptions when they are due according to the schedule stored before a
+/// restart, instead of polling all of them at once.
+fn restore_schedule(config: &config::Config, schedule: &mut PollSchedule) -> Result<()> {
+    let db = db::Database::open(config)?;
+    for sub in db.get_all_subscriptions()? {
+        if let Some(state) = db.get_poll_state(sub.chat_id, &sub.subreddit)? {
+            schedule.restore(&sub, &state);
+        }
+    }
+    Ok(())
+}
+
+fn subscription_time(sub: &Subscription, config: &config::Config) -> TopPostsTimePeriod {
+    sub.time
+        .or(config.default_time)
+        .unwrap_or(config::DEFAULT_TIME_PERIOD)
+}
+
+/// A post fetched for a subscription that is yet to be checked and possibly sent.
+#[derive(Debug, Clone)]
+pub struct PendingPost {
+    subreddit: String,
+    message_thread_id: Option<i32>,
+    filter: PostFilter,
+    top_comment: bool,
+    text_only: bool,
+    only_mark_seen: bool,
+    post: reddit::Post,
+}
+
+/// Listings fetched during a poll cycle, keyed by query with the subreddit name lowercased.
+type CycleListings = HashMap<reddit::ListingQuery, Vec<reddit::Post>>;
+
+/// Fetches the subscription's top posts, in the order they should be sent in. Returns the time
+/// fetching took along with the posts, or no time if the listing was already fetched for another
+/// subscription in `listings`.
+async fn fetch_new_posts_for_subscription<R: RedditApi>(
     config: &config::Config,
-    tg: &Bot,
+    reddit: &R,
     sub: &Subscription,
-) -> Result<()> {
+    state: &BotState,
+    listings: &mut CycleListings,
+) -> Result<(Option<Duration>, Vec<PendingPost>)> {
     let db = db::Database::open(config)?;
     let subreddit = &sub.subreddit;
+    let chat_id = sub.chat_id;
+    // First run should not send anything to telegram but the post should be marked
+    // as seen, unless skip_initial_send is enabled
+    let is_new_subreddit = !db
+        .existing_posts_for_subreddit(chat_id, subreddit)
+        .context("failed to query if subreddit has existing posts")?;
+    let only_mark_seen = is_new_subreddit && config.skip_initial_send;
+    // The first run of a subscription with catch_up sends that many of the current posts instead
+    let catch_up = sub.catch_up.filter(|_| is_new_subreddit);
+
     let limit = sub
         .limit
         .or(config.default_limit)
-        .unwrap_or(config::DEFAULT_LIMIT);
-    let time = sub
-        .time
-        .or(config.default_time)
-        .unwrap_or(config::DEFAULT_TIME_PERIOD);
-    let filter = sub.filter.or(config.default_filter);
-    let chat_id = sub.chat_id;
+        .unwrap_or(config::DEFAULT_LIMIT)
+        .max(catch_up.unwrap_or(0));
+    let time = subscription_time(sub, config);
+    let filter = PostFilter::for_subscription(sub, config);
 
-    match reddit::get_subreddit_top_posts(subreddit, limit, &time).await {
-        Ok(posts) => {
-            debug!("got {} post(s) for subreddit /r/{}", posts.len(), subreddit);
-
-            // First run should not send anything to telegram but the post should be marked
-            // as seen, unless skip_initial_send is enabled
-            let is_new_subreddit = !db
-                .existing_posts_for_subreddit(chat_id, subreddit)
-                .context("failed to query if subreddit has existing posts")?;
-            let only_mark_seen = is_new_subreddit && config.skip_initial_send;
-
-            for post in posts {
-                debug!("got {post:?}");
-                check_post_newness(config, tg, chat_id, filter, &post, only_mark_seen)
-                    .await
-                    .unwrap_or_else(|err| {
-                        error!("failed to check post newness: {err:?}");
-                    });
+    let query = reddit::ListingQuery {
+        subreddit: subreddit.to_owned(),
+        limit,
+        sort: sub.sort.unwrap_or_default(),
+        time,
+        include_over_18: sub.allow_nsfw,
+    };
+    let key = reddit::ListingQuery {
+        subreddit: subreddit.to_lowercase(),
+        ..query.clone()
+    };
+    let (fetch_duration, fetch_result) = match listings.get(&key) {
+        Some(posts) => {
+            debug!("reusing listing of /r/{subreddit} fetched in this cycle");
+            (None, Ok(posts.clone()))
+        }
+        None => {
+            let fetch_started_at = Instant::now();
+            let fetch_result = reddit.fetch(&query).await;
+            let fetch_duration = fetch_started_at.elapsed();
+            state.record_fetch(subreddit, fetch_duration);
+            if let Ok(posts) = &fetch_result {
+                listings.insert(key, posts.clone());
             }
+            (Some(fetch_duration), fetch_result)
+        }
+    };
+
+    let posts = match fetch_result {
+        Ok(posts) => {
+            db.record_subscription_success(chat_id, subreddit)?;
+            posts
         }
         Err(e) => {
-            error!("failed to get posts for {}: {e:?}", subreddit)
+            error!("failed to get posts for {}: {e:?}", subreddit);
+            db.record_subscription_failure(chat_id, subreddit, &format!("{e:#}"))?;
+            return Ok((fetch_duration, vec![]));
         }
     };
+    debug!("got {} post(s) for subreddit /r/{}", posts.len(), subreddit);
+    let filter = filter.with_top_percentile(&posts, sub.top_percentile);
+    let posts = filter::rank_posts(posts, sub.rank, sub.min_rank, chrono::Utc::now());
+    // The first matching posts in listing order are caught up on, the rest are marked seen
+    let caught_up = catch_up.map(|catch_up| {
+        posts
+            .iter()
+            .filter(|post| filter.matches(post))
+            .take(catch_up as usize)
+            .map(|post| post.id.clone())
+            .collect::<Vec<_>>()
+    });
+
+    let mut pending = vec![];
+    if is_new_subreddit && config.send_sticky_on_subscribe {
+        match reddit.fetch_sticky(subreddit).await {
+            // Sent as context for the subscription regardless of its filters
+            Ok(Some(post)) => pending.push(PendingPost {
+                subreddit: subreddit.to_owned(),
+                message_thread_id: sub.message_thread_id,
+                filter: PostFilter::default(),
+                top_comment: false,
+                text_only: sub.text_only,
+                only_mark_seen: false,
+                post,
+            }),
+            Ok(None) => {}
+            Err(e) => warn!("failed to get sticky post of {subreddit}: {e:?}"),
+        }
+    }
+    pending.extend(
+        order_posts(posts, sub.send_order)
+            .into_iter()
+            .map(|post| PendingPost {
+                subreddit: subreddit.to_owned(),
+                message_thread_id: sub.message_thread_id,
+                filter: filter.clone(),
+                top_comment: sub.top_comment,
+                text_only: sub.text_only,
+                only_mark_seen: match &caught_up {
+                    Some(caught_up) => !caught_up.contains(&post.id),
+                    None => only_mark_seen,
+                },
+                post,
+            }),
+    );
+
+    Ok((fetch_duration, pending))
+}
+
+/// Checks and sends the posts in the given order. Posts of subscriptions this instance has lost
+/// its claim on are skipped.
+async fn send_pending_posts<R: RedditApi>(
+    config: &Arc<config::Config>,
+    tg: &Bot,
+    reddit: &R,
+    state: &BotState,
+    chat_id: i64,
+    pending: Vec<PendingPost>,
+) {
+    let db = match db::Database::open(config) {
+        Ok(db) => db,
+        Err(err) => {
+            error!("failed to send new posts: {err:?}");
+            return;
+        }
+    };
+
+    for pending in pending {
+        let subreddit = &pending.subreddit;
+        // Renew the claim, as sending posts may take longer than the lease
+        match db.claim_subscription(
+            chat_id,
+            subreddit,
+            &state.instance_id,
+            chrono::Utc::now(),
+            claim_lease(),
+        ) {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("lost claim on subscription to /r/{subreddit}, skipping post");
+                continue;
+            }
+            Err(err) => {
+                error!("failed to renew claim on subscription: {err:?}");
+                continue;
+            }
+        }
+
+        debug!("got {:?}", pending.post);
+        if let Err(err) = check_post_newness(config, tg, reddit, state, chat_id, &pending).await {
+            error!("failed to check post newness: {err:?}");
+        }
+    }
+}
 
+/// Adds the top comment to the post if the subscription includes it. The post is sent without it
+/// if it can't be fetched.
+async fn with_top_comment<R: RedditApi>(
+    reddit: &R,
+    post: &reddit::Post,
+    top_comment: bool,
+) -> reddit::Post {
+    let mut post = post.clone();
+    if !top_comment {
+        return post;
+    }
+
+    match reddit.fetch_top_comment(&post.id).await {
+        Ok(comment) => post.top_comment = comment,
+        Err(err) => warn!("failed to get top comment of post {}: {err:?}", post.id),
+    }
+    post
+}
+
+/// Runs the deliveries of different chats concurrently, at most `concurrency` chats at a time.
+/// The deliveries of a chat run one after another in the order they were given, so that its posts
+/// arrive in order.
+async fn deliver_concurrently<T, F, Fut>(deliveries: Vec<(i64, T)>, concurrency: usize, deliver: F)
+where
+    F: Fn(i64, T) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut by_chat: Vec<(i64, Vec<Fut>)> = vec![];
+    for (chat_id, delivery) in deliveries {
+        let delivery = deliver(chat_id, delivery);
+        match by_chat.iter_mut().find(|(id, _)| *id == chat_id) {
+            Some((_, chat_deliveries)) => chat_deliveries.push(delivery),
+            None => by_chat.push((chat_id, vec![delivery])),
+        }
+    }
+
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(concurrency.max(1)));
+    let mut tasks = tokio::task::JoinSet::new();
+    for (_, chat_deliveries) in by_chat {
+        let permit = semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Semaphore is never closed");
+        tasks.spawn(async move {
+            let _permit = permit;
+            for delivery in chat_deliveries {
+                delivery.await;
+            }
+        });
+    }
+    while let Some(result) = tasks.join_next().await {
+        if let Err(err) = result {
+            error!("failed to send new posts: {err}");
+        }
+    }
+}
+
+fn release_subscriptions(
+    config: &config::Config,
+    state: &BotState,
+    chat_id: i64,
+    subreddits: &[String],
+) -> Result<()> {
+    let db = db::Database::open(config)?;
+    for subreddit in subreddits {
+        db.release_subscription(chat_id, subreddit, &state.instance_id)?;
+    }
     Ok(())
 }
+
+/// Merges the new posts of a chat's subscriptions into a single feed, oldest post first.
+fn merge_feeds(feeds: Vec<Vec<PendingPost>>) -> Vec<PendingPost> {
+    let mut posts = feeds.into_iter().flatten().collect::<Vec<_>>();
+    posts.sort_by(|a, b| a.post.created_utc.total_cmp(&b.post.created_utc));
+    posts
+}
+
+fn claim_lease() -> chrono::Duration {
+    chrono::Duration::seconds(CLAIM_LEASE_SECS)
+}
+
+fn order_posts(mut posts: Vec<reddit::Post>, order: SendOrder) -> Vec<reddit::Post> {
+    match order {
+        SendOrder::TopFirst => {}
+        SendOrder::Chronological => {
+            posts.sort_by(|a, b| a.created_utc.total_cmp(&b.created_utc));
+        }
+    }
+    posts
+}
+
+/// Keeps only posts created at or after `since`.
+fn filter_posts_since(
+    posts: Vec<reddit::Post>,
+    since: &chrono::DateTime<chrono::Utc>,
+) -> Vec<reddit::Post> {
+    posts
+        .into_iter()
+        .filter(|post| post.is_created_since(since))
+        .collect()
+}
+
+/// Delivers unseen posts created since the given time for each of the chat's subscriptions, for
+/// catching up after the bot has been down. The posts are checked and sent like new posts found by
+/// the poll loop. At most `config::catch_up_max_posts` posts are delivered. Returns the number of
+/// delivered posts.
+pub async fn catch_up_since<R: RedditApi>(
+    config: &Arc<config::Config>,
+    tg: &Bot,
+    reddit: &R,
+    state: &BotState,
+    chat_id: i64,
+    since: &chrono::DateTime<chrono::Utc>,
+) -> Result<u32> {
+    let db = db::Database::open(config)?;
+    let max_posts = config.catch_up_max_posts;
+    let mut delivered = 0;
+
+    for sub in db.get_subscriptions_for_chat(chat_id)? {
+        if delivered >= max_posts {
+            break;
+        }
+
+        let query = reddit::ListingQuery {
+            subreddit: sub.subreddit.clone(),
+            limit: max_posts.min(reddit::MAX_LISTING_LIMIT),
+            sort: sub.sort.unwrap_or_default(),
+            time: subscription_time(&sub, config),
+            include_over_18: sub.allow_nsfw,
+        };
+        let posts = match reddit.fetch(&query).await {
+            Ok(posts) => posts,
+            Err(err) => {
+                error!("failed to get posts for {}: {err:?}", sub.subreddit);
+                continue;
+            }
+        };
+
+        let filter = PostFilter::for_subscription(&sub, config);
+        for post in order_posts(filter_posts_since(posts, since), sub.send_order) {
+            if delivered >= max_posts {
+                break;
+            }
+            let pending = PendingPost {
+                subreddit: sub.subreddit.clone(),
+                message_thread_id: sub.message_thread_id,
+                filter: filter.clone(),
+                top_comment: sub.top_comment,
+                text_only: sub.text_only,
+                only_mark_seen: false,
+                post,
+            };
+            match check_post_newness(config, tg, reddit, state, chat_id, &pending).await {
+                Ok(true) => delivered += 1,
+                Ok(false) => {}
+                Err(err) => error!("failed to check post newness: {err:?}"),
+            }
+        }
+    }
+
+    info!("caught up chat_id={chat_id} since={since} delivered={delivered}");
+    Ok(delivered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::sync::Mutex;
+
+    #[derive(Clone)]
+    struct MockReddit {
+        posts: Vec<reddit::Post>,
+        fetched: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RedditApi for MockReddit {
+        async fn fetch(&self, query: &reddit::ListingQuery) -> Result<Vec<reddit::Post>> {
+            self.fetched
+                .lock()
+                .expect("No poison")
+                .push(query.subreddit.clone());
+            Ok(self
+                .posts
+                .iter()
+                .take(query.limit as usize)
+                .cloned()
+                .collect())
+        }
+
+        async fn fetch_sticky(&self, _subreddit: &str) -> Result<Option<reddit::Post>> {
+            Ok(self.posts.iter().find(|post| post.stickied).cloned())
+        }
+
+        async fn fetch_top_comment(&self, post_id: &str) -> Result<Option<reddit::Comment>> {
+            Ok(Some(reddit::Comment {
+                author: "commenter".to_string(),
+                body: format!("top comment of {post_id}"),
+                permalink: format!("/r/foo/comments/{post_id}/_/comment/"),
+                stickied: false,
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_new_posts_marks_initial_posts_seen() {
+        let config = Arc::new(config::Config {
+            db_path: "test_check_new_posts_marks_initial_posts_seen".into(),
+            skip_initial_send: true,
+            check_interval_secs: 600,
+            ..Default::default()
+        });
+        // Keeps the shared in-memory database alive for the duration of the test
+        let mut db = db::Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "foo".to_string(),
+                limit: Some(2),
+                filter: Some(PostType::Video),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let post = |id: &str, post_type: PostType| reddit::Post {
+            id: id.to_string(),
+            subreddit: "foo".to_string(),
+            post_type,
+            ..Default::default()
+        };
+        let reddit = MockReddit {
+            posts: vec![
+                post("video", PostType::Video),
+                post("image", PostType::Image),
+                post("over_limit", PostType::Video),
+            ],
+            fetched: Default::default(),
+        };
+        let tg = Bot::new("token");
+        let state = Arc::new(BotState::new());
+        let mut schedule = PollSchedule::new(chrono::Duration::zero(), Some(1));
+
+        check_new_posts(&config, &tg, &reddit, &mut schedule, &state)
+            .await
+            .unwrap();
+
+        assert_eq!(*reddit.fetched.lock().unwrap(), vec!["foo"]);
+        assert!(db.is_post_seen(1, &post("video", PostType::Video)).unwrap());
+        assert!(!db.is_post_seen(1, &post("image", PostType::Image)).unwrap());
+        assert!(!db
+            .is_post_seen(1, &post("over_limit", PostType::Video))
+            .unwrap());
+        assert_eq!(state.last_cycle().unwrap().fetch_count, 1);
+
+        // Not due again until the interval has passed
+        check_new_posts(&config, &tg, &reddit, &mut schedule, &state)
+            .await
+            .unwrap();
+        assert_eq!(reddit.fetched.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_check_new_posts_fetches_shared_subreddits_once() {
+        let config = Arc::new(config::Config {
+            db_path: "test_check_new_posts_fetches_shared_subreddits_once".into(),
+            skip_initial_send: true,
+            ..Default::default()
+        });
+        // Keeps the shared in-memory database alive for the duration of the test
+        let mut db = db::Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        for (chat_id, subreddit, limit) in
+            [(1, "foo", 2), (2, "Foo", 2), (3, "foo", 1), (1, "bar", 2)]
+        {
+            db.subscribe(
+                chat_id,
+                &SubscriptionArgs {
+                    subreddit: subreddit.to_string(),
+                    limit: Some(limit),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+
+        let reddit = MockReddit {
+            posts: vec![reddit::Post {
+                id: "abc".to_string(),
+                subreddit: "foo".to_string(),
+                ..Default::default()
+            }],
+            fetched: Default::default(),
+        };
+        let tg = Bot::new("token");
+        let state = Arc::new(BotState::new());
+        let mut schedule = PollSchedule::new(chrono::Duration::zero(), Some(1));
+
+        check_new_posts(&config, &tg, &reddit, &mut schedule, &state)
+            .await
+            .unwrap();
+
+        // Chat 3 asks for a different limit, so its listing is fetched separately
+        assert_eq!(*reddit.fetched.lock().unwrap(), vec!["bar", "foo", "foo"]);
+        assert_eq!(state.last_cycle().unwrap().fetch_count, 3);
+    }
+
+    #[tokio::test]
+    async fn test_one_off_poll_leaves_poll_state_unchanged() {
+        let config = Arc::new(config::Config {
+            db_path: "test_one_off_poll_leaves_poll_state_unchanged".into(),
+            skip_initial_send: true,
+            all_time_poll_decay: 2.0,
+            ..Default::default()
+        });
+        // Keeps the shared in-memory database alive for the duration of the test
+        let mut db = db::Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "foo".to_string(),
+                time: Some(TopPostsTimePeriod::All),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let polled_at = chrono::Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+        let poll_state = PollState {
+            last_polled_at: polled_at,
+            next_poll_at: polled_at + chrono::Duration::hours(4),
+            idle_polls: 3,
+        };
+        db.set_poll_state(1, "foo", &poll_state).unwrap();
+
+        let reddit = MockReddit {
+            posts: vec![],
+            fetched: Default::default(),
+        };
+        let tg = Bot::new("token");
+        let state = Arc::new(BotState::new());
+        // As with /pollnow
+        let mut schedule =
+            PollSchedule::new(chrono::Duration::zero(), Some(1)).with_persistence(false);
+
+        check_new_posts(&config, &tg, &reddit, &mut schedule, &state)
+            .await
+            .unwrap();
+
+        assert_eq!(reddit.fetched.lock().unwrap().len(), 1);
+        assert_eq!(db.get_poll_state(1, "foo").unwrap(), Some(poll_state));
+    }
+
+    #[tokio::test]
+    async fn test_all_time_decay_only_applies_to_top_sort() {
+        let config = Arc::new(config::Config {
+            db_path: "test_all_time_decay_only_applies_to_top_sort".into(),
+            skip_initial_send: true,
+            ..Default::default()
+        });
+        // Keeps the shared in-memory database alive for the duration of the test
+        let mut db = db::Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        for (subreddit, sort) in [("foo", ListingSort::Top), ("bar", ListingSort::New)] {
+            db.subscribe(
+                1,
+                &SubscriptionArgs {
+                    subreddit: subreddit.to_string(),
+                    sort: Some(sort),
+                    time: Some(TopPostsTimePeriod::All),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        }
+
+        let reddit = MockReddit {
+            posts: vec![],
+            fetched: Default::default(),
+        };
+        let tg = Bot::new("token");
+        let state = Arc::new(BotState::new());
+        let mut schedule = PollSchedule::new(chrono::Duration::zero(), Some(1));
+
+        check_new_posts(&config, &tg, &reddit, &mut schedule, &state)
+            .await
+            .unwrap();
+
+        let top = db.get_subscription(1, "foo").unwrap().unwrap();
+        let new = db.get_subscription(1, "bar").unwrap().unwrap();
+        assert_eq!(schedule.idle_polls(&top), 1);
+        // The time period has no effect on the new listing, so it is polled at the usual interval
+        assert_eq!(schedule.idle_polls(&new), 0);
+    }
+
+    #[tokio::test]
+    async fn test_catch_up_sends_current_posts_once() {
+        use crate::bot::tests::{spawn_mock_telegram, SentRequests};
+
+        let config = Arc::new(config::Config {
+            db_path: "test_catch_up_sends_current_posts_once".into(),
+            skip_initial_send: true,
+            check_interval_secs: 0,
+            ..Default::default()
+        });
+        // Keeps the shared in-memory database alive for the duration of the test
+        let mut db = db::Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "foo".to_string(),
+                limit: Some(3),
+                catch_up: Some(2),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let post = |id: &str| reddit::Post {
+            id: id.to_string(),
+            subreddit: "foo".to_string(),
+            title: format!("Post {id}"),
+            post_type: PostType::SelfText,
+            post_hint: Some("self".to_string()),
+            ..Default::default()
+        };
+        let reddit = MockReddit {
+            posts: vec![post("a"), post("b"), post("c")],
+            fetched: Default::default(),
+        };
+        let sent = SentRequests::default();
+        let tg = Bot::new("token").set_api_url(spawn_mock_telegram(sent.clone()).await);
+        let state = Arc::new(BotState::new());
+        let mut schedule = PollSchedule::new(chrono::Duration::zero(), Some(1));
+        let sent_titles = || {
+            sent.lock()
+                .unwrap()
+                .iter()
+                .map(|(_, body)| {
+                    body["text"]
+                        .as_str()
+                        .unwrap()
+                        .lines()
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        check_new_posts(&config, &tg, &reddit, &mut schedule, &state)
+            .await
+            .unwrap();
+        // The rest of the current posts are only marked seen
+        assert_eq!(sent_titles(), vec!["Post a", "Post b"]);
+        assert!(db.is_post_seen(1, &post("c")).unwrap());
+
+        check_new_posts(&config, &tg, &reddit, &mut schedule, &state)
+            .await
+            .unwrap();
+        assert_eq!(reddit.fetched.lock().unwrap().len(), 2);
+        assert_eq!(sent_titles().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_top_comment_is_fetched_with_poll_client() {
+        use crate::bot::tests::{spawn_mock_telegram, SentRequests};
+
+        let config = Arc::new(config::Config {
+            db_path: "test_top_comment_is_fetched_with_poll_client".into(),
+            skip_initial_send: false,
+            ..Default::default()
+        });
+        // Keeps the shared in-memory database alive for the duration of the test
+        let mut db = db::Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "foo".to_string(),
+                limit: Some(1),
+                top_comment: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let reddit = MockReddit {
+            posts: vec![reddit::Post {
+                id: "abc".to_string(),
+                subreddit: "foo".to_string(),
+                title: "Post".to_string(),
+                post_type: PostType::SelfText,
+                post_hint: Some("self".to_string()),
+                ..Default::default()
+            }],
+            fetched: Default::default(),
+        };
+        let sent = SentRequests::default();
+        let tg = Bot::new("token").set_api_url(spawn_mock_telegram(sent.clone()).await);
+        let state = Arc::new(BotState::new());
+        let mut schedule = PollSchedule::new(chrono::Duration::zero(), Some(1));
+
+        check_new_posts(&config, &tg, &reddit, &mut schedule, &state)
+            .await
+            .unwrap();
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].1["text"]
+            .as_str()
+            .unwrap()
+            .contains("top comment of abc"));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_burst_is_delivered_over_several_cycles() {
+        use crate::bot::tests::{spawn_mock_telegram, SentRequests};
+        use crate::schedule::SendRateLimiter;
+
+        let config = Arc::new(config::Config {
+            db_path: "test_rate_limited_burst_is_delivered_over_several_cycles".into(),
+            skip_initial_send: false,
+            check_interval_secs: 0,
+            max_messages_per_minute: Some(2),
+            ..Default::default()
+        });
+        // Keeps the shared in-memory database alive for the duration of the test
+        let mut db = db::Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "foo".to_string(),
+                limit: Some(4),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let post = |id: &str| reddit::Post {
+            id: id.to_string(),
+            subreddit: "foo".to_string(),
+            title: format!("Post {id}"),
+            post_type: PostType::SelfText,
+            post_hint: Some("self".to_string()),
+            ..Default::default()
+        };
+        let reddit = |posts: Vec<reddit::Post>| MockReddit {
+            posts,
+            fetched: Default::default(),
+        };
+        let sent = SentRequests::default();
+        let tg = Bot::new("token").set_api_url(spawn_mock_telegram(sent.clone()).await);
+        let window = Duration::from_millis(200);
+        let state = Arc::new(BotState::new().with_send_limiter(SendRateLimiter::new(window)));
+        let mut schedule = PollSchedule::new(chrono::Duration::zero(), Some(1));
+        let sent_titles = || {
+            sent.lock()
+                .unwrap()
+                .iter()
+                .map(|(_, body)| {
+                    body["text"]
+                        .as_str()
+                        .unwrap()
+                        .lines()
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let burst = reddit(vec![post("a"), post("b"), post("c"), post("d")]);
+        check_new_posts(&config, &tg, &burst, &mut schedule, &state)
+            .await
+            .unwrap();
+        assert_eq!(sent_titles(), vec!["Post a", "Post b"]);
+        assert!(!db.is_post_seen(1, &post("c")).unwrap());
+
+        // The rest of the burst is sent before newer posts, although it has dropped out of
+        // the listing
+        tokio::time::sleep(window).await;
+        check_new_posts(
+            &config,
+            &tg,
+            &reddit(vec![post("e")]),
+            &mut schedule,
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(sent_titles()[2..], ["Post c", "Post d"]);
+
+        tokio::time::sleep(window).await;
+        check_new_posts(&config, &tg, &reddit(vec![]), &mut schedule, &state)
+            .await
+            .unwrap();
+        assert_eq!(
+            sent_titles(),
+            vec!["Post a", "Post b", "Post c", "Post d", "Post e"]
+        );
+        for id in ["a", "b", "c", "d", "e"] {
+            assert!(db.is_post_seen(1, &post(id)).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deliver_concurrently_keeps_chat_order() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let in_flight = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let max_in_flight = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let deliveries = vec![
+            (1, ("1a", 30)),
+            (2, ("2a", 0)),
+            (1, ("1b", 0)),
+            (3, ("3a", 0)),
+            (2, ("2b", 10)),
+            (1, ("1c", 0)),
+        ];
+
+        deliver_concurrently(deliveries, 2, |_, (name, delay_ms)| {
+            let events = events.clone();
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            async move {
+                let count = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(count, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                events.lock().unwrap().push(name);
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            }
+        })
+        .await;
+
+        let events = events.lock().unwrap().clone();
+        let chat_events = |chat: char| {
+            events
+                .iter()
+                .filter(|name| name.starts_with(chat))
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(chat_events('1'), vec!["1a", "1b", "1c"]);
+        assert_eq!(chat_events('2'), vec!["2a", "2b"]);
+        assert_eq!(chat_events('3'), vec!["3a"]);
+        // Chat 2 is sent to while the first post of chat 1 is still being sent
+        assert_eq!(events[0], "2a");
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sticky_is_sent_once_on_subscribe() {
+        let config = config::Config {
+            db_path: "test_sticky_is_sent_once_on_subscribe".into(),
+            skip_initial_send: true,
+            send_sticky_on_subscribe: true,
+            ..Default::default()
+        };
+        // Keeps the shared in-memory database alive for the duration of the test
+        let mut db = db::Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        let args = SubscriptionArgs {
+            subreddit: "foo".to_string(),
+            limit: Some(5),
+            filter: Some(PostType::Video),
+            ..Default::default()
+        };
+        db.subscribe(1, &args).unwrap();
+        let sub = db.get_subscriptions_for_chat(1).unwrap().remove(0);
+
+        let post = |id: &str, stickied| reddit::Post {
+            id: id.to_string(),
+            subreddit: "foo".to_string(),
+            stickied,
+            ..Default::default()
+        };
+        let reddit = MockReddit {
+            posts: vec![
+                post("rules", true),
+                post("announcement", true),
+                post("top", false),
+            ],
+            fetched: Default::default(),
+        };
+        let state = BotState::new();
+
+        let (_, pending) = fetch_new_posts_for_subscription(
+            &config,
+            &reddit,
+            &sub,
+            &state,
+            &mut CycleListings::new(),
+        )
+        .await
+        .unwrap();
+        let sent = pending
+            .iter()
+            .filter(|pending| !pending.only_mark_seen && pending.filter.matches(&pending.post))
+            .map(|pending| pending.post.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(sent, vec!["rules"]);
+
+        for pending in &pending {
+            db.record_post_seen_with_current_time(1, &pending.post)
+                .unwrap();
+        }
+        let (_, pending) = fetch_new_posts_for_subscription(
+            &config,
+            &reddit,
+            &sub,
+            &state,
+            &mut CycleListings::new(),
+        )
+        .await
+        .unwrap();
+        // Only the listing, the sticky is not added again
+        assert_eq!(pending.len(), 3);
+        assert!(pending
+            .iter()
+            .all(|pending| db.is_post_seen(1, &pending.post).unwrap()));
+
+        // Disabled, the sticky is only marked seen like the other initial posts
+        let config = config::Config {
+            send_sticky_on_subscribe: false,
+            ..config
+        };
+        db.subscribe(
+            2,
+            &SubscriptionArgs {
+                subreddit: "foo".to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let sub = db.get_subscriptions_for_chat(2).unwrap().remove(0);
+        let (_, pending) = fetch_new_posts_for_subscription(
+            &config,
+            &reddit,
+            &sub,
+            &state,
+            &mut CycleListings::new(),
+        )
+        .await
+        .unwrap();
+        assert!(pending.iter().all(|pending| pending.only_mark_seen));
+    }
+
+    #[tokio::test]
+    async fn test_catch_up_since_checks_posts_like_the_poll_loop() {
+        use crate::bot::tests::{spawn_mock_telegram, SentRequests};
+
+        let config = Arc::new(config::Config {
+            db_path: "test_catch_up_since_checks_posts_like_the_poll_loop".into(),
+            catch_up_max_posts: 10,
+            ..Default::default()
+        });
+        // Keeps the shared in-memory database alive for the duration of the test
+        let mut db = db::Database::open(&config).unwrap();
+        db.migrate().unwrap();
+        db.subscribe(
+            1,
+            &SubscriptionArgs {
+                subreddit: "foo".to_string(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let since = chrono::Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+        let post = |id: &str, created_utc: f64| reddit::Post {
+            id: id.to_string(),
+            subreddit: "foo".to_string(),
+            title: format!("Post {id}"),
+            created_utc,
+            post_type: PostType::SelfText,
+            post_hint: Some("self".to_string()),
+            ..Default::default()
+        };
+        db.record_post_seen_with_current_time(1, &post("seen", 0.0))
+            .unwrap();
+        let reddit = MockReddit {
+            posts: vec![
+                post("old", 1682935200.0),
+                post("new", 1682946000.0),
+                post("seen", 1682946000.0),
+            ],
+            fetched: Default::default(),
+        };
+        let sent = SentRequests::default();
+        let tg = Bot::new("token").set_api_url(spawn_mock_telegram(sent.clone()).await);
+        let state = BotState::new();
+
+        let delivered = catch_up_since(&config, &tg, &reddit, &state, 1, &since)
+            .await
+            .unwrap();
+        assert_eq!(delivered, 1);
+        assert_eq!(*reddit.fetched.lock().unwrap(), vec!["foo"]);
+        assert_eq!(sent.lock().unwrap().len(), 1);
+        assert!(db.is_post_seen(1, &post("new", 0.0)).unwrap());
+
+        // Snoozed chats are not caught up either
+        db.snooze_chat(1, Some(chrono::Utc::now() + chrono::Duration::hours(1)))
+            .unwrap();
+        let reddit = MockReddit {
+            posts: vec![post("snoozed", 1682946000.0)],
+            fetched: Default::default(),
+        };
+        let delivered = catch_up_since(&config, &tg, &reddit, &state, 1, &since)
+            .await
+            .unwrap();
+        assert_eq!(delivered, 0);
+        assert_eq!(sent.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_filter_posts_since() {
+        let post = |id: &str, created_utc: f64| reddit::Post {
+            id: id.to_string(),
+            created_utc,
+            ..Default::default()
+        };
+        let posts = vec![
+            post("old", 1682935200.0),
+            post("exact", 1682942400.0),
+            post("new", 1682946000.0),
+        ];
+        let since = chrono::Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+        let ids = filter_posts_since(posts, &since)
+            .into_iter()
+            .map(|post| post.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["exact", "new"]);
+    }
+
+    #[test]
+    fn test_order_posts() {
+        let post = |id: &str, created_utc: f64| reddit::Post {
+            id: id.to_string(),
+            created_utc,
+            ..Default::default()
+        };
+        let posts = || {
+            vec![
+                post("top", 1682946000.0),
+                post("second", 1682935200.0),
+                post("third", 1682942400.0),
+            ]
+        };
+        let ids = |posts: Vec<reddit::Post>| posts.into_iter().map(|p| p.id).collect::<Vec<_>>();
+
+        assert_eq!(
+            ids(order_posts(posts(), SendOrder::TopFirst)),
+            vec!["top", "second", "third"]
+        );
+        assert_eq!(
+            ids(order_posts(posts(), SendOrder::Chronological)),
+            vec!["second", "third", "top"]
+        );
+    }
+
+    #[test]
+    fn test_merge_feeds() {
+        let pending = |subreddit: &str, id: &str, created_utc: f64| PendingPost {
+            subreddit: subreddit.to_string(),
+            message_thread_id: None,
+            filter: PostFilter::default(),
+            top_comment: false,
+            text_only: false,
+            only_mark_seen: false,
+            post: reddit::Post {
+                id: id.to_string(),
+                subreddit: subreddit.to_string(),
+                created_utc,
+                ..Default::default()
+            },
+        };
+        let feeds = vec![
+            vec![
+                pending("foo", "foo_new", 300.0),
+                pending("foo", "foo_old", 100.0),
+            ],
+            vec![],
+            vec![
+                pending("bar", "bar_mid", 200.0),
+                pending("bar", "bar_newest", 400.0),
+            ],
+        ];
+
+        let merged = merge_feeds(feeds);
+        assert_eq!(
+            merged
+                .iter()
+                .map(|p| p.post.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["foo_old", "bar_mid", "foo_new", "bar_newest"]
+        );
+        assert_eq!(
+            merged
+                .iter()
+                .map(|p| p.subreddit.as_str())
+                .collect::<Vec<_>>(),
+            vec!["foo", "bar", "foo", "bar"]
+        );
+    }
+}
@@ -1,47 +1,550 @@
 use crate::*;
 use crate::{
     db::Recordable,
+    handle_post::SendPlan,
     reddit::{self},
 };
 use itertools::Itertools;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+};
+use strum::IntoEnumIterator;
+
+/// Telegram's limit for the length of a media caption, after parsing entities.
+const MAX_CAPTION_LEN: usize = 1024;
+
+/// Telegram's limit for the length of a text message, after parsing entities.
+const MAX_MESSAGE_LEN: usize = 4096;
+
+/// Most characters of the top comment quoted in captions, so that the title still fits.
+const MAX_TOP_COMMENT_LEN: usize = 300;
+
+/// Telegram's limit for the length of a poll question.
+const MAX_POLL_QUESTION_LEN: usize = 300;
+
+/// Telegram's limit for the length of a poll option.
+const MAX_POLL_OPTION_LEN: usize = 100;
+
+/// Telegram's limits for the number of options of a poll.
+const POLL_OPTION_COUNT: std::ops::RangeInclusive<usize> = 2..=10;
+
+/// Put before the titles of posts marked as a spoiler on Reddit with `reddit_spoilers = "mark_caption"`.
+const SPOILER_MARK: &str = "⚠️ Spoiler: ";
 
 fn escape(html: &str) -> String {
-    html.replace('<', "&lt;").replace('>', "&gt;")
+    html.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Characters that have to be escaped with a backslash in MarkdownV2 text.
+const MARKDOWN_V2_RESERVED: &str = r"_*[]()~`>#+-=|{}.!\";
+
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if MARKDOWN_V2_RESERVED.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn escape_text(text: &str, parse_mode: ParseMode) -> String {
+    match parse_mode {
+        ParseMode::Html => escape(text),
+        ParseMode::MarkdownV2 => escape_markdown(text),
+        ParseMode::None => text.to_owned(),
+    }
 }
 
 fn format_html_anchor(href: &str, text: &str) -> String {
     format!(r#"<a href="{href}">{}</a>"#, escape(text))
 }
 
-fn format_subreddit_link(subreddit: &str, base_url: Option<&str>) -> String {
-    format_html_anchor(
+/// Without a parse mode, the url is shown after the text.
+fn format_anchor(href: &str, text: &str, parse_mode: ParseMode) -> String {
+    match parse_mode {
+        ParseMode::Html => format_html_anchor(href, text),
+        // Only ) and \ have to be escaped in the url of a link
+        ParseMode::MarkdownV2 => format!(
+            "[{}]({})",
+            escape_markdown(text),
+            href.replace('\\', r"\\").replace(')', r"\)")
+        ),
+        ParseMode::None if text == href => href.to_owned(),
+        ParseMode::None => format!("{text} ({href})"),
+    }
+}
+
+fn format_italic(text: &str, parse_mode: ParseMode) -> String {
+    match parse_mode {
+        ParseMode::Html => format!("<i>{}</i>", escape(text)),
+        ParseMode::MarkdownV2 => format!("_{}_", escape_markdown(text)),
+        ParseMode::None => text.to_owned(),
+    }
+}
+
+/// Quotes the text. An expandable quote is collapsed to its first lines until it is tapped.
+fn format_quote(text: &str, expandable: bool, parse_mode: ParseMode) -> String {
+    match parse_mode {
+        ParseMode::Html if expandable => {
+            format!("<blockquote expandable>{}</blockquote>", escape(text))
+        }
+        ParseMode::Html => format!("<blockquote>{}</blockquote>", escape(text)),
+        ParseMode::MarkdownV2 => {
+            let quote = text
+                .lines()
+                .map(|line| format!(">{}", escape_markdown(line)))
+                .join("\n");
+            if expandable {
+                format!("**{quote}||")
+            } else {
+                quote
+            }
+        }
+        ParseMode::None => text.to_owned(),
+    }
+}
+
+fn format_subreddit_link(subreddit: &str, base_url: Option<&str>, parse_mode: ParseMode) -> String {
+    format_anchor(
         &reddit::format_subreddit_url(subreddit, base_url),
         &format!("/r/{}", &subreddit),
+        parse_mode,
     )
 }
 
-fn format_meta_html(post: &reddit::Post, links_base_url: Option<&str>) -> String {
-    let subreddit_link = format_subreddit_link(&post.subreddit, links_base_url);
-    let comments_link = format_html_anchor(&post.format_permalink_url(links_base_url), "comments");
+/// Formats the enabled components other than the title on a single line, or `None` if there are
+/// none to show.
+fn format_meta(
+    post: &reddit::Post,
+    links_base_url: Option<&str>,
+    components: &[CaptionComponent],
+    parse_mode: ParseMode,
+) -> Option<String> {
+    let parts = CaptionComponent::iter()
+        .filter(|component| components.contains(component))
+        .filter_map(|component| match component {
+            CaptionComponent::Title => None,
+            CaptionComponent::Flair => post
+                .link_flair_text
+                .as_deref()
+                .filter(|flair| !flair.is_empty())
+                .map(|flair| format_italic(flair, parse_mode)),
+            CaptionComponent::Score => Some(format!("{} points", post.ups)),
+            CaptionComponent::Author => {
+                format_author_link(&post.author, links_base_url, parse_mode)
+            }
+            CaptionComponent::Subreddit => Some(format_subreddit_link(
+                &post.subreddit,
+                links_base_url,
+                parse_mode,
+            )),
+            CaptionComponent::Comments => {
+                let comments_link = format_anchor(
+                    &post.format_permalink_url(links_base_url),
+                    "comments",
+                    parse_mode,
+                );
+                // If using custom links base url, the old reddit link doesn't make sense.
+                let links = match links_base_url {
+                    Some(_) => comments_link,
+                    None => {
+                        let old_comments_link =
+                            format_anchor(&post.format_old_permalink_url(), "old", parse_mode);
+                        format!("{comments_link}, {old_comments_link}")
+                    }
+                };
+                Some(format!(
+                    "{}{links}{}",
+                    escape_text("[", parse_mode),
+                    escape_text("]", parse_mode)
+                ))
+            }
+            CaptionComponent::Hashtag => {
+                format_hashtag(&post.subreddit).map(|hashtag| escape_text(&hashtag, parse_mode))
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(" "))
+    }
+}
+
+/// Telegram hashtags may only contain letters, digits and underscores, and are not recognized if
+/// they consist of digits only.
+fn format_hashtag(subreddit: &str) -> Option<String> {
+    let tag = subreddit
+        .chars()
+        .filter(|c| c.is_alphanumeric() || *c == '_')
+        .collect::<String>();
+    if tag.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(format!("#{tag}"))
+}
+
+/// Links to the author's profile, unless the account has been deleted.
+fn format_author_link(
+    author: &str,
+    links_base_url: Option<&str>,
+    parse_mode: ParseMode,
+) -> Option<String> {
+    if author.is_empty() || author == "[deleted]" {
+        return None;
+    }
+    Some(format_anchor(
+        &reddit::format_user_url(author, links_base_url),
+        &format!("u/{author}"),
+        parse_mode,
+    ))
+}
+
+/// Lines of the caption below the title: the top comment and the meta line, if any, and the
+/// configured footer.
+fn format_caption_lines(
+    post: &reddit::Post,
+    config: &config::Config,
+    components: &[CaptionComponent],
+    parse_mode: ParseMode,
+) -> Vec<String> {
+    let links_base_url = config.links_base_url.as_deref();
+    [
+        post.top_comment
+            .as_ref()
+            .map(|comment| format_top_comment(comment, links_base_url, parse_mode)),
+        format_meta(post, links_base_url, components, parse_mode),
+        format_footer(config, parse_mode),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Quotes the beginning of the comment, followed by a link to it.
+fn format_top_comment(
+    comment: &reddit::Comment,
+    links_base_url: Option<&str>,
+    parse_mode: ParseMode,
+) -> String {
+    let body = comment.body.split_whitespace().join(" ");
+    format!(
+        "{}\n{}",
+        format_quote(&truncate(&body, MAX_TOP_COMMENT_LEN), false, parse_mode),
+        format_anchor(
+            &comment.format_permalink_url(links_base_url),
+            "top comment",
+            parse_mode
+        )
+    )
+}
 
-    // If using custom links base url, the old reddit link doesn't make sense.
-    match links_base_url {
-        Some(_) => format!("{subreddit_link} [{comments_link}]"),
-        None => {
-            let old_comments_link = format_html_anchor(&post.format_old_permalink_url(), "old");
-            format!("{subreddit_link} [{comments_link}, {old_comments_link}]")
+/// A raw footer is only used as is with HTML captions, with other parse modes it is escaped.
+fn format_footer(config: &config::Config, parse_mode: ParseMode) -> Option<String> {
+    let footer = config.caption_footer.as_ref()?;
+    if config.caption_footer_raw && parse_mode == ParseMode::Html {
+        Some(footer.to_owned())
+    } else {
+        Some(escape_text(footer, parse_mode))
+    }
+}
+
+/// Length of the text Telegram displays for the HTML, in UTF-16 code units like Telegram counts
+/// it. Tags are not counted, and entities count as a single character.
+fn visible_len(html: &str) -> usize {
+    let mut len = 0;
+    let mut in_tag = false;
+    let mut in_entity = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if in_tag => {}
+            '&' => {
+                in_entity = true;
+                len += 1;
+            }
+            ';' if in_entity => in_entity = false,
+            _ if in_entity => {}
+            c => len += c.len_utf16(),
         }
     }
+    len
 }
 
-pub fn format_media_caption_html(post: &reddit::Post, links_base_url: Option<&str>) -> String {
-    let title = &post.title;
-    let meta = format_meta_html(post, links_base_url);
-    format!("{title}\n{meta}")
+/// Length of the text Telegram displays for the MarkdownV2, in UTF-16 code units. Markup
+/// characters and the urls of links are not counted, and escaped characters count once.
+fn visible_len_markdown(markdown: &str) -> usize {
+    let mut len = 0;
+    let mut in_url = false;
+    let mut prev = None;
+    let mut chars = markdown.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    if !in_url {
+                        len += escaped.len_utf16();
+                    }
+                }
+                prev = None;
+                continue;
+            }
+            ')' if in_url => in_url = false,
+            _ if in_url => {}
+            '(' if prev == Some(']') => in_url = true,
+            '_' | '*' | '~' | '|' | '`' | '[' | ']' | '>' => {}
+            c => len += c.len_utf16(),
+        }
+        prev = Some(c);
+    }
+    len
+}
+
+fn visible_len_in(text: &str, parse_mode: ParseMode) -> usize {
+    match parse_mode {
+        ParseMode::Html => visible_len(text),
+        ParseMode::MarkdownV2 => visible_len_markdown(text),
+        ParseMode::None => text.encode_utf16().count(),
+    }
+}
+
+/// Truncates text to at most `max_len` UTF-16 code units, marking truncation with an ellipsis.
+fn truncate(text: &str, max_len: usize) -> String {
+    if text.encode_utf16().count() <= max_len {
+        return text.to_owned();
+    }
+
+    let mut len = 0;
+    let mut truncated = String::new();
+    for c in text.chars() {
+        // Leave room for the ellipsis
+        if len + c.len_utf16() + 1 > max_len {
+            break;
+        }
+        len += c.len_utf16();
+        truncated.push(c);
+    }
+    truncated.push('…');
+    truncated
+}
+
+/// Formats a caption of the title followed by formatted lines, e.g. links and the configured
+/// footer. The title is truncated so that the caption fits in Telegram's caption limit, so that
+/// the rest is always shown in full. Without a title, the caption is only the lines.
+fn format_caption(
+    title: Option<&str>,
+    format_title: impl Fn(&str) -> String,
+    lines: &[String],
+    parse_mode: ParseMode,
+) -> String {
+    let rest = lines.join("\n");
+    let Some(title) = title else {
+        return rest;
+    };
+    if rest.is_empty() {
+        return format_title(&truncate(title, MAX_CAPTION_LEN));
+    }
+    // One character for the newline between the title and the rest
+    let title_budget = MAX_CAPTION_LEN.saturating_sub(visible_len_in(&rest, parse_mode) + 1);
+    let title = format_title(&truncate(title, title_budget));
+    format!("{title}\n{rest}")
+}
+
+/// Posts marked as a spoiler on Reddit get a warning before the title, when configured so.
+fn title_if_enabled<'a>(
+    post: &'a reddit::Post,
+    config: &config::Config,
+    components: &[CaptionComponent],
+) -> Option<Cow<'a, str>> {
+    if !components.contains(&CaptionComponent::Title) {
+        return None;
+    }
+    if post.spoiler && config.reddit_spoilers == RedditSpoilers::MarkCaption {
+        Some(Cow::Owned(format!("{SPOILER_MARK}{}", post.title)))
+    } else {
+        Some(Cow::Borrowed(&post.title))
+    }
+}
+
+pub fn format_media_caption(
+    post: &reddit::Post,
+    config: &config::Config,
+    components: &[CaptionComponent],
+    parse_mode: ParseMode,
+) -> String {
+    let lines = format_caption_lines(post, config, components, parse_mode);
+    // Titles are requested with raw_json=1, so they are not HTML-escaped by Reddit
+    format_caption(
+        title_if_enabled(post, config, components).as_deref(),
+        |title| escape_text(title, parse_mode),
+        &lines,
+        parse_mode,
+    )
+}
+
+/// Caption of a media group that leaves out `more_images` of the post's images, with a link to the
+/// post to see them.
+pub fn format_album_caption(
+    post: &reddit::Post,
+    config: &config::Config,
+    components: &[CaptionComponent],
+    more_images: usize,
+    parse_mode: ParseMode,
+) -> String {
+    let mut lines = format_caption_lines(post, config, components, parse_mode);
+    if more_images > 0 {
+        lines.insert(
+            0,
+            format_anchor(
+                &post.format_permalink_url(config.links_base_url.as_deref()),
+                &format!("+{more_images} more"),
+                parse_mode,
+            ),
+        );
+    }
+    format_caption(
+        title_if_enabled(post, config, components).as_deref(),
+        |title| escape_text(title, parse_mode),
+        &lines,
+        parse_mode,
+    )
+}
+
+/// Question of the Telegram poll a poll post is sent as. Polls are sent as plain text.
+pub fn format_poll_question(post: &reddit::Post) -> String {
+    truncate(&post.title, MAX_POLL_QUESTION_LEN)
+}
+
+/// Options of the Telegram poll a poll post is sent as, or `None` if Telegram can't show the poll,
+/// e.g. because it has too many options.
+pub fn format_poll_options(poll: &reddit::PollData) -> Option<Vec<String>> {
+    if !POLL_OPTION_COUNT.contains(&poll.options.len())
+        || poll.options.iter().any(|option| option.text.is_empty())
+    {
+        return None;
+    }
+    Some(
+        poll.options
+            .iter()
+            .map(|option| truncate(&option.text, MAX_POLL_OPTION_LEN))
+            .collect(),
+    )
+}
+
+/// Sent along with the Telegram poll, as votes on it are separate from the votes on Reddit.
+pub fn format_poll_caption(
+    post: &reddit::Post,
+    poll: &reddit::PollData,
+    config: &config::Config,
+    components: &[CaptionComponent],
+    parse_mode: ParseMode,
+) -> String {
+    let votes = match poll.total_vote_count {
+        1 => "1 vote on Reddit".to_owned(),
+        count => format!("{count} votes on Reddit"),
+    };
+    let option_votes = poll.options.iter().filter_map(|option| {
+        option
+            .vote_count
+            .map(|count| format!("{}: {count}", escape_text(&option.text, parse_mode)))
+    });
+    [escape_text(&votes, parse_mode)]
+        .into_iter()
+        .chain(option_votes)
+        .chain(format_caption_lines(post, config, components, parse_mode))
+        .join("\n")
+}
+
+/// Splits text into paragraphs separated by blank lines. Line breaks within a paragraph are kept.
+fn split_paragraphs(text: &str) -> Vec<String> {
+    let mut paragraphs = vec![];
+    let mut current: Vec<&str> = vec![];
+    for line in text.lines().map(str::trim_end) {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                paragraphs.push(current.join("\n"));
+                current.clear();
+            }
+        } else {
+            current.push(line);
+        }
+    }
+    if !current.is_empty() {
+        paragraphs.push(current.join("\n"));
+    }
+    paragraphs
+}
+
+/// Formats the paragraphs as an expandable quote of at most `max_len` visible characters. If the
+/// text does not fit, it is truncated and followed by the `read_more` link.
+fn format_selftext_quote(
+    paragraphs: &[String],
+    max_len: usize,
+    read_more: &str,
+    parse_mode: ParseMode,
+) -> String {
+    let text = paragraphs.join("\n\n");
+    if text.encode_utf16().count() <= max_len {
+        return format_quote(&text, true, parse_mode);
+    }
+
+    // One character for the newline before the link
+    let text_budget = max_len.saturating_sub(visible_len_in(read_more, parse_mode) + 1);
+    format!(
+        "{}\n{read_more}",
+        format_quote(&truncate(&text, text_budget), true, parse_mode)
+    )
+}
+
+/// Formats a text post as its title followed by the selftext as a quote. The selftext is truncated
+/// to fit in Telegram's message limit, in which case a link to read the rest is added.
+pub fn format_self_post(
+    post: &reddit::Post,
+    config: &config::Config,
+    components: &[CaptionComponent],
+    parse_mode: ParseMode,
+) -> String {
+    let paragraphs = split_paragraphs(&post.selftext);
+    if paragraphs.is_empty() {
+        return format_media_caption(post, config, components, parse_mode);
+    }
+
+    let links_base_url = config.links_base_url.as_deref();
+    let title =
+        title_if_enabled(post, config, components).map(|title| escape_text(&title, parse_mode));
+    let rest = format_caption_lines(post, config, components, parse_mode).join("\n");
+    let read_more = format_anchor(
+        &post.format_permalink_url(links_base_url),
+        "Read more",
+        parse_mode,
+    );
+    // Characters for the newlines around the quote
+    let quote_budget = MAX_MESSAGE_LEN.saturating_sub(
+        title
+            .as_deref()
+            .map_or(0, |title| visible_len_in(title, parse_mode) + 1)
+            + if rest.is_empty() {
+                0
+            } else {
+                visible_len_in(&rest, parse_mode) + 1
+            },
+    );
+    let quote = format_selftext_quote(&paragraphs, quote_budget, &read_more, parse_mode);
+    [title, Some(quote), (!rest.is_empty()).then_some(rest)]
+        .into_iter()
+        .flatten()
+        .join("\n")
 }
 
 pub fn format_link_video_caption_html(video: &Video) -> String {
-    let title = &video.title;
+    let title = escape(&video.title);
     let meta = format_html_anchor(&video.url, "video link");
     format!("{title}\n{meta}")
 }
@@ -72,15 +575,77 @@ pub fn format_repost_buttons<T: Recordable>(post: &T) -> InlineKeyboardMarkup {
     format_repost_buttons_gallery(post, false)
 }
 
-pub fn format_link_message_html(post: &reddit::Post, links_base_url: Option<&str>) -> String {
-    let title = format_html_anchor(&post.url, &post.title);
-    let meta = format_meta_html(post, links_base_url);
-    format!("{title}\n{meta}")
+/// The title links to the post's url, without the configured tracking parameters. If the title is
+/// disabled, the url itself is shown instead, so that the link is never lost.
+pub fn format_link_message(
+    post: &reddit::Post,
+    config: &config::Config,
+    components: &[CaptionComponent],
+    parse_mode: ParseMode,
+) -> String {
+    let lines = format_caption_lines(post, config, components, parse_mode);
+    let url = strip_tracking_params(&post.url, &config.tracking_params);
+    let title = title_if_enabled(post, config, components);
+    format_caption(
+        Some(title.as_deref().unwrap_or(&url)),
+        |title| format_anchor(&url, title, parse_mode),
+        &lines,
+        parse_mode,
+    )
+}
+
+/// Removes the query parameters matching `params` from the url. A param ending with `*` matches
+/// any parameter starting with the rest of it. The remaining parameters are kept as they are, in
+/// their original encoding.
+pub fn strip_tracking_params(url: &str, params: &[String]) -> String {
+    let is_tracking = |key: &str| {
+        params.iter().any(|param| match param.strip_suffix('*') {
+            Some(prefix) => key.starts_with(prefix),
+            None => key == param,
+        })
+    };
+    let Some((base, rest)) = url.split_once('?') else {
+        return url.to_owned();
+    };
+    let (query, fragment) = match rest.split_once('#') {
+        Some((query, fragment)) => (query, Some(fragment)),
+        None => (rest, None),
+    };
+
+    let kept = query
+        .split('&')
+        .filter(|pair| {
+            let key = pair.split_once('=').map_or(*pair, |(key, _)| key);
+            !is_tracking(key)
+        })
+        .join("&");
+    let mut cleaned = base.to_owned();
+    if !kept.is_empty() {
+        cleaned.push('?');
+        cleaned.push_str(&kept);
+    }
+    if let Some(fragment) = fragment {
+        cleaned.push('#');
+        cleaned.push_str(fragment);
+    }
+    cleaned
+}
+
+pub fn format_caption_components(components: &[CaptionComponent]) -> String {
+    format!(
+        "Captions show: {}",
+        CaptionComponent::iter()
+            .filter(|component| components.contains(component))
+            .join(", ")
+    )
 }
 
 pub fn format_subscription_list(post: &[Subscription]) -> String {
     fn format_subscription(sub: &Subscription) -> String {
         let mut args = vec![];
+        if let Some(sort) = sub.sort {
+            args.push(format!("sort={sort}"));
+        }
         if let Some(time) = sub.time {
             args.push(format!("time={}", time));
         }
@@ -90,6 +655,48 @@ pub fn format_subscription_list(post: &[Subscription]) -> String {
         if let Some(filter) = sub.filter {
             args.push(format!("filter={}", filter));
         }
+        if let Some(min_score) = sub.min_score {
+            args.push(format!("min_score={}", min_score));
+        }
+        if let Some(min_upvote_ratio) = sub.min_upvote_ratio {
+            args.push(format!("min_upvote_ratio={min_upvote_ratio}"));
+        }
+        if let Some(top_percentile) = sub.top_percentile {
+            args.push(format!("top_percentile={top_percentile}"));
+        }
+        if let Some(rank) = sub.rank {
+            args.push(format!("rank={rank}"));
+        }
+        if let Some(min_rank) = sub.min_rank {
+            args.push(format!("min_rank={min_rank}"));
+        }
+        if sub.dedup_titles {
+            args.push("dedup_titles=true".to_string());
+        }
+        if sub.oc_only {
+            args.push("oc_only=true".to_string());
+        }
+        if sub.media_only {
+            args.push("media_only=true".to_string());
+        }
+        if sub.text_only {
+            args.push("text_only=true".to_string());
+        }
+        if let Some(catch_up) = sub.catch_up {
+            args.push(format!("catch_up={catch_up}"));
+        }
+        if sub.allow_nsfw {
+            args.push("nsfw=true".to_string());
+        }
+        if sub.top_comment {
+            args.push("top_comment=true".to_string());
+        }
+        if sub.send_order != SendOrder::default() {
+            args.push(format!("send_order={}", sub.send_order));
+        }
+        if !sub.blocked_authors.is_empty() {
+            args.push(format!("block_authors={}", sub.blocked_authors.join(",")));
+        }
 
         let args_str = if !args.is_empty() {
             format!("({})", args.join(", "))
@@ -110,18 +717,890 @@ pub fn format_subscription_list(post: &[Subscription]) -> String {
     }
 }
 
+/// Lists the subscriptions under their groups, sorted by group, followed by the subscriptions
+/// without a group.
+pub fn format_grouped_subscription_list(mut subs: Vec<Subscription>) -> String {
+    if subs.iter().all(|sub| sub.group.is_none()) {
+        return format_subscription_list(&subs);
+    }
+
+    let group_key = |sub: &Subscription| sub.group.as_ref().map(|group| group.to_lowercase());
+    subs.sort_by_key(|sub| {
+        (
+            sub.group.is_none(),
+            group_key(sub),
+            sub.subreddit.to_lowercase(),
+        )
+    });
+    subs.into_iter()
+        .group_by(group_key)
+        .into_iter()
+        .map(|(_, group)| {
+            let group = group.collect::<Vec<_>>();
+            let title = match &group[0].group {
+                Some(name) => format!("{name}:"),
+                None => "No group:".to_string(),
+            };
+            format!("{title}\n{}", format_subscription_list(&group))
+        })
+        .join("\n\n")
+}
+
+pub fn format_filter_list() -> String {
+    let post_types = PostType::iter()
+        .map(|post_type| format!("type={post_type}: {}", post_type.description()))
+        .join("\n");
+    let rankings = Ranking::iter()
+        .map(|rank| {
+            format!(
+                "rank={rank}: send the highest ranked posts first, by {}",
+                rank.description()
+            )
+        })
+        .join("\n");
+    format!(
+        "Supported filters:\n{post_types}\n\
+         min_score=<n>: only posts with at least n upvotes\n\
+         min_upvote_ratio=<0.0-1.0>: skip controversial posts with a lower share of upvotes\n\
+         top_percentile=<1-100>: only the highest scoring n% of the fetched posts\n\
+         {rankings}\n\
+         min_rank=<n>: only posts ranked at least n, with rank\n\
+         dedup_titles=true: skip posts with a title similar to a recently sent post\n\
+         oc_only=true: only posts marked as original content\n\
+         media_only=true: only image, video and gallery posts\n\
+         text_only=true: send every post as text, without downloading media\n\
+         nsfw=true: include NSFW posts\n\
+         block_authors=<user,...>: skip posts by these users\n\n\
+         Other options:\n\
+         limit=<n>: how many of the subreddit's posts are considered\n\
+         sort=<top|new|hot|rising>: which listing of the subreddit is checked\n\
+         time=<hour|day|week|month|year|all>: the period of the top listing\n\
+         send_order=<top_first|chronological>: the order new posts are sent in\n\
+         catch_up=<n>: send up to n current posts on the first check\n\
+         top_comment=true: quote the post's top comment in the caption"
+    )
+}
+
+pub fn format_reddit_status(
+    status: Option<&reddit::RateLimitStatus>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> String {
+    let Some(status) = status else {
+        return "Reddit has not reported a rate limit yet".to_string();
+    };
+    let used = status
+        .used
+        .map(|used| format!(", {used} used"))
+        .unwrap_or_default();
+    format!(
+        "Reddit rate limit: {} requests remaining{used}, resets in {}s",
+        status.remaining.floor(),
+        (status.resets_at - now).num_seconds().max(0)
+    )
+}
+
+fn format_duration(duration: &chrono::Duration) -> String {
+    let days = duration.num_days();
+    let hours = duration.num_hours() % 24;
+    let minutes = duration.num_minutes() % 60;
+    if days > 0 {
+        format!("{days}d {hours}h {minutes}m")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else {
+        format!("{minutes}m")
+    }
+}
+
+pub fn format_status(status: &BotStatus) -> String {
+    let last_poll = status
+        .last_poll_at
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| "never".to_string());
+
+    [
+        format!("{PKG_NAME} {}", status.version),
+        format!("Uptime: {}", format_duration(&status.uptime)),
+        format!("Last successful poll: {last_poll}"),
+        format!("Subscriptions: {}", status.subscription_count),
+        format!("Posts sent to this chat: {}", status.chat_post_count),
+        format!("Schema version: {}", status.schema_version),
+    ]
+    .join("\n")
+}
+
+pub fn format_history(entries: &[HistoryEntry], links_base_url: Option<&str>) -> String {
+    if entries.is_empty() {
+        return "No matching posts".to_owned();
+    }
+
+    entries
+        .iter()
+        .map(|entry| {
+            let comments_url = reddit::format_url_from_path(
+                &format!("/r/{}/comments/{}/", entry.subreddit, entry.post_id),
+                links_base_url,
+            );
+            format!(
+                "{} ({})",
+                format_html_anchor(&comments_url, &entry.title),
+                entry.seen_at.format("%Y-%m-%d")
+            )
+        })
+        .join("\n")
+}
+
+pub fn format_overlap(overlap: &[(String, String, u32)]) -> String {
+    if overlap.is_empty() {
+        return "No subscriptions delivered the same content".to_owned();
+    }
+    let lines = overlap
+        .iter()
+        .map(|(subreddit_a, subreddit_b, shared_count)| {
+            format!("r/{subreddit_a} and r/{subreddit_b}: {shared_count} shared")
+        })
+        .join("\n");
+    format!("Subscriptions delivering the same content:\n{lines}")
+}
+
+pub fn format_duplicate_stats(stats: &[DuplicateStats]) -> String {
+    if stats.is_empty() {
+        return "No subscriptions delivered duplicates of content from another subscription"
+            .to_owned();
+    }
+    let lines = stats
+        .iter()
+        .map(|stats| {
+            format!(
+                "r/{}: {} of {} posts",
+                stats.subreddit, stats.duplicate_count, stats.post_count
+            )
+        })
+        .join("\n");
+    format!("Posts that were duplicates of content delivered from another subscription:\n{lines}")
+}
+
+pub fn format_post_record(record: &PostRecord) -> String {
+    let seen_at = match record.seen_at {
+        Some(seen_at) => seen_at.to_rfc3339(),
+        None => "not sent yet".to_owned(),
+    };
+    [
+        format!("Post: {}", record.post_id),
+        format!("Subreddit: r/{}", record.subreddit),
+        format!("Title: {}", record.title),
+        format!("Seen at: {seen_at}"),
+        format!("Name: {}", record.name.as_deref().unwrap_or("unknown")),
+    ]
+    .join("\n")
+}
+
+/// Formats the size of the database file, if it is known, and the row counts sorted by table.
+pub fn format_db_stats(file_size: Option<u64>, table_counts: &HashMap<String, i64>) -> String {
+    let size = match file_size {
+        Some(bytes) if bytes >= 1024 * 1024 => {
+            format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+        }
+        Some(bytes) if bytes >= 1024 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        Some(bytes) => format!("{bytes} bytes"),
+        None => "unknown".to_string(),
+    };
+    std::iter::once(format!("Database size: {size}"))
+        .chain(
+            table_counts
+                .iter()
+                .sorted()
+                .map(|(table, count)| format!("{table}: {count} rows")),
+        )
+        .join("\n")
+}
+
+/// Header sent before the first post of the day when no text of its own is set for the chat.
+pub const DEFAULT_DAILY_HEADER: &str = "📅 {date}";
+
+/// Lists the outcome for each subreddit of /subscribe_many, followed by how many were subscribed
+/// to.
+pub fn format_subscribe_summary(results: &[(String, SubscribeOutcome)]) -> String {
+    let lines = results.iter().map(|(subreddit, outcome)| {
+        let outcome = match outcome {
+            SubscribeOutcome::Subscribed => "subscribed".to_string(),
+            SubscribeOutcome::Rejected(db::SubscribeError::AlreadySubscribed(_)) => {
+                "already subscribed".to_string()
+            }
+            SubscribeOutcome::Rejected(db::SubscribeError::Blocked(_)) => "blocked".to_string(),
+            SubscribeOutcome::Rejected(db::SubscribeError::QuotaExceeded(max)) => {
+                format!("limit of {max} subscriptions reached")
+            }
+            SubscribeOutcome::Rejected(err) => err.to_string(),
+            SubscribeOutcome::NoSuchSubreddit => "invalid, no such subreddit".to_string(),
+            SubscribeOutcome::Frontpage => "invalid, requires Reddit OAuth".to_string(),
+        };
+        format!("r/{subreddit}: {outcome}")
+    });
+    let subscribed = results
+        .iter()
+        .filter(|(_, outcome)| matches!(outcome, SubscribeOutcome::Subscribed))
+        .count();
+    lines
+        .chain([format!(
+            "\nSubscribed to {subscribed} of {} subreddits",
+            results.len()
+        )])
+        .join("\n")
+}
+
+pub fn format_bot_permissions(permissions: &BotPermissions) -> String {
+    let yes_no = |value| if value { "yes" } else { "no" };
+    let mut lines = vec![
+        format!(
+            "Can send messages: {}",
+            yes_no(permissions.can_send_messages)
+        ),
+        format!("Can send media: {}", yes_no(permissions.can_send_media)),
+    ];
+    if !permissions.missing.is_empty() {
+        lines.push(format!("Missing: {}", permissions.missing.join(", ")));
+    }
+    lines.join("\n")
+}
+
+/// Replaces `{date}` in the header with the day's date, e.g. `2023-05-01`.
+pub fn format_daily_header(header: &str, date: chrono::NaiveDate) -> String {
+    header.replace("{date}", &date.format("%Y-%m-%d").to_string())
+}
+
+pub fn format_failure_digest(subs: &[FailingSubscription]) -> String {
+    let lines = subs
+        .iter()
+        .map(|sub| {
+            format!(
+                "r/{} in chat {}: {} failed checks, last error: {}",
+                sub.subreddit,
+                sub.chat_id,
+                sub.consecutive_failures,
+                sub.last_error.as_deref().unwrap_or("unknown")
+            )
+        })
+        .join("\n");
+    format!("Failing subscriptions:\n{lines}")
+}
+
+pub fn format_inactive_subscriptions(subs: &[Subscription]) -> String {
+    if subs.is_empty() {
+        return "No inactive subscriptions".to_owned();
+    }
+    let lines = subs
+        .iter()
+        .map(|sub| format!("r/{} in chat {}", escape(&sub.subreddit), sub.chat_id))
+        .join("\n");
+    format!("Inactive subscriptions:\n{lines}")
+}
+
+pub fn format_subscription_raw(row: &BTreeMap<String, String>) -> String {
+    row.iter()
+        .map(|(column, value)| format!("{column}: {value}"))
+        .join("\n")
+}
+
+/// Lists the posts waiting to be sent, noting how many of the `total` are left out.
+pub fn format_queue(posts: &[QueuedPost], total: u32, links_base_url: Option<&str>) -> String {
+    if posts.is_empty() {
+        return "No posts waiting to be sent".to_owned();
+    }
+
+    let left_out = (total as usize).saturating_sub(posts.len());
+    posts
+        .iter()
+        .map(|post| {
+            let comments_url = reddit::format_url_from_path(
+                &format!("/r/{}/comments/{}/", post.subreddit, post.post_id),
+                links_base_url,
+            );
+            format!(
+                "{} (/r/{})",
+                format_html_anchor(&comments_url, &post.title),
+                escape(&post.subreddit)
+            )
+        })
+        .chain((left_out > 0).then(|| format!("and {left_out} more")))
+        .join("\n")
+}
+
+pub fn format_send_plan(post: &reddit::Post, plan: &anyhow::Result<SendPlan>) -> String {
+    let outcome = match plan {
+        Ok(SendPlan::Video { size_bytes }) => format!(
+            "Would send as video ({:.1} MB)",
+            *size_bytes as f64 / 1024.0 / 1024.0
+        ),
+        Ok(SendPlan::Photo) => "Would send as photo".to_string(),
+        Ok(SendPlan::Animation) => "Would send as animation".to_string(),
+        Ok(SendPlan::Album { count }) => format!("Would send as album of {count} images"),
+        Ok(SendPlan::Link {
+            with_thumbnail: true,
+        }) => "Would send as link with thumbnail".to_string(),
+        Ok(SendPlan::Link {
+            with_thumbnail: false,
+        }) => "Would send as link".to_string(),
+        Ok(SendPlan::Text) => "Would send as text".to_string(),
+        Ok(SendPlan::Poll { options }) => format!("Would send as poll with {options} options"),
+        Err(err) => format!("Would fail: {err:#}"),
+    };
+
+    [
+        format!("Post: {} (/r/{})", post.title, post.subreddit),
+        format!("Type: {}", post.post_type),
+        outcome,
+    ]
+    .join("\n")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::reddit::TopPostsTimePeriod;
 
     #[test]
     fn test_format_html_anchor() {
         assert_eq!(
             format_html_anchor("https://example.com", "<hello></world>"),
             r#"<a href="https://example.com">&lt;hello&gt;&lt;/world&gt;</a>"#
+        );
+        assert_eq!(
+            format_html_anchor("https://example.com", "Cats & Dogs"),
+            r#"<a href="https://example.com">Cats &amp; Dogs</a>"#
         )
     }
 
+    #[test]
+    fn test_format_history() {
+        use chrono::TimeZone;
+
+        let entries = [HistoryEntry {
+            post_id: "abc123".to_string(),
+            subreddit: "pics".to_string(),
+            title: "Cats & Dogs".to_string(),
+            seen_at: chrono::Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap(),
+        }];
+        assert_eq!(
+            format_history(&entries, None),
+            r#"<a href="https://www.reddit.com/r/pics/comments/abc123/">Cats &amp; Dogs</a> (2023-05-01)"#
+        );
+        assert_eq!(format_history(&[], None), "No matching posts");
+    }
+
+    #[test]
+    fn test_visible_len() {
+        assert_eq!(visible_len("plain"), 5);
+        assert_eq!(
+            visible_len(r#"<a href="https://example.com">Cats &amp; Dogs</a>"#),
+            11
+        );
+        assert_eq!(visible_len("🦀"), 2);
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_text("1 < 2 & [OC] *bold* (v2.0)", ParseMode::Html),
+            "1 &lt; 2 &amp; [OC] *bold* (v2.0)"
+        );
+        assert_eq!(escape_text("<b>", ParseMode::None), "<b>");
+    }
+
+    #[test]
+    fn test_strip_tracking_params() {
+        let params = vec!["utm_*".to_string(), "share_id".to_string()];
+        assert_eq!(
+            strip_tracking_params(
+                "https://example.com/a?utm_source=reddit&id=1&utm_medium=social",
+                &params
+            ),
+            "https://example.com/a?id=1"
+        );
+        assert_eq!(
+            strip_tracking_params("https://example.com/a?utm_source=x&share_id=y", &params),
+            "https://example.com/a"
+        );
+        // Functional params keep their encoding and the fragment is kept
+        assert_eq!(
+            strip_tracking_params(
+                "https://youtube.com/watch?v=abc&t=1m30s&q=a%20b&share_id=y#comments",
+                &params
+            ),
+            "https://youtube.com/watch?v=abc&t=1m30s&q=a%20b#comments"
+        );
+        assert_eq!(
+            strip_tracking_params("https://example.com/a?utm=1&share_identity=2", &params),
+            "https://example.com/a?utm=1&share_identity=2"
+        );
+        assert_eq!(
+            strip_tracking_params("https://example.com/a?utm_source=x", &[]),
+            "https://example.com/a?utm_source=x"
+        );
+
+        let post = reddit::Post {
+            title: "Title".into(),
+            url: "https://example.com/a?utm_source=reddit".into(),
+            ..Default::default()
+        };
+        let config = config::Config {
+            tracking_params: params,
+            ..Default::default()
+        };
+        let message =
+            format_link_message(&post, &config, &[CaptionComponent::Title], ParseMode::Html);
+        assert!(message.contains(r#"<a href="https://example.com/a">Title</a>"#));
+    }
+
+    #[test]
+    fn test_escape_markdown() {
+        assert_eq!(
+            escape_markdown("1 < 2 & [OC] *bold* (v2.0)"),
+            r"1 < 2 & \[OC\] \*bold\* \(v2\.0\)"
+        );
+        assert_eq!(
+            escape_markdown(r"_~`>#+-=|{}.!\"),
+            r"\_\~\`\>\#\+\-\=\|\{\}\.\!\\"
+        );
+        assert_eq!(escape_markdown("Cats & Dogs 🦀"), "Cats & Dogs 🦀");
+        assert_eq!(visible_len_markdown(r"\[OC\] \*bold\* 🦀"), 14);
+        assert_eq!(
+            visible_len_markdown(r"**>Quote\.|| [Read more](https://example.com/a_\(b\))"),
+            16
+        );
+    }
+
+    #[test]
+    fn test_media_caption_parse_modes() {
+        let config = config::Config::default();
+        let post = reddit::Post {
+            title: "Cats & dogs [OC] (2023)!".to_string(),
+            link_flair_text: Some("Pets_Only".to_string()),
+            subreddit: "aww".to_string(),
+            permalink: "/r/aww/comments/abc123/title/".to_string(),
+            ..Default::default()
+        };
+        let components = &[
+            CaptionComponent::Title,
+            CaptionComponent::Flair,
+            CaptionComponent::Subreddit,
+        ];
+
+        assert_eq!(
+            format_media_caption(&post, &config, components, ParseMode::MarkdownV2),
+            r"Cats & dogs \[OC\] \(2023\)\!
+_Pets\_Only_ [/r/aww](https://www.reddit.com/r/aww)"
+        );
+        assert_eq!(
+            format_media_caption(&post, &config, components, ParseMode::None),
+            "Cats & dogs [OC] (2023)!\nPets_Only /r/aww (https://www.reddit.com/r/aww)"
+        );
+
+        let post = reddit::Post {
+            title: "a".repeat(2000),
+            ..post
+        };
+        let caption = format_media_caption(&post, &config, components, ParseMode::MarkdownV2);
+        assert_eq!(visible_len_markdown(&caption), MAX_CAPTION_LEN);
+    }
+
+    #[test]
+    fn test_media_caption_fits_limit_with_footer() {
+        let config = config::Config {
+            caption_footer: Some("via @mychannel & friends".to_string()),
+            ..Default::default()
+        };
+        let post = reddit::Post {
+            title: "a".repeat(2000),
+            subreddit: "pics".to_string(),
+            permalink: "/r/pics/comments/abc123/title/".to_string(),
+            ..Default::default()
+        };
+
+        let caption =
+            format_media_caption(&post, &config, DEFAULT_CAPTION_COMPONENTS, ParseMode::Html);
+        assert_eq!(visible_len(&caption), MAX_CAPTION_LEN);
+        assert!(caption.ends_with("\nvia @mychannel &amp; friends"));
+        assert!(caption.starts_with("aaaa"));
+        assert!(caption.contains("a…\n"));
+
+        let post = reddit::Post {
+            title: "Short & sweet".to_string(),
+            ..post
+        };
+        let caption =
+            format_media_caption(&post, &config, DEFAULT_CAPTION_COMPONENTS, ParseMode::Html);
+        assert!(caption.starts_with("Short &amp; sweet\n"));
+    }
+
+    #[test]
+    fn test_format_album_caption() {
+        let config = config::Config::default();
+        let post = reddit::Post {
+            title: "Album".to_string(),
+            subreddit: "pics".to_string(),
+            permalink: "/r/pics/comments/abc123/title/".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            format_album_caption(
+                &post,
+                &config,
+                DEFAULT_CAPTION_COMPONENTS,
+                0,
+                ParseMode::Html
+            ),
+            format_media_caption(&post, &config, DEFAULT_CAPTION_COMPONENTS, ParseMode::Html)
+        );
+        let caption = format_album_caption(
+            &post,
+            &config,
+            DEFAULT_CAPTION_COMPONENTS,
+            15,
+            ParseMode::Html,
+        );
+        assert!(caption.starts_with(
+            "Album\n<a href=\"https://www.reddit.com/r/pics/comments/abc123/title/\">+15 more</a>\n"
+        ));
+    }
+
+    #[test]
+    fn test_format_subscribe_summary() {
+        let results = vec![
+            ("aww".to_string(), SubscribeOutcome::Subscribed),
+            (
+                "pics".to_string(),
+                SubscribeOutcome::Rejected(db::SubscribeError::AlreadySubscribed(
+                    "pics".to_string(),
+                )),
+            ),
+            (
+                "casino".to_string(),
+                SubscribeOutcome::Rejected(db::SubscribeError::Blocked("casino".to_string())),
+            ),
+            (
+                "gifs".to_string(),
+                SubscribeOutcome::Rejected(db::SubscribeError::QuotaExceeded(3)),
+            ),
+            ("nosuchsub".to_string(), SubscribeOutcome::NoSuchSubreddit),
+            ("frontpage".to_string(), SubscribeOutcome::Frontpage),
+        ];
+        assert_eq!(
+            format_subscribe_summary(&results),
+            "r/aww: subscribed\n\
+             r/pics: already subscribed\n\
+             r/casino: blocked\n\
+             r/gifs: limit of 3 subscriptions reached\n\
+             r/nosuchsub: invalid, no such subreddit\n\
+             r/frontpage: invalid, requires Reddit OAuth\n\
+             \n\
+             Subscribed to 1 of 6 subreddits"
+        );
+    }
+
+    #[test]
+    fn test_format_top_comment_in_caption() {
+        let config = config::Config::default();
+        let post = reddit::Post {
+            title: "Question".to_string(),
+            top_comment: Some(reddit::Comment {
+                author: "ferris".to_string(),
+                body: "Use <Vec>\n\nor a slice".to_string(),
+                permalink: "/r/rust/comments/abc123/title/def456/".to_string(),
+                stickied: false,
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            format_media_caption(&post, &config, &[CaptionComponent::Title], ParseMode::Html),
+            "Question\n<blockquote>Use &lt;Vec&gt; or a slice</blockquote>\n\
+             <a href=\"https://www.reddit.com/r/rust/comments/abc123/title/def456/\">top comment</a>"
+        );
+
+        let post = reddit::Post {
+            top_comment: post.top_comment.map(|comment| reddit::Comment {
+                body: "a".repeat(1000),
+                ..comment
+            }),
+            ..post
+        };
+        let caption =
+            format_media_caption(&post, &config, &[CaptionComponent::Title], ParseMode::Html);
+        assert!(caption.contains(&format!(
+            "{}…</blockquote>",
+            "a".repeat(MAX_TOP_COMMENT_LEN - 1)
+        )));
+    }
+
+    #[test]
+    fn test_format_media_caption_marks_spoilers() {
+        let post = reddit::Post {
+            title: "Season finale".to_string(),
+            spoiler: true,
+            ..Default::default()
+        };
+        let components = &[CaptionComponent::Title];
+
+        let config = config::Config::default();
+        assert_eq!(
+            format_media_caption(&post, &config, components, ParseMode::Html),
+            "Season finale"
+        );
+
+        let config = config::Config {
+            reddit_spoilers: RedditSpoilers::MarkCaption,
+            ..Default::default()
+        };
+        assert_eq!(
+            format_media_caption(&post, &config, components, ParseMode::Html),
+            "⚠️ Spoiler: Season finale"
+        );
+        let post = reddit::Post {
+            spoiler: false,
+            ..post
+        };
+        assert_eq!(
+            format_media_caption(&post, &config, components, ParseMode::Html),
+            "Season finale"
+        );
+    }
+
+    #[test]
+    fn test_format_poll() {
+        let option = |text: &str, vote_count| reddit::PollOption {
+            text: text.to_string(),
+            vote_count,
+        };
+        let poll = reddit::PollData {
+            options: vec![option("Cats & dogs", Some(30)), option("Neither", Some(12))],
+            total_vote_count: 42,
+        };
+        let post = reddit::Post {
+            title: "Favourite pets?".to_string(),
+            poll_data: Some(poll.clone()),
+            ..Default::default()
+        };
+
+        assert_eq!(format_poll_question(&post), "Favourite pets?");
+        assert_eq!(
+            format_poll_options(&poll).unwrap(),
+            vec!["Cats & dogs", "Neither"]
+        );
+        assert_eq!(
+            format_poll_caption(
+                &post,
+                &poll,
+                &config::Config::default(),
+                &[],
+                ParseMode::Html
+            ),
+            "42 votes on Reddit\nCats &amp; dogs: 30\nNeither: 12"
+        );
+
+        let long = reddit::PollData {
+            options: vec![option(&"a".repeat(150), None), option("b", None)],
+            total_vote_count: 1,
+        };
+        assert_eq!(
+            format_poll_options(&long).unwrap()[0]
+                .encode_utf16()
+                .count(),
+            MAX_POLL_OPTION_LEN
+        );
+        assert_eq!(
+            format_poll_caption(
+                &post,
+                &long,
+                &config::Config::default(),
+                &[],
+                ParseMode::Html
+            ),
+            "1 vote on Reddit"
+        );
+
+        let too_few = reddit::PollData {
+            options: vec![option("Only", None)],
+            total_vote_count: 0,
+        };
+        assert_eq!(format_poll_options(&too_few), None);
+        let too_many = reddit::PollData {
+            options: (0..11).map(|i| option(&i.to_string(), None)).collect(),
+            total_vote_count: 0,
+        };
+        assert_eq!(format_poll_options(&too_many), None);
+    }
+
+    #[test]
+    fn test_caption_components() {
+        let config = config::Config::default();
+        let post = reddit::Post {
+            title: "Waves & wind".to_string(),
+            author: "sailor".to_string(),
+            link_flair_text: Some("OC".to_string()),
+            ups: 1234,
+            subreddit: "pics".to_string(),
+            permalink: "/r/pics/comments/abc123/title/".to_string(),
+            url: "https://example.com/waves".to_string(),
+            ..Default::default()
+        };
+        let subreddit = r#"<a href="https://www.reddit.com/r/pics">/r/pics</a>"#;
+        let author = r#"<a href="https://www.reddit.com/u/sailor">u/sailor</a>"#;
+        let comments = r#"[<a href="https://www.reddit.com/r/pics/comments/abc123/title/">comments</a>, <a href="https://old.reddit.com/r/pics/comments/abc123/title/">old</a>]"#;
+        let caption = |components: &[CaptionComponent]| {
+            format_media_caption(&post, &config, components, ParseMode::Html)
+        };
+
+        assert_eq!(
+            caption(DEFAULT_CAPTION_COMPONENTS),
+            format!("Waves &amp; wind\n{subreddit} {comments}")
+        );
+        // Components are shown in a fixed order
+        assert_eq!(
+            caption(&[
+                CaptionComponent::Comments,
+                CaptionComponent::Author,
+                CaptionComponent::Score,
+                CaptionComponent::Flair,
+                CaptionComponent::Title,
+                CaptionComponent::Subreddit,
+            ]),
+            format!("Waves &amp; wind\n<i>OC</i> 1234 points {author} {subreddit} {comments}")
+        );
+        assert_eq!(
+            caption(&[CaptionComponent::Score, CaptionComponent::Author]),
+            format!("1234 points {author}")
+        );
+        assert_eq!(
+            caption(&[CaptionComponent::Hashtag, CaptionComponent::Title]),
+            "Waves &amp; wind\n#pics"
+        );
+        assert_eq!(caption(&[CaptionComponent::Title]), "Waves &amp; wind");
+        assert_eq!(caption(&[]), "");
+
+        let post = reddit::Post {
+            link_flair_text: None,
+            author: "".to_string(),
+            ..post
+        };
+        assert_eq!(
+            format_media_caption(
+                &post,
+                &config,
+                &[
+                    CaptionComponent::Flair,
+                    CaptionComponent::Author,
+                    CaptionComponent::Score
+                ],
+                ParseMode::Html
+            ),
+            "1234 points"
+        );
+        assert_eq!(
+            format_link_message(&post, &config, &[CaptionComponent::Score], ParseMode::Html),
+            "<a href=\"https://example.com/waves\">https://example.com/waves</a>\n1234 points"
+        );
+    }
+
+    #[test]
+    fn test_format_hashtag() {
+        assert_eq!(
+            format_hashtag("AnimalsBeingJerks").unwrap(),
+            "#AnimalsBeingJerks"
+        );
+        assert_eq!(format_hashtag("ask_science").unwrap(), "#ask_science");
+        assert_eq!(format_hashtag("r/pics+gifs-2").unwrap(), "#rpicsgifs2");
+        assert_eq!(format_hashtag("<b>&amp;</b>").unwrap(), "#bampb");
+        assert_eq!(format_hashtag("2007"), None);
+        assert_eq!(format_hashtag("+-"), None);
+    }
+
+    #[test]
+    fn test_format_author_link() {
+        assert_eq!(
+            format_author_link("sailor", None, ParseMode::Html).unwrap(),
+            r#"<a href="https://www.reddit.com/u/sailor">u/sailor</a>"#
+        );
+        assert_eq!(
+            format_author_link("<b>&", Some("https://teddit.net"), ParseMode::Html).unwrap(),
+            r#"<a href="https://teddit.net/u/%3Cb%3E&">u/&lt;b&gt;&amp;</a>"#
+        );
+        assert_eq!(format_author_link("[deleted]", None, ParseMode::Html), None);
+        assert_eq!(format_author_link("", None, ParseMode::Html), None);
+    }
+
+    #[test]
+    fn test_split_paragraphs() {
+        assert_eq!(
+            split_paragraphs("First line\nsecond line\n\n\n  \nSecond paragraph  \r\n\r\nThird"),
+            vec!["First line\nsecond line", "Second paragraph", "Third"]
+        );
+        assert!(split_paragraphs("").is_empty());
+        assert!(split_paragraphs(" \n\n ").is_empty());
+    }
+
+    #[test]
+    fn test_format_self_post() {
+        let config = config::Config::default();
+        let post = reddit::Post {
+            title: "Ask <me> anything".to_string(),
+            subreddit: "test".to_string(),
+            permalink: "/r/test/comments/abc/".to_string(),
+            selftext: "Is 1 < 2 & 3 > 2?\n\nYes.".to_string(),
+            ..Default::default()
+        };
+        let message = format_self_post(&post, &config, DEFAULT_CAPTION_COMPONENTS, ParseMode::Html);
+        assert!(message.starts_with(
+            "Ask &lt;me&gt; anything\n<blockquote expandable>Is 1 &lt; 2 &amp; 3 &gt; 2?\n\nYes.</blockquote>\n"
+        ));
+        assert!(!message.contains("Read more"));
+
+        let post = reddit::Post {
+            selftext: "a".repeat(5000),
+            ..post
+        };
+        let message = format_self_post(&post, &config, DEFAULT_CAPTION_COMPONENTS, ParseMode::Html);
+        assert_eq!(visible_len(&message), MAX_MESSAGE_LEN);
+        assert!(message.contains(
+            "a…</blockquote>\n<a href=\"https://www.reddit.com/r/test/comments/abc/\">Read more</a>\n"
+        ));
+
+        let post = reddit::Post {
+            selftext: "".to_string(),
+            ..post
+        };
+        assert_eq!(
+            format_self_post(&post, &config, DEFAULT_CAPTION_COMPONENTS, ParseMode::Html),
+            format_media_caption(&post, &config, DEFAULT_CAPTION_COMPONENTS, ParseMode::Html)
+        );
+    }
+
+    #[test]
+    fn test_format_grouped_subscription_list() {
+        let sub = |subreddit: &str, group: Option<&str>| Subscription {
+            chat_id: 1,
+            subreddit: subreddit.to_string(),
+            group: group.map(str::to_string),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            format_grouped_subscription_list(vec![sub("foo", None), sub("bar", None)]),
+            "foo\nbar"
+        );
+        assert_eq!(
+            format_grouped_subscription_list(vec![
+                sub("pics", None),
+                sub("worldnews", Some("news")),
+                sub("aww", Some("Animals")),
+                sub("europe", Some("News")),
+                sub("cats", Some("animals")),
+            ]),
+            "Animals:\naww\ncats\n\nNews:\neurope\nworldnews\n\nNo group:\npics"
+        );
+    }
+
     #[test]
     fn test_format_subscription_list() {
         assert_eq!(
@@ -130,18 +1609,129 @@ mod tests {
                     chat_id: 1,
                     subreddit: "foo".to_owned(),
                     limit: None,
+                    sort: None,
                     time: None,
                     filter: None,
+                    min_score: None,
+                    min_upvote_ratio: None,
+                    top_percentile: None,
+                    rank: None,
+                    min_rank: None,
+                    dedup_titles: false,
+                    oc_only: false,
+                    media_only: false,
+                    text_only: false,
+                    catch_up: None,
+                    send_order: SendOrder::TopFirst,
+                    allow_nsfw: false,
+                    top_comment: false,
+                    blocked_authors: vec![],
+                    message_thread_id: None,
+                    group: None,
                 },
                 Subscription {
                     chat_id: 1,
                     subreddit: "bar".to_owned(),
                     limit: Some(1),
+                    sort: None,
                     time: Some(TopPostsTimePeriod::Week),
                     filter: None,
+                    min_score: None,
+                    min_upvote_ratio: None,
+                    top_percentile: None,
+                    rank: None,
+                    min_rank: None,
+                    dedup_titles: false,
+                    oc_only: false,
+                    media_only: false,
+                    text_only: false,
+                    catch_up: None,
+                    send_order: SendOrder::TopFirst,
+                    allow_nsfw: false,
+                    top_comment: false,
+                    blocked_authors: vec![],
+                    message_thread_id: None,
+                    group: None,
                 },
             ]),
             "foo\nbar (time=week, limit=1)"
         )
     }
+
+    #[test]
+    fn test_format_filter_list() {
+        let list = format_filter_list();
+        for post_type in PostType::iter() {
+            assert!(list.contains(&format!("type={post_type}:")));
+        }
+        assert!(list.contains("media_only=true:"));
+        assert!(list.contains("text_only=true:"));
+    }
+
+    #[test]
+    fn test_format_db_stats() {
+        let counts = HashMap::from([
+            ("subscription".to_string(), 3),
+            ("chat".to_string(), 2),
+            ("post".to_string(), 1500),
+        ]);
+        assert_eq!(
+            format_db_stats(Some(5 * 1024 * 1024 + 300 * 1024), &counts),
+            "Database size: 5.3 MB\nchat: 2 rows\npost: 1500 rows\nsubscription: 3 rows"
+        );
+        assert_eq!(
+            format_db_stats(Some(2048), &HashMap::new()),
+            "Database size: 2.0 KB"
+        );
+        assert_eq!(
+            format_db_stats(None, &HashMap::new()),
+            "Database size: unknown"
+        );
+    }
+
+    #[test]
+    fn test_format_status() {
+        use chrono::TimeZone;
+
+        let status = BotStatus {
+            version: "1.2.3",
+            uptime: chrono::Duration::minutes(60 * 26 + 5),
+            last_poll_at: Some(chrono::Utc.with_ymd_and_hms(2023, 5, 1, 12, 30, 0).unwrap()),
+            subscription_count: 4,
+            chat_post_count: 120,
+            schema_version: 20,
+        };
+        assert_eq!(
+            format_status(&status),
+            "tgreddit 1.2.3\nUptime: 1d 2h 5m\nLast successful poll: 2023-05-01 12:30:00 UTC\nSubscriptions: 4\nPosts sent to this chat: 120\nSchema version: 20"
+        );
+
+        let status = BotStatus {
+            version: "1.2.3",
+            uptime: chrono::Duration::minutes(3),
+            last_poll_at: None,
+            subscription_count: 0,
+            chat_post_count: 0,
+            schema_version: 20,
+        };
+        assert_eq!(
+            format_status(&status),
+            "tgreddit 1.2.3\nUptime: 3m\nLast successful poll: never\nSubscriptions: 0\nPosts sent to this chat: 0\nSchema version: 20"
+        );
+    }
+
+    #[test]
+    fn test_format_queue() {
+        let posts = vec![QueuedPost {
+            post_id: "abc".to_string(),
+            subreddit: "rust".to_string(),
+            title: "Post".to_string(),
+        }];
+        assert_eq!(
+            format_queue(&posts, 1, None),
+            "<a href=\"https://www.reddit.com/r/rust/comments/abc/\">Post</a> (/r/rust)"
+        );
+        assert!(format_queue(&posts, 31, None).ends_with("(/r/rust)\nand 30 more"));
+        assert_eq!(format_queue(&[], 0, None), "No posts waiting to be sent");
+    }
 }
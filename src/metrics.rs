@@ -0,0 +1,263 @@
+use itertools::Itertools;
+use std::{collections::HashMap, fmt, time::Duration};
+
+use crate::reddit::RateLimitStatus;
+
+/// Upper bounds in seconds of the fetch duration histogram buckets.
+const FETCH_DURATION_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Debug, Clone)]
+struct Histogram {
+    bucket_counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            bucket_counts: vec![0; FETCH_DURATION_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, secs: f64) {
+        for (i, le) in FETCH_DURATION_BUCKETS.iter().enumerate() {
+            if secs <= *le {
+                self.bucket_counts[i] += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum / self.count as f64
+    }
+}
+
+/// Histogram of subreddit fetch durations, labeled by subreddit.
+#[derive(Debug, Default)]
+pub struct FetchMetrics {
+    by_subreddit: HashMap<String, Histogram>,
+}
+
+impl FetchMetrics {
+    pub fn observe(&mut self, subreddit: &str, duration: Duration) {
+        self.by_subreddit
+            .entry(subreddit.to_owned())
+            .or_insert_with(Histogram::new)
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Renders the histogram in Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut lines = vec![
+            "# HELP tgreddit_fetch_duration_seconds Duration of subreddit fetches".to_string(),
+            "# TYPE tgreddit_fetch_duration_seconds histogram".to_string(),
+        ];
+        for (subreddit, histogram) in self.by_subreddit.iter().sorted_by_key(|(k, _)| *k) {
+            for (le, count) in FETCH_DURATION_BUCKETS.iter().zip(&histogram.bucket_counts) {
+                lines.push(format!(
+                    "tgreddit_fetch_duration_seconds_bucket{{subreddit=\"{subreddit}\",le=\"{le}\"}} {count}"
+                ));
+            }
+            lines.push(format!(
+                "tgreddit_fetch_duration_seconds_bucket{{subreddit=\"{subreddit}\",le=\"+Inf\"}} {}",
+                histogram.count
+            ));
+            lines.push(format!(
+                "tgreddit_fetch_duration_seconds_sum{{subreddit=\"{subreddit}\"}} {}",
+                histogram.sum
+            ));
+            lines.push(format!(
+                "tgreddit_fetch_duration_seconds_count{{subreddit=\"{subreddit}\"}} {}",
+                histogram.count
+            ));
+        }
+        lines.join("\n")
+    }
+
+    /// One line per subreddit with how many fetches there were and how long they took on average,
+    /// slowest first, for at most `limit` subreddits.
+    pub fn digest(&self, limit: usize) -> String {
+        let by_mean = self
+            .by_subreddit
+            .iter()
+            .map(|(subreddit, histogram)| (subreddit, histogram.count, histogram.mean()))
+            .sorted_by(|(a, _, a_mean), (b, _, b_mean)| b_mean.total_cmp(a_mean).then(a.cmp(b)))
+            .collect::<Vec<_>>();
+        let mut lines = by_mean
+            .iter()
+            .take(limit)
+            .map(|(subreddit, count, mean)| {
+                format!("/r/{subreddit}: {count} fetch(es), mean {mean:.2}s")
+            })
+            .collect::<Vec<_>>();
+        if by_mean.len() > limit {
+            lines.push(format!("and {} more", by_mean.len() - limit));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Renders Reddit's last reported rate limit as gauges in Prometheus text exposition format.
+pub fn render_rate_limit(status: &RateLimitStatus, now: chrono::DateTime<chrono::Utc>) -> String {
+    let reset_secs = (status.resets_at - now).num_seconds().max(0);
+    let mut lines = vec![
+        "# HELP tgreddit_reddit_ratelimit_remaining Requests left in Reddit's rate limit period"
+            .to_string(),
+        "# TYPE tgreddit_reddit_ratelimit_remaining gauge".to_string(),
+        format!("tgreddit_reddit_ratelimit_remaining {}", status.remaining),
+        "# HELP tgreddit_reddit_ratelimit_reset_seconds Seconds until Reddit's rate limit period resets"
+            .to_string(),
+        "# TYPE tgreddit_reddit_ratelimit_reset_seconds gauge".to_string(),
+        format!("tgreddit_reddit_ratelimit_reset_seconds {reset_secs}"),
+    ];
+    if let Some(used) = status.used {
+        lines.extend([
+            "# HELP tgreddit_reddit_ratelimit_used Requests made in Reddit's rate limit period"
+                .to_string(),
+            "# TYPE tgreddit_reddit_ratelimit_used gauge".to_string(),
+            format!("tgreddit_reddit_ratelimit_used {used}"),
+        ]);
+    }
+    lines.join("\n")
+}
+
+/// Summary of the fetches made during one poll cycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleSummary {
+    pub cycle_duration: Duration,
+    pub fetch_count: usize,
+    pub total_fetch_duration: Duration,
+    pub mean_fetch_duration: Duration,
+    pub slowest_fetch: Option<(String, Duration)>,
+}
+
+impl CycleSummary {
+    pub fn new(cycle_duration: Duration, fetch_durations: &[(String, Duration)]) -> Self {
+        let fetch_count = fetch_durations.len();
+        let total_fetch_duration = fetch_durations.iter().map(|(_, d)| *d).sum::<Duration>();
+        let mean_fetch_duration = if fetch_count > 0 {
+            total_fetch_duration / fetch_count as u32
+        } else {
+            Duration::ZERO
+        };
+        let slowest_fetch = fetch_durations.iter().max_by_key(|(_, d)| *d).cloned();
+
+        CycleSummary {
+            cycle_duration,
+            fetch_count,
+            total_fetch_duration,
+            mean_fetch_duration,
+            slowest_fetch,
+        }
+    }
+}
+
+impl fmt::Display for CycleSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "poll cycle took {:.2?}, {} fetch(es) took {:.2?} in total, mean {:.2?}",
+            self.cycle_duration,
+            self.fetch_count,
+            self.total_fetch_duration,
+            self.mean_fetch_duration
+        )?;
+        if let Some((subreddit, duration)) = &self.slowest_fetch {
+            write!(f, ", slowest /r/{subreddit} {duration:.2?}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycle_summary() {
+        let fetch_durations = vec![
+            ("foo".to_string(), Duration::from_millis(100)),
+            ("bar".to_string(), Duration::from_millis(700)),
+            ("baz".to_string(), Duration::from_millis(400)),
+        ];
+        let summary = CycleSummary::new(Duration::from_secs(3), &fetch_durations);
+        assert_eq!(
+            summary,
+            CycleSummary {
+                cycle_duration: Duration::from_secs(3),
+                fetch_count: 3,
+                total_fetch_duration: Duration::from_millis(1200),
+                mean_fetch_duration: Duration::from_millis(400),
+                slowest_fetch: Some(("bar".to_string(), Duration::from_millis(700))),
+            }
+        );
+    }
+
+    #[test]
+    fn test_cycle_summary_no_fetches() {
+        let summary = CycleSummary::new(Duration::from_millis(5), &[]);
+        assert_eq!(summary.fetch_count, 0);
+        assert_eq!(summary.mean_fetch_duration, Duration::ZERO);
+        assert_eq!(summary.slowest_fetch, None);
+    }
+
+    #[test]
+    fn test_fetch_metrics_render() {
+        let mut metrics = FetchMetrics::default();
+        metrics.observe("foo", Duration::from_millis(300));
+        metrics.observe("foo", Duration::from_secs(3));
+        let rendered = metrics.render();
+        assert!(rendered
+            .contains("tgreddit_fetch_duration_seconds_bucket{subreddit=\"foo\",le=\"0.25\"} 0"));
+        assert!(rendered
+            .contains("tgreddit_fetch_duration_seconds_bucket{subreddit=\"foo\",le=\"0.5\"} 1"));
+        assert!(rendered
+            .contains("tgreddit_fetch_duration_seconds_bucket{subreddit=\"foo\",le=\"5\"} 2"));
+        assert!(rendered.contains("tgreddit_fetch_duration_seconds_count{subreddit=\"foo\"} 2"));
+    }
+
+    #[test]
+    fn test_fetch_metrics_digest() {
+        let mut metrics = FetchMetrics::default();
+        metrics.observe("fast", Duration::from_millis(100));
+        metrics.observe("slow", Duration::from_secs(2));
+        metrics.observe("slow", Duration::from_secs(4));
+        metrics.observe("medium", Duration::from_secs(1));
+        assert_eq!(
+            metrics.digest(2),
+            "/r/slow: 2 fetch(es), mean 3.00s\n/r/medium: 1 fetch(es), mean 1.00s\nand 1 more"
+        );
+        assert_eq!(metrics.digest(3).lines().count(), 3);
+        assert_eq!(FetchMetrics::default().digest(10), "");
+    }
+
+    #[test]
+    fn test_render_rate_limit() {
+        let now = chrono::Utc::now();
+        let status = RateLimitStatus {
+            used: Some(5),
+            remaining: 95.0,
+            resets_at: now + chrono::Duration::seconds(200),
+        };
+        let rendered = render_rate_limit(&status, now);
+        assert!(rendered.contains("tgreddit_reddit_ratelimit_remaining 95\n"));
+        assert!(rendered.contains("tgreddit_reddit_ratelimit_reset_seconds 200\n"));
+        assert!(rendered.ends_with("tgreddit_reddit_ratelimit_used 5"));
+
+        let status = RateLimitStatus {
+            used: None,
+            resets_at: now - chrono::Duration::seconds(5),
+            ..status
+        };
+        let rendered = render_rate_limit(&status, now);
+        assert!(rendered.ends_with("tgreddit_reddit_ratelimit_reset_seconds 0"));
+    }
+}
@@ -1,16 +1,28 @@
 use super::*;
+use crate::config::Config;
 use anyhow::{Context, Result};
-use log::info;
+use log::{debug, info};
+use reqwest::{header, StatusCode};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
 use thiserror::Error;
 use url::Url;
 
 static REDDIT_BASE_URL: &str = "https://www.reddit.com";
+/// Maximum number of posts Reddit returns in one listing.
+pub const MAX_LISTING_LIMIT: u32 = 100;
+/// Subscribing to this name follows the personalized "best" feed of the authenticated user
+/// instead of a subreddit.
+pub const FRONTPAGE_SUBSCRIPTION: &str = "frontpage";
+/// The frontpage is only personalized for an authenticated user, and Reddit OAuth is not
+/// configurable yet, so subscribing to it is rejected.
+pub const FRONTPAGE_REQUIRES_OAUTH: &str =
+    "Following the frontpage requires Reddit OAuth, which is not configured";
 const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
-fn get_base_url() -> Url {
-    Url::parse(REDDIT_BASE_URL).unwrap()
-}
-
 pub fn format_url_from_path(path: &str, base_url: Option<&str>) -> String {
     let base_url = match base_url {
         Some(u) => u,
@@ -19,6 +31,33 @@ pub fn format_url_from_path(path: &str, base_url: Option<&str>) -> String {
     format!("{base_url}{path}")
 }
 
+/// Fullname of a post, as used by Reddit's `before` and `after` cursors and the info endpoint.
+pub fn format_fullname(post_id: &str) -> String {
+    format!("t3_{post_id}")
+}
+
+/// Url of a post's content reduced to `host/path?query`, for recognizing the same content posted
+/// to several subreddits. The scheme, fragment, a `www.` or `old.` host prefix and a trailing slash
+/// are left out. `None` if the url can't be parsed.
+pub fn normalize_url(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    let host = host
+        .strip_prefix("www.")
+        .or_else(|| host.strip_prefix("old."))
+        .unwrap_or(host);
+    let mut normalized = format!("{host}{}", url.path().trim_end_matches('/'));
+    if let Some(query) = url.query() {
+        normalized.push('?');
+        normalized.push_str(query);
+    }
+    Some(normalized)
+}
+
+pub fn is_frontpage(subreddit: &str) -> bool {
+    subreddit.eq_ignore_ascii_case(FRONTPAGE_SUBSCRIPTION)
+}
+
 pub fn to_old_reddit_url(url: &str) -> String {
     // If this fails it's bug
     let mut url = Url::parse(url).unwrap();
@@ -30,53 +69,40 @@ pub fn format_subreddit_url(subreddit: &str, base_url: Option<&str>) -> String {
     format_url_from_path(&format!("/r/{subreddit}"), base_url)
 }
 
-pub async fn get_subreddit_top_posts(
-    subreddit: &str,
-    limit: u32,
-    time: &TopPostsTimePeriod,
-) -> Result<Vec<Post>> {
-    info!("getting top posts for /r/{subreddit} limit={limit} time={time:?}");
-    let url = get_base_url()
-        .join(&format!("/r/{subreddit}/top.json"))
-        .unwrap();
-    let client = create_client().build()?;
-    let res = client
-        .get(url)
-        .query(&[
-            ("limit", &limit.to_string()),
-            ("t", &format!("{:?}", time).to_lowercase()),
-        ])
-        .send()
-        .await?
-        .error_for_status()?
-        .json::<ListingResponse>()
-        .await?;
-    let posts = res.data.children.into_iter().map(|e| e.data).collect();
-    Ok(posts)
+/// Url of a user's profile. The username is percent-encoded.
+pub fn format_user_url(username: &str, base_url: Option<&str>) -> String {
+    let mut url =
+        Url::parse(&format_url_from_path("/u/", base_url)).expect("base url should be a valid url");
+    url.path_segments_mut()
+        .expect("base url should be able to have a path")
+        .pop_if_empty()
+        .push(username);
+    url.to_string()
 }
 
-fn create_client() -> reqwest::ClientBuilder {
-    reqwest::Client::builder().user_agent(USER_AGENT)
+/// Extracts the post id from a Reddit post url, e.g.
+/// `https://www.reddit.com/r/rust/comments/abc123/some_title/` or `https://redd.it/abc123`.
+pub fn parse_post_id(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    let mut segments = url.path_segments()?.filter(|s| !s.is_empty());
+
+    let id = if host == "redd.it" {
+        segments.next()?
+    } else if host == "reddit.com" || host.ends_with(".reddit.com") {
+        segments.find(|s| *s == "comments")?;
+        segments.next()?
+    } else {
+        return None;
+    };
+
+    id.chars()
+        .all(|c| c.is_ascii_alphanumeric())
+        .then(|| id.to_string())
 }
 
-pub async fn get_link(link_id: &str) -> Result<Post> {
-    info!("getting link id {link_id}");
-    let url = get_base_url().join("/api/info.json")?;
-    let client = create_client().build()?;
-    let res = client
-        .get(url)
-        .query(&[("id", &format!("t3_{link_id}"))])
-        .send()
-        .await?
-        .json::<ListingResponse>()
-        .await?;
-
-    res.data
-        .children
-        .into_iter()
-        .map(|e| e.data)
-        .next()
-        .context("no post in response")
+fn create_client() -> reqwest::ClientBuilder {
+    reqwest::Client::builder().user_agent(USER_AGENT)
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -92,19 +118,468 @@ pub enum SubredditAboutError {
     IO(#[from] std::io::Error),
 }
 
-pub async fn get_subreddit_about(subreddit: &str) -> Result<SubredditAbout, SubredditAboutError> {
-    info!("getting subreddit about for /r/{subreddit}");
-    let client = create_client()
-        .redirect(reqwest::redirect::Policy::none())
-        .build()?;
-    let url = get_base_url().join(&format!("/r/{subreddit}/about.json"))?;
-    let res = client.get(url).send().await?.error_for_status()?;
+/// Parameters of a request for a subreddit's listing.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ListingQuery {
+    pub subreddit: String,
+    pub limit: u32,
+    pub sort: ListingSort,
+    /// Time period of a top listing. Ignored for other sorts.
+    pub time: TopPostsTimePeriod,
+    pub include_over_18: bool,
+}
+
+/// Source of subreddit listings. The poll loop depends on this rather than on the concrete client
+/// so that it can be tested without network access.
+pub trait RedditApi: Send + Sync {
+    fn fetch(&self, query: &ListingQuery) -> impl Future<Output = Result<Vec<Post>>> + Send;
+
+    /// The subreddit's first stickied post, e.g. its rules.
+    fn fetch_sticky(&self, subreddit: &str) -> impl Future<Output = Result<Option<Post>>> + Send;
+
+    /// The post's most upvoted comment, if it has any that can be shown.
+    fn fetch_top_comment(
+        &self,
+        post_id: &str,
+    ) -> impl Future<Output = Result<Option<Comment>>> + Send;
+}
+
+/// Posts of a previously fetched listing, with the validators Reddit sent for it.
+#[derive(Debug, Clone)]
+struct CachedListing {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    posts: Vec<Post>,
+}
+
+/// Listings fetched by the client, keyed by url, so that unchanged listings can be requested
+/// conditionally and reused when Reddit responds with `304 Not Modified`.
+#[derive(Debug, Default)]
+struct ListingCache {
+    listings: Mutex<HashMap<String, CachedListing>>,
+}
+
+impl ListingCache {
+    fn conditional_headers(&self, url: &str) -> Vec<(header::HeaderName, String)> {
+        let listings = self.listings.lock().expect("No poison");
+        let Some(cached) = listings.get(url) else {
+            return vec![];
+        };
+        [
+            (header::IF_NONE_MATCH, cached.etag.clone()),
+            (header::IF_MODIFIED_SINCE, cached.last_modified.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.map(|value| (name, value)))
+        .collect()
+    }
+
+    fn posts(&self, url: &str) -> Option<Vec<Post>> {
+        self.listings
+            .lock()
+            .expect("No poison")
+            .get(url)
+            .map(|cached| cached.posts.clone())
+    }
+
+    /// Listings without validators can't be requested conditionally, so they are not stored.
+    fn store(&self, url: &str, headers: &header::HeaderMap, posts: &[Post]) {
+        let header_value = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        let etag = header_value(header::ETAG);
+        let last_modified = header_value(header::LAST_MODIFIED);
+        let mut listings = self.listings.lock().expect("No poison");
+        if etag.is_none() && last_modified.is_none() {
+            listings.remove(url);
+            return;
+        }
+        listings.insert(
+            url.to_string(),
+            CachedListing {
+                etag,
+                last_modified,
+                posts: posts.to_vec(),
+            },
+        );
+    }
+}
+
+/// Reddit's rate limit for the client, as last reported in the `x-ratelimit-*` headers of a
+/// response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitStatus {
+    /// Requests made in the current period, if reported.
+    pub used: Option<u32>,
+    /// Requests left in the current period. Reddit reports this as a decimal number.
+    pub remaining: f64,
+    pub resets_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl RateLimitStatus {
+    /// Returns `None` unless the headers include both the remaining requests and the seconds until
+    /// the period resets.
+    pub fn from_headers(
+        headers: &header::HeaderMap,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Option<Self> {
+        fn header_value<T: std::str::FromStr>(
+            headers: &header::HeaderMap,
+            name: &str,
+        ) -> Option<T> {
+            headers.get(name)?.to_str().ok()?.trim().parse().ok()
+        }
+        let remaining = header_value::<f64>(headers, "x-ratelimit-remaining")?;
+        let reset_secs = header_value::<u32>(headers, "x-ratelimit-reset")?;
+        Some(RateLimitStatus {
+            used: header_value(headers, "x-ratelimit-used"),
+            remaining,
+            resets_at: now + chrono::Duration::seconds(reset_secs.into()),
+        })
+    }
+}
+
+/// Client for Reddit's JSON API, or an alternative frontend exposing the same API as configured
+/// with `reddit_base_url`.
+#[derive(Debug, Clone)]
+pub struct RedditClient {
+    base_url: Url,
+    client: reqwest::Client,
+    listing_cache: Arc<ListingCache>,
+    rate_limit: Arc<Mutex<Option<RateLimitStatus>>>,
+}
+
+impl RedditClient {
+    pub fn new(config: &Config) -> Result<Self> {
+        let base_url = config.reddit_base_url.as_deref().unwrap_or(REDDIT_BASE_URL);
+        Ok(RedditClient {
+            base_url: Url::parse(base_url).context("invalid reddit base url")?,
+            client: create_client().build()?,
+            listing_cache: Arc::default(),
+            rate_limit: Arc::default(),
+        })
+    }
+
+    /// The rate limit reported in the latest response that included one.
+    pub fn rate_limit(&self) -> Option<RateLimitStatus> {
+        self.rate_limit.lock().expect("No poison").clone()
+    }
+
+    fn record_rate_limit(&self, res: &reqwest::Response) {
+        if let Some(status) = RateLimitStatus::from_headers(res.headers(), chrono::Utc::now()) {
+            *self.rate_limit.lock().expect("No poison") = Some(status);
+        }
+    }
+
+    /// Builds the url of a subreddit listing, or of the best listing for the frontpage. Only top
+    /// listings of subreddits have a time period. `raw_json=1` makes Reddit return text without HTML entity encoding, and
+    /// `include_over_18=on` is needed for NSFW posts to be listed.
+    fn listing_url(&self, query: &ListingQuery) -> Result<Url> {
+        let mut url = if is_frontpage(&query.subreddit) {
+            self.base_url.join("/best.json")?
+        } else {
+            self.base_url
+                .join(&format!("/r/{}/{}.json", query.subreddit, query.sort))?
+        };
+        url.query_pairs_mut()
+            .append_pair("limit", &query.limit.to_string());
+        if !is_frontpage(&query.subreddit) && query.sort == ListingSort::Top {
+            url.query_pairs_mut()
+                .append_pair("t", &query.time.to_string());
+        }
+        url.query_pairs_mut().append_pair("raw_json", "1");
+        if query.include_over_18 {
+            url.query_pairs_mut().append_pair("include_over_18", "on");
+        }
+        Ok(url)
+    }
+
+    pub async fn get_subreddit_top_posts(&self, query: &ListingQuery) -> Result<Vec<Post>> {
+        info!(
+            "getting {} posts for /r/{} limit={} time={:?}",
+            query.sort, query.subreddit, query.limit, query.time
+        );
+        let url = self.listing_url(query)?;
+        let mut request = self.client.get(url.clone());
+        for (name, value) in self.listing_cache.conditional_headers(url.as_str()) {
+            request = request.header(name, value);
+        }
+        let res = request.send().await?;
+        self.record_rate_limit(&res);
+        let res = res.error_for_status()?;
+
+        if res.status() == StatusCode::NOT_MODIFIED {
+            debug!("top posts for /r/{} not modified", query.subreddit);
+            return self
+                .listing_cache
+                .posts(url.as_str())
+                .context("listing not modified but not cached");
+        }
+
+        let headers = res.headers().clone();
+        let posts = res.json::<ListingResponse>().await?.into_posts();
+        self.listing_cache.store(url.as_str(), &headers, &posts);
+        Ok(posts)
+    }
+
+    pub async fn get_link(&self, link_id: &str) -> Result<Post> {
+        info!("getting link id {link_id}");
+        let url = self.base_url.join("/api/info.json")?;
+        let res = self
+            .client
+            .get(url)
+            .query(&[("id", format_fullname(link_id).as_str()), ("raw_json", "1")])
+            .send()
+            .await?;
+        self.record_rate_limit(&res);
+        let res = res.json::<ListingResponse>().await?;
+
+        res.into_posts()
+            .into_iter()
+            .next()
+            .context("no post in response")
+    }
+
+    /// Stickied posts are at the top of the hot listing, which has at most two of them.
+    pub async fn get_sticky_post(&self, subreddit: &str) -> Result<Option<Post>> {
+        info!("getting sticky post of /r/{subreddit}");
+        let url = self.base_url.join(&format!("/r/{subreddit}/hot.json"))?;
+        let res = self
+            .client
+            .get(url)
+            .query(&[("limit", "2"), ("raw_json", "1")])
+            .send()
+            .await?;
+        self.record_rate_limit(&res);
+        let res = res.error_for_status()?.json::<ListingResponse>().await?;
+        Ok(res.into_first_sticky())
+    }
+
+    /// The post's most upvoted comment, if it has any that can be shown.
+    pub async fn get_top_comment(&self, post_id: &str) -> Result<Option<Comment>> {
+        info!("getting top comment of post id {post_id}");
+        let url = self.base_url.join(&format!("/comments/{post_id}.json"))?;
+        let res = self
+            .client
+            .get(url)
+            .query(&[("limit", "1"), ("sort", "top"), ("raw_json", "1")])
+            .send()
+            .await?;
+        self.record_rate_limit(&res);
+        let res = res.error_for_status()?.json::<CommentsResponse>().await?;
+        Ok(res.into_top_comment())
+    }
+
+    pub async fn get_subreddit_about(
+        &self,
+        subreddit: &str,
+    ) -> Result<SubredditAbout, SubredditAboutError> {
+        info!("getting subreddit about for /r/{subreddit}");
+        let client = create_client()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let url = self.base_url.join(&format!("/r/{subreddit}/about.json"))?;
+        let res = client.get(url).send().await?;
+        self.record_rate_limit(&res);
+        let res = res.error_for_status()?;
+
+        match res.status() {
+            reqwest::StatusCode::FOUND => Err(SubredditAboutError::NoSuchSubreddit),
+            _ => {
+                let data = res.json::<SubredditAboutResponse>().await?.data;
+                Ok(data)
+            }
+        }
+    }
+}
+
+impl RedditApi for RedditClient {
+    async fn fetch(&self, query: &ListingQuery) -> Result<Vec<Post>> {
+        self.get_subreddit_top_posts(query).await
+    }
+
+    async fn fetch_sticky(&self, subreddit: &str) -> Result<Option<Post>> {
+        self.get_sticky_post(subreddit).await
+    }
+
+    async fn fetch_top_comment(&self, post_id: &str) -> Result<Option<Comment>> {
+        self.get_top_comment(post_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listing_url() {
+        let client = RedditClient::new(&Config::default()).unwrap();
+        let mut query = ListingQuery {
+            subreddit: "rust".to_string(),
+            limit: 5,
+            sort: ListingSort::Top,
+            time: TopPostsTimePeriod::Week,
+            include_over_18: false,
+        };
+        assert_eq!(
+            client.listing_url(&query).unwrap().as_str(),
+            "https://www.reddit.com/r/rust/top.json?limit=5&t=week&raw_json=1"
+        );
+
+        query.include_over_18 = true;
+        assert_eq!(
+            client.listing_url(&query).unwrap().as_str(),
+            "https://www.reddit.com/r/rust/top.json?limit=5&t=week&raw_json=1&include_over_18=on"
+        );
+
+        query.sort = ListingSort::New;
+        query.include_over_18 = false;
+        assert_eq!(
+            client.listing_url(&query).unwrap().as_str(),
+            "https://www.reddit.com/r/rust/new.json?limit=5&raw_json=1"
+        );
+
+        query.subreddit = "Frontpage".to_string();
+        query.include_over_18 = false;
+        assert_eq!(
+            client.listing_url(&query).unwrap().as_str(),
+            "https://www.reddit.com/best.json?limit=5&raw_json=1"
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_status_from_headers() {
+        use chrono::TimeZone;
+
+        let now = chrono::Utc.with_ymd_and_hms(2023, 5, 1, 12, 0, 0).unwrap();
+        let mut headers = header::HeaderMap::new();
+        assert_eq!(RateLimitStatus::from_headers(&headers, now), None);
+
+        headers.insert("x-ratelimit-remaining", "95.0".parse().unwrap());
+        headers.insert("x-ratelimit-reset", "200".parse().unwrap());
+        assert_eq!(
+            RateLimitStatus::from_headers(&headers, now),
+            Some(RateLimitStatus {
+                used: None,
+                remaining: 95.0,
+                resets_at: now + chrono::Duration::seconds(200),
+            })
+        );
+
+        headers.insert("x-ratelimit-used", "5".parse().unwrap());
+        assert_eq!(
+            RateLimitStatus::from_headers(&headers, now).unwrap().used,
+            Some(5)
+        );
+
+        headers.insert("x-ratelimit-reset", "soon".parse().unwrap());
+        assert_eq!(RateLimitStatus::from_headers(&headers, now), None);
+    }
+
+    #[test]
+    fn test_is_frontpage() {
+        assert!(is_frontpage("frontpage"));
+        assert!(is_frontpage("FrontPage"));
+        assert!(!is_frontpage("frontpagememes"));
+        assert!(!is_frontpage("rust"));
+    }
 
-    match res.status() {
-        reqwest::StatusCode::FOUND => Err(SubredditAboutError::NoSuchSubreddit),
-        _ => {
-            let data = res.json::<SubredditAboutResponse>().await?.data;
-            Ok(data)
+    #[test]
+    fn test_format_user_url() {
+        assert_eq!(
+            format_user_url("some_user-1", None),
+            "https://www.reddit.com/u/some_user-1"
+        );
+        assert_eq!(
+            format_user_url("a b/c?d", Some("https://teddit.net")),
+            "https://teddit.net/u/a%20b%2Fc%3Fd"
+        );
+    }
+
+    #[test]
+    fn test_parse_post_id() {
+        for url in [
+            "https://www.reddit.com/r/rust/comments/abc123/some_title/",
+            "https://www.reddit.com/r/rust/comments/abc123/some_title/?utm_source=share",
+            "https://old.reddit.com/r/rust/comments/abc123/",
+            "https://reddit.com/r/rust/comments/abc123",
+            "https://www.reddit.com/comments/abc123",
+            "https://redd.it/abc123",
+        ] {
+            assert_eq!(parse_post_id(url), Some("abc123".to_string()), "{url}");
         }
+
+        assert_eq!(parse_post_id("https://www.reddit.com/r/rust/"), None);
+        assert_eq!(
+            parse_post_id("https://example.com/r/rust/comments/abc123"),
+            None
+        );
+        assert_eq!(parse_post_id("abc123"), None);
+    }
+
+    #[tokio::test]
+    async fn test_not_modified_listing_is_reused() {
+        use axum::{
+            http::{HeaderMap, StatusCode},
+            response::IntoResponse,
+            routing::get,
+            Router,
+        };
+
+        const LISTING: &str = r#"{"kind": "Listing", "data": {"after": null, "dist": 1, "children": [
+            {"kind": "t3", "data": {
+                "id": "post0",
+                "created": 1684000000.0,
+                "created_utc": 1684000000.0,
+                "subreddit": "rust",
+                "title": "Post 0",
+                "is_video": false,
+                "ups": 10,
+                "permalink": "/r/rust/comments/post0/",
+                "url": "https://example.com/0",
+                "is_self": false
+            }}
+        ]}}"#;
+
+        // Responds to a matching If-None-Match with an empty body, which would fail to parse
+        async fn listing(headers: HeaderMap) -> axum::response::Response {
+            if headers.get(header::IF_NONE_MATCH).map(|v| v.as_bytes()) == Some(b"\"v1\"") {
+                return StatusCode::NOT_MODIFIED.into_response();
+            }
+            ([(header::ETAG, "\"v1\"")], LISTING).into_response()
+        }
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/r/rust/top.json", get(listing));
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let client = RedditClient::new(&Config {
+            reddit_base_url: Some(format!("http://{addr}")),
+            ..Default::default()
+        })
+        .unwrap();
+        let query = ListingQuery {
+            subreddit: "rust".to_string(),
+            limit: 1,
+            sort: ListingSort::Top,
+            time: TopPostsTimePeriod::Day,
+            include_over_18: false,
+        };
+
+        let posts = client.get_subreddit_top_posts(&query).await.unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].id, "post0");
+
+        let cached = client.get_subreddit_top_posts(&query).await.unwrap();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].id, "post0");
     }
 }
@@ -4,11 +4,24 @@ use crate::db::Recordable;
 
 use super::*;
 use anyhow::{Context, Result};
-use serde::{Deserialize, Deserializer};
-use strum_macros::{Display, EnumString};
+use serde::{Deserialize, Deserializer, Serialize};
+use strum_macros::{Display, EnumIter, EnumString};
 use url::Url;
 
-#[derive(Display, Debug, Clone, PartialEq, Hash, Eq, Deserialize, Copy, EnumString)]
+#[derive(
+    Display,
+    Debug,
+    Default,
+    Clone,
+    PartialEq,
+    Hash,
+    Eq,
+    Serialize,
+    Deserialize,
+    Copy,
+    EnumString,
+    EnumIter,
+)]
 #[serde(rename_all = "snake_case")]
 #[strum(serialize_all = "snake_case")]
 pub enum PostType {
@@ -17,28 +30,146 @@ pub enum PostType {
     Link,
     SelfText,
     Gallery,
+    #[default]
     Unknown,
 }
 
-#[derive(Display, Debug, Clone, PartialEq, Hash, Eq, Deserialize, Copy, EnumString)]
+impl PostType {
+    pub fn description(&self) -> &'static str {
+        match self {
+            PostType::Image => "image posts, including gifs",
+            PostType::Video => "videos hosted on reddit or elsewhere, e.g. imgur gifv",
+            PostType::Link => "links to external sites",
+            PostType::SelfText => "text posts",
+            PostType::Gallery => "reddit image galleries",
+            PostType::Unknown => "posts whose type could not be determined",
+        }
+    }
+
+    /// Whether the post's media was resolved to something that is sent as media rather than as a
+    /// link or text.
+    pub fn has_media(&self) -> bool {
+        match self {
+            PostType::Image | PostType::Video | PostType::Gallery => true,
+            PostType::Link | PostType::SelfText | PostType::Unknown => false,
+        }
+    }
+}
+
+/// Order of a subreddit listing. Only top listings are limited to a time period.
+#[derive(
+    Display, Debug, Default, Clone, PartialEq, Hash, Eq, Serialize, Deserialize, Copy, EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case", ascii_case_insensitive)]
+pub enum ListingSort {
+    #[default]
+    Top,
+    New,
+    Hot,
+    Rising,
+}
+
+#[derive(Display, Debug, Clone, PartialEq, Hash, Eq, Serialize, Deserialize, Copy, EnumString)]
 #[serde(rename_all = "snake_case")]
 #[strum(serialize_all = "snake_case")]
+/// Besides the canonical names used by Reddit, common aliases like `24h` or `today` are accepted
+/// when parsing. Periods are always displayed and stored in their canonical form.
 pub enum TopPostsTimePeriod {
+    #[strum(to_string = "hour", serialize = "1h", serialize = "hourly")]
+    #[serde(alias = "1h", alias = "hourly")]
     Hour,
+    #[strum(
+        to_string = "day",
+        serialize = "24h",
+        serialize = "1d",
+        serialize = "today",
+        serialize = "daily"
+    )]
+    #[serde(alias = "24h", alias = "1d", alias = "today", alias = "daily")]
     Day,
+    #[strum(
+        to_string = "week",
+        serialize = "7d",
+        serialize = "1w",
+        serialize = "weekly"
+    )]
+    #[serde(alias = "7d", alias = "1w", alias = "weekly")]
     Week,
+    #[strum(
+        to_string = "month",
+        serialize = "30d",
+        serialize = "1m",
+        serialize = "monthly"
+    )]
+    #[serde(alias = "30d", alias = "1m", alias = "monthly")]
     Month,
+    #[strum(
+        to_string = "year",
+        serialize = "365d",
+        serialize = "1y",
+        serialize = "yearly"
+    )]
+    #[serde(alias = "365d", alias = "1y", alias = "yearly")]
     Year,
+    #[strum(to_string = "all", serialize = "all_time", serialize = "alltime")]
+    #[serde(alias = "all_time", alias = "alltime")]
     All,
 }
 
+/// Reddit HTML-encodes URLs in its JSON, e.g. `&` becomes `&amp;`, which breaks fetching them.
+pub fn decode_html_entities(url: &str) -> String {
+    url.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Every URL field in Reddit's responses should be deserialized with this.
+fn deserialize_url<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let url = String::deserialize(deserializer)?;
+    Ok(decode_html_entities(&url))
+}
+
+fn deserialize_optional_url<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let url = Option::<String>::deserialize(deserializer)?;
+    Ok(url.as_deref().map(decode_html_entities))
+}
+
+/// Reddit uses placeholder values like "self" or "nsfw" in the thumbnail field when there's no
+/// actual thumbnail to show.
+pub fn is_valid_thumbnail(thumbnail: &str) -> bool {
+    thumbnail.starts_with("http://") || thumbnail.starts_with("https://")
+}
+
 #[derive(Deserialize, Debug)]
 pub struct ListingResponse {
     pub data: ListingResponseData,
 }
 
+impl ListingResponse {
+    /// Posts in the listing. Reddit may return fewer posts than requested, or none at all, e.g.
+    /// for a subreddit with only a few posts in the time period.
+    pub fn into_posts(self) -> Vec<Post> {
+        self.data.children.into_iter().map(|e| e.data).collect()
+    }
+
+    /// The first post pinned by the subreddit's moderators, if any.
+    pub fn into_first_sticky(self) -> Option<Post> {
+        self.into_posts().into_iter().find(|post| post.stickied)
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct ListingResponseData {
+    #[serde(default)]
     pub children: Vec<ListingItem>,
 }
 
@@ -59,11 +190,26 @@ pub struct GalleryData {
     pub items: Vec<GalleryDataItem>,
 }
 
+/// An option of a poll post.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PollOption {
+    pub text: String,
+    /// Reddit only tells the votes of each option once voting has ended.
+    pub vote_count: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PollData {
+    pub options: Vec<PollOption>,
+    #[serde(default)]
+    pub total_vote_count: u64,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Media {
     pub x: u16,
     pub y: u16,
-    #[serde(rename = "u", alias = "gif")]
+    #[serde(rename = "u", alias = "gif", deserialize_with = "deserialize_url")]
     pub url: String,
 }
 
@@ -76,23 +222,60 @@ pub struct MediaMetadata {
     pub s: Option<Media>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Deserialize, Debug, Clone)]
+pub struct PreviewSource {
+    #[serde(deserialize_with = "deserialize_url")]
+    pub url: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PreviewImage {
+    pub source: PreviewSource,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Preview {
+    pub images: Vec<PreviewImage>,
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct Post {
     pub id: String,
+    /// Fullname of the post, i.e. the id prefixed with the `t3_` kind, used by Reddit's listing
+    /// cursors.
+    pub name: String,
     pub created: f32,
+    pub created_utc: f64,
     pub subreddit: String,
     pub title: String,
+    pub author: String,
+    pub link_flair_text: Option<String>,
     pub is_video: bool,
     pub ups: u32,
+    /// Share of votes that are upvotes, from 0.0 to 1.0. Low ratios indicate a controversial post.
+    pub upvote_ratio: f64,
     pub permalink: String,
     pub url: String,
     pub post_hint: Option<String>,
     pub is_self: bool,
     pub is_gallery: Option<bool>,
+    pub is_original_content: bool,
+    pub over_18: bool,
+    /// Pinned to the top of the subreddit by its moderators.
+    pub stickied: bool,
+    /// Marked as a spoiler on Reddit.
+    pub spoiler: bool,
+    pub selftext: String,
     pub post_type: PostType,
     pub crosspost_parent_list: Option<Vec<Post>>,
     pub gallery_data: Option<GalleryData>,
+    /// Options of a poll post.
+    pub poll_data: Option<PollData>,
     pub media_metadata: Option<HashMap<String, MediaMetadata>>,
+    pub thumbnail: Option<String>,
+    pub preview: Option<Preview>,
+    /// Top comment of the post, only fetched for subscriptions that include it in captions.
+    pub top_comment: Option<Comment>,
 }
 
 impl<'de> Deserialize<'de> for Post {
@@ -103,19 +286,44 @@ impl<'de> Deserialize<'de> for Post {
         #[derive(Deserialize)]
         pub struct PostHelper {
             pub id: String,
+            #[serde(default)]
+            pub name: String,
             pub created: f32,
+            pub created_utc: f64,
             pub subreddit: String,
             pub title: String,
+            #[serde(default)]
+            pub author: String,
+            pub link_flair_text: Option<String>,
             pub is_video: bool,
             pub ups: u32,
+            // Listings always include the ratio, so a missing ratio shouldn't make the post look
+            // controversial
+            #[serde(default = "default_upvote_ratio")]
+            pub upvote_ratio: f64,
             pub permalink: String,
+            #[serde(deserialize_with = "deserialize_url")]
             pub url: String,
             pub post_hint: Option<String>,
             pub is_self: bool,
             pub is_gallery: Option<bool>,
+            #[serde(default)]
+            pub is_original_content: bool,
+            #[serde(default)]
+            pub over_18: bool,
+            #[serde(default)]
+            pub stickied: bool,
+            #[serde(default)]
+            pub spoiler: bool,
+            #[serde(default)]
+            pub selftext: String,
             pub crosspost_parent_list: Option<Vec<Post>>,
             pub gallery_data: Option<GalleryData>,
+            pub poll_data: Option<PollData>,
             pub media_metadata: Option<HashMap<String, MediaMetadata>>,
+            #[serde(default, deserialize_with = "deserialize_optional_url")]
+            pub thumbnail: Option<String>,
+            pub preview: Option<Preview>,
         }
 
         impl PostHelper {
@@ -164,24 +372,42 @@ impl<'de> Deserialize<'de> for Post {
 
         Ok(Post {
             id: helper.id,
+            name: helper.name,
             created: helper.created,
+            created_utc: helper.created_utc,
             subreddit: helper.subreddit,
             title: helper.title,
+            author: helper.author,
+            link_flair_text: helper.link_flair_text,
             is_video: helper.is_video,
             ups: helper.ups,
+            upvote_ratio: helper.upvote_ratio,
             permalink: helper.permalink,
             url: helper.url,
             post_hint: helper.post_hint,
             is_self: helper.is_self,
             crosspost_parent_list: helper.crosspost_parent_list,
             is_gallery: helper.is_gallery,
+            is_original_content: helper.is_original_content,
+            over_18: helper.over_18,
+            stickied: helper.stickied,
+            spoiler: helper.spoiler,
+            selftext: helper.selftext,
             post_type,
             gallery_data: helper.gallery_data,
+            poll_data: helper.poll_data,
             media_metadata: helper.media_metadata,
+            thumbnail: helper.thumbnail,
+            preview: helper.preview,
+            top_comment: None,
         })
     }
 }
 
+fn default_upvote_ratio() -> f64 {
+    1.0
+}
+
 impl Post {
     pub(crate) fn format_permalink_url(&self, base_url: Option<&str>) -> String {
         format_url_from_path(&self.permalink, base_url)
@@ -190,6 +416,25 @@ impl Post {
     pub(crate) fn format_old_permalink_url(&self) -> String {
         to_old_reddit_url(&format_url_from_path(&self.permalink, None))
     }
+
+    pub fn is_created_since(&self, since: &chrono::DateTime<chrono::Utc>) -> bool {
+        self.created_utc >= since.timestamp() as f64
+    }
+
+    /// Image that can be shown as a visual for the post, preferring the full size preview over
+    /// the small thumbnail.
+    pub fn thumbnail_url(&self) -> Option<&str> {
+        let preview_url = self
+            .preview
+            .as_ref()
+            .and_then(|preview| preview.images.first())
+            .map(|image| image.source.url.as_str());
+        let thumbnail_url = self
+            .thumbnail
+            .as_deref()
+            .filter(|thumbnail| is_valid_thumbnail(thumbnail));
+        preview_url.or(thumbnail_url)
+    }
 }
 
 impl Recordable for Post {
@@ -197,6 +442,18 @@ impl Recordable for Post {
         &self.id
     }
 
+    fn fullname(&self) -> Option<String> {
+        if self.name.is_empty() {
+            Some(format_fullname(&self.id))
+        } else {
+            Some(self.name.clone())
+        }
+    }
+
+    fn normalized_url(&self) -> Option<String> {
+        normalize_url(&self.url)
+    }
+
     fn title(&self) -> &str {
         &self.title
     }
@@ -206,6 +463,61 @@ impl Recordable for Post {
     }
 }
 
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Comment {
+    #[serde(default)]
+    pub author: String,
+    pub body: String,
+    pub permalink: String,
+    #[serde(default)]
+    pub stickied: bool,
+}
+
+impl Comment {
+    pub fn format_permalink_url(&self, base_url: Option<&str>) -> String {
+        format_url_from_path(&self.permalink, base_url)
+    }
+}
+
+/// Response of `/comments/<id>.json`: a listing with the post, followed by a listing of its
+/// comments.
+#[derive(Deserialize, Debug)]
+pub struct CommentsResponse(serde::de::IgnoredAny, CommentListing);
+
+#[derive(Deserialize, Debug)]
+struct CommentListing {
+    data: CommentListingData,
+}
+
+#[derive(Deserialize, Debug)]
+struct CommentListingData {
+    #[serde(default)]
+    children: Vec<CommentListingItem>,
+}
+
+/// Comments have the `t1` kind. Listings may also contain `more` items, which link to comments
+/// that were left out.
+#[derive(Deserialize, Debug)]
+struct CommentListingItem {
+    kind: String,
+    data: serde_json::Value,
+}
+
+impl CommentsResponse {
+    /// The first comment that is not stickied, deleted or removed.
+    pub fn into_top_comment(self) -> Option<Comment> {
+        self.1
+            .data
+            .children
+            .into_iter()
+            .filter(|item| item.kind == "t1")
+            .filter_map(|item| serde_json::from_value::<Comment>(item.data).ok())
+            .find(|comment| {
+                !comment.stickied && comment.body != "[deleted]" && comment.body != "[removed]"
+            })
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct SubredditAboutResponse {
     pub data: SubredditAbout,
@@ -214,5 +526,319 @@ pub struct SubredditAboutResponse {
 #[derive(Deserialize, Debug)]
 pub struct SubredditAbout {
     pub display_name: String,
-    pub display_name_prefixed: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_top_posts_time_period_aliases() {
+        let cases = [
+            ("hour", TopPostsTimePeriod::Hour),
+            ("1h", TopPostsTimePeriod::Hour),
+            ("hourly", TopPostsTimePeriod::Hour),
+            ("day", TopPostsTimePeriod::Day),
+            ("24h", TopPostsTimePeriod::Day),
+            ("1d", TopPostsTimePeriod::Day),
+            ("today", TopPostsTimePeriod::Day),
+            ("daily", TopPostsTimePeriod::Day),
+            ("week", TopPostsTimePeriod::Week),
+            ("7d", TopPostsTimePeriod::Week),
+            ("1w", TopPostsTimePeriod::Week),
+            ("weekly", TopPostsTimePeriod::Week),
+            ("month", TopPostsTimePeriod::Month),
+            ("30d", TopPostsTimePeriod::Month),
+            ("1m", TopPostsTimePeriod::Month),
+            ("monthly", TopPostsTimePeriod::Month),
+            ("year", TopPostsTimePeriod::Year),
+            ("365d", TopPostsTimePeriod::Year),
+            ("1y", TopPostsTimePeriod::Year),
+            ("yearly", TopPostsTimePeriod::Year),
+            ("all", TopPostsTimePeriod::All),
+            ("all_time", TopPostsTimePeriod::All),
+            ("alltime", TopPostsTimePeriod::All),
+        ];
+        for (alias, period) in cases {
+            assert_eq!(TopPostsTimePeriod::from_str(alias).unwrap(), period);
+            assert_eq!(
+                serde_json::from_str::<TopPostsTimePeriod>(&format!("\"{alias}\"")).unwrap(),
+                period
+            );
+        }
+        assert!(TopPostsTimePeriod::from_str("fortnight").is_err());
+
+        assert_eq!(TopPostsTimePeriod::Hour.to_string(), "hour");
+        assert_eq!(TopPostsTimePeriod::Day.to_string(), "day");
+        assert_eq!(TopPostsTimePeriod::Week.to_string(), "week");
+        assert_eq!(TopPostsTimePeriod::Month.to_string(), "month");
+        assert_eq!(TopPostsTimePeriod::Year.to_string(), "year");
+        assert_eq!(TopPostsTimePeriod::All.to_string(), "all");
+        assert_eq!(
+            serde_json::to_string(&TopPostsTimePeriod::Day).unwrap(),
+            "\"day\""
+        );
+    }
+
+    #[test]
+    fn test_decode_html_entities() {
+        assert_eq!(
+            decode_html_entities("https://preview.redd.it/abc.jpg?width=640&amp;s=123abc"),
+            "https://preview.redd.it/abc.jpg?width=640&s=123abc"
+        );
+        assert_eq!(
+            decode_html_entities("https://i.redd.it/abc.jpg"),
+            "https://i.redd.it/abc.jpg"
+        );
+    }
+
+    #[test]
+    fn test_is_valid_thumbnail() {
+        assert!(is_valid_thumbnail(
+            "https://b.thumbs.redditmedia.com/abc.jpg"
+        ));
+        assert!(!is_valid_thumbnail("self"));
+        assert!(!is_valid_thumbnail("default"));
+        assert!(!is_valid_thumbnail("nsfw"));
+        assert!(!is_valid_thumbnail("spoiler"));
+        assert!(!is_valid_thumbnail(""));
+    }
+
+    #[test]
+    fn test_media_url_is_decoded() {
+        let media: Media = serde_json::from_str(
+            r#"{"x": 640, "y": 480, "u": "https://preview.redd.it/abc.jpg?width=640&amp;s=123abc"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            media.url,
+            "https://preview.redd.it/abc.jpg?width=640&s=123abc"
+        );
+    }
+
+    #[test]
+    fn test_post_is_original_content() {
+        let json = r#"{
+            "id": "abc123",
+            "created": 1684000000.0,
+            "created_utc": 1684000000.0,
+            "subreddit": "pics",
+            "title": "I made this",
+            "is_video": false,
+            "ups": 100,
+            "permalink": "/r/pics/comments/abc123/i_made_this/",
+            "url": "https://i.redd.it/abc123.jpg",
+            "is_self": false,
+            "is_original_content": true
+        }"#;
+        let post: Post = serde_json::from_str(json).unwrap();
+        assert!(post.is_original_content);
+        assert_eq!(post.name, "");
+        assert_eq!(post.fullname().unwrap(), "t3_abc123");
+
+        let json = json.replace(",\n            \"is_original_content\": true", "");
+        let post: Post = serde_json::from_str(&json).unwrap();
+        assert!(!post.is_original_content);
+    }
+
+    #[test]
+    fn test_post_spoiler() {
+        let json = r#"{
+            "id": "abc123",
+            "created": 1684000000.0,
+            "created_utc": 1684000000.0,
+            "subreddit": "television",
+            "title": "Season finale",
+            "is_video": false,
+            "ups": 100,
+            "permalink": "/r/television/comments/abc123/season_finale/",
+            "url": "https://i.redd.it/abc123.jpg",
+            "is_self": false,
+            "spoiler": true
+        }"#;
+        let post: Post = serde_json::from_str(json).unwrap();
+        assert!(post.spoiler);
+
+        let json = json.replace(",\n            \"spoiler\": true", "");
+        let post: Post = serde_json::from_str(&json).unwrap();
+        assert!(!post.spoiler);
+    }
+
+    #[test]
+    fn test_post_poll_data() {
+        let json = r#"{
+            "id": "abc123",
+            "created": 1684000000.0,
+            "created_utc": 1684000000.0,
+            "subreddit": "rust",
+            "title": "Which edition do you use?",
+            "is_video": false,
+            "ups": 100,
+            "permalink": "/r/rust/comments/abc123/which_edition_do_you_use/",
+            "url": "https://www.reddit.com/r/rust/comments/abc123/which_edition_do_you_use/",
+            "is_self": true,
+            "poll_data": {
+                "prediction_status": null,
+                "total_stake_amount": null,
+                "voting_end_timestamp": 1684600000000,
+                "options": [
+                    { "text": "2021", "id": "1", "vote_count": 30 },
+                    { "text": "2018", "id": "2", "vote_count": 12 }
+                ],
+                "vote_updates_remained": null,
+                "is_prediction": false,
+                "resolved_option_id": null,
+                "user_won_amount": null,
+                "user_selection": null,
+                "total_vote_count": 42,
+                "tournament_id": null
+            }
+        }"#;
+        let post: Post = serde_json::from_str(json).unwrap();
+        let poll = post.poll_data.unwrap();
+        assert_eq!(
+            poll.options,
+            vec![
+                PollOption {
+                    text: "2021".to_string(),
+                    vote_count: Some(30)
+                },
+                PollOption {
+                    text: "2018".to_string(),
+                    vote_count: Some(12)
+                },
+            ]
+        );
+        assert_eq!(poll.total_vote_count, 42);
+
+        // Votes of each option are missing while voting is open
+        let json = json.replace(r#", "vote_count": 30"#, "");
+        let post: Post = serde_json::from_str(&json).unwrap();
+        assert_eq!(post.poll_data.unwrap().options[0].vote_count, None);
+    }
+
+    #[test]
+    fn test_post_upvote_ratio() {
+        let json = r#"{
+            "id": "abc123",
+            "created": 1684000000.0,
+            "created_utc": 1684000000.0,
+            "subreddit": "pics",
+            "title": "Hot take",
+            "is_video": false,
+            "ups": 100,
+            "upvote_ratio": 0.55,
+            "permalink": "/r/pics/comments/abc123/hot_take/",
+            "url": "https://i.redd.it/abc123.jpg",
+            "is_self": false
+        }"#;
+        let post: Post = serde_json::from_str(json).unwrap();
+        assert_eq!(post.upvote_ratio, 0.55);
+
+        let json = json.replace("\n            \"upvote_ratio\": 0.55,", "");
+        let post: Post = serde_json::from_str(&json).unwrap();
+        assert_eq!(post.upvote_ratio, 1.0);
+    }
+
+    #[test]
+    fn test_post_name() {
+        let json = r#"{
+            "id": "abc123",
+            "name": "t3_abc123",
+            "created": 1684000000.0,
+            "created_utc": 1684000000.0,
+            "subreddit": "pics",
+            "title": "I made this",
+            "is_video": false,
+            "ups": 100,
+            "permalink": "/r/pics/comments/abc123/i_made_this/",
+            "url": "https://i.redd.it/abc123.jpg",
+            "is_self": false
+        }"#;
+        let post: Post = serde_json::from_str(json).unwrap();
+        assert_eq!(post.name, "t3_abc123");
+        assert_eq!(post.fullname().unwrap(), "t3_abc123");
+    }
+
+    fn listing_json(post_count: usize) -> String {
+        let children = (0..post_count)
+            .map(|i| {
+                format!(
+                    r#"{{"kind": "t3", "data": {{
+                        "id": "post{i}",
+                        "created": 1684000000.0,
+                        "created_utc": 1684000000.0,
+                        "subreddit": "rust",
+                        "title": "Post {i}",
+                        "is_video": false,
+                        "ups": 10,
+                        "permalink": "/r/rust/comments/post{i}/",
+                        "url": "https://example.com/{i}",
+                        "is_self": false
+                    }}}}"#
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            r#"{{"kind": "Listing", "data": {{"after": null, "dist": {post_count}, "children": [{children}]}}}}"#
+        )
+    }
+
+    #[test]
+    fn test_short_listings() {
+        let listing: ListingResponse = serde_json::from_str(&listing_json(0)).unwrap();
+        assert!(listing.into_posts().is_empty());
+
+        let listing: ListingResponse = serde_json::from_str(&listing_json(3)).unwrap();
+        let posts = listing.into_posts();
+        assert_eq!(posts.len(), 3);
+        assert_eq!(posts[2].id, "post2");
+
+        let listing: ListingResponse =
+            serde_json::from_str(r#"{"kind": "Listing", "data": {"after": null}}"#).unwrap();
+        assert!(listing.into_posts().is_empty());
+    }
+
+    #[test]
+    fn test_comments_response_top_comment() {
+        let json = r#"[
+            {"kind": "Listing", "data": {"children": [{"kind": "t3", "data": {"id": "abc123"}}]}},
+            {"kind": "Listing", "data": {"children": [
+                {"kind": "t1", "data": {
+                    "author": "AutoModerator",
+                    "body": "Please read the rules",
+                    "permalink": "/r/rust/comments/abc123/title/mod1/",
+                    "stickied": true
+                }},
+                {"kind": "t1", "data": {
+                    "author": "ferris",
+                    "body": "Great post & <thanks>",
+                    "permalink": "/r/rust/comments/abc123/title/def456/",
+                    "stickied": false
+                }},
+                {"kind": "more", "data": {"count": 10, "children": ["ghi789"]}}
+            ]}}
+        ]"#;
+        let response: CommentsResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            response.into_top_comment(),
+            Some(Comment {
+                author: "ferris".to_string(),
+                body: "Great post & <thanks>".to_string(),
+                permalink: "/r/rust/comments/abc123/title/def456/".to_string(),
+                stickied: false,
+            })
+        );
+
+        let json = r#"[
+            {"kind": "Listing", "data": {"children": []}},
+            {"kind": "Listing", "data": {"children": [
+                {"kind": "t1", "data": {"body": "[deleted]", "permalink": "/r/rust/comments/abc123/title/x/"}}
+            ]}}
+        ]"#;
+        let response: CommentsResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(response.into_top_comment(), None);
+    }
 }
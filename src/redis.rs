@@ -0,0 +1,225 @@
+use ::redis::aio::ConnectionManager;
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use log::info;
+use std::future::Future;
+
+use crate::{db::Database, reddit::Post};
+
+/// How long the seen posts of a chat are kept in Redis after the chat was last checked. Chats that
+/// are no longer polled eventually drop out of Redis.
+const SEEN_TTL_SECS: usize = 30 * 24 * 60 * 60;
+/// How many post ids are added to Redis in one command when backfilling.
+const BACKFILL_BATCH_SIZE: usize = 1000;
+
+/// The Redis commands needed to keep track of seen posts. The seen posts of each chat are a set.
+pub trait RedisCommands: Send + Sync {
+    /// Whether `member` is in the set, pushing back when the set expires.
+    fn is_member(
+        &self,
+        key: &str,
+        member: &str,
+        ttl_secs: usize,
+    ) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Adds the members to the set, pushing back when the set expires.
+    fn add_members(
+        &self,
+        key: &str,
+        members: &[&str],
+        ttl_secs: usize,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+impl RedisCommands for ConnectionManager {
+    async fn is_member(&self, key: &str, member: &str, ttl_secs: usize) -> Result<bool> {
+        // The connection manager is a cheap handle to a shared, reconnecting connection
+        let mut conn = self.clone();
+        let (is_member,): (bool,) = ::redis::pipe()
+            .sismember(key, member)
+            .expire(key, ttl_secs)
+            .ignore()
+            .query_async(&mut conn)
+            .await?;
+        Ok(is_member)
+    }
+
+    async fn add_members(&self, key: &str, members: &[&str], ttl_secs: usize) -> Result<()> {
+        let mut conn = self.clone();
+        ::redis::pipe()
+            .sadd(key, members)
+            .ignore()
+            .expire(key, ttl_secs)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Seen posts in Redis, next to the database. Posts are still recorded in the database, which
+/// is copied to Redis at startup. Posts Redis doesn't know are looked up in the database, as
+/// posts sent outside the poll loop are only recorded there.
+#[derive(Clone)]
+pub struct RedisSeenPosts<C> {
+    redis: C,
+}
+
+impl RedisSeenPosts<ConnectionManager> {
+    /// Connects to a `redis://[:password@]host[:port][/db]` url and backfills the posts seen
+    /// according to the database.
+    pub async fn connect(url: &str, db: &Database) -> Result<Self> {
+        let client = ::redis::Client::open(url).context("invalid redis url")?;
+        let redis = ConnectionManager::new(client)
+            .await
+            .context("could not connect to redis")?;
+        let seen = RedisSeenPosts::new(redis);
+        seen.backfill(db).await?;
+        Ok(seen)
+    }
+}
+
+fn seen_key(chat_id: i64) -> String {
+    format!("tgreddit:seen:{chat_id}")
+}
+
+impl<C: RedisCommands> RedisSeenPosts<C> {
+    pub fn new(redis: C) -> Self {
+        RedisSeenPosts { redis }
+    }
+
+    /// Adds every post seen according to the database to Redis.
+    pub async fn backfill(&self, db: &Database) -> Result<()> {
+        let seen = db.get_seen_post_ids()?;
+        info!("backfilling {} seen posts to redis", seen.len());
+        for (chat_id, post_ids) in seen.into_iter().into_group_map() {
+            let key = seen_key(chat_id);
+            for batch in post_ids.chunks(BACKFILL_BATCH_SIZE) {
+                let post_ids = batch.iter().map(String::as_str).collect_vec();
+                self.redis
+                    .add_members(&key, &post_ids, SEEN_TTL_SECS)
+                    .await
+                    .context("could not backfill seen posts to redis")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the post was seen according to Redis, or else the database. Posts only found in
+    /// the database are added to Redis.
+    pub async fn is_post_seen(&self, db: &Database, chat_id: i64, post: &Post) -> Result<bool> {
+        let key = seen_key(chat_id);
+        if self.redis.is_member(&key, &post.id, SEEN_TTL_SECS).await? {
+            return Ok(true);
+        }
+        if !db.is_post_seen(chat_id, post)? {
+            return Ok(false);
+        }
+        self.redis
+            .add_members(&key, &[&post.id], SEEN_TTL_SECS)
+            .await?;
+        Ok(true)
+    }
+
+    pub async fn record_post_seen(&self, db: &Database, chat_id: i64, post: &Post) -> Result<()> {
+        db.record_post_seen_with_current_time(chat_id, post)?;
+        self.redis
+            .add_members(&seen_key(chat_id), &[&post.id], SEEN_TTL_SECS)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Mutex,
+    };
+
+    #[derive(Default)]
+    struct FakeRedis {
+        sets: Mutex<HashMap<String, HashSet<String>>>,
+        ttls: Mutex<HashMap<String, usize>>,
+    }
+
+    impl RedisCommands for FakeRedis {
+        async fn is_member(&self, key: &str, member: &str, ttl_secs: usize) -> Result<bool> {
+            self.ttls.lock().unwrap().insert(key.to_string(), ttl_secs);
+            Ok(self
+                .sets
+                .lock()
+                .unwrap()
+                .get(key)
+                .is_some_and(|set| set.contains(member)))
+        }
+
+        async fn add_members(&self, key: &str, members: &[&str], ttl_secs: usize) -> Result<()> {
+            self.ttls.lock().unwrap().insert(key.to_string(), ttl_secs);
+            self.sets
+                .lock()
+                .unwrap()
+                .entry(key.to_string())
+                .or_default()
+                .extend(members.iter().map(|member| member.to_string()));
+            Ok(())
+        }
+    }
+
+    fn post(id: &str) -> Post {
+        Post {
+            id: id.to_string(),
+            subreddit: "rust".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redis_seen_posts() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        let seen = RedisSeenPosts::new(FakeRedis::default());
+
+        assert!(!seen.is_post_seen(&db, 1, &post("a")).await.unwrap());
+        seen.record_post_seen(&db, 1, &post("a")).await.unwrap();
+        assert!(seen.is_post_seen(&db, 1, &post("a")).await.unwrap());
+        assert!(!seen.is_post_seen(&db, 2, &post("a")).await.unwrap());
+        assert!(db.is_post_seen(1, &post("a")).unwrap());
+        assert_eq!(
+            seen.redis.ttls.lock().unwrap().get("tgreddit:seen:1"),
+            Some(&SEEN_TTL_SECS)
+        );
+
+        // Posts sent outside the poll loop are only recorded in the database
+        db.record_post_seen_with_current_time(1, &post("b"))
+            .unwrap();
+        assert!(seen.is_post_seen(&db, 1, &post("b")).await.unwrap());
+        assert!(seen.redis.sets.lock().unwrap()["tgreddit:seen:1"].contains("b"));
+    }
+
+    #[tokio::test]
+    async fn test_redis_seen_posts_backfill() {
+        let mut db = Database::open(&Config::default()).unwrap();
+        db.migrate().unwrap();
+        for (chat_id, id) in [(1, "a"), (1, "b"), (2, "c")] {
+            db.record_post_seen_with_current_time(chat_id, &post(id))
+                .unwrap();
+        }
+        // Not delivered yet
+        db.record_post(1, &post("pending"), None).unwrap();
+
+        let seen = RedisSeenPosts::new(FakeRedis::default());
+        seen.backfill(&db).await.unwrap();
+
+        for (chat_id, id) in [(1, "a"), (1, "b"), (2, "c")] {
+            assert!(seen
+                .redis
+                .is_member(&seen_key(chat_id), id, SEEN_TTL_SECS)
+                .await
+                .unwrap());
+        }
+        assert!(!seen.is_post_seen(&db, 1, &post("pending")).await.unwrap());
+        assert!(!seen.is_post_seen(&db, 2, &post("a")).await.unwrap());
+    }
+}
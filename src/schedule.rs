@@ -0,0 +1,479 @@
+use chrono::{DateTime, Duration, Utc};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Instant,
+};
+
+use crate::types::{PollState, Subscription};
+
+type SubscriptionKey = (i64, String);
+
+fn subscription_key(sub: &Subscription) -> SubscriptionKey {
+    (sub.chat_id, sub.subreddit.to_owned())
+}
+
+/// Keeps track of when each subscription should be polled next. Each subscription's next poll is
+/// offset by a random jitter so that requests to Reddit are spread out instead of being sent in a
+/// burst on the same tick.
+#[derive(Debug)]
+pub struct PollSchedule {
+    next_poll_at: HashMap<SubscriptionKey, DateTime<Utc>>,
+    idle_polls: HashMap<SubscriptionKey, u32>,
+    max_jitter: Duration,
+    first_poll_stagger: Duration,
+    aligned: bool,
+    persisted: bool,
+    rng: StdRng,
+}
+
+impl PollSchedule {
+    pub fn new(max_jitter: Duration, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        PollSchedule {
+            next_poll_at: HashMap::new(),
+            idle_polls: HashMap::new(),
+            max_jitter,
+            first_poll_stagger: Duration::zero(),
+            aligned: false,
+            persisted: true,
+            rng,
+        }
+    }
+
+    /// Schedules polls on the boundaries of their interval, see [`next_aligned_time`], instead of
+    /// an interval after the previous poll.
+    pub fn with_aligned_polls(self, aligned: bool) -> Self {
+        PollSchedule { aligned, ..self }
+    }
+
+    /// Whether polls with this schedule are stored as the subscriptions' poll state, see
+    /// [`PollSchedule::restore`]. Off for one-off polls, which don't affect when subscriptions
+    /// are polled next.
+    pub fn with_persistence(self, persisted: bool) -> Self {
+        PollSchedule { persisted, ..self }
+    }
+
+    pub fn is_persisted(&self) -> bool {
+        self.persisted
+    }
+
+    /// Delays the first poll of subscriptions that are not scheduled yet by a random time up to
+    /// `stagger`, so that many new subscriptions don't all poll at once.
+    pub fn with_first_poll_stagger(self, stagger: Duration) -> Self {
+        PollSchedule {
+            first_poll_stagger: stagger,
+            ..self
+        }
+    }
+
+    /// Schedules the first poll of a subscription that is not scheduled yet, spread over the
+    /// first poll stagger. Without a stagger the subscription stays due right away.
+    pub fn schedule_first_poll(&mut self, sub: &Subscription, now: DateTime<Utc>) {
+        let key = subscription_key(sub);
+        if self.next_poll_at.contains_key(&key) {
+            return;
+        }
+        let max_millis = self.first_poll_stagger.num_milliseconds();
+        if max_millis <= 0 {
+            return;
+        }
+        let delay = Duration::milliseconds(self.rng.gen_range(0..=max_millis));
+        self.next_poll_at.insert(key, now + delay);
+    }
+
+    /// Subscriptions that have not been polled yet are always due.
+    pub fn is_due(&self, sub: &Subscription, now: DateTime<Utc>) -> bool {
+        self.next_poll_at
+            .get(&subscription_key(sub))
+            .map(|next| *next <= now)
+            .unwrap_or(true)
+    }
+
+    /// Returns when the subscription is due next.
+    pub fn schedule_next(
+        &mut self,
+        sub: &Subscription,
+        now: DateTime<Utc>,
+        interval: Duration,
+    ) -> DateTime<Utc> {
+        let next_poll_at = if self.aligned {
+            next_aligned_time(now, interval)
+        } else {
+            now + interval
+        };
+        let next_poll_at = next_poll_at + self.jitter();
+        self.next_poll_at
+            .insert(subscription_key(sub), next_poll_at);
+        next_poll_at
+    }
+
+    /// Resumes the schedule of a subscription from before a restart.
+    pub fn restore(&mut self, sub: &Subscription, state: &PollState) {
+        let key = subscription_key(sub);
+        self.next_poll_at.insert(key.clone(), state.next_poll_at);
+        if state.idle_polls > 0 {
+            self.idle_polls.insert(key, state.idle_polls);
+        }
+    }
+
+    /// Forgets subscriptions that no longer exist.
+    pub fn retain(&mut self, subs: &[Subscription]) {
+        let keys = subs.iter().map(subscription_key).collect::<Vec<_>>();
+        self.next_poll_at.retain(|key, _| keys.contains(key));
+        self.idle_polls.retain(|key, _| keys.contains(key));
+    }
+
+    /// Counts consecutive polls of the subscription that found no new posts.
+    pub fn record_poll(&mut self, sub: &Subscription, found_new_posts: bool) {
+        let key = subscription_key(sub);
+        if found_new_posts {
+            self.idle_polls.remove(&key);
+        } else {
+            *self.idle_polls.entry(key).or_default() += 1;
+        }
+    }
+
+    pub fn idle_polls(&self, sub: &Subscription) -> u32 {
+        self.idle_polls
+            .get(&subscription_key(sub))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Time until the next subscription is due, if any are scheduled.
+    pub fn next_due_in(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.next_poll_at
+            .values()
+            .min()
+            .map(|next| (*next - now).max(Duration::zero()))
+    }
+
+    fn jitter(&mut self) -> Duration {
+        let max_millis = self.max_jitter.num_milliseconds();
+        if max_millis <= 0 {
+            return Duration::zero();
+        }
+        Duration::milliseconds(self.rng.gen_range(0..=max_millis))
+    }
+}
+
+/// The first multiple of `interval` since the Unix epoch after `now`, e.g. the next full ten
+/// minutes for an interval of ten minutes. A time on a boundary is followed by the next boundary.
+/// Intervals shorter than a millisecond are not aligned.
+pub fn next_aligned_time(now: DateTime<Utc>, interval: Duration) -> DateTime<Utc> {
+    let interval_millis = interval.num_milliseconds();
+    if interval_millis <= 0 {
+        return now + interval;
+    }
+    let now_millis = now.timestamp_millis();
+    let next_millis = (now_millis.div_euclid(interval_millis) + 1) * interval_millis;
+    now + Duration::milliseconds(next_millis - now_millis)
+}
+
+/// Window over which [`SendRateLimiter`] counts sent messages.
+const SEND_RATE_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Keeps track of the messages recently sent to each chat so that a burst of new posts is spread
+/// over several minutes instead of being sent at once.
+#[derive(Debug)]
+pub struct SendRateLimiter {
+    window: std::time::Duration,
+    sent_at: HashMap<i64, VecDeque<Instant>>,
+}
+
+impl Default for SendRateLimiter {
+    fn default() -> Self {
+        SendRateLimiter::new(SEND_RATE_WINDOW)
+    }
+}
+
+impl SendRateLimiter {
+    /// Counts the messages sent within `window` instead of a minute.
+    pub fn new(window: std::time::Duration) -> Self {
+        SendRateLimiter {
+            window,
+            sent_at: HashMap::new(),
+        }
+    }
+
+    /// Counts a message sent to the chat and returns true, or returns false if `max_per_minute`
+    /// messages were already sent to the chat within the last minute.
+    pub fn try_acquire(&mut self, chat_id: i64, now: Instant, max_per_minute: u32) -> bool {
+        let sent_at = self.sent_at.entry(chat_id).or_default();
+        while sent_at
+            .front()
+            .is_some_and(|sent| now.duration_since(*sent) >= self.window)
+        {
+            sent_at.pop_front();
+        }
+
+        if sent_at.len() >= max_per_minute as usize {
+            return false;
+        }
+        sent_at.push_back(now);
+        true
+    }
+}
+
+/// Grows the interval by `decay` for each consecutive poll that found no new posts, up to
+/// `max_interval`. A decay of 1 or less keeps the interval as it is.
+pub fn decayed_interval(
+    interval: Duration,
+    idle_polls: u32,
+    decay: f64,
+    max_interval: Duration,
+) -> Duration {
+    if decay <= 1.0 || idle_polls == 0 {
+        return interval;
+    }
+
+    let factor = decay.powi(idle_polls.min(i32::MAX as u32) as i32);
+    let millis = interval.num_milliseconds() as f64 * factor;
+    if !millis.is_finite() || millis >= max_interval.num_milliseconds() as f64 {
+        return max_interval.max(interval);
+    }
+    Duration::milliseconds(millis as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(subreddit: &str) -> Subscription {
+        Subscription {
+            chat_id: 1,
+            subreddit: subreddit.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_jitter_within_bound() {
+        let max_jitter = Duration::seconds(30);
+        let mut schedule = PollSchedule::new(max_jitter, Some(42));
+        for _ in 0..1000 {
+            let jitter = schedule.jitter();
+            assert!(jitter >= Duration::zero());
+            assert!(jitter <= max_jitter);
+        }
+
+        let mut schedule = PollSchedule::new(Duration::zero(), Some(42));
+        assert_eq!(schedule.jitter(), Duration::zero());
+    }
+
+    #[test]
+    fn test_jitter_is_deterministic_with_seed() {
+        let mut a = PollSchedule::new(Duration::seconds(30), Some(7));
+        let mut b = PollSchedule::new(Duration::seconds(30), Some(7));
+        for _ in 0..10 {
+            assert_eq!(a.jitter(), b.jitter());
+        }
+    }
+
+    #[test]
+    fn test_schedule_next() {
+        let now = Utc::now();
+        let interval = Duration::seconds(600);
+        let max_jitter = Duration::seconds(30);
+        let mut schedule = PollSchedule::new(max_jitter, Some(42));
+        let sub = subscription("foo");
+
+        assert!(schedule.is_due(&sub, now));
+        schedule.schedule_next(&sub, now, interval);
+        assert!(!schedule.is_due(&sub, now));
+        assert!(!schedule.is_due(&sub, now + interval - Duration::seconds(1)));
+        assert!(schedule.is_due(&sub, now + interval + max_jitter));
+
+        let next_due_in = schedule.next_due_in(now).unwrap();
+        assert!(next_due_in >= interval && next_due_in <= interval + max_jitter);
+
+        schedule.retain(&[subscription("bar")]);
+        assert!(schedule.is_due(&sub, now));
+        assert_eq!(schedule.next_due_in(now), None);
+    }
+
+    #[test]
+    fn test_next_aligned_time() {
+        use chrono::TimeZone;
+
+        let at = |h, m, s| Utc.with_ymd_and_hms(2023, 5, 1, h, m, s).unwrap();
+        let ten_minutes = Duration::minutes(10);
+
+        assert_eq!(next_aligned_time(at(12, 3, 20), ten_minutes), at(12, 10, 0));
+        assert_eq!(next_aligned_time(at(12, 9, 59), ten_minutes), at(12, 10, 0));
+        // On a boundary, the next one follows
+        assert_eq!(next_aligned_time(at(12, 10, 0), ten_minutes), at(12, 20, 0));
+        assert_eq!(
+            next_aligned_time(at(23, 55, 0), ten_minutes),
+            Utc.with_ymd_and_hms(2023, 5, 2, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            next_aligned_time(at(12, 0, 30), Duration::minutes(1)),
+            at(12, 1, 0)
+        );
+        assert_eq!(
+            next_aligned_time(at(12, 30, 0), Duration::hours(1)),
+            at(13, 0, 0)
+        );
+        // Sub-second times are aligned too
+        assert_eq!(
+            next_aligned_time(
+                at(12, 0, 59) + Duration::milliseconds(999),
+                Duration::minutes(1)
+            ),
+            at(12, 1, 0)
+        );
+        assert_eq!(
+            next_aligned_time(at(12, 3, 20), Duration::zero()),
+            at(12, 3, 20)
+        );
+
+        let mut schedule = PollSchedule::new(Duration::zero(), Some(42)).with_aligned_polls(true);
+        let sub = subscription("foo");
+        assert_eq!(
+            schedule.schedule_next(&sub, at(12, 3, 20), ten_minutes),
+            at(12, 10, 0)
+        );
+    }
+
+    #[test]
+    fn test_first_poll_stagger() {
+        let now = Utc::now();
+        let stagger = Duration::seconds(600);
+        let mut schedule =
+            PollSchedule::new(Duration::zero(), Some(42)).with_first_poll_stagger(stagger);
+        let subs = (0..200)
+            .map(|i| subscription(&format!("sub{i}")))
+            .collect::<Vec<_>>();
+        for sub in &subs {
+            schedule.schedule_first_poll(sub, now);
+        }
+
+        // Every first poll is within the stagger, and each tenth of it gets some of them
+        let mut buckets = [0; 10];
+        for sub in &subs {
+            let delay = schedule.next_poll_at[&subscription_key(sub)] - now;
+            assert!(delay >= Duration::zero() && delay <= stagger);
+            let bucket = (delay.num_milliseconds() * 10 / stagger.num_milliseconds()).min(9);
+            buckets[bucket as usize] += 1;
+        }
+        assert!(buckets.iter().all(|count| *count > 0), "{buckets:?}");
+        assert!(subs.iter().all(|sub| schedule.is_due(sub, now + stagger)));
+
+        // Scheduled subscriptions keep their next poll
+        let sub = subscription("scheduled");
+        let next_poll_at = schedule.schedule_next(&sub, now, Duration::seconds(60));
+        schedule.schedule_first_poll(&sub, now);
+        assert_eq!(schedule.next_poll_at[&subscription_key(&sub)], next_poll_at);
+
+        // Without a stagger, new subscriptions are due right away
+        let mut schedule = PollSchedule::new(Duration::zero(), Some(42));
+        schedule.schedule_first_poll(&subs[0], now);
+        assert!(schedule.is_due(&subs[0], now));
+    }
+
+    #[test]
+    fn test_decayed_interval() {
+        let interval = Duration::minutes(10);
+        let max_interval = Duration::hours(24);
+
+        assert_eq!(decayed_interval(interval, 0, 2.0, max_interval), interval);
+        assert_eq!(
+            decayed_interval(interval, 1, 2.0, max_interval),
+            Duration::minutes(20)
+        );
+        assert_eq!(
+            decayed_interval(interval, 3, 2.0, max_interval),
+            Duration::minutes(80)
+        );
+        assert_eq!(
+            decayed_interval(interval, 3, 1.5, max_interval),
+            Duration::seconds(2025)
+        );
+        assert_eq!(
+            decayed_interval(interval, 20, 2.0, max_interval),
+            max_interval
+        );
+        assert_eq!(
+            decayed_interval(interval, u32::MAX, 2.0, max_interval),
+            max_interval
+        );
+        // Decay disabled
+        assert_eq!(decayed_interval(interval, 5, 1.0, max_interval), interval);
+        // The maximum never shortens the interval
+        assert_eq!(
+            decayed_interval(interval, 5, 2.0, Duration::minutes(5)),
+            interval
+        );
+    }
+
+    #[test]
+    fn test_send_rate_limiter_drains_burst() {
+        let start = Instant::now();
+        let mut limiter = SendRateLimiter::default();
+        let mut queue = (0..5).collect::<VecDeque<_>>();
+        let mut sent = vec![];
+
+        // Sends what the limit allows from the queue, leaving the rest for a later tick
+        let mut drain = |limiter: &mut SendRateLimiter, now| {
+            while let Some(post) = queue.front().copied() {
+                if !limiter.try_acquire(1, now, 2) {
+                    break;
+                }
+                queue.pop_front();
+                sent.push(post);
+            }
+            sent.len()
+        };
+
+        assert_eq!(drain(&mut limiter, start), 2);
+        assert_eq!(drain(&mut limiter, start + SEND_RATE_WINDOW / 2), 2);
+        // Other chats have their own limit
+        assert!(limiter.try_acquire(2, start, 2));
+        assert_eq!(drain(&mut limiter, start + SEND_RATE_WINDOW), 4);
+        assert_eq!(drain(&mut limiter, start + SEND_RATE_WINDOW * 2), 5);
+        assert_eq!(sent, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_restore() {
+        let now = Utc::now();
+        let mut schedule = PollSchedule::new(Duration::zero(), Some(42));
+        let sub = subscription("foo");
+        schedule.restore(
+            &sub,
+            &PollState {
+                last_polled_at: now - Duration::minutes(5),
+                next_poll_at: now + Duration::minutes(5),
+                idle_polls: 3,
+            },
+        );
+
+        assert!(!schedule.is_due(&sub, now));
+        assert!(schedule.is_due(&sub, now + Duration::minutes(5)));
+        assert_eq!(schedule.idle_polls(&sub), 3);
+    }
+
+    #[test]
+    fn test_record_poll() {
+        let mut schedule = PollSchedule::new(Duration::zero(), Some(42));
+        let sub = subscription("foo");
+
+        assert_eq!(schedule.idle_polls(&sub), 0);
+        schedule.record_poll(&sub, true);
+        assert_eq!(schedule.idle_polls(&sub), 0);
+        schedule.record_poll(&sub, false);
+        schedule.record_poll(&sub, false);
+        assert_eq!(schedule.idle_polls(&sub), 2);
+        schedule.record_poll(&sub, true);
+        assert_eq!(schedule.idle_polls(&sub), 0);
+
+        schedule.record_poll(&sub, false);
+        schedule.retain(&[]);
+        assert_eq!(schedule.idle_polls(&sub), 0);
+    }
+}
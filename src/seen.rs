@@ -0,0 +1,51 @@
+use anyhow::Result;
+
+use crate::{config::Config, db::Database, reddit::Post};
+
+/// Keeps track of the posts already delivered to each chat. Posts are recorded in the database,
+/// and checked in Redis first when `redis_url` is configured.
+#[derive(Clone, Default)]
+pub struct SeenPosts {
+    #[cfg(feature = "redis")]
+    redis: Option<crate::redis::RedisSeenPosts<::redis::aio::ConnectionManager>>,
+}
+
+impl SeenPosts {
+    /// Connects to Redis if it is configured, copying the posts seen according to the database
+    /// to it. Meant to be called once at startup.
+    #[cfg_attr(not(feature = "redis"), allow(unused_variables))]
+    pub async fn open(config: &Config, db: &Database) -> Result<Self> {
+        #[cfg(feature = "redis")]
+        if let Some(url) = &config.redis_url {
+            let redis = crate::redis::RedisSeenPosts::connect(url.expose_secret(), db).await?;
+            return Ok(SeenPosts { redis: Some(redis) });
+        }
+        Ok(SeenPosts::default())
+    }
+
+    pub async fn is_post_seen(&self, db: &Database, chat_id: i64, post: &Post) -> Result<bool> {
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            return redis.is_post_seen(db, chat_id, post).await;
+        }
+        db.is_post_seen(chat_id, post)
+    }
+
+    pub async fn record_post_seen(&self, db: &Database, chat_id: i64, post: &Post) -> Result<()> {
+        #[cfg(feature = "redis")]
+        if let Some(redis) = &self.redis {
+            return redis.record_post_seen(db, chat_id, post).await;
+        }
+        db.record_post_seen_with_current_time(chat_id, post)
+    }
+}
+
+impl std::fmt::Debug for SeenPosts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        #[cfg(feature = "redis")]
+        let redis = self.redis.is_some();
+        #[cfg(not(feature = "redis"))]
+        let redis = false;
+        f.debug_struct("SeenPosts").field("redis", &redis).finish()
+    }
+}
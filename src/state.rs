@@ -0,0 +1,257 @@
+use chrono::{DateTime, Utc};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+
+use crate::{
+    metrics::{self, CycleSummary, FetchMetrics},
+    reddit::RateLimitStatus,
+    schedule::SendRateLimiter,
+    seen::SeenPosts,
+    PendingPost,
+};
+
+/// Runtime state shared between the poll loop and the bot.
+#[derive(Debug)]
+pub struct BotState {
+    pub started_at: DateTime<Utc>,
+    /// Identifies this process when claiming subscriptions in a database shared by several
+    /// instances.
+    pub instance_id: String,
+    pub seen_posts: SeenPosts,
+    last_poll_at: Mutex<Option<DateTime<Utc>>>,
+    last_cycle: Mutex<Option<CycleSummary>>,
+    fetch_metrics: Mutex<FetchMetrics>,
+    reddit_rate_limit: Mutex<Option<RateLimitStatus>>,
+    send_limiter: Mutex<SendRateLimiter>,
+    /// Posts the send rate limit kept from being sent, by chat in the order they are to be sent.
+    deferred_posts: Mutex<HashMap<i64, Vec<PendingPost>>>,
+    /// The last send queued for each chat. Each queued send waits for the one queued before it.
+    queued_sends: Mutex<HashMap<i64, JoinHandle<()>>>,
+    poll_in_progress: AtomicBool,
+}
+
+/// Marks a poll cycle as in progress until dropped.
+#[derive(Debug)]
+pub struct PollGuard {
+    state: Arc<BotState>,
+}
+
+impl Drop for PollGuard {
+    fn drop(&mut self) {
+        self.state.poll_in_progress.store(false, Ordering::Release);
+    }
+}
+
+impl BotState {
+    pub fn new() -> Self {
+        BotState {
+            started_at: Utc::now(),
+            instance_id: format!("{}-{:08x}", std::process::id(), rand::random::<u32>()),
+            seen_posts: SeenPosts::default(),
+            last_poll_at: Mutex::new(None),
+            last_cycle: Mutex::new(None),
+            fetch_metrics: Mutex::new(FetchMetrics::default()),
+            reddit_rate_limit: Mutex::new(None),
+            send_limiter: Mutex::new(SendRateLimiter::default()),
+            deferred_posts: Mutex::new(HashMap::new()),
+            queued_sends: Mutex::new(HashMap::new()),
+            poll_in_progress: AtomicBool::new(false),
+        }
+    }
+
+    pub fn with_seen_posts(mut self, seen_posts: SeenPosts) -> Self {
+        self.seen_posts = seen_posts;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_send_limiter(mut self, send_limiter: SendRateLimiter) -> Self {
+        self.send_limiter = Mutex::new(send_limiter);
+        self
+    }
+
+    /// Starts a poll cycle, or returns `None` if another cycle is in progress. Scheduled cycles
+    /// and those requested with /pollnow never overlap.
+    pub fn try_start_poll(self: &Arc<Self>) -> Option<PollGuard> {
+        self.poll_in_progress
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| PollGuard {
+                state: self.clone(),
+            })
+    }
+
+    pub fn record_successful_poll(&self) {
+        *self.last_poll_at.lock().expect("No poison") = Some(Utc::now());
+    }
+
+    pub fn last_poll_at(&self) -> Option<DateTime<Utc>> {
+        *self.last_poll_at.lock().expect("No poison")
+    }
+
+    pub fn record_fetch(&self, subreddit: &str, duration: Duration) {
+        self.fetch_metrics
+            .lock()
+            .expect("No poison")
+            .observe(subreddit, duration);
+    }
+
+    pub fn record_cycle(&self, summary: CycleSummary) {
+        *self.last_cycle.lock().expect("No poison") = Some(summary);
+    }
+
+    pub fn last_cycle(&self) -> Option<CycleSummary> {
+        self.last_cycle.lock().expect("No poison").clone()
+    }
+
+    /// Whether a message may be sent to the chat now without exceeding `max_per_minute`. Sending
+    /// is never limited without a maximum.
+    pub fn try_acquire_send(&self, chat_id: i64, max_per_minute: Option<u32>) -> bool {
+        max_per_minute.map_or(true, |max| {
+            self.send_limiter
+                .lock()
+                .expect("No poison")
+                .try_acquire(chat_id, Instant::now(), max)
+        })
+    }
+
+    /// Queues a post the send rate limit kept from being sent, to be sent before the chat's new
+    /// posts on a later cycle. A post already queued for the chat is not queued again.
+    pub fn defer_post(&self, chat_id: i64, pending: PendingPost) {
+        let mut deferred_posts = self.deferred_posts.lock().expect("No poison");
+        let deferred = deferred_posts.entry(chat_id).or_default();
+        if !deferred
+            .iter()
+            .any(|deferred| deferred.post.id == pending.post.id)
+        {
+            deferred.push(pending);
+        }
+    }
+
+    /// Removes and returns the posts deferred for the chat, in the order they are to be sent.
+    pub fn take_deferred_posts(&self, chat_id: i64) -> Vec<PendingPost> {
+        self.deferred_posts
+            .lock()
+            .expect("No poison")
+            .remove(&chat_id)
+            .unwrap_or_default()
+    }
+
+    pub fn chats_with_deferred_posts(&self) -> Vec<i64> {
+        self.deferred_posts
+            .lock()
+            .expect("No poison")
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    /// Runs `send` in the background once the sends queued for the chat before it have finished,
+    /// so that the chat's messages arrive in the order they were queued.
+    pub fn queue_send(&self, chat_id: i64, send: impl Future<Output = ()> + Send + 'static) {
+        let mut queued_sends = self.queued_sends.lock().expect("No poison");
+        queued_sends.retain(|_, queued| !queued.is_finished());
+        let previous = queued_sends.remove(&chat_id);
+        let queued = tokio::spawn(async move {
+            if let Some(previous) = previous {
+                // A send that panicked doesn't keep the later ones from being sent
+                let _ = previous.await;
+            }
+            send.await;
+        });
+        queued_sends.insert(chat_id, queued);
+    }
+
+    /// Whether sends queued for the chat are yet to finish.
+    pub fn has_queued_sends(&self, chat_id: i64) -> bool {
+        self.queued_sends
+            .lock()
+            .expect("No poison")
+            .get(&chat_id)
+            .is_some_and(|queued| !queued.is_finished())
+    }
+
+    /// Waits for every send queued so far to finish.
+    pub async fn finish_queued_sends(&self) {
+        let queued_sends = std::mem::take(&mut *self.queued_sends.lock().expect("No poison"));
+        for (_, queued) in queued_sends {
+            let _ = queued.await;
+        }
+    }
+
+    /// Keeps the rate limit Reddit reported to the poll loop's client, if it reported one.
+    pub fn record_reddit_rate_limit(&self, status: Option<RateLimitStatus>) {
+        if let Some(status) = status {
+            *self.reddit_rate_limit.lock().expect("No poison") = Some(status);
+        }
+    }
+
+    pub fn reddit_rate_limit(&self) -> Option<RateLimitStatus> {
+        self.reddit_rate_limit.lock().expect("No poison").clone()
+    }
+
+    /// The slowest subreddits to fetch, see `FetchMetrics::digest`.
+    pub fn fetch_digest(&self, limit: usize) -> String {
+        self.fetch_metrics.lock().expect("No poison").digest(limit)
+    }
+
+    pub fn render_metrics(&self) -> String {
+        let fetch_metrics = self.fetch_metrics.lock().expect("No poison").render();
+        match self.reddit_rate_limit() {
+            Some(status) => format!(
+                "{fetch_metrics}\n{}",
+                metrics::render_rate_limit(&status, Utc::now())
+            ),
+            None => fetch_metrics,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_start_poll() {
+        let state = Arc::new(BotState::new());
+        let guard = state.try_start_poll().unwrap();
+        assert!(state.try_start_poll().is_none());
+        drop(guard);
+
+        let guard = state.try_start_poll();
+        assert!(guard.is_some());
+        assert!(state.try_start_poll().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_queued_sends_keep_chat_order() {
+        let state = BotState::new();
+        let sent = Arc::new(Mutex::new(vec![]));
+        let send = |name: &'static str, delay_ms: u64| {
+            let sent = sent.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+                sent.lock().unwrap().push(name);
+            }
+        };
+
+        // Queueing doesn't wait for the sends
+        state.queue_send(1, send("1a", 50));
+        state.queue_send(2, send("2a", 0));
+        state.queue_send(1, send("1b", 0));
+        assert!(state.has_queued_sends(1));
+        assert!(sent.lock().unwrap().is_empty());
+
+        state.finish_queued_sends().await;
+        assert_eq!(*sent.lock().unwrap(), vec!["2a", "1a", "1b"]);
+        assert!(!state.has_queued_sends(1));
+    }
+}
@@ -1,12 +1,16 @@
 use serde_derive::{Deserialize, Serialize};
+use strum_macros::{Display, EnumIter, EnumString};
 use tempdir::TempDir;
 
 use crate::{
-    db::Recordable,
-    reddit::{PostType, TopPostsTimePeriod},
+    db::{Recordable, SubscribeError},
+    reddit::{ListingSort, Post, PostType, TopPostsTimePeriod},
 };
 use std::path::PathBuf;
 
+/// Recorded as the subreddit of videos downloaded from links sent to the bot.
+pub const VIDEO_LINK_SUBREDDIT: &str = "youtube download";
+
 #[derive(Debug)]
 pub struct Video {
     pub path: PathBuf,
@@ -15,6 +19,8 @@ pub struct Video {
     pub title: String,
     pub width: u16,
     pub height: u16,
+    // Only held so the downloaded file lives as long as the Video
+    #[allow(dead_code)]
     pub video_tempdir: TempDir,
 }
 
@@ -28,25 +34,440 @@ impl Recordable for Video {
     }
 
     fn subreddit(&self) -> &str {
-        "youtube download"
+        VIDEO_LINK_SUBREDDIT
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Predefined formula a subscription's posts are ranked by. `age` is the age of the post in hours,
+/// but at least an hour, so that brand new posts don't get an outsized rank.
+#[derive(
+    Display, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumString, EnumIter,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Ranking {
+    /// `score`
+    Score,
+    /// `score * upvote_ratio`, which favors uncontroversial posts.
+    Ratio,
+    /// `score / age`, which favors posts gaining upvotes quickly.
+    Velocity,
+    /// `score * upvote_ratio / age`
+    Quality,
+}
+
+impl Ranking {
+    pub fn description(&self) -> &'static str {
+        match self {
+            Ranking::Score => "score",
+            Ranking::Ratio => "score * upvote_ratio",
+            Ranking::Velocity => "score / age in hours",
+            Ranking::Quality => "score * upvote_ratio / age in hours",
+        }
+    }
+
+    /// Rank of the post at `now`, higher is better.
+    pub fn rank(&self, post: &Post, now: chrono::DateTime<chrono::Utc>) -> f64 {
+        let score = f64::from(post.ups);
+        let age_hours = ((now.timestamp() as f64 - post.created_utc) / 3600.0).max(1.0);
+        match self {
+            Ranking::Score => score,
+            Ranking::Ratio => score * post.upvote_ratio,
+            Ranking::Velocity => score / age_hours,
+            Ranking::Quality => score * post.upvote_ratio / age_hours,
+        }
+    }
+}
+
+/// Order in which a poll cycle's new posts of a subscription are sent.
+#[derive(
+    Display, Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SendOrder {
+    /// In listing order, which for top posts is highest score first.
+    #[default]
+    TopFirst,
+    /// Oldest post first.
+    Chronological,
+}
+
+/// Filters of a subscription as stored in the `filters_json` column, so that adding a filter
+/// doesn't need a schema change. New filters must have a default so that stored filters without
+/// them can still be read.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilterConfig {
+    #[serde(rename = "type")]
+    pub post_type: Option<PostType>,
+    pub min_score: Option<u32>,
+    pub min_upvote_ratio: Option<f64>,
+    pub top_percentile: Option<u8>,
+    pub rank: Option<Ranking>,
+    pub min_rank: Option<f64>,
+    pub dedup_titles: bool,
+    pub oc_only: bool,
+    pub media_only: bool,
+    pub text_only: bool,
+    pub catch_up: Option<u32>,
+    pub nsfw: bool,
+    pub top_comment: bool,
+    pub blocked_authors: Vec<String>,
+}
+
+impl From<&SubscriptionArgs> for FilterConfig {
+    fn from(args: &SubscriptionArgs) -> Self {
+        FilterConfig {
+            post_type: args.filter,
+            min_score: args.min_score,
+            min_upvote_ratio: args.min_upvote_ratio,
+            top_percentile: args.top_percentile,
+            rank: args.rank,
+            min_rank: args.min_rank,
+            dedup_titles: args.dedup_titles,
+            oc_only: args.oc_only,
+            media_only: args.media_only,
+            text_only: args.text_only,
+            catch_up: args.catch_up,
+            nsfw: args.allow_nsfw,
+            top_comment: args.top_comment,
+            blocked_authors: args.blocked_authors.clone(),
+        }
+    }
+}
+
+/// What is shown with a link post in a chat.
+#[derive(Display, Debug, Default, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum WebPreview {
+    /// The post's thumbnail as a photo, or Telegram's preview of the link if there is none.
+    #[default]
+    Thumbnail,
+    /// Telegram's preview of the link.
+    Preview,
+    /// Only the link.
+    None,
+}
+
+/// How posts marked as a spoiler on Reddit are sent.
+#[derive(Display, Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, EnumString)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum RedditSpoilers {
+    /// Like any other post.
+    #[default]
+    Ignore,
+    /// With a warning before the title.
+    MarkCaption,
+    /// With the media covered by Telegram's spoiler effect.
+    SpoilerMedia,
+}
+
+/// Markup of the captions and messages posts are sent with in a chat.
+#[derive(Display, Debug, Default, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum ParseMode {
+    #[default]
+    Html,
+    MarkdownV2,
+    /// Plain text, with links spelled out.
+    None,
+}
+
+impl ParseMode {
+    /// The parse mode to send messages with, `None` for plain text.
+    pub fn telegram_parse_mode(self) -> Option<teloxide::types::ParseMode> {
+        match self {
+            ParseMode::Html => Some(teloxide::types::ParseMode::Html),
+            ParseMode::MarkdownV2 => Some(teloxide::types::ParseMode::MarkdownV2),
+            ParseMode::None => None,
+        }
+    }
+}
+
+/// Order of a chat's subscriptions in /listsubs.
+#[derive(Display, Debug, Default, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum SubscriptionSort {
+    /// Alphabetically by subreddit, ignoring case.
+    #[default]
+    Name,
+    /// Oldest subscription first.
+    Created,
+    /// Most delivered posts first.
+    Delivered,
+    /// Most consecutive failed checks first.
+    Errors,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
 pub struct Subscription {
     pub chat_id: i64,
     pub subreddit: String,
     pub limit: Option<u32>,
+    pub sort: Option<ListingSort>,
     pub time: Option<TopPostsTimePeriod>,
+    #[serde(rename = "type")]
     pub filter: Option<PostType>,
+    pub min_score: Option<u32>,
+    pub min_upvote_ratio: Option<f64>,
+    pub top_percentile: Option<u8>,
+    pub rank: Option<Ranking>,
+    pub min_rank: Option<f64>,
+    pub dedup_titles: bool,
+    pub oc_only: bool,
+    pub media_only: bool,
+    /// Posts are sent as text, without fetching their media.
+    pub text_only: bool,
+    /// How many of the current posts the first check sends, instead of only marking them seen.
+    pub catch_up: Option<u32>,
+    pub send_order: SendOrder,
+    pub allow_nsfw: bool,
+    pub top_comment: bool,
+    pub blocked_authors: Vec<String>,
+    /// Forum topic the posts are sent to, or the chat's general topic if `None`.
+    pub message_thread_id: Option<i32>,
+    /// Label for organizing the chat's subscriptions. Doesn't affect delivery.
+    pub group: Option<String>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SubscriptionArgs {
     pub subreddit: String,
     pub limit: Option<u32>,
+    pub sort: Option<ListingSort>,
     pub time: Option<TopPostsTimePeriod>,
+    #[serde(rename = "type", alias = "filter")]
     pub filter: Option<PostType>,
+    pub min_score: Option<u32>,
+    pub min_upvote_ratio: Option<f64>,
+    pub top_percentile: Option<u8>,
+    pub rank: Option<Ranking>,
+    pub min_rank: Option<f64>,
+    pub dedup_titles: bool,
+    pub oc_only: bool,
+    pub media_only: bool,
+    pub text_only: bool,
+    pub catch_up: Option<u32>,
+    pub send_order: SendOrder,
+    pub allow_nsfw: bool,
+    pub top_comment: bool,
+    pub blocked_authors: Vec<String>,
+}
+
+impl From<&Subscription> for SubscriptionArgs {
+    fn from(sub: &Subscription) -> Self {
+        SubscriptionArgs {
+            subreddit: sub.subreddit.clone(),
+            limit: sub.limit,
+            sort: sub.sort,
+            time: sub.time,
+            filter: sub.filter,
+            min_score: sub.min_score,
+            min_upvote_ratio: sub.min_upvote_ratio,
+            top_percentile: sub.top_percentile,
+            rank: sub.rank,
+            min_rank: sub.min_rank,
+            dedup_titles: sub.dedup_titles,
+            oc_only: sub.oc_only,
+            media_only: sub.media_only,
+            text_only: sub.text_only,
+            catch_up: sub.catch_up,
+            send_order: sub.send_order,
+            allow_nsfw: sub.allow_nsfw,
+            top_comment: sub.top_comment,
+            blocked_authors: sub.blocked_authors.clone(),
+        }
+    }
+}
+
+impl Subscription {
+    /// Summarizes the options of the subscription, e.g. "top/week, limit 10, type image, min score
+    /// 500, NSFW off". Options that are not set are left out, except for NSFW.
+    pub fn describe(&self) -> String {
+        let mut parts = vec![match (self.sort.unwrap_or_default(), self.time) {
+            (ListingSort::Top, Some(time)) => format!("top/{time}"),
+            (sort, _) => sort.to_string(),
+        }];
+        if let Some(limit) = self.limit {
+            parts.push(format!("limit {limit}"));
+        }
+        if let Some(filter) = self.filter {
+            parts.push(format!("type {filter}"));
+        }
+        if let Some(min_score) = self.min_score {
+            parts.push(format!("min score {min_score}"));
+        }
+        if let Some(min_upvote_ratio) = self.min_upvote_ratio {
+            parts.push(format!("min upvote ratio {min_upvote_ratio}"));
+        }
+        if let Some(top_percentile) = self.top_percentile {
+            parts.push(format!("top {top_percentile}% of listing"));
+        }
+        if let Some(rank) = self.rank {
+            parts.push(format!("ranked by {rank}"));
+        }
+        if let Some(min_rank) = self.min_rank {
+            parts.push(format!("min rank {min_rank}"));
+        }
+        if self.oc_only {
+            parts.push("OC only".to_string());
+        }
+        if self.media_only {
+            parts.push("media only".to_string());
+        }
+        if self.text_only {
+            parts.push("text only".to_string());
+        }
+        if let Some(catch_up) = self.catch_up {
+            parts.push(format!("catch up {catch_up}"));
+        }
+        if self.dedup_titles {
+            parts.push("similar titles skipped".to_string());
+        }
+        if !self.blocked_authors.is_empty() {
+            parts.push(format!(
+                "authors blocked: {}",
+                self.blocked_authors.join(", ")
+            ));
+        }
+        if self.top_comment {
+            parts.push("top comment included".to_string());
+        }
+        if self.send_order == SendOrder::Chronological {
+            parts.push("oldest first".to_string());
+        }
+        parts.push(format!(
+            "NSFW {}",
+            if self.allow_nsfw { "on" } else { "off" }
+        ));
+        if let Some(message_thread_id) = self.message_thread_id {
+            parts.push(format!("topic {message_thread_id}"));
+        }
+        if let Some(group) = &self.group {
+            parts.push(format!("group {group}"));
+        }
+        parts.join(", ")
+    }
+}
+
+/// Part of the caption of a delivered post. Enabled components are shown in the order of the
+/// variants, regardless of the order they were configured in.
+#[derive(
+    Display, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, EnumString, EnumIter,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum CaptionComponent {
+    Title,
+    Flair,
+    Score,
+    Author,
+    Subreddit,
+    Comments,
+    /// The post's subreddit as a hashtag, so that posts in a channel can be searched by subreddit.
+    Hashtag,
+}
+
+/// Components of captions in chats that have not configured them.
+pub const DEFAULT_CAPTION_COMPONENTS: &[CaptionComponent] = &[
+    CaptionComponent::Title,
+    CaptionComponent::Subreddit,
+    CaptionComponent::Comments,
+];
+
+/// A post delivered to a chat.
+#[derive(Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub post_id: String,
+    pub subreddit: String,
+    pub title: String,
+    pub seen_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// What is stored about a post recorded for a chat.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PostRecord {
+    pub post_id: String,
+    pub subreddit: String,
+    pub title: String,
+    /// When the post was sent, or `None` if it is yet to be sent.
+    pub seen_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Reddit's fullname of the post, e.g. `t3_abc123`, for posts recorded since it is stored.
+    pub name: Option<String>,
+}
+
+/// When a subscription was last polled and is due next, kept across restarts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PollState {
+    pub last_polled_at: chrono::DateTime<chrono::Utc>,
+    pub next_poll_at: chrono::DateTime<chrono::Utc>,
+    /// Consecutive polls that found no new posts.
+    pub idle_polls: u32,
+}
+
+/// What copying a chat's subscriptions to another chat did.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ChatCopy {
+    pub added: usize,
+    /// Subreddits that could not be added, with the reason.
+    pub skipped: Vec<(String, String)>,
+}
+
+/// A post recorded for a chat but not sent yet.
+#[derive(Debug, PartialEq, Eq)]
+pub struct QueuedPost {
+    pub post_id: String,
+    pub subreddit: String,
+    pub title: String,
+}
+
+/// How many of the posts a subreddit delivered to a chat had the same content as a post delivered
+/// earlier from another subreddit.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DuplicateStats {
+    pub subreddit: String,
+    pub duplicate_count: u32,
+    pub post_count: u32,
+}
+
+/// A subscription whose latest checks have failed.
+#[derive(Debug, PartialEq, Eq)]
+pub struct FailingSubscription {
+    pub chat_id: i64,
+    pub subreddit: String,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+}
+
+/// Result of subscribing a chat to a subreddit with /sub or /subscribe_many.
+#[derive(Debug)]
+pub enum SubscribeOutcome {
+    Subscribed,
+    Rejected(SubscribeError),
+    NoSuchSubreddit,
+    Frontpage,
+}
+
+/// What the bot may do in a chat, as reported by Telegram's getChatMember.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BotPermissions {
+    pub can_send_messages: bool,
+    pub can_send_media: bool,
+    /// Telegram's names of the rights the bot lacks to send posts, e.g. `can_post_messages`.
+    pub missing: Vec<&'static str>,
+}
+
+#[derive(Debug)]
+pub struct BotStatus {
+    pub version: &'static str,
+    pub uptime: chrono::Duration,
+    pub last_poll_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub subscription_count: usize,
+    /// Posts sent to the chat the status was requested in.
+    pub chat_post_count: u64,
+    pub schema_version: i64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -59,3 +480,96 @@ pub struct ButtonCallbackData {
     #[serde(rename = "d")]
     pub is_gallery: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_config_serialization() {
+        let filters = FilterConfig {
+            post_type: Some(PostType::SelfText),
+            min_score: Some(500),
+            min_upvote_ratio: Some(0.75),
+            top_percentile: None,
+            rank: None,
+            min_rank: None,
+            dedup_titles: true,
+            oc_only: false,
+            media_only: false,
+            text_only: false,
+            catch_up: None,
+            nsfw: true,
+            top_comment: false,
+            blocked_authors: vec!["spammer".to_string(), "[deleted]".to_string()],
+        };
+        let json = serde_json::to_string(&filters).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"self_text","min_score":500,"min_upvote_ratio":0.75,"top_percentile":null,"rank":null,"min_rank":null,"dedup_titles":true,"oc_only":false,"media_only":false,"text_only":false,"catch_up":null,"nsfw":true,"top_comment":false,"blocked_authors":["spammer","[deleted]"]}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<FilterConfig>(&json).unwrap(),
+            filters
+        );
+
+        let json = serde_json::to_string(&FilterConfig::default()).unwrap();
+        assert_eq!(
+            serde_json::from_str::<FilterConfig>(&json).unwrap(),
+            FilterConfig::default()
+        );
+
+        // Filters missing from stored JSON get their default, unknown ones are ignored
+        assert_eq!(
+            serde_json::from_str::<FilterConfig>(r#"{"min_score":10,"some_future_filter":1}"#)
+                .unwrap(),
+            FilterConfig {
+                min_score: Some(10),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_subscription_describe() {
+        let sub = Subscription {
+            chat_id: 1,
+            subreddit: "pics".to_string(),
+            limit: Some(10),
+            sort: None,
+            time: Some(TopPostsTimePeriod::Week),
+            filter: Some(PostType::Image),
+            min_score: Some(500),
+            min_upvote_ratio: None,
+            top_percentile: None,
+            rank: None,
+            min_rank: None,
+            dedup_titles: true,
+            oc_only: true,
+            media_only: false,
+            text_only: false,
+            catch_up: None,
+            send_order: SendOrder::Chronological,
+            allow_nsfw: true,
+            top_comment: false,
+            blocked_authors: vec!["spammer".to_string(), "reposter".to_string()],
+            message_thread_id: Some(7),
+            group: None,
+        };
+        assert_eq!(
+            sub.describe(),
+            "top/week, limit 10, type image, min score 500, OC only, similar titles skipped, \
+             authors blocked: spammer, reposter, oldest first, NSFW on, topic 7"
+        );
+
+        let sub = Subscription {
+            chat_id: 1,
+            subreddit: "pics".to_string(),
+            time: Some(TopPostsTimePeriod::Week),
+            limit: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(sub.describe(), "top/week, limit 10, NSFW off");
+        assert_eq!(Subscription::default().describe(), "top, NSFW off");
+    }
+}